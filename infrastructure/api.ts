@@ -13,37 +13,81 @@ export const apiGateway = new sst.aws.ApiGatewayV1('regionalRestAPI', {
 	cors: true
 });
 
+const parquetDeadLetterQueue = new sst.aws.Queue(`parqueCreationDeadLetterQueue`, {
+	transform: {
+		queue: { name: `${$app.stage}-parque-creation-dead-letter`, messageRetentionSeconds: 1209600 }
+	}
+});
+
+// A conversion too large for one execution is redelivered each time it runs out of time
+// and resumes from its checkpoint, so this allows far more deliveries than failures. The
+// processor stops a job after PARQUET_MAX_ATTEMPTS executions in a row that saved nothing.
 const parquetQueue = new sst.aws.Queue(`parqueCreationProcessorQueue`, {
 	visibilityTimeout: '500 seconds',
+	dlq: { queue: parquetDeadLetterQueue.arn, retry: 20 },
 	transform: {
 		queue: { name: `${$app.stage}-parque-creation-processor`, receiveWaitTimeSeconds: 20 }
 	}
 });
 
+// Signs completion webhooks; see notifications.rs
+const webhookSigningSecret = new sst.Secret('WebhookSigningSecret');
+
+// Small files skip the big processor's queue; see PARQUET_FAST_PATH_MAX_BYTES
+const parquetFastQueue = new sst.aws.Queue(`parqueCreationFastQueue`, {
+	visibilityTimeout: '120 seconds',
+	dlq: { queue: parquetDeadLetterQueue.arn, retry: 3 },
+	transform: {
+		queue: { name: `${$app.stage}-parque-creation-fast`, receiveWaitTimeSeconds: 20 }
+	}
+});
+
 apiGateway.route('POST /parquet-creation', {
 	handler: './.parquet-creation',
 	runtime: 'rust',
 	memory: '128 MB',
 	logging: { logGroup: `${$app.stage}-create-test-parquet` },
 	environment: {
+		S3_UPLOAD_BUCKET_NAME: s3Bucket.name,
 		DYNAMODB_NAME: dynamoTable.name,
-		PARQUET_QUEUE_URL: parquetQueue.url
+		PARQUET_QUEUE_URL: parquetQueue.url,
+		PARQUET_FAST_QUEUE_URL: parquetFastQueue.url,
+		PARQUET_FAST_PATH_MAX_BYTES: String(10 * 1024 * 1024),
+		TRACE_EXPORTER: 'xray'
 	},
 	permissions: [
 		{
-			actions: ['dynamodb:PutItem'],
+			actions: [
+				'dynamodb:PutItem',
+				'dynamodb:GetItem',
+				'dynamodb:UpdateItem',
+				'dynamodb:DeleteItem',
+				'dynamodb:Query',
+				'dynamodb:BatchWriteItem'
+			],
 			effect: 'allow',
 			resources: [dynamoTable.arn]
 		},
+		{
+			actions: ['s3:GetObject'],
+			effect: 'allow',
+			resources: [s3Bucket.arn, s3Bucket.arn.apply((arn) => `${arn}/*`)]
+		},
 		{
 			actions: ['sqs:SendMessage'],
 			effect: 'allow',
-			resources: [parquetQueue.arn]
+			resources: [parquetQueue.arn, parquetFastQueue.arn]
+		},
+		{
+			actions: ['xray:PutTraceSegments', 'xray:PutTelemetryRecords'],
+			effect: 'allow',
+			resources: ['*']
 		}
 	],
 	transform: {
 		function: {
-			name: `${$app.stage}-create-parquet`
+			name: `${$app.stage}-create-parquet`,
+			tracingConfig: { mode: 'Active' }
 		}
 	}
 });
@@ -54,53 +98,256 @@ const parquetProcessorLambda = new sst.aws.Function(`createParquetProcessor`, {
 	memory: '3008 MB',
 	timeout: '500 seconds',
 	logging: { logGroup: `${$app.stage}-create-parquet-processor` },
-	environment: { S3_UPLOAD_BUCKET_NAME: s3Bucket.name, DYNAMODB_NAME: dynamoTable.name },
+	environment: {
+		S3_UPLOAD_BUCKET_NAME: s3Bucket.name,
+		DYNAMODB_NAME: dynamoTable.name,
+		PARQUET_QUEUE_URL: parquetQueue.url,
+		PARQUET_DLQ_URL: parquetDeadLetterQueue.url,
+		// Matches the queue's redrive policy
+		PARQUET_MAX_RECEIVE_COUNT: '20',
+		PARQUET_MAX_ATTEMPTS: '3',
+		WEBHOOK_SIGNING_SECRET: webhookSigningSecret.value,
+		TRACE_EXPORTER: 'xray'
+	},
 	permissions: [
 		{
-			actions: ['s3:GetObject', 's3:Putobject'],
+			actions: ['s3:GetObject', 's3:Putobject', 's3:DeleteObject'],
 			effect: 'allow',
 			resources: [s3Bucket.arn, s3Bucket.arn.apply((arn) => `${arn}/*`)]
 		},
 		{
-			actions: ['sqs:ReceiveMessage', 'sqs:DeleteMessage', 'sqs:GetQueueAttributes'],
+			// A cancelled checkpointed conversion drops the upload it was building
+			actions: ['s3:AbortMultipartUpload'],
+			effect: 'allow',
+			resources: [s3Bucket.arn.apply((arn) => `${arn}/parquet/*`)]
+		},
+		{
+			actions: [
+				'sqs:ReceiveMessage',
+				'sqs:DeleteMessage',
+				'sqs:GetQueueAttributes',
+				'sqs:ChangeMessageVisibility'
+			],
 			effect: 'allow',
 			resources: [parquetQueue.arn]
 		},
 		{
-			actions: ['dynamodb:UpdateItem'],
+			actions: ['sqs:SendMessage'],
+			effect: 'allow',
+			resources: [parquetDeadLetterQueue.arn]
+		},
+		{
+			actions: ['dynamodb:UpdateItem', 'dynamodb:GetItem'],
+			effect: 'allow',
+			resources: [dynamoTable.arn]
+		},
+		{
+			// Completion events go to whichever topic the submitter names
+			actions: ['sns:Publish'],
+			effect: 'allow',
+			resources: ['*']
+		},
+		{
+			actions: ['xray:PutTraceSegments', 'xray:PutTelemetryRecords'],
+			effect: 'allow',
+			resources: ['*']
+		}
+	],
+	transform: {
+		function: {
+			name: `${$app.stage}-create-parquet-processor`,
+			tracingConfig: { mode: 'Active' }
+		}
+	}
+});
+
+parquetQueue.subscribe(parquetProcessorLambda.arn, { batch: { partialResponses: true } });
+
+const parquetFastProcessorLambda = new sst.aws.Function(`createParquetFastProcessor`, {
+	handler: './.parquet-creation-processor',
+	runtime: 'rust',
+	memory: '512 MB',
+	timeout: '100 seconds',
+	logging: { logGroup: `${$app.stage}-create-parquet-fast-processor` },
+	environment: {
+		S3_UPLOAD_BUCKET_NAME: s3Bucket.name,
+		DYNAMODB_NAME: dynamoTable.name,
+		PARQUET_QUEUE_URL: parquetFastQueue.url,
+		PARQUET_DLQ_URL: parquetDeadLetterQueue.url,
+		PARQUET_MAX_RECEIVE_COUNT: '3',
+		PARQUET_MAX_ATTEMPTS: '3',
+		PARQUET_PROCESSING_PATH: 'fast',
+		WEBHOOK_SIGNING_SECRET: webhookSigningSecret.value,
+		TRACE_EXPORTER: 'xray'
+	},
+	permissions: [
+		{
+			actions: ['s3:GetObject', 's3:Putobject', 's3:DeleteObject'],
+			effect: 'allow',
+			resources: [s3Bucket.arn, s3Bucket.arn.apply((arn) => `${arn}/*`)]
+		},
+		{
+			actions: [
+				'sqs:ReceiveMessage',
+				'sqs:DeleteMessage',
+				'sqs:GetQueueAttributes',
+				'sqs:ChangeMessageVisibility'
+			],
+			effect: 'allow',
+			resources: [parquetFastQueue.arn]
+		},
+		{
+			actions: ['sqs:SendMessage'],
+			effect: 'allow',
+			resources: [parquetDeadLetterQueue.arn]
+		},
+		{
+			actions: ['dynamodb:UpdateItem', 'dynamodb:GetItem'],
 			effect: 'allow',
 			resources: [dynamoTable.arn]
+		},
+		{
+			// Completion events go to whichever topic the submitter names
+			actions: ['sns:Publish'],
+			effect: 'allow',
+			resources: ['*']
+		},
+		{
+			actions: ['xray:PutTraceSegments', 'xray:PutTelemetryRecords'],
+			effect: 'allow',
+			resources: ['*']
 		}
 	],
 	transform: {
 		function: {
-			name: `${$app.stage}-create-parquet-processor`
+			name: `${$app.stage}-create-parquet-fast-processor`,
+			tracingConfig: { mode: 'Active' }
 		}
 	}
 });
 
-parquetQueue.subscribe(parquetProcessorLambda.arn);
+parquetFastQueue.subscribe(parquetFastProcessorLambda.arn, { batch: { partialResponses: true } });
 
-apiGateway.route('POST /generate-parquet-query', {
+// Queues scheduled jobs once they're within SQS's 15 minute delay limit
+new sst.aws.Cron('scheduledJobDispatcher', {
+	schedule: 'rate(5 minutes)',
+	function: {
+		handler: './.dispatch-scheduled-jobs',
+		runtime: 'rust',
+		memory: '128 MB',
+		timeout: '60 seconds',
+		logging: { logGroup: `${$app.stage}-dispatch-scheduled-jobs` },
+		environment: {
+			DYNAMODB_NAME: dynamoTable.name,
+			PARQUET_QUEUE_URL: parquetQueue.url,
+			PARQUET_FAST_QUEUE_URL: parquetFastQueue.url
+		},
+		permissions: [
+			{
+				actions: ['dynamodb:Query'],
+				effect: 'allow',
+				resources: [dynamoTable.arn.apply((arn) => `${arn}/index/scheduleIndex`)]
+			},
+			{
+				actions: ['dynamodb:UpdateItem'],
+				effect: 'allow',
+				resources: [dynamoTable.arn]
+			},
+			{
+				actions: ['sqs:SendMessage'],
+				effect: 'allow',
+				resources: [parquetQueue.arn, parquetFastQueue.arn]
+			}
+		],
+		transform: {
+			function: {
+				name: `${$app.stage}-dispatch-scheduled-jobs`
+			}
+		}
+	}
+});
+
+apiGateway.route('POST /upload-url', {
+	handler: './.create-upload-url',
+	runtime: 'rust',
+	memory: '128 MB',
+	logging: { logGroup: `${$app.stage}-create-upload-url` },
+	environment: {
+		S3_UPLOAD_BUCKET_NAME: s3Bucket.name,
+		DYNAMODB_NAME: dynamoTable.name,
+		UPLOAD_MAX_BYTES: String(5 * 1024 * 1024 * 1024),
+		UPLOAD_URL_EXPIRY_SECONDS: String(15 * 60)
+	},
+	permissions: [
+		{
+			actions: ['dynamodb:PutItem', 'dynamodb:GetItem'],
+			effect: 'allow',
+			resources: [dynamoTable.arn]
+		},
+		{
+			// Upload URLs are signed with this role, so it needs to be able to write them
+			actions: ['s3:PutObject'],
+			effect: 'allow',
+			resources: [s3Bucket.arn.apply((arn) => `${arn}/csvUpload/*`)]
+		}
+	],
+	transform: {
+		function: {
+			name: `${$app.stage}-create-upload-url`
+		}
+	}
+});
+
+const generateQueryFunction = {
 	handler: './.generate-parquet-query',
 	runtime: 'rust',
 	memory: '1024 MB',
 	timeout: '500 seconds',
 	logging: { logGroup: `${$app.stage}-generate-parquet-query` },
-	environment: { S3_UPLOAD_BUCKET_NAME: s3Bucket.name, DYNAMODB_NAME: dynamoTable.name },
+	environment: {
+		S3_UPLOAD_BUCKET_NAME: s3Bucket.name,
+		DYNAMODB_NAME: dynamoTable.name,
+		// Downloaded parquet is kept in /tmp for warm invocations, within this many bytes
+		PARQUET_CACHE_BUDGET_BYTES: String(400 * 1024 * 1024),
+		// Files this big or larger are read in place through DuckDB's httpfs instead
+		S3_DIRECT_QUERY_THRESHOLD_BYTES: String(256 * 1024 * 1024),
+		// Result rows per page when the caller doesn't set page_size
+		QUERY_PAGE_SIZE: String(500),
+		// Rows a query without its own LIMIT is cut off at
+		MAX_RESULT_ROWS: String(10_000),
+		// Bytes of rows one page can hold, under Lambda's 6 MB response limit
+		MAX_RESULT_BYTES: String(4 * 1024 * 1024),
+		// Queries running longer are interrupted and answered with a 408
+		QUERY_TIMEOUT_SECONDS: String(25),
+		// A few rows of the file go into the SQL prompt; turn off where the data is sensitive
+		PROMPT_SAMPLE_ROWS: 'true',
+		// Tried in order; Haiku keeps questions answered when Sonnet is unavailable
+		BEDROCK_MODEL_IDS: [
+			'apac.anthropic.claude-sonnet-4-20250514-v1:0',
+			'apac.anthropic.claude-3-haiku-20240307-v1:0'
+		].join(','),
+		// Tried first for the summary, which only rephrases results and can be cheaper
+		BEDROCK_SUMMARY_MODEL_ID: 'apac.anthropic.claude-sonnet-4-20250514-v1:0'
+	},
 	permissions: [
 		{
 			actions: ['s3:GetObject'],
 			effect: 'allow',
 			resources: [s3Bucket.arn, s3Bucket.arn.apply((arn) => `${arn}/*`)]
 		},
+		{
+			// Query results materialized as datasets of their own
+			actions: ['s3:PutObject'],
+			effect: 'allow',
+			resources: [s3Bucket.arn.apply((arn) => `${arn}/derived/*`)]
+		},
 		{
 			effect: 'allow',
 			actions: ['bedrock:*'],
 			resources: ['*']
 		},
 		{
-			actions: ['dynamodb:GetItem'],
+			actions: ['dynamodb:GetItem', 'dynamodb:PutItem', 'dynamodb:UpdateItem'],
 			effect: 'allow',
 			resources: [dynamoTable.arn]
 		}
@@ -110,13 +357,165 @@ apiGateway.route('POST /generate-parquet-query', {
 			name: `${$app.stage}-generate-parquet-query`
 		}
 	}
-});
+} satisfies sst.aws.FunctionArgs;
+
+apiGateway.route('POST /generate-parquet-query', generateQueryFunction);
+
+// The same handler with its progress streamed back as server-sent events. The integration
+// invokes it through Lambda's response streaming API, which has no buffered fallback, so it
+// is a separate function from the route above.
+apiGateway.route(
+	'POST /generate-parquet-query/stream',
+	{
+		...generateQueryFunction,
+		logging: { logGroup: `${$app.stage}-generate-parquet-query-stream` },
+		environment: { ...generateQueryFunction.environment, RESPONSE_STREAMING: 'true' },
+		transform: {
+			function: {
+				name: `${$app.stage}-generate-parquet-query-stream`
+			}
+		}
+	},
+	{
+		transform: {
+			integration: (args) => {
+				args.timeoutMilliseconds = 120000;
+				args.responseTransferMode = 'STREAM';
+				args.uri = $output(args.uri).apply((uri) =>
+					uri
+						.replace('/2015-03-31/', '/2021-11-15/')
+						.replace(/\/invocations$/, '/response-streaming-invocations')
+				);
+			}
+		}
+	}
+);
 
-apiGateway.route('GET /poll-parquet-status/{job_id}', {
+const pollParquetStatus = new sst.aws.Function(`pollParquetStatus`, {
 	handler: './.poll-parquet-status',
 	runtime: 'rust',
 	memory: '128 MB',
 	logging: { logGroup: `${$app.stage}-poll-parquet-status` },
+	environment: {
+		DYNAMODB_NAME: dynamoTable.name,
+		DOWNLOAD_URL_EXPIRY_SECONDS: String(15 * 60)
+	},
+	permissions: [
+		{
+			actions: ['dynamodb:GetItem', 'dynamodb:BatchGetItem'],
+			effect: 'allow',
+			resources: [dynamoTable.arn]
+		},
+		{
+			// Download links are signed with this role, so it needs to be able to read output
+			actions: ['s3:GetObject'],
+			effect: 'allow',
+			resources: [s3Bucket.arn.apply((arn) => `${arn}/parquet/*`)]
+		}
+	],
+	transform: {
+		function: {
+			name: `${$app.stage}-poll-parquet-status`
+		}
+	}
+});
+
+apiGateway.route('GET /poll-parquet-status/{job_id}', pollParquetStatus.arn);
+// Same function as the single poll; a POST body of { job_ids } polls up to 50 jobs at once
+apiGateway.route('POST /poll-parquet-status', pollParquetStatus.arn);
+
+apiGateway.route('POST /cancel-parquet-job/{job_id}', {
+	handler: './.cancel-parquet-job',
+	runtime: 'rust',
+	memory: '128 MB',
+	logging: { logGroup: `${$app.stage}-cancel-parquet-job` },
+	environment: {
+		DYNAMODB_NAME: dynamoTable.name
+	},
+	permissions: [
+		{
+			actions: ['dynamodb:UpdateItem', 'dynamodb:GetItem'],
+			effect: 'allow',
+			resources: [dynamoTable.arn]
+		}
+	],
+	transform: {
+		function: {
+			name: `${$app.stage}-cancel-parquet-job`
+		}
+	}
+});
+
+apiGateway.route('DELETE /jobs/{job_id}', {
+	handler: './.delete-job',
+	runtime: 'rust',
+	memory: '128 MB',
+	logging: { logGroup: `${$app.stage}-delete-job` },
+	environment: {
+		DYNAMODB_NAME: dynamoTable.name,
+		S3_UPLOAD_BUCKET_NAME: s3Bucket.name
+	},
+	permissions: [
+		{
+			actions: [
+				'dynamodb:GetItem',
+				'dynamodb:UpdateItem',
+				'dynamodb:DeleteItem',
+				'dynamodb:Query',
+				'dynamodb:BatchWriteItem'
+			],
+			effect: 'allow',
+			resources: [dynamoTable.arn]
+		},
+		{
+			actions: ['s3:ListBucket'],
+			effect: 'allow',
+			resources: [s3Bucket.arn]
+		},
+		{
+			actions: ['s3:DeleteObject'],
+			effect: 'allow',
+			resources: [
+				s3Bucket.arn.apply((arn) => `${arn}/csvUpload/*`),
+				s3Bucket.arn.apply((arn) => `${arn}/parquet/*`),
+				s3Bucket.arn.apply((arn) => `${arn}/derived/*`)
+			]
+		}
+	],
+	transform: {
+		function: {
+			name: `${$app.stage}-delete-job`
+		}
+	}
+});
+
+apiGateway.route('GET /list-queries/{job_id}', {
+	handler: './.list-queries',
+	runtime: 'rust',
+	memory: '128 MB',
+	logging: { logGroup: `${$app.stage}-list-queries` },
+	environment: {
+		DYNAMODB_NAME: dynamoTable.name
+	},
+	permissions: [
+		{
+			actions: ['dynamodb:GetItem', 'dynamodb:Query'],
+			effect: 'allow',
+			resources: [dynamoTable.arn]
+		}
+	],
+	transform: {
+		function: {
+			name: `${$app.stage}-list-queries`
+		}
+	}
+});
+
+apiGateway.route('GET /jobs', {
+	handler: './.list-jobs',
+	runtime: 'rust',
+	memory: '128 MB',
+	logging: { logGroup: `${$app.stage}-list-jobs` },
 	environment: {
 		DYNAMODB_NAME: dynamoTable.name
 	},
@@ -125,11 +524,16 @@ apiGateway.route('GET /poll-parquet-status/{job_id}', {
 			actions: ['dynamodb:GetItem'],
 			effect: 'allow',
 			resources: [dynamoTable.arn]
+		},
+		{
+			actions: ['dynamodb:Query'],
+			effect: 'allow',
+			resources: [dynamoTable.arn.apply((arn) => `${arn}/index/byCreatedAt`)]
 		}
 	],
 	transform: {
 		function: {
-			name: `${$app.stage}-poll-parquet-status`
+			name: `${$app.stage}-list-jobs`
 		}
 	}
 });
@@ -144,7 +548,7 @@ apiGateway.route('POST /update-context', {
 	},
 	permissions: [
 		{
-			actions: ['dynamodb:UpdateItem'],
+			actions: ['dynamodb:UpdateItem', 'dynamodb:GetItem'],
 			effect: 'allow',
 			resources: [dynamoTable.arn]
 		}
@@ -156,6 +560,28 @@ apiGateway.route('POST /update-context', {
 	}
 });
 
+apiGateway.route('PATCH /jobs/{job_id}/labels', {
+	handler: './.update-job-labels',
+	runtime: 'rust',
+	memory: '128 MB',
+	logging: { logGroup: `${$app.stage}-update-job-labels` },
+	environment: {
+		DYNAMODB_NAME: dynamoTable.name
+	},
+	permissions: [
+		{
+			actions: ['dynamodb:UpdateItem', 'dynamodb:GetItem'],
+			effect: 'allow',
+			resources: [dynamoTable.arn]
+		}
+	],
+	transform: {
+		function: {
+			name: `${$app.stage}-update-job-labels`
+		}
+	}
+});
+
 apiGateway.deploy();
 
 const testProcessor = new sst.aws.Function(`test`, {
@@ -1,8 +1,17 @@
 export const dynamoTable = new sst.aws.Dynamo('dynamo', {
 	fields: {
 		service: 'string',
-		serviceId: 'string'
+		serviceId: 'string',
+		schedule_bucket: 'string',
+		start_after: 'string',
+		entity: 'string',
+		created_at: 'string'
 	},
 	primaryIndex: { hashKey: 'service', rangeKey: 'serviceId' },
+	globalIndexes: {
+		scheduleIndex: { hashKey: 'schedule_bucket', rangeKey: 'start_after' },
+		byCreatedAt: { hashKey: 'entity', rangeKey: 'created_at' }
+	},
+	ttl: 'expires_at',
 	transform: { table: { name: `${$app.stage}-csv-single-table` } }
 });
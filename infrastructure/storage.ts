@@ -1,3 +1,16 @@
 export const s3Bucket = new sst.aws.Bucket('csvUpload', {
 	transform: { bucket: { bucket: `${$app.stage}-csv-upload` } }
 });
+
+// A checkpointed conversion that never finishes leaves its multipart upload behind
+new aws.s3.BucketLifecycleConfigurationV2('csvUploadLifecycle', {
+	bucket: s3Bucket.name,
+	rules: [
+		{
+			id: 'abort-incomplete-parquet-uploads',
+			status: 'Enabled',
+			filter: { prefix: 'parquet/' },
+			abortIncompleteMultipartUpload: { daysAfterInitiation: 7 }
+		}
+	]
+});
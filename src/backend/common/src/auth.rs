@@ -0,0 +1,410 @@
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::cors::create_cors_response;
+use crate::creation_parsing::parse_boolean;
+use crate::dynamo::{ApiKeyRecord, get_api_key};
+use crate::error::Error;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+// How long a key lookup is reused by a warm Lambda. Revoking a key takes up to this long
+// to reach every running instance.
+const KEY_CACHE_TTL: Duration = Duration::from_secs(300);
+
+// Jobs created before keys were recorded on them have no owner. Any key may use them
+// while this is set, which is only meant to last until their owners have been backfilled.
+pub const ALLOW_UNOWNED_JOBS_ENV: &str = "ALLOW_UNOWNED_JOBS";
+
+// The caller an API key belongs to, recorded on the jobs it creates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub id: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("missing x-api-key header")]
+    MissingKey,
+    #[error("unknown API key")]
+    UnknownKey,
+    #[error("API key has been revoked")]
+    Revoked,
+    #[error("API key expired at {0}")]
+    Expired(DateTime<Utc>),
+    #[error("API key is not valid")]
+    Invalid,
+    #[error("API key lookup failed: {0}")]
+    Lookup(#[from] Error),
+}
+
+impl AuthError {
+    // 401 when the caller didn't identify themselves, 403 when they did with a key that
+    // no longer works
+    pub fn status_code(&self) -> i64 {
+        match self {
+            AuthError::MissingKey | AuthError::UnknownKey => 401,
+            AuthError::Revoked | AuthError::Expired(_) | AuthError::Invalid => 403,
+            AuthError::Lookup(_) => 500,
+        }
+    }
+
+    pub fn to_response(&self) -> ApiGatewayProxyResponse {
+        let message = match self {
+            AuthError::Lookup(_) => "Internal server error".to_string(),
+            _ => self.to_string(),
+        };
+        create_cors_response(
+            self.status_code(),
+            Some(json!({"error": message}).to_string()),
+        )
+    }
+}
+
+impl ApiKeyRecord {
+    // Whether the key may be used at `now`; kept apart from the lookup so cached records
+    // still expire on time
+    pub fn check(&self, now: DateTime<Utc>) -> Result<Principal, AuthError> {
+        if self.revoked {
+            return Err(AuthError::Revoked);
+        }
+        if self.expiry_unreadable {
+            return Err(AuthError::Invalid);
+        }
+        if let Some(valid_until) = self.valid_until.filter(|valid_until| *valid_until <= now) {
+            return Err(AuthError::Expired(valid_until));
+        }
+        Ok(Principal {
+            id: self.principal.clone(),
+        })
+    }
+}
+
+type KeyCache = Mutex<HashMap<String, (Instant, Option<ApiKeyRecord>)>>;
+
+fn key_cache() -> &'static KeyCache {
+    static CACHE: OnceLock<KeyCache> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Checks the request's x-api-key header and returns who it belongs to. Callers answer
+// OPTIONS preflights before calling this, as browsers never send the key with them.
+pub async fn authorize(
+    event: &ApiGatewayProxyRequest,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+) -> Result<Principal, AuthError> {
+    let key = event
+        .headers
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .filter(|key| !key.is_empty())
+        .ok_or(AuthError::MissingKey)?;
+
+    let key_hash = hash_api_key(key);
+    let record = match cached_record(&key_hash, Instant::now()) {
+        Some(record) => record,
+        None => {
+            let record = get_api_key(dynamodb_client, table_name, &key_hash)
+                .await
+                .map_err(|e| {
+                    error!(error = %e, "Failed to look up API key");
+                    AuthError::Lookup(e)
+                })?;
+            if let Ok(mut cache) = key_cache().lock() {
+                cache.insert(key_hash, (Instant::now(), record.clone()));
+            }
+            record
+        }
+    };
+
+    let result = record.ok_or(AuthError::UnknownKey)?.check(Utc::now());
+    if let Err(e) = &result {
+        info!(reason = %e, "Rejected API request");
+    }
+    result
+}
+
+fn cached_record(key_hash: &str, now: Instant) -> Option<Option<ApiKeyRecord>> {
+    let cache = key_cache().lock().ok()?;
+    let (fetched_at, record) = cache.get(key_hash)?;
+    is_fresh(*fetched_at, now).then(|| record.clone())
+}
+
+// Whether `principal` may use the job `owner` created. A job with no owner is refused
+// unless ALLOW_UNOWNED_JOBS is set, and every use the flag allows is logged, so the jobs
+// still missing an owner can be found before it is turned off.
+pub fn may_access_job(principal: &Principal, owner: Option<&str>, job_id: &str) -> bool {
+    let allow_unowned = std::env::var(ALLOW_UNOWNED_JOBS_ENV)
+        .ok()
+        .and_then(|value| parse_boolean(&value))
+        .unwrap_or(false);
+    job_access(principal, owner, allow_unowned, job_id)
+}
+
+fn job_access(
+    principal: &Principal,
+    owner: Option<&str>,
+    allow_unowned: bool,
+    job_id: &str,
+) -> bool {
+    match owner {
+        Some(owner) => owner == principal.id,
+        None if allow_unowned => {
+            warn!(
+                job_id,
+                principal = %principal.id,
+                "Allowed use of a job with no owner"
+            );
+            true
+        }
+        None => {
+            info!(
+                job_id,
+                principal = %principal.id,
+                "Refused use of a job with no owner"
+            );
+            false
+        }
+    }
+}
+
+// Whether a lookup made at `fetched_at` may still be reused at `now`
+fn is_fresh(fetched_at: Instant, now: Instant) -> bool {
+    now.saturating_duration_since(fetched_at) < KEY_CACHE_TTL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubResponse, at, key_table};
+    use serde_json::json;
+
+    fn record(revoked: bool, valid_until: Option<DateTime<Utc>>) -> ApiKeyRecord {
+        ApiKeyRecord {
+            principal: "team-a".to_string(),
+            revoked,
+            valid_until,
+            expiry_unreadable: false,
+        }
+    }
+
+    fn request_with_key(key: &str) -> ApiGatewayProxyRequest {
+        let mut event = ApiGatewayProxyRequest::default();
+        event.headers.insert(API_KEY_HEADER, key.parse().unwrap());
+        event
+    }
+
+    #[test]
+    fn a_live_key_resolves_to_its_principal() {
+        let principal = record(false, Some(at(12))).check(at(11)).unwrap();
+
+        assert_eq!(principal.id, "team-a");
+        assert!(record(false, None).check(at(11)).is_ok());
+    }
+
+    #[test]
+    fn a_revoked_key_is_refused_even_before_it_expires() {
+        let result = record(true, Some(at(12))).check(at(11));
+
+        assert!(matches!(result, Err(AuthError::Revoked)));
+    }
+
+    #[test]
+    fn a_key_expires_at_its_valid_until() {
+        let result = record(false, Some(at(12))).check(at(12));
+
+        assert!(matches!(result, Err(AuthError::Expired(time)) if time == at(12)));
+        assert!(record(false, Some(at(12))).check(at(13)).is_err());
+    }
+
+    #[test]
+    fn a_key_with_an_unreadable_expiry_is_refused() {
+        let record = ApiKeyRecord {
+            expiry_unreadable: true,
+            ..record(false, None)
+        };
+
+        assert!(matches!(record.check(at(11)), Err(AuthError::Invalid)));
+    }
+
+    #[test]
+    fn only_the_owner_may_use_an_owned_job() {
+        let principal = record(false, None).check(at(11)).unwrap();
+
+        for allow_unowned in [false, true] {
+            assert!(job_access(
+                &principal,
+                Some("team-a"),
+                allow_unowned,
+                "job-1"
+            ));
+            assert!(!job_access(
+                &principal,
+                Some("team-b"),
+                allow_unowned,
+                "job-1"
+            ));
+        }
+    }
+
+    #[test]
+    fn a_job_with_no_owner_is_only_open_while_the_flag_is_set() {
+        let principal = record(false, None).check(at(11)).unwrap();
+
+        assert!(!job_access(&principal, None, false, "job-1"));
+        assert!(job_access(&principal, None, true, "job-1"));
+    }
+
+    #[test]
+    fn refused_keys_are_forbidden_and_missing_ones_unauthorized() {
+        assert_eq!(AuthError::MissingKey.status_code(), 401);
+        assert_eq!(AuthError::UnknownKey.status_code(), 401);
+        assert_eq!(AuthError::Revoked.status_code(), 403);
+        assert_eq!(AuthError::Expired(at(12)).status_code(), 403);
+        assert_eq!(AuthError::Invalid.status_code(), 403);
+    }
+
+    #[test]
+    fn a_cached_lookup_is_reused_until_the_ttl_runs_out() {
+        let fetched_at = Instant::now();
+
+        assert!(is_fresh(fetched_at, fetched_at));
+        assert!(is_fresh(
+            fetched_at,
+            fetched_at + KEY_CACHE_TTL - Duration::from_secs(1)
+        ));
+        assert!(!is_fresh(fetched_at, fetched_at + KEY_CACHE_TTL));
+        // A clock read before the lookup doesn't make the entry stale
+        assert!(is_fresh(fetched_at + Duration::from_secs(1), fetched_at));
+    }
+
+    #[tokio::test]
+    async fn a_request_without_a_key_is_not_looked_up() {
+        let stub = key_table(None);
+
+        let result = authorize(
+            &ApiGatewayProxyRequest::default(),
+            &stub.dynamodb_client(),
+            "jobs",
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::MissingKey)));
+        assert!(stub.requests().is_empty());
+    }
+
+    // Each test below uses its own key, as the cache is shared by every test in the binary
+
+    #[tokio::test]
+    async fn an_unknown_key_is_unauthorized() {
+        let stub = key_table(None);
+
+        let result = authorize(
+            &request_with_key("unknown-key"),
+            &stub.dynamodb_client(),
+            "jobs",
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::UnknownKey)));
+    }
+
+    #[tokio::test]
+    async fn a_revoked_key_is_forbidden() {
+        let stub = key_table(Some(json!({
+            "principal": {"S": "team-a"},
+            "revoked": {"BOOL": true}
+        })));
+
+        let result = authorize(
+            &request_with_key("revoked-key"),
+            &stub.dynamodb_client(),
+            "jobs",
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::Revoked)));
+    }
+
+    #[tokio::test]
+    async fn an_expired_key_is_forbidden() {
+        let stub = key_table(Some(json!({
+            "principal": {"S": "team-a"},
+            "valid_until": {"S": "2020-01-01T00:00:00Z"}
+        })));
+
+        let result = authorize(
+            &request_with_key("expired-key"),
+            &stub.dynamodb_client(),
+            "jobs",
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::Expired(_))));
+    }
+
+    #[tokio::test]
+    async fn a_key_whose_valid_until_is_malformed_is_forbidden() {
+        for (key, valid_until) in [
+            ("malformed-expiry-key", json!({"S": "next tuesday"})),
+            ("non-string-expiry-key", json!({"N": "1700000000"})),
+        ] {
+            let stub = key_table(Some(json!({
+                "principal": {"S": "team-a"},
+                "valid_until": valid_until
+            })));
+
+            let result = authorize(&request_with_key(key), &stub.dynamodb_client(), "jobs").await;
+
+            assert!(matches!(result, Err(AuthError::Invalid)), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_valid_key_is_looked_up_once_and_then_cached() {
+        let stub = key_table(Some(json!({"principal": {"S": "team-b"}})));
+        let client = stub.dynamodb_client();
+
+        for _ in 0..2 {
+            let principal = authorize(&request_with_key("valid-key"), &client, "jobs")
+                .await
+                .unwrap();
+            assert_eq!(principal.id, "team-b");
+        }
+
+        assert_eq!(stub.operations("GetItem").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_failed_lookup_is_an_internal_error() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error("ResourceNotFoundException", None)
+        });
+
+        let result = authorize(
+            &request_with_key("lookup-fails-key"),
+            &stub.dynamodb_client(),
+            "jobs",
+        )
+        .await;
+
+        let e = result.unwrap_err();
+        assert_eq!(e.status_code(), 500);
+        assert!(matches!(e, AuthError::Lookup(_)));
+    }
+}
@@ -0,0 +1,631 @@
+use aws_sdk_bedrockruntime::config::retry::RetryConfig;
+use aws_sdk_bedrockruntime::error::{
+    BuildError, DisplayErrorContext, ProvideErrorMetadata, SdkError,
+};
+use aws_sdk_bedrockruntime::operation::converse::builders::ConverseFluentBuilder;
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ConversationRole, InferenceConfiguration, Message, SystemContentBlock,
+};
+use aws_sdk_bedrockruntime::{Client as BedrockClient, config::Builder as BedrockConfigBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::error::{Error, sdk_error_is_retryable};
+use crate::parquet_query::ConverseUsage;
+
+pub const DEFAULT_SQL_MODEL_ID: &str = "apac.anthropic.claude-sonnet-4-20250514-v1:0";
+const CLAUDE_3_HAIKU_MODEL_ID: &str = "apac.anthropic.claude-3-haiku-20240307-v1:0";
+
+// On-demand list prices for the models the deployment uses, for when BEDROCK_MODEL_PRICES
+// doesn't say otherwise
+const DEFAULT_MODEL_PRICES: &[(&str, ModelPrice)] = &[
+    (
+        DEFAULT_SQL_MODEL_ID,
+        ModelPrice {
+            input_usd_per_million: 3.0,
+            output_usd_per_million: 15.0,
+        },
+    ),
+    (
+        CLAUDE_3_HAIKU_MODEL_ID,
+        ModelPrice {
+            input_usd_per_million: 0.25,
+            output_usd_per_million: 1.25,
+        },
+    ),
+];
+
+// Throttling under load usually clears within a few seconds; a question that still can't
+// get through after this is better answered with an error than left hanging
+pub const BEDROCK_MAX_ATTEMPTS: u32 = 3;
+const BEDROCK_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+// A Retry-After longer than this is ignored in favour of failing the request
+const BEDROCK_MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// A client whose calls are retried by `with_bedrock_retries` alone. Leaving the SDK's own
+// retries on as well would multiply the attempts.
+pub fn bedrock_client(config: &aws_config::SdkConfig) -> BedrockClient {
+    let config = BedrockConfigBuilder::from(config)
+        .retry_config(RetryConfig::disabled())
+        .build();
+    BedrockClient::from_conf(config)
+}
+
+// Failures that say this model can't serve the request right now, as opposed to the request
+// itself being wrong. Only these, and retryable errors that outlasted their retries, move on
+// to the next model; anything else would fail the same way on every model.
+const MODEL_UNAVAILABLE_ERROR_CODES: &[&str] = &[
+    "AccessDeniedException",
+    "ResourceNotFoundException",
+    "ModelNotReadyException",
+    "ModelTimeoutException",
+    "ModelErrorException",
+    "ServiceUnavailableException",
+];
+
+// The models to try for each step, most preferred first. The summary only rephrases data
+// it is handed, so it can lead with a cheaper model than the SQL step.
+#[derive(Debug, Clone)]
+pub struct BedrockModels {
+    pub sql: Vec<String>,
+    pub summary: Vec<String>,
+    pub prices: HashMap<String, ModelPrice>,
+    pub sql_inference: InferenceSettings,
+    pub summary_inference: InferenceSettings,
+}
+
+// Sampling settings for one of the converse calls. top_p is only sent when configured, as
+// newer Claude models refuse a request that sets it alongside temperature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InferenceSettings {
+    pub temperature: f32,
+    pub top_p: Option<f32>,
+    pub max_tokens: i32,
+    pub stop_sequences: Vec<String>,
+}
+
+impl InferenceSettings {
+    // Reads <prefix>_TEMPERATURE, <prefix>_TOP_P, <prefix>_MAX_TOKENS and
+    // <prefix>_STOP_SEQUENCES (comma-separated, empty for none), keeping `defaults` for
+    // any that are unset
+    fn from_env(prefix: &str, defaults: InferenceSettings) -> Result<Self, Error> {
+        let var = |suffix: &str| {
+            let name = format!("{}_{}", prefix, suffix);
+            std::env::var(&name).ok().map(|value| (name, value))
+        };
+        let parse_error =
+            |name: &str, value: &str| Error::Config(format!("{} is invalid: {:?}", name, value));
+
+        let mut settings = defaults;
+        if let Some((name, value)) = var("TEMPERATURE") {
+            settings.temperature = value
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|t| (0.0..=1.0).contains(t))
+                .ok_or_else(|| parse_error(&name, &value))?;
+        }
+        if let Some((name, value)) = var("TOP_P") {
+            settings.top_p = Some(
+                value
+                    .trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or_else(|| parse_error(&name, &value))?,
+            );
+        }
+        if let Some((name, value)) = var("MAX_TOKENS") {
+            settings.max_tokens = value
+                .trim()
+                .parse::<i32>()
+                .ok()
+                .filter(|tokens| *tokens > 0)
+                .ok_or_else(|| parse_error(&name, &value))?;
+        }
+        if let Some((_, value)) = var("STOP_SEQUENCES") {
+            settings.stop_sequences = value
+                .split(',')
+                .filter(|sequence| !sequence.trim().is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        Ok(settings)
+    }
+
+    fn to_configuration(&self) -> InferenceConfiguration {
+        let mut configuration = InferenceConfiguration::builder()
+            .temperature(self.temperature)
+            .max_tokens(self.max_tokens)
+            .set_stop_sequences(Some(self.stop_sequences.clone()));
+        if let Some(top_p) = self.top_p {
+            configuration = configuration.top_p(top_p);
+        }
+        configuration.build()
+    }
+
+    pub fn log(&self, step: &str, job_id: &str) {
+        info!(
+            job_id,
+            step,
+            temperature = self.temperature,
+            top_p = ?self.top_p,
+            max_tokens = self.max_tokens,
+            stop_sequences = ?self.stop_sequences,
+            "Bedrock inference configuration"
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ModelPrice {
+    pub input_usd_per_million: f64,
+    pub output_usd_per_million: f64,
+}
+
+impl ModelPrice {
+    fn cost(&self, usage: ConverseUsage) -> f64 {
+        (usage.input_tokens as f64 * self.input_usd_per_million
+            + usage.output_tokens as f64 * self.output_usd_per_million)
+            / 1_000_000.0
+    }
+}
+
+// Tokens and estimated cost across the Bedrock calls that answered one request. Each is
+// null when a call didn't report usage or a model has no price, rather than a total that
+// quietly leaves part out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct QueryUsage {
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    pub estimated_usd: Option<f64>,
+}
+
+impl BedrockModels {
+    // SQL models from BEDROCK_MODEL_IDS, comma-separated, defaulting to the single Sonnet
+    // profile. The summary tries BEDROCK_SUMMARY_MODEL_ID first when set, then falls back
+    // through the same list. Set but blank is a deployment mistake and fails here rather
+    // than on the first question.
+    pub fn from_env() -> Result<Self, Error> {
+        let sql = match std::env::var("BEDROCK_MODEL_IDS") {
+            Ok(ids) => parse_model_ids("BEDROCK_MODEL_IDS", &ids)?,
+            Err(_) => vec![DEFAULT_SQL_MODEL_ID.to_string()],
+        };
+
+        let mut summary = match std::env::var("BEDROCK_SUMMARY_MODEL_ID") {
+            Ok(id) => parse_model_ids("BEDROCK_SUMMARY_MODEL_ID", &id)?,
+            Err(_) => Vec::new(),
+        };
+        for id in &sql {
+            if !summary.contains(id) {
+                summary.push(id.clone());
+            }
+        }
+
+        // BEDROCK_MODEL_PRICES is JSON keyed by model ID, e.g.
+        // {"model-id": {"input_usd_per_million": 3.0, "output_usd_per_million": 15.0}},
+        // and adds to or overrides the built-in prices
+        let mut prices: HashMap<String, ModelPrice> = DEFAULT_MODEL_PRICES
+            .iter()
+            .map(|(id, price)| (id.to_string(), *price))
+            .collect();
+        if let Ok(configured) = std::env::var("BEDROCK_MODEL_PRICES") {
+            let configured: HashMap<String, ModelPrice> = serde_json::from_str(&configured)
+                .map_err(|e| Error::Config(format!("BEDROCK_MODEL_PRICES is invalid: {}", e)))?;
+            prices.extend(configured);
+        }
+
+        // The SQL is run as written, so it is generated deterministically. A fence is
+        // where a model that ignores the prompt starts wrapping its answer in markdown.
+        let sql_inference = InferenceSettings::from_env(
+            "BEDROCK_SQL",
+            InferenceSettings {
+                temperature: 0.0,
+                top_p: None,
+                max_tokens: 1024,
+                stop_sequences: vec!["```".to_string()],
+            },
+        )?;
+        let summary_inference = InferenceSettings::from_env(
+            "BEDROCK_SUMMARY",
+            InferenceSettings {
+                temperature: 0.3,
+                top_p: None,
+                max_tokens: 512,
+                stop_sequences: Vec::new(),
+            },
+        )?;
+
+        Ok(BedrockModels {
+            sql,
+            summary,
+            prices,
+            sql_inference,
+            summary_inference,
+        })
+    }
+
+    // Adds up the calls made for one request, each as the model that served it and the
+    // usage it reported
+    pub fn usage(&self, calls: &[(String, Option<ConverseUsage>)]) -> QueryUsage {
+        let mut input_tokens = 0;
+        let mut output_tokens = 0;
+        let mut estimated_usd = Some(0.0);
+        for (model_id, usage) in calls {
+            let Some(usage) = usage else {
+                return QueryUsage::default();
+            };
+            input_tokens += usage.input_tokens;
+            output_tokens += usage.output_tokens;
+            estimated_usd = estimated_usd
+                .zip(self.prices.get(model_id))
+                .map(|(total, price)| total + price.cost(*usage));
+        }
+        QueryUsage {
+            input_tokens: Some(input_tokens),
+            output_tokens: Some(output_tokens),
+            estimated_usd,
+        }
+    }
+}
+
+fn parse_model_ids(name: &str, value: &str) -> Result<Vec<String>, Error> {
+    let ids: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .collect();
+    if ids.is_empty() {
+        return Err(Error::Config(format!("{} is set but empty", name)));
+    }
+    Ok(ids)
+}
+
+// A single-turn converse call: `system` as the instructions and `user_message` as the
+// only message. The model is left for `with_model_fallback` to fill in per attempt.
+pub fn converse_request(
+    client: &BedrockClient,
+    system: &str,
+    user_message: String,
+    inference: &InferenceSettings,
+) -> Result<ConverseFluentBuilder, BuildError> {
+    let message = Message::builder()
+        .role(ConversationRole::User)
+        .content(ContentBlock::Text(user_message))
+        .build()?;
+    Ok(client
+        .converse()
+        .system(SystemContentBlock::Text(system.to_string()))
+        .messages(message)
+        .inference_config(inference.to_configuration()))
+}
+
+// What a call through `with_model_fallback` returned and which model returned it
+#[derive(Debug)]
+pub struct ServedBy<T> {
+    pub output: T,
+    pub model_id: String,
+    // How many models before this one were given up on
+    pub fallbacks: usize,
+}
+
+fn should_try_next_model<E: ProvideErrorMetadata>(error: &SdkError<E>) -> bool {
+    sdk_error_is_retryable(error)
+        || error
+            .code()
+            .is_some_and(|code| MODEL_UNAVAILABLE_ERROR_CODES.contains(&code))
+}
+
+// Calls `call` with each of `models` in turn, each with its own retries, until one serves
+// the request. A failure that isn't about the model being unavailable is returned as is,
+// without trying the rest. When every model fails the last model's error is returned.
+pub async fn with_model_fallback<T, E, F, Fut>(
+    operation: &'static str,
+    job_id: &str,
+    models: &[String],
+    mut call: F,
+) -> Result<ServedBy<T>, SdkError<E>>
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    F: FnMut(&str) -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+{
+    let mut last_error = None;
+    for (fallbacks, model_id) in models.iter().enumerate() {
+        let error = match with_bedrock_retries(operation, job_id, || call(model_id)).await {
+            Ok(output) => {
+                return Ok(ServedBy {
+                    output,
+                    model_id: model_id.clone(),
+                    fallbacks,
+                });
+            }
+            Err(error) => error,
+        };
+
+        if !should_try_next_model(&error) {
+            return Err(error);
+        }
+        if fallbacks + 1 < models.len() {
+            warn!(
+                operation,
+                job_id,
+                model_id = %model_id,
+                next_model_id = %models[fallbacks + 1],
+                error = %DisplayErrorContext(&error),
+                "Bedrock model unavailable, falling back"
+            );
+        }
+        last_error = Some(error);
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| SdkError::construction_failure("no Bedrock models configured")))
+}
+
+// Seconds from a Retry-After header, when the service sent one
+fn retry_after<E>(error: &SdkError<E>) -> Option<Duration> {
+    error
+        .raw_response()
+        .and_then(|response| response.headers().get("retry-after"))
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+// Full jitter: anywhere from nothing up to the exponential delay for this attempt, so
+// callers throttled together don't all come back at once
+fn backoff_delay(attempt: u32) -> Duration {
+    let ceiling = BEDROCK_RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+    let random = uuid::Uuid::new_v4().as_u128();
+    let millis = (random % (ceiling.as_millis() + 1)) as u64;
+    Duration::from_millis(millis)
+}
+
+// Runs `call` until it succeeds, fails with an error that another attempt wouldn't fix,
+// or has been tried BEDROCK_MAX_ATTEMPTS times. Only throttling, timeouts and 5xx
+// responses are retried; validation and access errors are returned straight away.
+pub async fn with_bedrock_retries<T, E, F, Fut>(
+    operation: &'static str,
+    job_id: &str,
+    mut call: F,
+) -> Result<T, SdkError<E>>
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match call().await {
+            Ok(output) => return Ok(output),
+            Err(error) => error,
+        };
+
+        if attempt >= BEDROCK_MAX_ATTEMPTS || !sdk_error_is_retryable(&error) {
+            return Err(error);
+        }
+
+        let delay = match retry_after(&error) {
+            Some(hint) if hint > BEDROCK_MAX_RETRY_DELAY => return Err(error),
+            Some(hint) => hint,
+            None => backoff_delay(attempt),
+        };
+        warn!(
+            operation,
+            job_id,
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            error = %DisplayErrorContext(&error),
+            "Bedrock call failed, retrying"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubRequest, StubResponse};
+    use aws_sdk_bedrockruntime::operation::converse::ConverseOutput;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn answered(text: &str) -> StubResponse {
+        StubResponse::json(json!({
+            "output": {"message": {"role": "assistant", "content": [{"text": text}]}},
+            "stopReason": "end_turn",
+            "usage": {"inputTokens": 10, "outputTokens": 5, "totalTokens": 15},
+            "metrics": {"latencyMs": 100}
+        }))
+    }
+
+    fn failed(status: u16, error_type: &str) -> StubResponse {
+        StubResponse {
+            content_type: "application/json",
+            ..StubResponse::bytes(
+                status,
+                json!({"message": "stubbed failure"})
+                    .to_string()
+                    .into_bytes(),
+            )
+        }
+        .with_header("x-amzn-errortype", error_type)
+    }
+
+    // Throttled, with a Retry-After that lets the retry go straight away
+    fn throttled() -> StubResponse {
+        failed(429, "ThrottlingException").with_header("retry-after", "0")
+    }
+
+    // The model each converse call was made with, in order
+    fn models_called(stub: &StubEndpoint) -> Vec<String> {
+        stub.requests()
+            .iter()
+            .map(|request| {
+                let path = request.target.trim_start_matches("/model/");
+                path.split('/').next().unwrap_or_default().to_string()
+            })
+            .collect()
+    }
+
+    fn for_model(request: &StubRequest, model_id: &str) -> bool {
+        request.target.starts_with(&format!("/model/{}/", model_id))
+    }
+
+    async fn converse(
+        stub: &StubEndpoint,
+        models: &[&str],
+    ) -> Result<ServedBy<ConverseOutput>, SdkError<impl ProvideErrorMetadata + std::fmt::Debug>>
+    {
+        let client = stub.bedrock_client();
+        let inference = InferenceSettings {
+            temperature: 0.0,
+            top_p: None,
+            max_tokens: 64,
+            stop_sequences: Vec::new(),
+        };
+        let request =
+            converse_request(&client, "system", "question".to_string(), &inference).unwrap();
+        let models: Vec<String> = models.iter().map(|model| model.to_string()).collect();
+        with_model_fallback("Converse", "job-1", &models, |model| {
+            request.clone().model_id(model).send()
+        })
+        .await
+    }
+
+    fn reply_text(served: &ServedBy<ConverseOutput>) -> &str {
+        match served.output.output() {
+            Some(aws_sdk_bedrockruntime::types::ConverseOutput::Message(message)) => {
+                message.content()[0].as_text().unwrap()
+            }
+            other => panic!("unexpected output: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_throttled_call_is_retried_until_it_succeeds() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let stub = StubEndpoint::start(move |_| {
+            if seen.fetch_add(1, Ordering::SeqCst) < 2 {
+                throttled()
+            } else {
+                answered("SELECT 1")
+            }
+        });
+
+        let served = converse(&stub, &["model-a"]).await.unwrap();
+
+        assert_eq!(reply_text(&served), "SELECT 1");
+        assert_eq!((served.model_id.as_str(), served.fallbacks), ("model-a", 0));
+        assert_eq!(models_called(&stub), ["model-a", "model-a", "model-a"]);
+    }
+
+    #[tokio::test]
+    async fn throttling_that_outlasts_the_attempts_is_returned() {
+        let stub = StubEndpoint::start(|_| throttled());
+
+        let error = converse(&stub, &["model-a"]).await.unwrap_err();
+
+        assert_eq!(error.code(), Some("ThrottlingException"));
+        assert_eq!(stub.requests().len(), BEDROCK_MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn a_retry_after_past_the_limit_fails_without_waiting() {
+        let stub = StubEndpoint::start(|_| {
+            failed(429, "ThrottlingException").with_header("retry-after", "60")
+        });
+
+        let error = converse(&stub, &["model-a"]).await.unwrap_err();
+
+        assert_eq!(error.code(), Some("ThrottlingException"));
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn an_unavailable_model_falls_back_to_the_next_in_order() {
+        let stub = StubEndpoint::start(|request| {
+            if for_model(request, "model-c") {
+                answered("from c")
+            } else {
+                failed(404, "ResourceNotFoundException")
+            }
+        });
+
+        let served = converse(&stub, &["model-a", "model-b", "model-c"])
+            .await
+            .unwrap();
+
+        assert_eq!(reply_text(&served), "from c");
+        assert_eq!((served.model_id.as_str(), served.fallbacks), ("model-c", 2));
+        assert_eq!(models_called(&stub), ["model-a", "model-b", "model-c"]);
+    }
+
+    #[tokio::test]
+    async fn a_model_still_throttled_after_its_retries_falls_back() {
+        let stub = StubEndpoint::start(|request| {
+            if for_model(request, "model-a") {
+                throttled()
+            } else {
+                answered("from b")
+            }
+        });
+
+        let served = converse(&stub, &["model-a", "model-b"]).await.unwrap();
+
+        assert_eq!((served.model_id.as_str(), served.fallbacks), ("model-b", 1));
+        assert_eq!(
+            models_called(&stub),
+            ["model-a", "model-a", "model-a", "model-b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_validation_error_does_not_cascade_through_the_models() {
+        let stub = StubEndpoint::start(|request| {
+            if for_model(request, "model-a") {
+                failed(400, "ValidationException")
+            } else {
+                answered("from b")
+            }
+        });
+
+        let error = converse(&stub, &["model-a", "model-b"]).await.unwrap_err();
+
+        assert_eq!(error.code(), Some("ValidationException"));
+        assert_eq!(models_called(&stub), ["model-a"]);
+    }
+
+    #[tokio::test]
+    async fn when_every_model_fails_the_last_error_is_returned() {
+        let stub = StubEndpoint::start(|request| {
+            if for_model(request, "model-a") {
+                failed(404, "ResourceNotFoundException")
+            } else {
+                failed(403, "AccessDeniedException")
+            }
+        });
+
+        let error = converse(&stub, &["model-a", "model-b"]).await.unwrap_err();
+
+        assert_eq!(error.code(), Some("AccessDeniedException"));
+        assert_eq!(models_called(&stub), ["model-a", "model-b"]);
+    }
+
+    #[test]
+    fn backoff_stays_under_each_attempts_ceiling() {
+        for attempt in 1..BEDROCK_MAX_ATTEMPTS {
+            let ceiling = BEDROCK_RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+            for _ in 0..100 {
+                assert!(backoff_delay(attempt) <= ceiling);
+            }
+        }
+    }
+}
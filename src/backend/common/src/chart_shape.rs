@@ -0,0 +1,386 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+
+// Most categories a bar chart is suggested for; past this the bars are unreadable and the
+// rows are better left as a table. Dates have no limit, a line can carry any number.
+pub const MAX_CHART_CATEGORIES: usize = 50;
+// Most slices a pie chart is suggested for
+pub const MAX_PIE_SLICES: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChartKind {
+    Bar,
+    Line,
+    Pie,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChartSeries {
+    pub name: String,
+    // One per label; None where the row had no number
+    pub values: Vec<Option<f64>>,
+}
+
+// A query result laid out for a chart, or `Table` with why it couldn't be
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum ChartShape {
+    Chart {
+        labels: Vec<String>,
+        series: Vec<ChartSeries>,
+        suggested_chart: ChartKind,
+    },
+    Table {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Numeric,
+    Temporal,
+    Categorical,
+    Other,
+}
+
+// How a chart can use a column of the DuckDB type `column_type`, as DESCRIBE reports it
+fn column_kind(column_type: &str) -> ColumnKind {
+    let column_type = column_type.trim().to_ascii_uppercase();
+    let base = column_type.split('(').next().unwrap_or_default().trim();
+    match base {
+        "TINYINT" | "SMALLINT" | "INTEGER" | "BIGINT" | "HUGEINT" | "UTINYINT" | "USMALLINT"
+        | "UINTEGER" | "UBIGINT" | "UHUGEINT" | "FLOAT" | "DOUBLE" | "DECIMAL" => {
+            ColumnKind::Numeric
+        }
+        "DATE"
+        | "TIMESTAMP"
+        | "TIMESTAMP WITH TIME ZONE"
+        | "TIMESTAMP_S"
+        | "TIMESTAMP_MS"
+        | "TIMESTAMP_NS" => ColumnKind::Temporal,
+        "VARCHAR" | "BOOLEAN" | "ENUM" => ColumnKind::Categorical,
+        _ => ColumnKind::Other,
+    }
+}
+
+fn table(reason: &str) -> ChartShape {
+    ChartShape::Table {
+        reason: reason.to_string(),
+    }
+}
+
+fn label_text(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(text)) => text.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+fn number(value: Option<&Value>) -> Option<f64> {
+    match value? {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+// Lays out `rows`, the JSON objects a query returned, as chart labels and series. The
+// labels come from the one date or text column, preferring a date, and every numeric
+// column becomes a series. Anything else, such as a second text column or a label that
+// repeats, can't be drawn without guessing and comes back as a table.
+pub fn chart_shape(columns: &[(String, String)], rows: &[Value]) -> ChartShape {
+    if columns.is_empty() {
+        return table("the result's columns are unknown");
+    }
+    if rows.is_empty() {
+        return table("the result has no rows");
+    }
+
+    let kinds: Vec<ColumnKind> = columns
+        .iter()
+        .map(|(_, column_type)| column_kind(column_type))
+        .collect();
+    let of_kind = |kind: ColumnKind| {
+        columns
+            .iter()
+            .zip(&kinds)
+            .filter(move |(_, column_kind)| **column_kind == kind)
+            .map(|((name, _), _)| name)
+    };
+
+    let temporal: Vec<&String> = of_kind(ColumnKind::Temporal).collect();
+    let categorical: Vec<&String> = of_kind(ColumnKind::Categorical).collect();
+    let numeric: Vec<&String> = of_kind(ColumnKind::Numeric).collect();
+
+    if kinds.contains(&ColumnKind::Other) {
+        return table("the result has a column no chart can show");
+    }
+    if temporal.len() + categorical.len() != 1 {
+        return table("the result needs exactly one date or text column for labels");
+    }
+    if numeric.is_empty() {
+        return table("the result has no numeric column");
+    }
+
+    let (label_column, is_temporal) = match temporal.first() {
+        Some(column) => (*column, true),
+        None => (categorical[0], false),
+    };
+
+    let labels: Vec<String> = rows
+        .iter()
+        .map(|row| label_text(row.get(label_column)))
+        .collect();
+    let distinct: HashSet<&String> = labels.iter().collect();
+    if distinct.len() != labels.len() {
+        return table("the label column repeats values");
+    }
+    if !is_temporal && labels.len() > MAX_CHART_CATEGORIES {
+        return table("the result has too many categories to chart");
+    }
+
+    let series: Vec<ChartSeries> = numeric
+        .iter()
+        .map(|name| ChartSeries {
+            name: name.to_string(),
+            values: rows
+                .iter()
+                .map(|row| number(row.get(name.as_str())))
+                .collect(),
+        })
+        .collect();
+
+    let suggested_chart = if is_temporal {
+        ChartKind::Line
+    } else if series.len() == 1
+        && (2..=MAX_PIE_SLICES).contains(&labels.len())
+        && series[0]
+            .values
+            .iter()
+            .all(|value| value.is_some_and(|v| v >= 0.0))
+    {
+        ChartKind::Pie
+    } else {
+        ChartKind::Bar
+    };
+
+    ChartShape::Chart {
+        labels,
+        series,
+        suggested_chart,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn columns(columns: &[(&str, &str)]) -> Vec<(String, String)> {
+        columns
+            .iter()
+            .map(|(name, column_type)| (name.to_string(), column_type.to_string()))
+            .collect()
+    }
+
+    fn reason(shape: ChartShape) -> String {
+        match shape {
+            ChartShape::Table { reason } => reason,
+            chart => panic!("expected a table, got {:?}", chart),
+        }
+    }
+
+    #[test]
+    fn a_few_non_negative_categories_suggest_a_pie() {
+        let shape = chart_shape(
+            &columns(&[("region", "VARCHAR"), ("sales", "BIGINT")]),
+            &[
+                json!({"region": "north", "sales": 10}),
+                json!({"region": "south", "sales": 0}),
+                json!({"region": "east", "sales": 5}),
+            ],
+        );
+
+        assert_eq!(
+            shape,
+            ChartShape::Chart {
+                labels: vec!["north".into(), "south".into(), "east".into()],
+                series: vec![ChartSeries {
+                    name: "sales".into(),
+                    values: vec![Some(10.0), Some(0.0), Some(5.0)],
+                }],
+                suggested_chart: ChartKind::Pie,
+            }
+        );
+    }
+
+    #[test]
+    fn a_negative_value_or_many_categories_suggest_bars() {
+        let negative = chart_shape(
+            &columns(&[("region", "VARCHAR"), ("profit", "DOUBLE")]),
+            &[
+                json!({"region": "north", "profit": 1.5}),
+                json!({"region": "south", "profit": -2.0}),
+            ],
+        );
+        let many: Vec<Value> = (0..MAX_PIE_SLICES + 1)
+            .map(|i| json!({"region": format!("r{}", i), "profit": 1}))
+            .collect();
+        let many = chart_shape(
+            &columns(&[("region", "VARCHAR"), ("profit", "DOUBLE")]),
+            &many,
+        );
+
+        for shape in [negative, many] {
+            let ChartShape::Chart {
+                suggested_chart, ..
+            } = shape
+            else {
+                panic!("expected a chart, got {:?}", shape);
+            };
+            assert_eq!(suggested_chart, ChartKind::Bar);
+        }
+    }
+
+    #[test]
+    fn dates_become_a_line_with_a_series_per_numeric_column() {
+        let shape = chart_shape(
+            &columns(&[
+                ("revenue", "DECIMAL(18,2)"),
+                ("day", "DATE"),
+                ("orders", "INTEGER"),
+            ]),
+            &[
+                // A decimal too precise for a double arrives as a string
+                json!({"day": "2024-01-01", "revenue": "100000000000000000.1", "orders": 3}),
+                json!({"day": "2024-01-02", "revenue": 12.5, "orders": null}),
+            ],
+        );
+
+        assert_eq!(
+            shape,
+            ChartShape::Chart {
+                labels: vec!["2024-01-01".into(), "2024-01-02".into()],
+                series: vec![
+                    ChartSeries {
+                        name: "revenue".into(),
+                        values: vec![Some(1e17), Some(12.5)],
+                    },
+                    ChartSeries {
+                        name: "orders".into(),
+                        values: vec![Some(3.0), None],
+                    },
+                ],
+                suggested_chart: ChartKind::Line,
+            }
+        );
+    }
+
+    #[test]
+    fn a_line_has_no_category_limit() {
+        let rows: Vec<Value> = (0..MAX_CHART_CATEGORIES + 10)
+            .map(|i| json!({"at": format!("2024-01-01 00:{:02}:00", i), "n": i}))
+            .collect();
+
+        let shape = chart_shape(&columns(&[("at", "TIMESTAMP"), ("n", "BIGINT")]), &rows);
+
+        assert!(matches!(
+            shape,
+            ChartShape::Chart {
+                suggested_chart: ChartKind::Line,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn results_that_cant_be_charted_come_back_as_a_table() {
+        let text_and_number = columns(&[("region", "VARCHAR"), ("sales", "BIGINT")]);
+        let too_many: Vec<Value> = (0..MAX_CHART_CATEGORIES + 1)
+            .map(|i| json!({"region": format!("r{}", i), "sales": i}))
+            .collect();
+
+        assert_eq!(
+            reason(chart_shape(&[], &[json!({})])),
+            "the result's columns are unknown"
+        );
+        assert_eq!(
+            reason(chart_shape(&text_and_number, &[])),
+            "the result has no rows"
+        );
+        assert_eq!(
+            reason(chart_shape(
+                &columns(&[("region", "VARCHAR"), ("tags", "VARCHAR[]")]),
+                &[json!({"region": "north", "tags": ["a"]})],
+            )),
+            "the result has a column no chart can show"
+        );
+        assert_eq!(
+            reason(chart_shape(
+                &columns(&[
+                    ("region", "VARCHAR"),
+                    ("city", "VARCHAR"),
+                    ("sales", "BIGINT")
+                ]),
+                &[json!({"region": "north", "city": "a", "sales": 1})],
+            )),
+            "the result needs exactly one date or text column for labels"
+        );
+        assert_eq!(
+            reason(chart_shape(
+                &columns(&[("sales", "BIGINT")]),
+                &[json!({"sales": 1})]
+            )),
+            "the result needs exactly one date or text column for labels"
+        );
+        assert_eq!(
+            reason(chart_shape(
+                &columns(&[("region", "VARCHAR")]),
+                &[json!({"region": "north"})]
+            )),
+            "the result has no numeric column"
+        );
+        assert_eq!(
+            reason(chart_shape(
+                &text_and_number,
+                &[
+                    json!({"region": "north", "sales": 1}),
+                    json!({"region": "north", "sales": 2}),
+                ],
+            )),
+            "the label column repeats values"
+        );
+        assert_eq!(
+            reason(chart_shape(&text_and_number, &too_many)),
+            "the result has too many categories to chart"
+        );
+    }
+
+    #[test]
+    fn shapes_serialize_with_their_tag() {
+        let chart = chart_shape(
+            &columns(&[("done", "BOOLEAN"), ("n", "UBIGINT")]),
+            &[
+                json!({"done": true, "n": 2}),
+                json!({"done": false, "n": 1}),
+            ],
+        );
+
+        assert_eq!(
+            serde_json::to_value(chart).unwrap(),
+            json!({
+                "shape": "chart",
+                "labels": ["true", "false"],
+                "series": [{"name": "n", "values": [2.0, 1.0]}],
+                "suggested_chart": "pie"
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(table("no rows")).unwrap(),
+            json!({"shape": "table", "reason": "no rows"})
+        );
+    }
+}
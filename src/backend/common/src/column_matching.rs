@@ -0,0 +1,300 @@
+use serde::{Deserialize, Serialize};
+
+use crate::creation_types::{ColumnDefinition, DataType};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnmatchedColumn {
+    pub column: String,
+    pub suggestion: Option<String>,
+}
+
+// How the requested schema lined up against the CSV's header row
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnReport {
+    pub unmatched_columns: Vec<UnmatchedColumn>,
+    pub ignored_columns: Vec<String>,
+    // Only the headers with problems, flagged here so they're fixed before they break a query
+    pub header_problems: Vec<HeaderValidation>,
+}
+
+pub fn build_column_report(
+    column_definitions: &[ColumnDefinition],
+    headers: &[String],
+) -> ColumnReport {
+    ColumnReport {
+        unmatched_columns: find_unmatched_columns(column_definitions, headers),
+        ignored_columns: find_ignored_headers(column_definitions, headers),
+        header_problems: validate_headers(headers)
+            .into_iter()
+            .filter(|validation| !validation.problems.is_empty())
+            .collect(),
+    }
+}
+
+// Schema columns with no CSV header of the same name, each with the closest header
+// as a suggestion so typos like "Sale Revenue" are easy to spot
+pub fn find_unmatched_columns(
+    column_definitions: &[ColumnDefinition],
+    headers: &[String],
+) -> Vec<UnmatchedColumn> {
+    column_definitions
+        .iter()
+        .filter(|col| !headers.iter().any(|h| h.trim() == col.column))
+        .map(|col| UnmatchedColumn {
+            column: col.column.clone(),
+            suggestion: closest_header(&col.column, headers),
+        })
+        .collect()
+}
+
+// CSV headers that no schema column asked for, in file order
+pub fn find_ignored_headers(
+    column_definitions: &[ColumnDefinition],
+    headers: &[String],
+) -> Vec<String> {
+    let mut ignored: Vec<String> = Vec::new();
+    for header in headers {
+        let header = header.trim();
+        if header.is_empty() || ignored.iter().any(|h| h == header) {
+            continue;
+        }
+        if !column_definitions.iter().any(|col| col.column == header) {
+            ignored.push(header.to_string());
+        }
+    }
+    ignored
+}
+
+pub fn remaining_headers_as_string_columns(
+    column_definitions: &[ColumnDefinition],
+    headers: &[String],
+) -> Vec<ColumnDefinition> {
+    find_ignored_headers(column_definitions, headers)
+        .into_iter()
+        .map(|header| ColumnDefinition {
+            column: header,
+            column_type: DataType::String,
+        })
+        .collect()
+}
+
+// Header issues that later break Arrow field names or DuckDB identifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderProblem {
+    Empty,
+    SurroundingWhitespace,
+    ControlCharacters,
+    DoubleQuote,
+    Duplicate,
+}
+
+impl HeaderProblem {
+    pub const ALL: [HeaderProblem; 5] = [
+        HeaderProblem::Empty,
+        HeaderProblem::SurroundingWhitespace,
+        HeaderProblem::ControlCharacters,
+        HeaderProblem::DoubleQuote,
+        HeaderProblem::Duplicate,
+    ];
+
+    pub fn parse(problem: &str) -> Option<Self> {
+        HeaderProblem::ALL
+            .into_iter()
+            .find(|known| known.as_str() == problem)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeaderProblem::Empty => "empty",
+            HeaderProblem::SurroundingWhitespace => "surrounding_whitespace",
+            HeaderProblem::ControlCharacters => "control_characters",
+            HeaderProblem::DoubleQuote => "double_quote",
+            HeaderProblem::Duplicate => "duplicate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeaderValidation {
+    pub header: String,
+    pub normalized: String,
+    pub problems: Vec<HeaderProblem>,
+}
+
+// One entry per header, in file order, with the name normalize_headers would give it
+pub fn validate_headers(headers: &[String]) -> Vec<HeaderValidation> {
+    let normalized = normalize_headers(headers);
+
+    headers
+        .iter()
+        .zip(normalized)
+        .enumerate()
+        .map(|(idx, (header, normalized))| {
+            let mut problems = Vec::new();
+            if header.trim().is_empty() {
+                problems.push(HeaderProblem::Empty);
+            } else if header.trim() != header {
+                problems.push(HeaderProblem::SurroundingWhitespace);
+            }
+            if header.chars().any(char::is_control) {
+                problems.push(HeaderProblem::ControlCharacters);
+            }
+            if header.contains('"') {
+                problems.push(HeaderProblem::DoubleQuote);
+            }
+            if headers[..idx].contains(header) {
+                problems.push(HeaderProblem::Duplicate);
+            }
+
+            HeaderValidation {
+                header: header.clone(),
+                normalized,
+                problems,
+            }
+        })
+        .collect()
+}
+
+// Trims, drops control characters and double quotes, names empty headers after their
+// position and suffixes repeats (`name_2`, `name_3`, ...) so every name is unique
+pub fn normalize_headers(headers: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::with_capacity(headers.len());
+
+    for (idx, header) in headers.iter().enumerate() {
+        let mut name = normalize_header(header);
+        if name.is_empty() {
+            name = format!("column_{}", idx + 1);
+        }
+
+        if normalized.contains(&name) {
+            let mut suffix = 2;
+            while normalized.contains(&format!("{}_{}", name, suffix)) {
+                suffix += 1;
+            }
+            name = format!("{}_{}", name, suffix);
+        }
+
+        normalized.push(name);
+    }
+
+    normalized
+}
+
+pub fn normalize_header(header: &str) -> String {
+    header
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+pub fn closest_header(column: &str, headers: &[String]) -> Option<String> {
+    let column_lower = column.to_lowercase();
+    // Anything further away than this is more likely a different column than a typo
+    let max_distance = (column_lower.chars().count() / 3).max(2);
+
+    headers
+        .iter()
+        .map(|header| {
+            let distance = levenshtein(&column_lower, &header.trim().to_lowercase());
+            (header.trim(), distance)
+        })
+        .filter(|(_, distance)| *distance <= max_distance)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(header, _)| header.to_string())
+}
+
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b_chars.len()).collect();
+    let mut current = vec![0; b_chars.len() + 1];
+
+    for (i, a_char) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b_chars.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn each_problem_is_flagged_against_its_header() {
+        let headers = headers(&["id", "", " name ", "tab\there", "say \"hi\"", "id"]);
+
+        let problems: Vec<Vec<HeaderProblem>> = validate_headers(&headers)
+            .into_iter()
+            .map(|validation| validation.problems)
+            .collect();
+
+        assert_eq!(
+            problems,
+            vec![
+                vec![],
+                vec![HeaderProblem::Empty],
+                vec![HeaderProblem::SurroundingWhitespace],
+                vec![HeaderProblem::ControlCharacters],
+                vec![HeaderProblem::DoubleQuote],
+                vec![HeaderProblem::Duplicate],
+            ]
+        );
+    }
+
+    #[test]
+    fn normalized_names_are_clean_and_unique() {
+        let headers = headers(&["id", "", " name ", "tab\there", "say \"hi\"", "id", "id_2"]);
+
+        assert_eq!(
+            normalize_headers(&headers),
+            [
+                "id", "column_2", "name", "tabhere", "say hi", "id_2", "id_2_2"
+            ]
+        );
+    }
+
+    #[test]
+    fn a_validation_carries_the_normalized_name() {
+        let validations = validate_headers(&headers(&["total", "total"]));
+
+        assert_eq!(validations[0].normalized, "total");
+        assert_eq!(validations[1].normalized, "total_2");
+    }
+
+    #[test]
+    fn a_column_report_only_lists_headers_with_problems() {
+        let columns = [ColumnDefinition {
+            column: "id".to_string(),
+            column_type: DataType::Integer,
+        }];
+
+        let report = build_column_report(&columns, &headers(&["id", "amount ", "id"]));
+
+        let flagged: Vec<&str> = report
+            .header_problems
+            .iter()
+            .map(|validation| validation.header.as_str())
+            .collect();
+        assert_eq!(flagged, ["amount ", "id"]);
+    }
+
+    #[test]
+    fn problems_read_back_from_their_names() {
+        for problem in HeaderProblem::ALL {
+            assert_eq!(HeaderProblem::parse(problem.as_str()), Some(problem));
+        }
+        assert_eq!(HeaderProblem::parse("unknown"), None);
+    }
+}
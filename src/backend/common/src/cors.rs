@@ -1,23 +1,45 @@
-use aws_lambda_events::{apigw::ApiGatewayProxyResponse, encodings::Body, http::HeaderMap};
+use aws_lambda_events::{
+    apigw::ApiGatewayProxyResponse,
+    encodings::Body,
+    http::{HeaderMap, HeaderValue},
+};
 
 pub fn create_cors_response(status_code: i64, body: Option<String>) -> ApiGatewayProxyResponse {
+    create_cors_response_with_headers(status_code, body, &[])
+}
+
+// The same response with extra headers, e.g. an ETag. A value that isn't a valid header
+// value is left out rather than failing the response.
+pub fn create_cors_response_with_headers(
+    status_code: i64,
+    body: Option<String>,
+    extra_headers: &[(&'static str, &str)],
+) -> ApiGatewayProxyResponse {
     let mut headers = HeaderMap::new();
 
     // Add CORS headers
     headers.insert("Access-Control-Allow-Origin", "*".parse().unwrap());
     headers.insert(
         "Access-Control-Allow-Methods",
-        "GET,POST,PUT,DELETE,OPTIONS".parse().unwrap(),
+        "GET,POST,PUT,PATCH,DELETE,OPTIONS".parse().unwrap(),
     );
     headers.insert(
         "Access-Control-Allow-Headers",
-        "Content-Type,Authorization,X-Amz-Date,X-Api-Key,X-Amz-Security-Token"
+        "Content-Type,Authorization,X-Amz-Date,X-Api-Key,X-Amz-Security-Token,If-None-Match"
             .parse()
             .unwrap(),
     );
+    // Browsers hide response headers from scripts unless they are listed here
+    headers.insert("Access-Control-Expose-Headers", "ETag".parse().unwrap());
     headers.insert("Access-Control-Max-Age", "86400".parse().unwrap());
     headers.insert("Content-Type", "application/json".parse().unwrap());
 
+    for (name, value) in extra_headers {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(*name, value);
+        }
+    }
+
     ApiGatewayProxyResponse {
         status_code,
         headers,
@@ -10,10 +10,12 @@ pub fn parse_date_to_days(s: &str) -> Option<i32> {
     let s = s.trim();
 
     // Fast path for ISO format (YYYY-MM-DD)
-    if s.len() == 10 && s.chars().nth(4) == Some('-') && s.chars().nth(7) == Some('-') {
-        if let Ok(parsed) = parse_date_string(s, "%Y-%m-%d") {
-            return Some(parsed);
-        }
+    if s.len() == 10
+        && s.chars().nth(4) == Some('-')
+        && s.chars().nth(7) == Some('-')
+        && let Ok(parsed) = parse_date_string(s, "%Y-%m-%d")
+    {
+        return Some(parsed);
     }
 
     // Try other formats
@@ -1,7 +1,9 @@
+use crate::duck_db::ParquetColumn;
 use arrow::datatypes::{DataType as ArrowDataType, TimeUnit};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum DataType {
     String,
@@ -26,6 +28,31 @@ impl DataType {
             }
         }
     }
+
+    // The type DuckDB reports for a column written as `to_arrow_type`
+    pub fn duckdb_type(&self) -> &'static str {
+        match self {
+            DataType::String => "VARCHAR",
+            DataType::Integer => "BIGINT",
+            DataType::Float => "DOUBLE",
+            DataType::Boolean => "BOOLEAN",
+            DataType::Date => "DATE",
+            DataType::DateTime | DataType::Timestamp => "TIMESTAMP WITH TIME ZONE",
+        }
+    }
+}
+
+// The schema `get_parquet_schema` would read back from a parquet file written with these
+// columns, all of which are nullable
+pub fn query_schema(column_definitions: &[ColumnDefinition]) -> Vec<ParquetColumn> {
+    column_definitions
+        .iter()
+        .map(|col| ParquetColumn {
+            name: col.column.clone(),
+            duckdb_type: col.column_type.duckdb_type().to_string(),
+            nullable: true,
+        })
+        .collect()
 }
 
 impl std::fmt::Display for DataType {
@@ -42,9 +69,136 @@ impl std::fmt::Display for DataType {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ColumnDefinition {
     pub column: String,
     #[serde(rename = "type")]
     pub column_type: DataType,
 }
+
+// Per-request switches that change how the conversion treats the source CSV
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ConversionOptions {
+    // Fail the job instead of writing an all-null column when a schema column has no header
+    #[serde(default)]
+    pub require_all_columns: bool,
+    // Convert CSV headers missing from the payload as string columns rather than dropping them
+    #[serde(default)]
+    pub include_remaining_as_string: bool,
+}
+
+// Where a job's data came from and who asked for it, kept on the job item so a job can be
+// traced back to its upload long after the fact
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct JobProvenance {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted_by: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+}
+
+// Where to announce that a job has finished. Either or both may be set.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct NotifySettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sns_topic_arn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+}
+
+// Body of a conversion submission; the submission lambda queues it re-serialized, so the
+// processor reads the same shape back off the queue
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ParquetCreationRequest {
+    pub payload: Vec<ColumnDefinition>,
+    pub s3_key: String,
+    pub job_id: String,
+    #[serde(flatten)]
+    pub options: ConversionOptions,
+    #[serde(flatten)]
+    pub provenance: JobProvenance,
+    // Dataset description and chosen schema, stored on the job at submission so the
+    // poller returns them without a separate update-context call. `context` is accepted
+    // to match the name update-context and the poller use.
+    #[serde(default, alias = "context")]
+    pub context_text: String,
+    #[serde(default)]
+    pub schema: HashMap<String, String>,
+    // Keep checking for the source file for a few seconds instead of rejecting at once,
+    // for submissions sent the moment an upload completes
+    #[serde(default)]
+    pub wait_for_upload: bool,
+    // Deliberately re-run a job that failed; any other existing job is still rejected
+    #[serde(default)]
+    pub resubmit: bool,
+    // Client-chosen key that makes a retried submission return the original job instead
+    // of creating another; the Idempotency-Key header takes precedence over this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    // RFC 3339 time before which the conversion must not start
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_after: Option<String>,
+    // Push a completion event instead of making the caller poll
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<NotifySettings>,
+}
+
+// Body of an upload URL request: what the caller is about to upload, which the signed
+// URL then holds them to
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UploadRequest {
+    pub content_type: String,
+    pub content_length: i64,
+}
+
+// Which processor a job runs on. Small files go to a separate queue and a low-memory
+// Lambda so they don't wait behind multi-GB conversions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessingPath {
+    Standard,
+    Fast,
+}
+
+impl ProcessingPath {
+    // Read from PARQUET_PROCESSING_PATH, which only the fast processor sets
+    pub fn from_env() -> Self {
+        std::env::var("PARQUET_PROCESSING_PATH")
+            .ok()
+            .and_then(|path| Self::parse(&path))
+            .unwrap_or(ProcessingPath::Standard)
+    }
+
+    pub fn parse(path: &str) -> Option<Self> {
+        match path {
+            "standard" => Some(ProcessingPath::Standard),
+            "fast" => Some(ProcessingPath::Fast),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessingPath::Standard => "standard",
+            ProcessingPath::Fast => "fast",
+        }
+    }
+}
+
+// Value counts for one column: empty cells, cells that couldn't be coerced to the column's
+// type (both written as null), and non-null values written
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColumnStats {
+    pub empty: u64,
+    pub coercion_failures: u64,
+    pub written: u64,
+}
+
+impl ColumnStats {
+    pub fn merge(&mut self, other: &ColumnStats) {
+        self.empty += other.empty;
+        self.coercion_failures += other.coercion_failures;
+        self.written += other.written;
+    }
+}
@@ -0,0 +1,645 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::creation_types::{DataType, ParquetCreationRequest, UploadRequest};
+use crate::s3::upload_key;
+
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+// Caps on provenance fields, which are stored on every job item and returned by the poller
+const MAX_FILENAME_LENGTH: usize = 1024;
+const MAX_SUBMITTED_BY_LENGTH: usize = 256;
+const MAX_LABELS: usize = 20;
+const MAX_LABEL_KEY_LENGTH: usize = 64;
+const MAX_LABEL_VALUE_LENGTH: usize = 256;
+
+const MAX_WEBHOOK_URL_LENGTH: usize = 2048;
+
+// Browsers label CSV files inconsistently; Windows often reports them as Excel files
+const CSV_CONTENT_TYPES: &[&str] = &[
+    "text/csv",
+    "application/csv",
+    "text/plain",
+    "application/vnd.ms-excel",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+// Parses a submission body and checks everything the processor would otherwise only
+// trip over after the job was queued. All problems are reported at once, keyed by the
+// field they belong to (`payload[2].type`), so the caller can point at each one.
+pub fn parse_creation_request(body: &str) -> Result<ParquetCreationRequest, Vec<FieldError>> {
+    let value: Value = serde_json::from_str(body)
+        .map_err(|e| vec![FieldError::new("body", format!("invalid JSON: {}", e))])?;
+
+    validate_creation_request(value)
+}
+
+// The same checks for a request that has already been parsed, e.g. one entry of a batch
+pub fn validate_creation_request(
+    mut value: Value,
+) -> Result<ParquetCreationRequest, Vec<FieldError>> {
+    let Some(fields) = value.as_object_mut() else {
+        return Err(vec![FieldError::new("body", "must be a JSON object")]);
+    };
+
+    let mut errors = Vec::new();
+
+    match fields.get("job_id") {
+        Some(Value::String(text)) if !text.trim().is_empty() => {}
+        Some(Value::String(_)) => errors.push(FieldError::new("job_id", "must not be empty")),
+        Some(_) => errors.push(FieldError::new("job_id", "must be a string")),
+        None => errors.push(FieldError::new("job_id", "is required")),
+    }
+
+    // Optional for jobs created through the upload endpoint, whose file is at a known key
+    match fields.get("s3_key") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(text)) if !text.trim().is_empty() => {}
+        Some(Value::String(_)) => errors.push(FieldError::new("s3_key", "must not be empty")),
+        Some(_) => errors.push(FieldError::new("s3_key", "must be a string")),
+    }
+
+    match fields.get("start_after") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(start_after))
+            if chrono::DateTime::parse_from_rfc3339(start_after).is_ok() => {}
+        Some(_) => errors.push(FieldError::new(
+            "start_after",
+            "must be an RFC 3339 timestamp",
+        )),
+    }
+
+    match fields.get("idempotency_key") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(key)) => {
+            if let Err(message) = check_idempotency_key(key) {
+                errors.push(FieldError::new("idempotency_key", message));
+            }
+        }
+        Some(_) => errors.push(FieldError::new("idempotency_key", "must be a string")),
+    }
+
+    for (field, max_length) in [
+        ("original_filename", MAX_FILENAME_LENGTH),
+        ("submitted_by", MAX_SUBMITTED_BY_LENGTH),
+    ] {
+        match fields.get(field) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(text)) if text.chars().count() > max_length => errors.push(
+                FieldError::new(field, format!("must be at most {} characters", max_length)),
+            ),
+            Some(Value::String(_)) => {}
+            Some(_) => errors.push(FieldError::new(field, "must be a string")),
+        }
+    }
+
+    match fields.get("labels") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(labels)) => validate_labels(labels, &mut errors),
+        Some(_) => errors.push(FieldError::new(
+            "labels",
+            "must be an object of string values",
+        )),
+    }
+
+    match fields.get("notify") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(notify)) => validate_notify(notify, &mut errors),
+        Some(_) => errors.push(FieldError::new("notify", "must be an object")),
+    }
+
+    match fields.get("payload") {
+        Some(Value::Array(columns)) if columns.is_empty() => errors.push(FieldError::new(
+            "payload",
+            "must contain at least one column",
+        )),
+        Some(Value::Array(columns)) => validate_columns(columns, &mut errors),
+        Some(_) => errors.push(FieldError::new("payload", "must be an array of columns")),
+        None => errors.push(FieldError::new("payload", "is required")),
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    if matches!(fields.get("s3_key"), None | Some(Value::Null)) {
+        let job_id = fields
+            .get("job_id")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let s3_key = upload_key(job_id);
+        fields.insert("s3_key".to_string(), Value::String(s3_key));
+    }
+
+    // Anything left is a type mismatch in an optional field, e.g. a non-boolean option
+    serde_json::from_value(value).map_err(|e| vec![FieldError::new("body", e.to_string())])
+}
+
+// Checks an upload URL request against the content types we convert and the largest
+// file the caller may upload
+pub fn parse_upload_request(body: &str, max_bytes: i64) -> Result<UploadRequest, Vec<FieldError>> {
+    let value: Value = serde_json::from_str(body)
+        .map_err(|e| vec![FieldError::new("body", format!("invalid JSON: {}", e))])?;
+    let Some(fields) = value.as_object() else {
+        return Err(vec![FieldError::new("body", "must be a JSON object")]);
+    };
+
+    let mut errors = Vec::new();
+
+    match fields.get("content_type") {
+        Some(Value::String(content_type)) if is_csv_content_type(content_type) => {}
+        Some(Value::String(_)) => errors.push(FieldError::new(
+            "content_type",
+            format!("must be one of {}", CSV_CONTENT_TYPES.join(", ")),
+        )),
+        Some(_) => errors.push(FieldError::new("content_type", "must be a string")),
+        None => errors.push(FieldError::new("content_type", "is required")),
+    }
+
+    match fields.get("content_length").map(Value::as_i64) {
+        Some(Some(length)) if length > 0 && length <= max_bytes => {}
+        Some(Some(_)) => errors.push(FieldError::new(
+            "content_length",
+            format!("must be between 1 and {} bytes", max_bytes),
+        )),
+        Some(None) => errors.push(FieldError::new("content_length", "must be an integer")),
+        None => errors.push(FieldError::new("content_length", "is required")),
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    serde_json::from_value(value).map_err(|e| vec![FieldError::new("body", e.to_string())])
+}
+
+fn is_csv_content_type(content_type: &str) -> bool {
+    CSV_CONTENT_TYPES.contains(&content_type)
+}
+
+// Idempotency keys become part of a DynamoDB key, so keep them short and printable
+pub fn check_idempotency_key(key: &str) -> Result<(), &'static str> {
+    if key.trim().is_empty() {
+        return Err("must not be empty");
+    }
+    if key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err("must be at most 255 characters");
+    }
+    if key.chars().any(char::is_control) {
+        return Err("must not contain control characters");
+    }
+    Ok(())
+}
+
+// Checks a job's replacement labels against the same caps as at submission. An empty
+// object clears them.
+pub fn parse_labels(value: &Value) -> Result<HashMap<String, String>, Vec<FieldError>> {
+    let Some(labels) = value.as_object() else {
+        return Err(vec![FieldError::new(
+            "labels",
+            "must be an object of string values",
+        )]);
+    };
+
+    let mut errors = Vec::new();
+    validate_labels(labels, &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(labels
+        .iter()
+        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+        .collect())
+}
+
+fn validate_labels(labels: &serde_json::Map<String, Value>, errors: &mut Vec<FieldError>) {
+    if labels.len() > MAX_LABELS {
+        errors.push(FieldError::new(
+            "labels",
+            format!("must have at most {} entries", MAX_LABELS),
+        ));
+    }
+
+    for (key, value) in labels {
+        let field = format!("labels.{}", key);
+        if key.trim().is_empty() || key.chars().count() > MAX_LABEL_KEY_LENGTH {
+            errors.push(FieldError::new(
+                field,
+                format!("key must be 1 to {} characters", MAX_LABEL_KEY_LENGTH),
+            ));
+            continue;
+        }
+        match value {
+            Value::String(text) if text.chars().count() > MAX_LABEL_VALUE_LENGTH => {
+                errors.push(FieldError::new(
+                    field,
+                    format!("must be at most {} characters", MAX_LABEL_VALUE_LENGTH),
+                ))
+            }
+            Value::String(_) => {}
+            _ => errors.push(FieldError::new(field, "must be a string")),
+        }
+    }
+}
+
+fn validate_notify(notify: &serde_json::Map<String, Value>, errors: &mut Vec<FieldError>) {
+    let errors_before = errors.len();
+    let mut targets = 0;
+
+    match notify.get("sns_topic_arn") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(arn)) if arn.starts_with("arn:aws:sns:") => targets += 1,
+        Some(_) => errors.push(FieldError::new(
+            "notify.sns_topic_arn",
+            "must be an SNS topic ARN",
+        )),
+    }
+
+    // Events are signed rather than encrypted, so they must not travel in the clear
+    match notify.get("webhook_url") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(url)) if url.len() > MAX_WEBHOOK_URL_LENGTH => {
+            errors.push(FieldError::new(
+                "notify.webhook_url",
+                format!("must be at most {} characters", MAX_WEBHOOK_URL_LENGTH),
+            ))
+        }
+        Some(Value::String(url)) if url.len() > 8 && url.starts_with("https://") => targets += 1,
+        Some(_) => errors.push(FieldError::new(
+            "notify.webhook_url",
+            "must be an https URL",
+        )),
+    }
+
+    if targets == 0 && errors.len() == errors_before {
+        errors.push(FieldError::new(
+            "notify",
+            "must set sns_topic_arn or webhook_url",
+        ));
+    }
+}
+
+fn validate_columns(columns: &[Value], errors: &mut Vec<FieldError>) {
+    let mut seen: Vec<&str> = Vec::with_capacity(columns.len());
+
+    for (idx, column) in columns.iter().enumerate() {
+        let Some(column) = column.as_object() else {
+            errors.push(FieldError::new(
+                format!("payload[{}]", idx),
+                "must be an object with column and type",
+            ));
+            continue;
+        };
+
+        match column.get("column") {
+            Some(Value::String(name)) if name.trim().is_empty() => errors.push(FieldError::new(
+                format!("payload[{}].column", idx),
+                "must not be empty",
+            )),
+            Some(Value::String(name)) if seen.contains(&name.as_str()) => {
+                errors.push(FieldError::new(
+                    format!("payload[{}].column", idx),
+                    format!("duplicate column name '{}'", name),
+                ))
+            }
+            Some(Value::String(name)) => seen.push(name),
+            Some(_) => errors.push(FieldError::new(
+                format!("payload[{}].column", idx),
+                "must be a string",
+            )),
+            None => errors.push(FieldError::new(
+                format!("payload[{}].column", idx),
+                "is required",
+            )),
+        }
+
+        match column.get("type") {
+            Some(type_value @ Value::String(type_name)) => {
+                if serde_json::from_value::<DataType>(type_value.clone()).is_err() {
+                    errors.push(FieldError::new(
+                        format!("payload[{}].type", idx),
+                        format!("unknown type '{}'", type_name),
+                    ));
+                }
+            }
+            Some(_) => errors.push(FieldError::new(
+                format!("payload[{}].type", idx),
+                "must be a string",
+            )),
+            None => errors.push(FieldError::new(
+                format!("payload[{}].type", idx),
+                "is required",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|error| error.field).collect()
+    }
+
+    #[test]
+    fn labels_are_read_as_strings() {
+        let labels = parse_labels(&json!({"project": "q3-campaign", "team": "growth"})).unwrap();
+
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["project"], "q3-campaign");
+    }
+
+    #[test]
+    fn an_empty_object_clears_the_labels() {
+        assert!(parse_labels(&json!({})).unwrap().is_empty());
+    }
+
+    #[test]
+    fn labels_must_be_an_object() {
+        for value in [json!(null), json!("project"), json!(["project"])] {
+            assert_eq!(fields(parse_labels(&value).unwrap_err()), ["labels"]);
+        }
+    }
+
+    #[test]
+    fn every_bad_label_is_reported() {
+        let errors = parse_labels(&json!({
+            "": "blank key",
+            "count": 3,
+            "note": "x".repeat(MAX_LABEL_VALUE_LENGTH + 1),
+            "k".repeat(MAX_LABEL_KEY_LENGTH + 1): "long key",
+            "fine": "ok"
+        }))
+        .unwrap_err();
+
+        let mut fields = fields(errors);
+        fields.sort();
+        assert_eq!(
+            fields,
+            [
+                "labels.".to_string(),
+                "labels.count".to_string(),
+                format!("labels.{}", "k".repeat(MAX_LABEL_KEY_LENGTH + 1)),
+                "labels.note".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_at_the_caps_are_accepted() {
+        let labels: serde_json::Map<String, Value> = (0..MAX_LABELS)
+            .map(|i| {
+                let key = format!("{:0>width$}", i, width = MAX_LABEL_KEY_LENGTH);
+                (key, json!("v".repeat(MAX_LABEL_VALUE_LENGTH)))
+            })
+            .collect();
+
+        assert_eq!(
+            parse_labels(&Value::Object(labels)).unwrap().len(),
+            MAX_LABELS
+        );
+    }
+
+    #[test]
+    fn too_many_labels_are_refused() {
+        let labels: serde_json::Map<String, Value> = (0..=MAX_LABELS)
+            .map(|i| (format!("key{}", i), json!("value")))
+            .collect();
+
+        assert_eq!(
+            fields(parse_labels(&Value::Object(labels)).unwrap_err()),
+            ["labels"]
+        );
+    }
+
+    fn request(overrides: Value) -> Value {
+        let mut body = json!({
+            "job_id": "job-1",
+            "s3_key": "uploads/sales.csv",
+            "payload": [
+                {"column": "id", "type": "integer"},
+                {"column": "name", "type": "string"}
+            ]
+        });
+        for (key, value) in overrides.as_object().unwrap() {
+            body[key] = value.clone();
+        }
+        body
+    }
+
+    fn rejected(overrides: Value) -> Vec<FieldError> {
+        validate_creation_request(request(overrides)).unwrap_err()
+    }
+
+    #[test]
+    fn a_valid_request_parses() {
+        let parsed = validate_creation_request(request(json!({}))).unwrap();
+
+        assert_eq!(parsed.job_id, "job-1");
+        assert_eq!(parsed.s3_key, "uploads/sales.csv");
+        assert_eq!(parsed.payload.len(), 2);
+    }
+
+    #[test]
+    fn a_missing_s3_key_defaults_to_the_upload_key() {
+        let mut body = request(json!({}));
+        body.as_object_mut().unwrap().remove("s3_key");
+
+        let parsed = validate_creation_request(body).unwrap();
+
+        assert_eq!(parsed.s3_key, upload_key("job-1"));
+    }
+
+    #[test]
+    fn a_body_that_is_not_a_json_object_is_refused() {
+        assert_eq!(fields(parse_creation_request("{").unwrap_err()), ["body"]);
+        assert_eq!(fields(parse_creation_request("[]").unwrap_err()), ["body"]);
+    }
+
+    #[test]
+    fn a_missing_or_blank_job_id_is_refused() {
+        let mut body = request(json!({}));
+        body.as_object_mut().unwrap().remove("job_id");
+        assert_eq!(
+            validate_creation_request(body).unwrap_err(),
+            [FieldError::new("job_id", "is required")]
+        );
+
+        assert_eq!(
+            rejected(json!({"job_id": " "})),
+            [FieldError::new("job_id", "must not be empty")]
+        );
+        assert_eq!(
+            rejected(json!({"job_id": 7})),
+            [FieldError::new("job_id", "must be a string")]
+        );
+    }
+
+    #[test]
+    fn a_blank_s3_key_is_refused() {
+        assert_eq!(
+            rejected(json!({"s3_key": ""})),
+            [FieldError::new("s3_key", "must not be empty")]
+        );
+    }
+
+    #[test]
+    fn an_empty_payload_is_refused() {
+        assert_eq!(
+            rejected(json!({"payload": []})),
+            [FieldError::new(
+                "payload",
+                "must contain at least one column"
+            )]
+        );
+        assert_eq!(
+            rejected(json!({"payload": "id"})),
+            [FieldError::new("payload", "must be an array of columns")]
+        );
+    }
+
+    #[test]
+    fn each_bad_column_is_reported_by_position() {
+        let errors = rejected(json!({"payload": [
+            {"column": "id", "type": "integer"},
+            {"column": "id", "type": "string"},
+            {"column": "", "type": "string"},
+            {"column": "price", "type": "money"},
+            {"type": "string"},
+            "amount"
+        ]}));
+
+        assert_eq!(
+            errors,
+            [
+                FieldError::new("payload[1].column", "duplicate column name 'id'"),
+                FieldError::new("payload[2].column", "must not be empty"),
+                FieldError::new("payload[3].type", "unknown type 'money'"),
+                FieldError::new("payload[4].column", "is required"),
+                FieldError::new("payload[5]", "must be an object with column and type"),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_start_after_that_is_not_rfc_3339_is_refused() {
+        assert_eq!(
+            fields(rejected(json!({"start_after": "tomorrow"}))),
+            ["start_after"]
+        );
+        assert!(
+            validate_creation_request(request(json!({"start_after": "2025-06-01T09:00:00Z"})))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn a_bad_idempotency_key_is_refused() {
+        for key in [json!(""), json!("k".repeat(256)), json!("a\nb"), json!(5)] {
+            assert_eq!(
+                fields(rejected(json!({"idempotency_key": key}))),
+                ["idempotency_key"]
+            );
+        }
+    }
+
+    #[test]
+    fn overlong_provenance_is_refused() {
+        let errors = rejected(json!({
+            "original_filename": "f".repeat(MAX_FILENAME_LENGTH + 1),
+            "submitted_by": "s".repeat(MAX_SUBMITTED_BY_LENGTH + 1)
+        }));
+
+        assert_eq!(fields(errors), ["original_filename", "submitted_by"]);
+    }
+
+    #[test]
+    fn notify_needs_a_valid_target() {
+        assert_eq!(fields(rejected(json!({"notify": {}}))), ["notify"]);
+        assert_eq!(
+            fields(rejected(
+                json!({"notify": {"webhook_url": "http://example.com/hook"}})
+            )),
+            ["notify.webhook_url"]
+        );
+        assert_eq!(
+            fields(rejected(json!({"notify": {"sns_topic_arn": "topic"}}))),
+            ["notify.sns_topic_arn"]
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let errors = rejected(json!({"job_id": "", "payload": [], "start_after": "soon"}));
+
+        assert_eq!(fields(errors), ["job_id", "start_after", "payload"]);
+    }
+
+    #[test]
+    fn an_option_of_the_wrong_type_is_refused() {
+        assert_eq!(
+            fields(rejected(json!({"wait_for_upload": "yes"}))),
+            ["body"]
+        );
+    }
+
+    const MAX_UPLOAD_BYTES: i64 = 1024;
+
+    #[test]
+    fn an_upload_of_a_csv_within_the_size_limit_is_accepted() {
+        for content_type in CSV_CONTENT_TYPES {
+            let body = json!({"content_type": content_type, "content_length": MAX_UPLOAD_BYTES});
+            assert!(parse_upload_request(&body.to_string(), MAX_UPLOAD_BYTES).is_ok());
+        }
+    }
+
+    #[test]
+    fn an_upload_outside_the_size_limit_is_refused() {
+        for length in [json!(0), json!(MAX_UPLOAD_BYTES + 1), json!("1KB")] {
+            let body = json!({"content_type": "text/csv", "content_length": length});
+            assert_eq!(
+                fields(parse_upload_request(&body.to_string(), MAX_UPLOAD_BYTES).unwrap_err()),
+                ["content_length"],
+                "{}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn an_upload_that_is_not_a_csv_is_refused() {
+        for content_type in [json!("application/pdf"), json!("text/CSV"), json!(1)] {
+            let body = json!({"content_type": content_type, "content_length": 10});
+            assert_eq!(
+                fields(parse_upload_request(&body.to_string(), MAX_UPLOAD_BYTES).unwrap_err()),
+                ["content_type"]
+            );
+        }
+    }
+
+    #[test]
+    fn an_upload_request_missing_its_fields_reports_both() {
+        assert_eq!(
+            fields(parse_upload_request("{}", MAX_UPLOAD_BYTES).unwrap_err()),
+            ["content_type", "content_length"]
+        );
+    }
+}
@@ -1,70 +1,2704 @@
-use duckdb::{Connection, Result};
+use duckdb::Connection;
+use duckdb::types::Value;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
 
-pub fn setup_duckdb_connection() -> Result<Connection> {
+use crate::error::Error;
+use crate::memory::function_memory_mb;
+use crate::query_result::{ColumnMeta, QueryResult, ResultLimits, json_value, row_object_bytes};
+
+// Opens an in-memory database sized for the function it runs in
+pub fn setup_duckdb_connection() -> Result<Connection, Error> {
     let conn = Connection::open_in_memory()?;
-    println!("Connected to duckdb");
+    let settings = DuckDbSettings::for_memory_mb(function_memory_mb());
+    conn.execute_batch(&settings.statements())?;
+    // Parquet columns of JSON type read as JSON rather than text
+    load_extensions(&conn, &[Extension::Json])?;
+    info!(
+        memory_limit_mb = settings.memory_limit_mb,
+        threads = settings.threads,
+        temp_directory = %settings.temp_directory,
+        "Connected to duckdb"
+    );
     Ok(conn)
 }
 
-pub fn get_schema_from_parquet_file(conn: &Connection, file_path: &str) -> Result<String> {
-    let describe_sql = format!("DESCRIBE SELECT * FROM read_parquet('{}')", file_path);
+// Share of the function's memory DuckDB may use before it spills; the rest is for the
+// runtime, the result JSON and the allocator
+const DUCKDB_MEMORY_PERCENT: usize = 60;
 
-    let mut stmt = conn.prepare(&describe_sql).map_err(|e| {
-        println!("[ERROR] Failed to prepare the DESCRIBE statement: {:?}", e);
-        e
+// Lambda gives a function one vCPU for every 1769 MB, up to six
+const MB_PER_VCPU: usize = 1769;
+const MAX_VCPUS: usize = 6;
+
+// Where a connection spills when nothing more specific is set. An in-memory database
+// otherwise spills to the working directory, which is read-only in Lambda, so a query
+// that outgrows memory would fail instead of spilling.
+const DEFAULT_SPILL_DIRECTORY: &str = "/tmp/duckdb_spill";
+
+// DuckDB's defaults assume it has the machine to itself: most of its memory and a thread
+// per core it can see. In a Lambda that takes the process over its memory cap before
+// DuckDB thinks to spill, so connections are limited to what the function was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuckDbSettings {
+    pub memory_limit_mb: usize,
+    pub threads: usize,
+    pub temp_directory: String,
+}
+
+impl DuckDbSettings {
+    pub fn for_memory_mb(memory_mb: usize) -> Self {
+        DuckDbSettings {
+            memory_limit_mb: (memory_mb * DUCKDB_MEMORY_PERCENT / 100).max(1),
+            threads: memory_mb.div_ceil(MB_PER_VCPU).clamp(1, MAX_VCPUS),
+            temp_directory: DEFAULT_SPILL_DIRECTORY.to_string(),
+        }
+    }
+
+    // The SET statements that apply these settings to a connection
+    pub fn statements(&self) -> String {
+        format!(
+            "SET memory_limit = '{}MiB'; SET threads = {}; SET temp_directory = {};",
+            self.memory_limit_mb,
+            self.threads,
+            sql_string(&self.temp_directory)
+        )
+    }
+}
+
+// Where DuckDB keeps installed extensions; the Lambda home directory is read-only
+const DUCKDB_HOME_DIRECTORY: &str = "/tmp";
+
+// A directory of `<name>.duckdb_extension` files, such as a Lambda layer ships, for functions
+// that can't reach DuckDB's extension repository
+const EXTENSION_DIR_ENV: &str = "DUCKDB_EXTENSION_DIR";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    Httpfs,
+    Json,
+}
+
+impl Extension {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extension::Httpfs => "httpfs",
+            Extension::Json => "json",
+        }
+    }
+}
+
+const EXTENSION_LOADED_SQL: &str =
+    "SELECT coalesce(bool_or(loaded), false) FROM duckdb_extensions() WHERE extension_name = ?";
+
+// Loads each extension the connection's database hasn't already: the bundled file in
+// DUCKDB_EXTENSION_DIR when there is one, otherwise installed from DuckDB's repository,
+// which needs internet access. Extensions built into the library, and ones a warm database
+// loaded earlier, are left as they are.
+pub fn load_extensions(conn: &Connection, extensions: &[Extension]) -> Result<(), Error> {
+    let bundled_dir = std::env::var(EXTENSION_DIR_ENV)
+        .ok()
+        .filter(|dir| !dir.is_empty());
+    for extension in extensions {
+        load_extension(conn, *extension, bundled_dir.as_deref().map(Path::new))?;
+    }
+    Ok(())
+}
+
+fn load_extension(
+    conn: &Connection,
+    extension: Extension,
+    bundled_dir: Option<&Path>,
+) -> Result<(), Error> {
+    let name = extension.name();
+    let loaded: bool = conn.query_row(EXTENSION_LOADED_SQL, [name], |row| row.get(0))?;
+    if loaded {
+        return Ok(());
+    }
+
+    // Why the bundled copy couldn't be used, for the error if installing fails as well
+    let bundled_error = match bundled_dir {
+        Some(dir) => {
+            let path = dir.join(format!("{}.duckdb_extension", name));
+            if !path.is_file() {
+                format!("no bundled copy at {}", path.display())
+            } else {
+                let load_sql = format!("LOAD {};", sql_string(&path.to_string_lossy()));
+                match conn.execute_batch(&load_sql) {
+                    Ok(()) => {
+                        info!(extension = name, path = %path.display(), "Loaded bundled DuckDB extension");
+                        return Ok(());
+                    }
+                    Err(e) => format!(
+                        "the bundled copy at {} failed to load: {}",
+                        path.display(),
+                        e
+                    ),
+                }
+            }
+        }
+        None => format!("{} is not set", EXTENSION_DIR_ENV),
+    };
+
+    warn!(extension = name, reason = %bundled_error, "Installing DuckDB extension");
+    conn.execute_batch(&format!(
+        "SET home_directory = {}; INSTALL {}; LOAD {};",
+        sql_string(DUCKDB_HOME_DIRECTORY),
+        name,
+        name
+    ))
+    .map_err(|e| Error::Extension {
+        name,
+        details: format!("{}, and installing it failed: {}", bundled_error, e),
     })?;
+    info!(extension = name, "Installed DuckDB extension");
+    Ok(())
+}
+
+// Points DuckDB's spill files at `dir`, capped at `max_bytes`. A query that needs more
+// fails with an out-of-memory error instead of filling /tmp.
+pub fn set_spill_directory(conn: &Connection, dir: &Path, max_bytes: u64) -> Result<(), Error> {
+    conn.execute_batch(&format!(
+        "SET temp_directory = {}; SET max_temp_directory_size = '{}B';",
+        sql_string(&dir.to_string_lossy()),
+        max_bytes
+    ))?;
+    Ok(())
+}
+
+pub fn s3_parquet_url(bucket: &str, key: &str) -> String {
+    format!("s3://{}/{}", bucket, key)
+}
+
+// The one place a value is spliced into SQL as a string literal, for the statements DuckDB
+// won't take a bound parameter in: CREATE VIEW, SET, CREATE SECRET, LOAD and COPY.
+// Everywhere else a path or value is bound instead. Doubling single quotes is all the
+// escaping a standard DuckDB string needs, as backslashes in one are plain characters.
+fn sql_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn sql_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Lets the connection read s3:// URLs directly, signed with the function's own temporary
+// credentials. HTTP requests are logged so `http_bytes_fetched` can say how much of a file
+// a query actually pulled. The log belongs to the database, which a warm container keeps,
+// so it is cleared here for each invocation to count only its own requests.
+pub fn enable_s3_access(conn: &Connection) -> Result<(), Error> {
+    let credential =
+        |name: &str| std::env::var(name).map_err(|_| Error::Config(format!("{} is not set", name)));
+    let key_id = credential("AWS_ACCESS_KEY_ID")?;
+    let secret = credential("AWS_SECRET_ACCESS_KEY")?;
+    let session_token = credential("AWS_SESSION_TOKEN")?;
+    let region = credential("AWS_REGION")?;
+
+    load_extensions(conn, &[Extension::Httpfs])?;
+    conn.execute_batch(&format!(
+        "CREATE OR REPLACE SECRET lambda_s3 (TYPE s3, KEY_ID {}, SECRET {}, SESSION_TOKEN {}, REGION {});",
+        sql_string(&key_id),
+        sql_string(&secret),
+        sql_string(&session_token),
+        sql_string(&region)
+    ))?;
+    conn.execute_batch("CALL enable_logging('HTTP'); CALL truncate_duckdb_logs();")?;
+
+    info!(region = %region, "Enabled DuckDB S3 access");
+    Ok(())
+}
+
+const HTTP_BYTES_FETCHED_SQL: &str = "
+    SELECT coalesce(sum(TRY_CAST(coalesce(
+        response.headers['Content-Length'],
+        response.headers['content-length']
+    ) AS BIGINT)), 0)::BIGINT
+    FROM duckdb_logs_parsed('HTTP')
+    WHERE request.type = 'GET'";
+
+// Response bytes of every GET the connection has made since `enable_s3_access`
+pub fn http_bytes_fetched(conn: &Connection) -> Result<u64, Error> {
+    let bytes: i64 = conn.query_row(HTTP_BYTES_FETCHED_SQL, [], |row| row.get(0))?;
+    Ok(bytes.max(0) as u64)
+}
 
-    let rows = stmt.query_map([], |row| {
-        let column_name: String = row.get("column_name")?;
-        let column_type: String = row.get("column_type")?;
-        let part = format!("{}: {}", column_name, column_type);
-        Ok(part)
-    }).map_err(|e| {
-        println!("[ERROR] Failed to execute query_map for DESCRIBE. This often means the file path is incorrect, the file is not a valid Parquet file, or there are permission issues. Error: {:?}", e);
+// The path is bound as a parameter, so no path can change what the statement does
+const PARQUET_DESCRIBE_SQL: &str = "DESCRIBE SELECT * FROM read_parquet(?)";
+
+// A column of a parquet file as DuckDB reads it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParquetColumn {
+    pub name: String,
+    pub duckdb_type: String,
+    pub nullable: bool,
+}
+
+pub fn get_parquet_schema(conn: &Connection, file_path: &str) -> Result<Vec<ParquetColumn>, Error> {
+    let mut stmt = conn.prepare(PARQUET_DESCRIBE_SQL).map_err(|e| {
+        error!(error = ?e, "Failed to prepare the DESCRIBE statement");
         e
     })?;
 
-    let mut schema_parts = Vec::new();
+    let rows = stmt
+        .query_map([file_path], |row| {
+            let null: String = row.get("null")?;
+            Ok(ParquetColumn {
+                name: row.get("column_name")?,
+                duckdb_type: row.get("column_type")?,
+                nullable: null == "YES",
+            })
+        })
+        .map_err(|e| {
+            // This often means the file path is incorrect, the file is not a valid Parquet
+            // file, or there are permission issues
+            error!(error = ?e, file_path, "Failed to execute query_map for DESCRIBE");
+            e
+        })?;
+
+    let mut columns = Vec::new();
     for row_result in rows {
         match row_result {
-            Ok(part) => schema_parts.push(part),
+            Ok(column) => columns.push(column),
             Err(e) => {
-                println!(
-                    "[ERROR] Failed to process a row from the DESCRIBE query: {:?}",
-                    e
-                );
-                return Err(e);
+                error!(error = ?e, "Failed to process a row from the DESCRIBE query");
+                return Err(e.into());
             }
         }
     }
 
-    if schema_parts.is_empty() {
-        println!(
-            "[ERROR] The DESCRIBE query returned no rows. The file might be empty or invalid."
+    if columns.is_empty() {
+        error!(
+            file_path,
+            "The DESCRIBE query returned no rows, the file might be empty or invalid"
         );
-        return Err(duckdb::Error::QueryReturnedNoRows);
+        return Err(duckdb::Error::QueryReturnedNoRows.into());
     }
 
-    let final_schema = schema_parts.join(", ");
-    Ok(final_schema)
+    Ok(columns)
+}
+
+// Whether `name` can go into SQL as it is, without double quotes
+fn is_plain_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// The schema as the SQL prompt shows it, `name: TYPE` joined by commas. A name that isn't a
+// plain identifier is shown double-quoted, the way the query has to write it, so a comma
+// or colon inside a name can't be read as the start of another column.
+pub fn render_schema_for_prompt(columns: &[ParquetColumn]) -> String {
+    columns
+        .iter()
+        .map(|column| {
+            let name = if is_plain_identifier(&column.name) {
+                column.name.clone()
+            } else {
+                sql_identifier(&column.name)
+            };
+            format!("{}: {}", name, column.duckdb_type)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Like the DESCRIBE, these only read the file's footer, never its row groups, and bind the
+// path as a parameter
+const PARQUET_ROW_COUNT_SQL: &str = "SELECT num_rows FROM parquet_file_metadata(?)";
+const PARQUET_FILE_STATS_SQL: &str =
+    "SELECT num_rows, num_row_groups FROM parquet_file_metadata(?)";
+const PARQUET_CODECS_SQL: &str =
+    "SELECT DISTINCT compression FROM parquet_metadata(?) ORDER BY compression";
+// read_blob skips the file's content when only its size is selected, so a file in S3 costs
+// one HEAD request
+const FILE_SIZE_SQL: &str = "SELECT size FROM read_blob(?)";
+
+// How many rows the parquet file holds, as its footer records it
+pub fn parquet_row_count(conn: &Connection, file_path: &str) -> Result<u64, Error> {
+    let rows: i64 = conn.query_row(PARQUET_ROW_COUNT_SQL, [file_path], |row| row.get(0))?;
+    Ok(rows.max(0) as u64)
+}
+
+// What a parquet file's footer says about it, without reading any of its rows
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParquetFileStats {
+    pub file_bytes: u64,
+    pub row_count: u64,
+    pub row_groups: u64,
+    // Every codec any column chunk uses, in name order; usually just the one
+    pub compression: Vec<String>,
+}
+
+pub fn parquet_file_stats(conn: &Connection, file_path: &str) -> Result<ParquetFileStats, Error> {
+    let (row_count, row_groups): (i64, i64) =
+        conn.query_row(PARQUET_FILE_STATS_SQL, [file_path], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    let file_bytes: i64 = conn.query_row(FILE_SIZE_SQL, [file_path], |row| row.get(0))?;
+
+    let mut stmt = conn.prepare(PARQUET_CODECS_SQL)?;
+    let compression = stmt
+        .query_map([file_path], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ParquetFileStats {
+        file_bytes: file_bytes.max(0) as u64,
+        row_count: row_count.max(0) as u64,
+        row_groups: row_groups.max(0) as u64,
+        compression,
+    })
+}
+
+// One row per column chunk, in file order. The `_value` statistics are the ones written in
+// the column's sort order; older writers only fill in the deprecated `min` and `max`.
+const PARQUET_METADATA_SQL: &str = "
+    SELECT row_group_id, row_group_num_rows, column_id, path_in_schema, type,
+        coalesce(stats_min_value, stats_min), coalesce(stats_max_value, stats_max),
+        stats_null_count, compression, encodings,
+        total_compressed_size, total_uncompressed_size
+    FROM parquet_metadata(?)
+    ORDER BY row_group_id, column_id";
+
+// Sizes of one row group, summed over its column chunks
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RowGroupReport {
+    pub rows: u64,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+}
+
+// A column's min and max in one row group, as the writer recorded them. Either is missing
+// when the writer left it out, which stops DuckDB skipping that row group on a filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnRange {
+    pub min: Option<String>,
+    pub max: Option<String>,
+}
+
+// A column across every row group
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnReport {
+    pub name: String,
+    pub physical_type: String,
+    pub compression: Vec<String>,
+    pub encodings: Vec<String>,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    // Row groups with both a min and a max for the column
+    pub row_groups_with_stats: u64,
+    // None unless every row group recorded its null count
+    pub null_count: Option<u64>,
+    // One per row group, in order
+    pub ranges: Vec<ColumnRange>,
+}
+
+// How a parquet file is laid out, for working out why queries on it are slow: how evenly
+// its rows are spread over row groups, what each column costs, and whether its statistics
+// let DuckDB skip row groups
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MetadataReport {
+    pub row_count: u64,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    pub row_groups: Vec<RowGroupReport>,
+    pub columns: Vec<ColumnReport>,
 }
 
-pub fn execute_sql_on_parquet_file(
+// Builds the report from the file's footer alone
+pub fn parquet_metadata_report(
     conn: &Connection,
     file_path: &str,
+) -> Result<MetadataReport, Error> {
+    let mut stmt = conn.prepare(PARQUET_METADATA_SQL)?;
+    let mut rows = stmt.query([file_path])?;
+
+    let mut report = MetadataReport::default();
+    let mut row_group_ids: Vec<i64> = Vec::new();
+    let mut column_ids: Vec<i64> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let row_group_id: i64 = row.get(0)?;
+        let row_group_rows: i64 = row.get(1)?;
+        let column_id: i64 = row.get(2)?;
+        let min: Option<String> = row.get(5)?;
+        let max: Option<String> = row.get(6)?;
+        let null_count: Option<i64> = row.get(7)?;
+        let compression: String = row.get(8)?;
+        let encodings: String = row.get(9)?;
+        let compressed_bytes = row.get::<_, i64>(10)?.max(0) as u64;
+        let uncompressed_bytes = row.get::<_, i64>(11)?.max(0) as u64;
+
+        if row_group_ids.last() != Some(&row_group_id) {
+            row_group_ids.push(row_group_id);
+            report.row_groups.push(RowGroupReport {
+                rows: row_group_rows.max(0) as u64,
+                ..RowGroupReport::default()
+            });
+        }
+        if let Some(row_group) = report.row_groups.last_mut() {
+            row_group.compressed_bytes += compressed_bytes;
+            row_group.uncompressed_bytes += uncompressed_bytes;
+        }
+
+        let index = match column_ids.iter().position(|id| *id == column_id) {
+            Some(index) => index,
+            None => {
+                column_ids.push(column_id);
+                report.columns.push(ColumnReport {
+                    name: row.get(3)?,
+                    physical_type: row.get(4)?,
+                    compression: Vec::new(),
+                    encodings: Vec::new(),
+                    compressed_bytes: 0,
+                    uncompressed_bytes: 0,
+                    row_groups_with_stats: 0,
+                    null_count: Some(0),
+                    ranges: Vec::new(),
+                });
+                column_ids.len() - 1
+            }
+        };
+        let column = &mut report.columns[index];
+        if !column.compression.contains(&compression) {
+            column.compression.push(compression);
+        }
+        for encoding in encodings.split(',').map(str::trim) {
+            if !encoding.is_empty() && !column.encodings.iter().any(|known| known == encoding) {
+                column.encodings.push(encoding.to_string());
+            }
+        }
+        column.compressed_bytes += compressed_bytes;
+        column.uncompressed_bytes += uncompressed_bytes;
+        column.row_groups_with_stats += u64::from(min.is_some() && max.is_some());
+        column.ranges.push(ColumnRange { min, max });
+        column.null_count = column
+            .null_count
+            .zip(null_count)
+            .map(|(total, count)| total + count.max(0) as u64);
+    }
+
+    report.row_count = report.row_groups.iter().map(|group| group.rows).sum();
+    report.compressed_bytes = report
+        .row_groups
+        .iter()
+        .map(|group| group.compressed_bytes)
+        .sum();
+    report.uncompressed_bytes = report
+        .row_groups
+        .iter()
+        .map(|group| group.uncompressed_bytes)
+        .sum();
+    Ok(report)
+}
+
+// The table name the query prompt tells the model to select from
+pub const PARQUET_VIEW_NAME: &str = "data";
+
+// Exposes the parquet file as the view `name`, replacing any view already called that. The
+// query prompt tells the model to select from `data`, so generated SQL runs exactly as
+// written rather than having the table name rewritten inside it; joins name each dataset's
+// view after its alias.
+pub fn register_parquet_view(conn: &Connection, name: &str, file_path: &str) -> Result<(), Error> {
+    create_parquet_view(
+        conn,
+        name,
+        &format!("read_parquet({})", sql_string(file_path)),
+    )
+}
+
+// The same over every file `prefix_glob` matches, such as the parts a checkpointed job
+// writes under its output prefix. Columns are matched by name, so a part missing a column
+// reads it as NULL.
+pub fn register_parquet_glob_view(
+    conn: &Connection,
+    name: &str,
+    prefix_glob: &str,
+) -> Result<(), Error> {
+    create_parquet_view(
+        conn,
+        name,
+        &format!(
+            "read_parquet({}, union_by_name = true)",
+            sql_string(prefix_glob)
+        ),
+    )
+}
+
+// A view's query can't hold parameters, so the source goes in as a literal. The name is
+// held to the alias rules before it is quoted, as the model has to be able to write it
+// without quotes. It isn't a temporary view, so every connection to the database sees it,
+// including those of a warm container's later invocations.
+fn create_parquet_view(conn: &Connection, name: &str, source: &str) -> Result<(), Error> {
+    if !is_valid_table_alias(name) {
+        return Err(Error::InvalidViewName(name.to_string()));
+    }
+    conn.execute_batch(&format!(
+        "CREATE OR REPLACE VIEW {} AS SELECT * FROM {};",
+        sql_identifier(name),
+        source
+    ))?;
+    Ok(())
+}
+
+pub fn drop_view(conn: &Connection, name: &str) -> Result<(), Error> {
+    conn.execute_batch(&format!("DROP VIEW IF EXISTS {};", sql_identifier(name)))?;
+    Ok(())
+}
+
+// Words an alias can't be, as a model writing `FROM order` or `JOIN select` would produce
+// SQL that doesn't parse
+const RESERVED_ALIASES: &[&str] = &[
+    "ALL", "AND", "AS", "ASC", "BETWEEN", "BY", "CASE", "CAST", "DESC", "DISTINCT", "ELSE", "END",
+    "FALSE", "FOR", "FROM", "FULL", "IN", "INNER", "IS", "JOIN", "LEFT", "LIKE", "NATURAL", "NOT",
+    "NULL", "OR", "OUTER", "RIGHT", "TABLE", "THEN", "TRUE", "WHEN", "WITH",
+];
+pub const MAX_TABLE_ALIAS_CHARS: usize = 32;
+
+// Whether `alias` can name a dataset's view: a lowercase identifier that needs no quoting
+// and isn't a keyword. Every registered view's name is held to this.
+pub fn is_valid_table_alias(alias: &str) -> bool {
+    let mut chars = alias.chars();
+    let starts_well = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_lowercase() || c == '_');
+    let upper = alias.to_ascii_uppercase();
+    starts_well
+        && alias.len() <= MAX_TABLE_ALIAS_CHARS
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        && !RESERVED_ALIASES.contains(&upper.as_str())
+        && !DENIED_KEYWORDS.contains(&upper.as_str())
+        && !FROM_CLAUSE_ENDS.contains(&upper.as_str())
+}
+
+// Most frequent values listed for a text column, and the most distinct values a column can
+// have and still count as a set of categories
+const STATS_TOP_VALUES: usize = 20;
+const STATS_MAX_CATEGORIES: usize = 100;
+// Listed values are cut to this many characters; long free text doesn't help the model
+const STATS_VALUE_CHARS: usize = 40;
+
+// What the SQL prompt is told about a column beyond its type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnProfile {
+    pub column: String,
+    pub null_percent: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<String>,
+    // Most frequent first; only set for low-cardinality text columns
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+}
+
+fn has_range(column_type: &str) -> bool {
+    let base = column_type.split('(').next().unwrap_or_default().trim();
+    matches!(
+        base,
+        "TINYINT"
+            | "SMALLINT"
+            | "INTEGER"
+            | "BIGINT"
+            | "HUGEINT"
+            | "UTINYINT"
+            | "USMALLINT"
+            | "UINTEGER"
+            | "UBIGINT"
+            | "UHUGEINT"
+            | "FLOAT"
+            | "DOUBLE"
+            | "DECIMAL"
+            | "DATE"
+            | "TIME"
+    ) || base.starts_with("TIMESTAMP")
+}
+
+// A single aggregate over `data` selecting, for each (name, DESCRIBE type) column in turn,
+// its null percentage, min, max and top values as a JSON list. Columns the measure doesn't
+// apply to get NULL in its place, so every column takes four result columns.
+pub fn column_stats_sql(columns: &[(String, String)]) -> String {
+    let selects: Vec<String> = columns
+        .iter()
+        .map(|(name, column_type)| {
+            let column = sql_identifier(name);
+            let null_percent = format!(
+                "(100.0 * count(*) FILTER (WHERE {} IS NULL) / greatest(count(*), 1))::DOUBLE",
+                column
+            );
+            let (min, max) = if has_range(column_type) {
+                (
+                    format!("min({})::VARCHAR", column),
+                    format!("max({})::VARCHAR", column),
+                )
+            } else {
+                ("NULL::VARCHAR".to_string(), "NULL::VARCHAR".to_string())
+            };
+            let values = if column_type == "VARCHAR" {
+                format!(
+                    "CASE WHEN approx_count_distinct({column}) <= {} \
+                     THEN to_json(approx_top_k(left({column}, {}), {}))::VARCHAR END",
+                    STATS_MAX_CATEGORIES, STATS_VALUE_CHARS, STATS_TOP_VALUES
+                )
+            } else {
+                "NULL::VARCHAR".to_string()
+            };
+            format!("{}, {}, {}, {}", null_percent, min, max, values)
+        })
+        .collect();
+
+    format!(
+        "SELECT {} FROM {}",
+        selects.join(", "),
+        sql_identifier(PARQUET_VIEW_NAME)
+    )
+}
+
+// Profiles every column of the view from `register_parquet_view` in one scan of the file
+pub fn compute_column_stats(conn: &Connection) -> Result<Vec<ColumnProfile>, Error> {
+    let mut stmt = conn.prepare(&format!("DESCRIBE {}", sql_identifier(PARQUET_VIEW_NAME)))?;
+    let columns = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>("column_name")?,
+                row.get::<_, String>("column_type")?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    if columns.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let profiles = conn.query_row(&column_stats_sql(&columns), [], |row| {
+        columns
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _))| {
+                let offset = index * 4;
+                let values: Option<String> = row.get(offset + 3)?;
+                Ok(ColumnProfile {
+                    column: name.clone(),
+                    null_percent: row.get(offset)?,
+                    min: row.get(offset + 1)?,
+                    max: row.get(offset + 2)?,
+                    values: values
+                        .and_then(|json| serde_json::from_str::<Vec<Option<String>>>(&json).ok())
+                        .map(|values| values.into_iter().flatten().collect())
+                        .unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    Ok(profiles)
+}
+
+// Renders profiles for the SQL prompt, one `;`-separated entry per column, stopping before
+// `max_chars` and saying how many columns were left out
+pub fn render_column_stats(profiles: &[ColumnProfile], max_chars: usize) -> String {
+    let mut rendered = String::new();
+    for (index, profile) in profiles.iter().enumerate() {
+        let mut entry = format!("{} ({:.1}% null", profile.column, profile.null_percent);
+        if let (Some(min), Some(max)) = (&profile.min, &profile.max) {
+            entry.push_str(&format!(", {} to {}", min, max));
+        }
+        if !profile.values.is_empty() {
+            let values: Vec<String> = profile
+                .values
+                .iter()
+                .map(|value| sql_string(value))
+                .collect();
+            entry.push_str(&format!(", values [{}]", values.join(", ")));
+        }
+        entry.push(')');
+
+        let separator = if rendered.is_empty() { "" } else { "; " };
+        if rendered.len() + separator.len() + entry.len() > max_chars {
+            let omitted = profiles.len() - index;
+            rendered.push_str(&format!("{}{} more columns omitted", separator, omitted));
+            break;
+        }
+        rendered.push_str(separator);
+        rendered.push_str(&entry);
+    }
+    rendered
+}
+
+// Sample values longer than this are cut, so one free-text column can't take the table over
+const SAMPLE_VALUE_CHARS: usize = 30;
+// Rows are sampled from the start of the file only; a reservoir sample of the whole file
+// would read all of it, which defeats querying a large file in place
+const SAMPLE_POOL_ROWS: usize = 10_000;
+
+fn markdown_cell(value: &str) -> String {
+    let value = value.replace(['\n', '\r'], " ").replace('|', "\\|");
+    if value.chars().count() > SAMPLE_VALUE_CHARS {
+        let cut: String = value.chars().take(SAMPLE_VALUE_CHARS).collect();
+        format!("{}…", cut)
+    } else {
+        value
+    }
+}
+
+// Up to `rows` random rows of a registered view as a markdown table, so the SQL prompt
+// shows how values are actually formatted. Rows that would take the table past `max_chars`
+// are left off; an empty string means not even the header fits.
+pub fn sample_rows_markdown(
+    conn: &Connection,
+    table: &str,
+    rows: usize,
+    max_chars: usize,
+) -> Result<String, Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT COLUMNS(*)::VARCHAR FROM (SELECT * FROM {} LIMIT {}) USING SAMPLE {} ROWS",
+        sql_identifier(table),
+        SAMPLE_POOL_ROWS,
+        rows
+    ))?;
+    let mut result = stmt.query([])?;
+    let column_count = result.as_ref().map_or(0, |stmt| stmt.column_count());
+    let columns = result
+        .as_ref()
+        .map(|stmt| stmt.column_names())
+        .unwrap_or_default();
+
+    let mut sampled = Vec::new();
+    while let Some(row) = result.next()? {
+        let cells = (0..column_count)
+            .map(|index| {
+                let value: Option<String> = row.get(index)?;
+                Ok(value.as_deref().map_or("NULL".to_string(), markdown_cell))
+            })
+            .collect::<Result<Vec<_>, duckdb::Error>>()?;
+        sampled.push(format!("| {} |", cells.join(" | ")));
+    }
+
+    let header = format!(
+        "| {} |\n|{}",
+        columns
+            .iter()
+            .map(|column| markdown_cell(column))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        "---|".repeat(column_count)
+    );
+    if header.len() > max_chars {
+        return Ok(String::new());
+    }
+
+    let mut table = header;
+    for row in sampled {
+        if table.len() + 1 + row.len() > max_chars {
+            break;
+        }
+        table.push('\n');
+        table.push_str(&row);
+    }
+    Ok(table)
+}
+
+// `column_type` with every DECIMAL narrower than 19 digits widened to 38, if it has any.
+// DuckDB hands those to Rust as 32- and 64-bit Arrow decimals, which the duckdb crate
+// panics on rather than converting.
+fn widened_decimal_type(column_type: &str) -> Option<String> {
+    let mut widened = String::with_capacity(column_type.len());
+    let mut rest = column_type;
+    let mut changed = false;
+    while let Some(start) = rest.find("DECIMAL(") {
+        let args_start = start + "DECIMAL(".len();
+        let Some(args_len) = rest[args_start..].find(')') else {
+            break;
+        };
+        let args = &rest[args_start..args_start + args_len];
+        widened.push_str(&rest[..args_start]);
+        match args.split_once(',') {
+            Some((width, scale)) if width.trim().parse::<u8>().is_ok_and(|w| w <= 18) => {
+                widened.push_str(&format!("38,{}", scale.trim()));
+                changed = true;
+            }
+            _ => widened.push_str(args),
+        }
+        rest = &rest[args_start + args_len..];
+    }
+    widened.push_str(rest);
+    changed.then_some(widened)
+}
+
+// Runs the query, keeping its rows in column order with each value converted to JSON
+// by its DuckDB type. Rows stop being read once `limits` are reached; the row that would go
+// over either one is left out and the result marked truncated, and the statement is
+// dropped with the rest unread.
+pub fn execute_sql_typed(
+    conn: &Connection,
+    sql_query: &str,
+    limits: ResultLimits,
+) -> Result<QueryResult, Error> {
+    debug!(sql = %sql_query, "Executing SQL");
+
+    let columns: Vec<ColumnMeta> = query_column_types(conn, sql_query)?
+        .into_iter()
+        .map(|(name, duckdb_type)| ColumnMeta { name, duckdb_type })
+        .collect();
+
+    // Columns are selected by position, as a query can return two with the same name
+    let needs_widening = columns
+        .iter()
+        .any(|column| widened_decimal_type(&column.duckdb_type).is_some());
+    let fetch_sql = if needs_widening {
+        let selected: Vec<String> = columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                let position = format!("#{}", index + 1);
+                match widened_decimal_type(&column.duckdb_type) {
+                    Some(widened) => format!("CAST({} AS {})", position, widened),
+                    None => position,
+                }
+            })
+            .collect();
+        format!(
+            "SELECT {} FROM {} t",
+            selected.join(", "),
+            as_subquery(sql_query)
+        )
+    } else {
+        sql_query.to_string()
+    };
+
+    let mut stmt = conn.prepare(&fetch_sql)?;
+    let mut rows = stmt.query([])?;
+    let mut result = QueryResult {
+        columns,
+        ..QueryResult::default()
+    };
+    while let Some(row) = rows.next()? {
+        let values = (0..result.columns.len())
+            .map(|index| row.get::<_, Value>(index).map(|value| json_value(&value)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let row_bytes = row_object_bytes(&result.columns, &values);
+        if result.rows.len() as u64 >= limits.max_rows
+            || result.bytes + row_bytes > limits.max_bytes
+        {
+            result.truncated = true;
+            break;
+        }
+        result.bytes += row_bytes;
+        result.rows.push(values);
+    }
+
+    if result.truncated {
+        info!(
+            rows = result.rows.len(),
+            bytes = result.bytes,
+            max_rows = limits.max_rows,
+            max_bytes = limits.max_bytes,
+            "Stopped reading rows at the result limit"
+        );
+    }
+    Ok(result)
+}
+
+// The query as a subquery, with any trailing semicolons dropped. It goes on its own line so
+// a trailing line comment can't swallow the closing parenthesis.
+fn as_subquery(sql_query: &str) -> String {
+    let sql_query = sql_query.trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+    format!("(\n{}\n)", sql_query)
+}
+
+// How many rows `sql_query` returns in total, without materialising them
+pub fn count_query_rows(conn: &Connection, sql_query: &str) -> Result<u64, Error> {
+    let count_sql = format!("SELECT count(*) FROM {} t", as_subquery(sql_query));
+    let count = conn.query_row(&count_sql, [], |row| row.get::<_, i64>(0))?;
+    Ok(count.max(0) as u64)
+}
+
+// The query with at most `limit` of its rows, in the order it returns them
+pub fn with_row_limit(sql_query: &str, limit: u64) -> String {
+    format!("SELECT * FROM {} t LIMIT {}", as_subquery(sql_query), limit)
+}
+
+// One page of the query's rows, cut short if its rows come to more than `max_bytes`. Only
+// for SQL that `check_read_only_sql` has already accepted as a single read-only query, as
+// it is spliced into another statement.
+pub fn execute_sql_page(
+    conn: &Connection,
     sql_query: &str,
-) -> Result<String> {
-    let full_sql = sql_query.replace("data", &format!("read_parquet('{}')", file_path));
-    println!("Executing full transformed SQL: {}", full_sql);
-
-    // DuckDB can output JSON directly!
-    let json_sql = format!(
-        "SELECT to_json(array_agg(row_to_json(t))) FROM ({}) t",
-        full_sql
+    limit: u64,
+    offset: u64,
+    max_bytes: u64,
+) -> Result<QueryResult, Error> {
+    let page_sql = format!(
+        "SELECT * FROM {} t LIMIT {} OFFSET {}",
+        as_subquery(sql_query),
+        limit,
+        offset
     );
+    let limits = ResultLimits {
+        max_rows: limit,
+        max_bytes,
+    };
+    execute_sql_typed(conn, &page_sql, limits)
+}
+
+// Names and DuckDB types of the columns `sql_query` returns, in the order it selects them
+pub fn query_column_types(
+    conn: &Connection,
+    sql_query: &str,
+) -> Result<Vec<(String, String)>, Error> {
+    let mut stmt = conn.prepare(&format!("DESCRIBE {}", sql_query))?;
+    let columns = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>("column_name")?,
+                row.get::<_, String>("column_type")?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(columns)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParquetCompression {
+    #[default]
+    Zstd,
+    Snappy,
+    Uncompressed,
+}
+
+impl ParquetCompression {
+    fn sql_name(&self) -> &'static str {
+        match self {
+            ParquetCompression::Zstd => "zstd",
+            ParquetCompression::Snappy => "snappy",
+            ParquetCompression::Uncompressed => "uncompressed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportOptions {
+    pub compression: ParquetCompression,
+}
+
+// Writes every row of `sql_query` to a parquet file at `out_path` and returns how many
+// there were. The query is held to `check_read_only_sql` against `tables` here as well as
+// wherever it came from, as COPY is the one statement that writes to disk.
+pub fn export_query_to_parquet(
+    conn: &Connection,
+    sql_query: &str,
+    tables: &[&str],
+    out_path: &Path,
+    options: ExportOptions,
+) -> Result<u64, Error> {
+    check_read_only_sql(sql_query, tables)?;
+    let copy_sql = format!(
+        "COPY {} TO {} (FORMAT parquet, COMPRESSION {});",
+        as_subquery(sql_query),
+        sql_string(&out_path.to_string_lossy()),
+        options.compression.sql_name()
+    );
+    let rows = conn.execute(&copy_sql, [])?;
+    info!(
+        path = %out_path.display(),
+        rows,
+        compression = options.compression.sql_name(),
+        "Exported query to parquet"
+    );
+    Ok(rows as u64)
+}
+
+// DuckDB's plan for `sql_query`, as the text EXPLAIN prints. With `analyze` the query is
+// run to profile it, so the plan has real row counts and timings but costs as much as the
+// query itself.
+pub fn explain_query(conn: &Connection, sql_query: &str, analyze: bool) -> Result<String, Error> {
+    let explain = if analyze {
+        "EXPLAIN ANALYZE"
+    } else {
+        "EXPLAIN"
+    };
+    let sql_query = sql_query.trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+    let mut stmt = conn.prepare(&format!("{}\n{}", explain, sql_query))?;
+    let sections = stmt
+        .query_map([], |row| row.get::<_, String>("explain_value"))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(sections.join("\n"))
+}
+
+// How often a timed-out query is interrupted again until it stops. An interrupt only
+// reaches the statement running at that moment, so one landing between two statements
+// would otherwise be lost.
+const INTERRUPT_REPEAT: Duration = Duration::from_millis(100);
+
+// Runs `work` on a blocking thread and interrupts whatever it has running on `conn` once
+// `timeout` passes, so a runaway query fails with Error::QueryTimeout instead of holding
+// the invocation until Lambda kills it. The connection is handed back for further use.
+pub async fn run_with_timeout<T, F>(
+    conn: Connection,
+    timeout: Duration,
+    work: F,
+) -> Result<(Connection, T), Error>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, Error> + Send + 'static,
+{
+    let interrupt = conn.interrupt_handle();
+    let mut task = tokio::task::spawn_blocking(move || {
+        let result = work(&conn);
+        (conn, result)
+    });
+
+    let mut timed_out = false;
+    let joined = match tokio::time::timeout(timeout, &mut task).await {
+        Ok(joined) => joined,
+        Err(_) => {
+            timed_out = true;
+            warn!(
+                timeout_ms = timeout.as_millis() as u64,
+                "Interrupting slow query"
+            );
+            loop {
+                interrupt.interrupt();
+                if let Ok(joined) = tokio::time::timeout(INTERRUPT_REPEAT, &mut task).await {
+                    break joined;
+                }
+            }
+        }
+    };
+
+    let (conn, result) = match joined {
+        Ok(finished) => finished,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    };
+    match result {
+        Ok(value) => Ok((conn, value)),
+        // Whatever the interrupted query failed with, the reason it failed is the timeout
+        Err(_) if timed_out => Err(Error::QueryTimeout(timeout)),
+        Err(e) => Err(e),
+    }
+}
+
+// Statements and clauses that can change state, reach outside the registered view or
+// reconfigure the connection. Matched as whole unquoted words anywhere in the query, so a
+// CTE or subquery can't hide one.
+const DENIED_KEYWORDS: &[&str] = &[
+    "ALTER",
+    "ATTACH",
+    "BEGIN",
+    "CALL",
+    "CHECKPOINT",
+    "COMMIT",
+    "COPY",
+    "CREATE",
+    "DELETE",
+    "DETACH",
+    "DEALLOCATE",
+    "DROP",
+    "EXECUTE",
+    "EXPORT",
+    "IMPORT",
+    "INSERT",
+    "INSTALL",
+    "LOAD",
+    "MERGE",
+    "PRAGMA",
+    "PREPARE",
+    "RESET",
+    "ROLLBACK",
+    "SET",
+    "TRUNCATE",
+    "UPDATE",
+    "USE",
+    "VACUUM",
+];
+
+// Table and scalar functions that read files, run SQL from a string, or expose the
+// environment and secrets, which is where the function's AWS credentials live
+const DENIED_FUNCTIONS: &[&str] = &[
+    "CURRENT_SETTING",
+    "DELTA_SCAN",
+    "DUCKDB_LOGS",
+    "DUCKDB_LOGS_PARSED",
+    "DUCKDB_SECRETS",
+    "DUCKDB_SETTINGS",
+    "GETENV",
+    "GLOB",
+    "JSON_DESERIALIZE_SQL",
+    "JSON_EXECUTE_SERIALIZED_SQL",
+    "JSON_SERIALIZE_SQL",
+    "QUERY",
+    "QUERY_TABLE",
+    "SNIFF_CSV",
+    "ST_READ",
+    "WHICH_SECRET",
+];
+const DENIED_FUNCTION_PREFIXES: &[&str] = &[
+    "READ_",
+    "PARQUET_",
+    "ICEBERG_",
+    "POSTGRES_",
+    "SQLITE_",
+    "MYSQL_",
+];
+
+// Functions a FROM list may call. None so far: the views are plain names, and a table
+// function is how a query would reach a file or run SQL handed to it as a string.
+const ALLOWED_TABLE_FUNCTIONS: &[&str] = &[];
+
+// Words that end a FROM list at the depth it started. ON doesn't: a comma after a join
+// condition still starts another table.
+const FROM_CLAUSE_ENDS: &[&str] = &[
+    "EXCEPT",
+    "GROUP",
+    "HAVING",
+    "INTERSECT",
+    "LIMIT",
+    "OFFSET",
+    "ORDER",
+    "QUALIFY",
+    "SELECT",
+    "UNION",
+    "USING",
+    "WHERE",
+    "WINDOW",
+];
+
+// Why generated SQL was refused, naming the construct that caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlRejection {
+    pub construct: String,
+}
+
+impl std::fmt::Display for SqlRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "query is not read-only: {}", self.construct)
+    }
+}
+
+impl std::error::Error for SqlRejection {}
+
+fn reject(construct: impl Into<String>) -> Result<(), SqlRejection> {
+    Err(SqlRejection {
+        construct: construct.into(),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum SqlToken {
+    // Unquoted identifier or keyword, uppercased
+    Word(String),
+    QuotedIdentifier(String),
+    StringLiteral(String),
+    Symbol(char),
+}
+
+// Splits SQL into words, quoted names, string literals and symbols, dropping comments and
+// whitespace. Anything inside quotes or comments is never mistaken for a keyword.
+fn tokenize_sql(sql: &str) -> Result<Vec<SqlToken>, SqlRejection> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    // Reads up to the closing `quote`, where a doubled quote stands for itself
+    let quoted = |start: usize, quote: char| -> Option<(String, usize)> {
+        let mut value = String::new();
+        let mut j = start + 1;
+        while j < chars.len() {
+            if chars[j] == quote {
+                if chars.get(j + 1) == Some(&quote) {
+                    value.push(quote);
+                    j += 2;
+                    continue;
+                }
+                return Some((value, j + 1));
+            }
+            value.push(chars[j]);
+            j += 1;
+        }
+        None
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            // DuckDB doesn't nest block comments, so the first `*/` ends it
+            let end = (i + 2..chars.len().saturating_sub(1))
+                .find(|&j| chars[j] == '*' && chars[j + 1] == '/');
+            match end {
+                Some(j) => i = j + 2,
+                None => {
+                    return Err(SqlRejection {
+                        construct: "an unterminated comment".to_string(),
+                    });
+                }
+            }
+        } else if c == '\'' || c == '"' {
+            let Some((value, next)) = quoted(i, c) else {
+                return Err(SqlRejection {
+                    construct: "an unterminated quote".to_string(),
+                });
+            };
+            tokens.push(if c == '\'' {
+                SqlToken::StringLiteral(value)
+            } else {
+                SqlToken::QuotedIdentifier(value)
+            });
+            i = next;
+        } else if c == '$' && chars.get(i + 1) == Some(&'$') {
+            // Dollar-quoted string
+            let end = (i + 2..chars.len().saturating_sub(1))
+                .find(|&j| chars[j] == '$' && chars[j + 1] == '$');
+            match end {
+                Some(j) => {
+                    tokens.push(SqlToken::StringLiteral(chars[i + 2..j].iter().collect()));
+                    i = j + 2;
+                }
+                None => {
+                    return Err(SqlRejection {
+                        construct: "an unterminated quote".to_string(),
+                    });
+                }
+            }
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(SqlToken::Word(word.to_uppercase()));
+        } else {
+            tokens.push(SqlToken::Symbol(c));
+            i += 1;
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Whether a table name in a FROM clause would make DuckDB open a file or URL by that name
+// instead of looking up a table
+fn looks_like_path(name: &str) -> bool {
+    name.contains('/') || name.contains('.') || name.contains(':') || name.contains('\\')
+}
+
+// Names the query defines for itself in its WITH clause, `name AS (` or
+// `name AS [NOT] MATERIALIZED (`
+fn cte_names(tokens: &[SqlToken]) -> Vec<String> {
+    let mut names = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+        let name = match token {
+            SqlToken::Word(word) => word.clone(),
+            SqlToken::QuotedIdentifier(name) => name.to_uppercase(),
+            _ => continue,
+        };
+        let mut rest = tokens[index + 1..].iter();
+        if rest.next() != Some(&SqlToken::Word("AS".to_string())) {
+            continue;
+        }
+        let defines = rest
+            .find(|token| {
+                !matches!(token, SqlToken::Word(word) if word == "NOT" || word == "MATERIALIZED")
+            })
+            .is_some_and(|token| *token == SqlToken::Symbol('('));
+        if defines {
+            names.push(name);
+        }
+    }
+    names
+}
+
+// Whether a call to `name` could read files, run SQL from a string or reveal secrets.
+// Quoted names are matched too, as `"getenv"(...)` calls the same function.
+fn denied_function(name: &str) -> bool {
+    let name = name.to_uppercase();
+    DENIED_FUNCTIONS.contains(&name.as_str())
+        || DENIED_FUNCTION_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+// Whether the `(` at `index` opens a function's arguments rather than a subquery, so a
+// FROM inside it is part of the call, as in `extract(year FROM day)`
+fn opens_call(tokens: &[SqlToken], index: usize) -> bool {
+    let after_name = index
+        .checked_sub(1)
+        .map(|i| &tokens[i])
+        .is_some_and(|token| matches!(token, SqlToken::Word(_) | SqlToken::QuotedIdentifier(_)));
+    let starts_query = matches!(
+        tokens.get(index + 1),
+        Some(SqlToken::Word(word)) if matches!(word.as_str(), "SELECT" | "WITH" | "FROM" | "VALUES")
+    );
+    after_name && !starts_query
+}
+
+// Accepts only a single SELECT (or WITH ... SELECT, or DuckDB's FROM-first form) that
+// reads nothing but the registered views in `tables` and its own CTEs. This is a lexical
+// check run on model output before it reaches DuckDB, so it errs towards refusing anything
+// it can't vouch for. It is the only check: `enable_external_access = false` can't be
+// undone, and would leave the warm database unable to load httpfs or move its spill
+// directory for the invocations after.
+pub fn check_read_only_sql(sql: &str, tables: &[&str]) -> Result<(), SqlRejection> {
+    let mut tokens = tokenize_sql(sql)?;
+
+    // One trailing semicolon is how most people end a statement
+    while tokens.last() == Some(&SqlToken::Symbol(';')) {
+        tokens.pop();
+    }
+    if tokens.contains(&SqlToken::Symbol(';')) {
+        return reject("multiple statements");
+    }
+
+    match tokens.iter().find(|token| **token != SqlToken::Symbol('(')) {
+        None => return reject("an empty query"),
+        Some(SqlToken::Word(word)) if matches!(word.as_str(), "SELECT" | "WITH" | "FROM") => {}
+        Some(SqlToken::Word(word)) => return reject(format!("{} statement", word)),
+        Some(_) => return reject("a query that doesn't start with SELECT"),
+    }
+
+    let mut readable: Vec<String> = tables.iter().map(|table| table.to_uppercase()).collect();
+    readable.extend(cte_names(&tokens));
+
+    let mut depth: usize = 0;
+    // Paren depth of each FROM list we're inside
+    let mut from_depths: Vec<usize> = Vec::new();
+    // Whether each open paren is a function call's
+    let mut calls: Vec<bool> = Vec::new();
+
+    for (index, token) in tokens.iter().enumerate() {
+        let in_from_list = from_depths.last() == Some(&depth);
+        // Right after FROM, JOIN or a comma in a FROM list is where a table name goes
+        let table_position = in_from_list
+            && match index.checked_sub(1).map(|i| &tokens[i]) {
+                Some(SqlToken::Symbol(',')) => true,
+                Some(SqlToken::Word(word)) => word == "FROM" || word == "JOIN",
+                _ => false,
+            };
+
+        let is_call = tokens.get(index + 1) == Some(&SqlToken::Symbol('('));
+        match token {
+            SqlToken::Symbol('(') => {
+                depth += 1;
+                calls.push(opens_call(&tokens, index));
+            }
+            SqlToken::Symbol(')') => {
+                while from_depths.last() == Some(&depth) {
+                    from_depths.pop();
+                }
+                depth = depth.saturating_sub(1);
+                calls.pop();
+            }
+            SqlToken::Word(word) | SqlToken::QuotedIdentifier(word)
+                if is_call && denied_function(word) =>
+            {
+                return reject(format!("{}()", word.to_lowercase()));
+            }
+            // `LATERAL (` opens a subquery, not a call
+            SqlToken::Word(word) | SqlToken::QuotedIdentifier(word)
+                if is_call
+                    && table_position
+                    && *token != SqlToken::Word("LATERAL".to_string())
+                    && !ALLOWED_TABLE_FUNCTIONS.contains(&word.to_uppercase().as_str()) =>
+            {
+                return reject(format!("a table function {}()", word.to_lowercase()));
+            }
+            SqlToken::Word(word) => {
+                if DENIED_KEYWORDS.contains(&word.as_str()) {
+                    return reject(word.as_str());
+                }
+
+                // `IS DISTINCT FROM` and a FROM inside a call's arguments start no FROM list
+                let starts_from_list = (word == "FROM"
+                    && calls.last() != Some(&true)
+                    && index
+                        .checked_sub(1)
+                        .is_none_or(|i| tokens[i] != SqlToken::Word("DISTINCT".to_string())))
+                    || (word == "JOIN" && !in_from_list);
+                if starts_from_list {
+                    from_depths.push(depth);
+                } else if in_from_list && FROM_CLAUSE_ENDS.contains(&word.as_str()) {
+                    from_depths.pop();
+                } else if table_position && !is_call && !readable.contains(word) {
+                    return reject(format!("an unknown table {}", word.to_lowercase()));
+                }
+            }
+            SqlToken::StringLiteral(value) if table_position => {
+                return reject(format!("a file reference '{}'", value));
+            }
+            SqlToken::QuotedIdentifier(name) if table_position && looks_like_path(name) => {
+                return reject(format!("a file reference \"{}\"", name));
+            }
+            SqlToken::QuotedIdentifier(name)
+                if table_position && !is_call && !readable.contains(&name.to_uppercase()) =>
+            {
+                return reject(format!("an unknown table \"{}\"", name));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the outermost query already limits its own rows. A LIMIT inside a subquery or CTE
+// doesn't count, as the query around it can still return any number of rows. SQL that
+// can't be tokenized is treated as unlimited.
+pub fn has_top_level_limit(sql: &str) -> bool {
+    let Ok(mut tokens) = tokenize_sql(sql) else {
+        return false;
+    };
+    while tokens.last() == Some(&SqlToken::Symbol(';')) {
+        tokens.pop();
+    }
+
+    // Parentheses wrapping the whole query put its top level that many parens in. Only the
+    // ones closed by the last tokens count; a leading paren closed earlier wraps just a
+    // part, such as the first branch of a UNION.
+    let mut closes = vec![0; tokens.len()];
+    let mut open = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+        match token {
+            SqlToken::Symbol('(') => open.push(index),
+            SqlToken::Symbol(')') => {
+                if let Some(start) = open.pop() {
+                    closes[start] = index;
+                }
+            }
+            _ => {}
+        }
+    }
+    let top_depth = (0..tokens.len() / 2)
+        .take_while(|&i| tokens[i] == SqlToken::Symbol('(') && closes[i] == tokens.len() - 1 - i)
+        .count();
+
+    let mut depth: usize = 0;
+    for token in &tokens {
+        match token {
+            SqlToken::Symbol('(') => depth += 1,
+            SqlToken::Symbol(')') => depth = depth.saturating_sub(1),
+            SqlToken::Word(word) if word == "LIMIT" && depth == top_depth => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+// Aggregates that collapse many rows into one, and the approximate ones approximate mode
+// asks for. A query calling any of them, at any depth, already summarises the data rather
+// than listing it.
+const AGGREGATE_FUNCTIONS: &[&str] = &[
+    "ANY_VALUE",
+    "APPROX_COUNT_DISTINCT",
+    "APPROX_QUANTILE",
+    "ARG_MAX",
+    "ARG_MIN",
+    "ARRAY_AGG",
+    "AVG",
+    "BOOL_AND",
+    "BOOL_OR",
+    "CORR",
+    "COUNT",
+    "COUNT_IF",
+    "COVAR_POP",
+    "COVAR_SAMP",
+    "FIRST",
+    "FSUM",
+    "HISTOGRAM",
+    "LAST",
+    "LIST",
+    "MAX",
+    "MAX_BY",
+    "MEAN",
+    "MEDIAN",
+    "MIN",
+    "MIN_BY",
+    "MODE",
+    "PRODUCT",
+    "QUANTILE",
+    "QUANTILE_CONT",
+    "QUANTILE_DISC",
+    "RESERVOIR_QUANTILE",
+    "STDDEV",
+    "STDDEV_POP",
+    "STDDEV_SAMP",
+    "STRING_AGG",
+    "SUM",
+    "VAR_POP",
+    "VAR_SAMP",
+    "VARIANCE",
+];
+
+// Whether approximate mode should sample `sql` itself because the model didn't. Only a
+// plain scan qualifies: a query that aggregates was left exact or sampled the way the
+// model meant it, a sampled one already is, and one with its own top-level LIMIT would
+// lose rows it asked for. SQL that can't be tokenized is left alone.
+pub fn needs_sample_clause(sql: &str) -> bool {
+    let Ok(tokens) = tokenize_sql(sql) else {
+        return false;
+    };
+    if has_top_level_limit(sql) {
+        return false;
+    }
+
+    for (index, token) in tokens.iter().enumerate() {
+        let SqlToken::Word(word) = token else {
+            continue;
+        };
+        let next = tokens.get(index + 1);
+        match word.as_str() {
+            "SAMPLE" | "TABLESAMPLE" | "DISTINCT" | "PIVOT" | "UNPIVOT" => return false,
+            "GROUP" if next == Some(&SqlToken::Word("BY".to_string())) => return false,
+            _ if next == Some(&SqlToken::Symbol('('))
+                && AGGREGATE_FUNCTIONS.contains(&word.as_str()) =>
+            {
+                return false;
+            }
+            _ => {}
+        }
+    }
+
+    true
+}
+
+// The query's rows sampled at `percent`. Bernoulli sampling keeps each row independently,
+// so a small result isn't cut down to whole vectors or nothing.
+pub fn with_sample(sql_query: &str, percent: f64) -> String {
+    format!(
+        "SELECT * FROM {} t USING SAMPLE {} PERCENT (bernoulli)",
+        as_subquery(sql_query),
+        percent
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn check(sql: &str) -> Result<(), String> {
+        check_read_only_sql(sql, &["data"]).map_err(|rejection| rejection.construct)
+    }
+
+    const NO_LIMITS: ResultLimits = ResultLimits {
+        max_rows: u64::MAX,
+        max_bytes: u64::MAX,
+    };
+
+    // Writes `select` to a parquet file and registers it as `data` on a new connection. The
+    // directory has to outlive the connection's reads of the file.
+    fn fixture(select: &str) -> (tempfile::TempDir, Connection) {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let path = write_parquet(&conn, &dir, "data.parquet", select);
+        register_parquet_view(&conn, PARQUET_VIEW_NAME, &path).unwrap();
+        (dir, conn)
+    }
+
+    fn write_parquet(
+        conn: &Connection,
+        dir: &tempfile::TempDir,
+        name: &str,
+        select: &str,
+    ) -> String {
+        let path = dir.path().join(name).to_string_lossy().to_string();
+        conn.execute_batch(&format!(
+            "COPY ({}) TO {} (FORMAT PARQUET);",
+            select,
+            sql_string(&path)
+        ))
+        .unwrap();
+        path
+    }
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<Vec<serde_json::Value>> {
+        execute_sql_typed(conn, sql, NO_LIMITS).unwrap().rows
+    }
+
+    #[test]
+    fn plain_queries_are_allowed() {
+        for sql in [
+            "SELECT * FROM data",
+            "select count(*) from data;",
+            "FROM data SELECT region, sum(amount) GROUP BY region",
+            "(SELECT a FROM data) UNION ALL (SELECT b FROM data)",
+            "WITH totals AS (SELECT region, sum(amount) AS total FROM data GROUP BY region) \
+             SELECT * FROM totals ORDER BY total DESC",
+            "WITH t AS MATERIALIZED (SELECT 1 AS x FROM data) SELECT x FROM t",
+            "SELECT * FROM data d JOIN data e ON d.id = e.parent_id, data f",
+            "SELECT * FROM (SELECT * FROM data) sub WHERE x IS DISTINCT FROM y",
+            "SELECT extract(year FROM order_date), trim(BOTH ' ' FROM name) FROM data",
+            "SELECT * FROM \"DATA\"",
+            "SELECT * FROM data d, LATERAL (SELECT d.id + 1 AS next) n",
+        ] {
+            assert_eq!(check(sql), Ok(()), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn statements_other_than_select_are_named() {
+        for (sql, construct) in [
+            ("INSTALL httpfs", "INSTALL statement"),
+            ("LOAD httpfs", "LOAD statement"),
+            ("COPY data TO 's3://bucket/out.csv'", "COPY statement"),
+            ("ATTACH 'other.db'", "ATTACH statement"),
+            ("PRAGMA database_list", "PRAGMA statement"),
+            ("CREATE TABLE t AS SELECT * FROM data", "CREATE statement"),
+            ("INSERT INTO data VALUES (1)", "INSERT statement"),
+            ("UPDATE data SET x = 1", "UPDATE statement"),
+            ("DELETE FROM data", "DELETE statement"),
+            ("", "an empty query"),
+            ("-- nothing but a comment", "an empty query"),
+            ("'SELECT'", "a query that doesn't start with SELECT"),
+        ] {
+            assert_eq!(check(sql), Err(construct.to_string()), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn denied_keywords_are_caught_anywhere_in_the_query() {
+        for (sql, construct) in [
+            (
+                "WITH x AS (COPY data TO '/tmp/out.csv') SELECT * FROM x",
+                "COPY",
+            ),
+            ("SELECT * FROM data WHERE 1 = 1 AND (INSERT)", "INSERT"),
+            ("SELECT (SELECT 1 FROM data), SET FROM data", "SET"),
+            (
+                "SELECT * FROM data WHERE id IN (WITH y AS (SELECT 1) DELETE FROM data)",
+                "DELETE",
+            ),
+        ] {
+            assert_eq!(check(sql), Err(construct.to_string()), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn case_does_not_matter() {
+        assert_eq!(
+            check("InStAlL httpfs"),
+            Err("INSTALL statement".to_string())
+        );
+        assert_eq!(
+            check("select * from ReAd_CsV('/etc/passwd')"),
+            Err("read_csv()".to_string())
+        );
+        assert_eq!(
+            check("WITH x AS (cOpY data TO 'out') SELECT 1"),
+            Err("COPY".to_string())
+        );
+    }
+
+    #[test]
+    fn stacked_statements_are_refused() {
+        for sql in [
+            "SELECT * FROM data; DROP TABLE data",
+            "SELECT * FROM data;SELECT * FROM data",
+            "SELECT 1 FROM data; -- trailing\nINSTALL httpfs",
+        ] {
+            assert_eq!(
+                check(sql),
+                Err("multiple statements".to_string()),
+                "{}",
+                sql
+            );
+        }
+        assert_eq!(check("SELECT * FROM data;;"), Ok(()));
+    }
+
+    #[test]
+    fn comments_and_literals_hide_nothing_and_are_not_matched() {
+        // Keywords inside comments and strings are not statements
+        for sql in [
+            "SELECT * FROM data -- DROP TABLE data",
+            "SELECT /* COPY data TO 'x' */ * FROM data",
+            "SELECT * FROM data WHERE note = 'please INSTALL httpfs; COPY it'",
+            "SELECT 'read_csv(''/etc/passwd'')' AS text FROM data",
+            "SELECT $$ATTACH 'x'$$ FROM data",
+            "SELECT \"delete\" FROM data",
+        ] {
+            assert_eq!(check(sql), Ok(()), "{}", sql);
+        }
+
+        // A comment can't split a statement away from its keyword
+        assert_eq!(
+            check("SELECT 1 FROM data /* ; */ ; COPY data TO 'x'"),
+            Err("multiple statements".to_string())
+        );
+        assert_eq!(
+            check("/* SELECT */ INSTALL httpfs"),
+            Err("INSTALL statement".to_string())
+        );
+        assert_eq!(
+            check("--SELECT\nLOAD httpfs"),
+            Err("LOAD statement".to_string())
+        );
+    }
+
+    #[test]
+    fn unterminated_comments_and_quotes_are_refused() {
+        assert_eq!(
+            check("SELECT * FROM data /* COPY"),
+            Err("an unterminated comment".to_string())
+        );
+        assert_eq!(
+            check("SELECT * FROM data WHERE x = 'open"),
+            Err("an unterminated quote".to_string())
+        );
+        assert_eq!(
+            check("SELECT $$ FROM data"),
+            Err("an unterminated quote".to_string())
+        );
+    }
+
+    #[test]
+    fn file_reading_functions_are_refused() {
+        for (sql, construct) in [
+            ("SELECT * FROM read_csv('/etc/passwd')", "read_csv()"),
+            (
+                "SELECT * FROM read_csv_auto('s3://other/x.csv')",
+                "read_csv_auto()",
+            ),
+            (
+                "SELECT * FROM read_parquet('s3://other/*.parquet')",
+                "read_parquet()",
+            ),
+            ("SELECT * FROM parquet_scan('x.parquet')", "parquet_scan()"),
+            ("SELECT * FROM glob('/tmp/*')", "glob()"),
+            (
+                "SELECT getenv('AWS_SECRET_ACCESS_KEY') FROM data",
+                "getenv()",
+            ),
+            (
+                "SELECT current_setting('s3_secret_access_key')",
+                "current_setting()",
+            ),
+            ("SELECT * FROM query('DROP TABLE data')", "query()"),
+            ("SELECT * FROM duckdb_secrets()", "duckdb_secrets()"),
+            (
+                "SELECT * FROM data WHERE id IN (SELECT id FROM read_json('x.json'))",
+                "read_json()",
+            ),
+        ] {
+            assert_eq!(check(sql), Err(construct.to_string()), "{}", sql);
+        }
+        // The same names as columns are fine
+        assert_eq!(check("SELECT glob, query FROM data"), Ok(()));
+    }
+
+    #[test]
+    fn other_tables_and_paths_are_refused() {
+        for (sql, construct) in [
+            (
+                "SELECT * FROM '/etc/passwd'",
+                "a file reference '/etc/passwd'",
+            ),
+            (
+                "SELECT * FROM data, 's3://other/x.parquet'",
+                "a file reference 's3://other/x.parquet'",
+            ),
+            (
+                "SELECT * FROM data JOIN \"other.csv\" ON true",
+                "a file reference \"other.csv\"",
+            ),
+            ("SELECT * FROM secrets", "an unknown table secrets"),
+            ("SELECT * FROM \"Other\"", "an unknown table \"Other\""),
+            (
+                "SELECT * FROM (SELECT * FROM data) s JOIN users u ON s.id = u.id",
+                "an unknown table users",
+            ),
+        ] {
+            assert_eq!(check(sql), Err(construct.to_string()), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn sql_handed_to_a_function_as_a_string_is_refused() {
+        for (sql, construct) in [
+            // Reads the function's environment, and with it the AWS credentials
+            (
+                "SELECT * FROM json_execute_serialized_sql(json_serialize_sql(\
+                 'SELECT content FROM read_text(''/proc/self/environ'')'))",
+                "json_execute_serialized_sql()",
+            ),
+            (
+                "SELECT json_serialize_sql('SELECT * FROM read_parquet(''s3://other/x'')')",
+                "json_serialize_sql()",
+            ),
+            (
+                "SELECT * FROM data WHERE id IN \
+                 (SELECT id FROM json_execute_serialized_sql(json_deserialize_sql(x)))",
+                "json_execute_serialized_sql()",
+            ),
+            (
+                "SELECT * FROM \"read_text\"('/proc/self/environ')",
+                "read_text()",
+            ),
+            ("SELECT \"GetEnv\"('HOME') FROM data", "getenv()"),
+        ] {
+            assert_eq!(check(sql), Err(construct.to_string()), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn table_functions_are_refused_whatever_their_name() {
+        for (sql, construct) in [
+            ("SELECT * FROM range(10)", "a table function range()"),
+            (
+                "SELECT * FROM data JOIN generate_series(1, 3) g ON true",
+                "a table function generate_series()",
+            ),
+            (
+                "SELECT * FROM data, \"unnest\"([1, 2])",
+                "a table function unnest()",
+            ),
+            (
+                "SELECT * FROM (SELECT * FROM some_new_reader('x')) t",
+                "a table function some_new_reader()",
+            ),
+        ] {
+            assert_eq!(check(sql), Err(construct.to_string()), "{}", sql);
+        }
+        // The same names as scalar calls are fine
+        assert_eq!(check("SELECT range(3), unnest([1, 2]) FROM data"), Ok(()));
+    }
+
+    #[test]
+    fn each_registered_view_may_be_read() {
+        let sql = "SELECT * FROM orders o JOIN customers c ON o.customer_id = c.id";
+
+        assert_eq!(check_read_only_sql(sql, &["orders", "customers"]), Ok(()));
+        assert!(check_read_only_sql(sql, &["orders"]).is_err());
+    }
+
+    #[test]
+    fn data_inside_a_column_name_or_literal_is_left_alone() {
+        let (_dir, conn) = fixture(
+            "SELECT * FROM (VALUES (DATE '2024-01-02', 'metadata'), (DATE '2024-03-04', 'other')) \
+             t(\"Date of data entry\", note)",
+        );
+
+        let sql = "SELECT \"Date of data entry\" FROM data WHERE note LIKE '%data%'";
+
+        assert_eq!(rows(&conn, sql), [[json!("2024-01-02")]]);
+    }
+
+    #[test]
+    fn a_view_is_replaced_when_registered_again() {
+        let (dir, conn) = fixture("SELECT 1 AS n");
+        let second = write_parquet(&conn, &dir, "second.parquet", "SELECT 2 AS n");
+
+        register_parquet_view(&conn, PARQUET_VIEW_NAME, &second).unwrap();
+
+        assert_eq!(rows(&conn, "SELECT n FROM data"), [[json!(2)]]);
+    }
+
+    #[test]
+    fn only_a_limit_on_the_outermost_query_counts() {
+        for (sql, limited) in [
+            ("SELECT * FROM data LIMIT 10", true),
+            ("SELECT * FROM data ORDER BY amount DESC LIMIT 5;", true),
+            ("SELECT * FROM data", false),
+            ("SELECT count(*) FROM data", false),
+            ("SELECT * FROM (SELECT * FROM data LIMIT 10) t", false),
+            (
+                "WITH top AS (SELECT * FROM data LIMIT 10) SELECT * FROM top",
+                false,
+            ),
+            (
+                "WITH top AS (SELECT * FROM data) SELECT * FROM top LIMIT 3",
+                true,
+            ),
+            ("SELECT * FROM data LIMIT 10 -- the first rows", true),
+            ("SELECT * FROM data -- LIMIT 10", false),
+            ("SELECT * FROM data WHERE note = 'LIMIT 10'", false),
+            ("(SELECT * FROM data LIMIT 10)", true),
+            (
+                "(SELECT a FROM data LIMIT 1) UNION ALL (SELECT b FROM data)",
+                false,
+            ),
+            (
+                "SELECT * FROM data WHERE note = 'unterminated LIMIT 1",
+                false,
+            ),
+        ] {
+            assert_eq!(has_top_level_limit(sql), limited, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn a_row_cap_wraps_a_query_ending_in_a_comment() {
+        let (_dir, conn) = fixture("SELECT * FROM range(10) t(n)");
+
+        let capped = with_row_limit("SELECT n FROM data ORDER BY n -- every row", 3);
 
-    let mut stmt = conn.prepare(&json_sql)?;
-    let rows = stmt.query_row([], |row| Ok(row.get::<_, String>(0)?))?;
+        assert_eq!(rows(&conn, &capped), [[json!(0)], [json!(1)], [json!(2)]]);
+    }
+
+    #[tokio::test]
+    async fn a_slow_query_is_interrupted_at_its_timeout() {
+        let conn = Connection::open_in_memory().unwrap();
+        let timeout = Duration::from_millis(200);
+        let started = std::time::Instant::now();
+
+        let result = run_with_timeout(conn, timeout, |conn| {
+            let count = conn.query_row(
+                "SELECT count(*) FROM generate_series(1, 1000000) a, generate_series(1, 1000000) b",
+                [],
+                |row| row.get::<_, i64>(0),
+            )?;
+            Ok(count)
+        })
+        .await;
+
+        assert!(matches!(result, Err(Error::QueryTimeout(t)) if t == timeout));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn a_quick_query_hands_the_connection_back() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        let (conn, answer) = run_with_timeout(conn, Duration::from_secs(10), |conn| {
+            Ok(conn.query_row("SELECT 40 + 2", [], |row| row.get::<_, i64>(0))?)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(answer, 42);
+        let again: i64 = conn.query_row("SELECT 1", [], |row| row.get(0)).unwrap();
+        assert_eq!(again, 1);
+    }
+
+    fn column(name: &str, column_type: &str) -> (String, String) {
+        (name.to_string(), column_type.to_string())
+    }
+
+    #[test]
+    fn stats_sql_measures_each_column_by_its_type() {
+        let sql = column_stats_sql(&[
+            column("amount", "DECIMAL(18,2)"),
+            column("ordered_on", "TIMESTAMP WITH TIME ZONE"),
+            column("region", "VARCHAR"),
+            column("paid", "BOOLEAN"),
+        ]);
+
+        assert!(sql.contains("min(\"amount\")::VARCHAR, max(\"amount\")::VARCHAR"));
+        assert!(sql.contains("min(\"ordered_on\")::VARCHAR, max(\"ordered_on\")::VARCHAR"));
+        assert!(sql.contains("approx_top_k(left(\"region\", 40), 20)"));
+        assert!(!sql.contains("min(\"region\")"));
+        assert!(!sql.contains("min(\"paid\")"));
+        assert!(!sql.contains("approx_top_k(left(\"paid\""));
+        assert!(sql.ends_with("FROM \"data\""));
+    }
+
+    #[test]
+    fn stats_sql_quotes_awkward_column_names() {
+        let sql = column_stats_sql(&[column("say \"hi\"", "INTEGER")]);
+
+        assert!(sql.contains("min(\"say \"\"hi\"\"\")::VARCHAR"), "{}", sql);
+    }
+
+    #[test]
+    fn column_stats_are_read_in_one_scan() {
+        let (_dir, conn) = fixture(
+            "SELECT * FROM (VALUES (3, 'WA', true), (7, 'WA', false), (NULL, 'NSW', NULL), \
+             (5, NULL, true)) t(amount, state, paid)",
+        );
+
+        let profiles = compute_column_stats(&conn).unwrap();
+
+        assert_eq!(profiles.len(), 3);
+        let amount = &profiles[0];
+        assert_eq!(amount.column, "amount");
+        assert_eq!(amount.null_percent, 25.0);
+        assert_eq!(amount.min.as_deref(), Some("3"));
+        assert_eq!(amount.max.as_deref(), Some("7"));
+        assert!(amount.values.is_empty());
+        let state = &profiles[1];
+        assert_eq!(state.min, None);
+        assert_eq!(state.values, ["WA", "NSW"]);
+        let paid = &profiles[2];
+        assert_eq!((paid.min.as_ref(), paid.values.len()), (None, 0));
+    }
+
+    #[test]
+    fn rendered_stats_stop_at_their_budget() {
+        let profile = |column: &str| ColumnProfile {
+            column: column.to_string(),
+            null_percent: 0.0,
+            min: Some("1".to_string()),
+            max: Some("9".to_string()),
+            values: Vec::new(),
+        };
+        let profiles = [profile("a"), profile("b"), profile("c")];
+
+        assert_eq!(
+            render_column_stats(&profiles, 1000),
+            "a (0.0% null, 1 to 9); b (0.0% null, 1 to 9); c (0.0% null, 1 to 9)"
+        );
+        assert_eq!(
+            render_column_stats(&profiles, 30),
+            "a (0.0% null, 1 to 9); 2 more columns omitted"
+        );
+    }
+
+    #[test]
+    fn a_query_matching_nothing_is_an_empty_typed_result() {
+        let (_dir, conn) = fixture("SELECT 1 AS id, 'WA' AS state");
+        let sql = "SELECT id, state FROM data WHERE state = 'TAS'";
+
+        let result = execute_sql_page(&conn, sql, 10, 0, u64::MAX).unwrap();
+
+        assert!(result.rows.is_empty());
+        assert!(!result.truncated);
+        let columns: Vec<(&str, &str)> = result
+            .columns
+            .iter()
+            .map(|column| (column.name.as_str(), column.duckdb_type.as_str()))
+            .collect();
+        assert_eq!(columns, [("id", "INTEGER"), ("state", "VARCHAR")]);
+        assert_eq!(json!(result.row_objects()), json!([]));
+        assert_eq!(count_query_rows(&conn, sql).unwrap(), 0);
+    }
 
-    Ok(rows)
+    #[test]
+    fn only_a_plain_scan_is_sampled() {
+        for (sql, sampled) in [
+            ("SELECT * FROM data", true),
+            ("SELECT region, amount FROM data WHERE amount > 10", true),
+            ("SELECT count FROM data", true),
+            ("SELECT * FROM (SELECT * FROM data LIMIT 5) t", true),
+            ("SELECT count(*) FROM data", false),
+            (
+                "SELECT region, sum(amount) FROM data GROUP BY region",
+                false,
+            ),
+            (
+                "SELECT * FROM (SELECT approx_count_distinct(id) AS n FROM data) t",
+                false,
+            ),
+            ("SELECT DISTINCT region FROM data", false),
+            ("SELECT * FROM data USING SAMPLE 5%", false),
+            ("SELECT * FROM data TABLESAMPLE 5%", false),
+            ("SELECT * FROM data LIMIT 100", false),
+            ("SELECT * FROM data WHERE note = 'unterminated", false),
+        ] {
+            assert_eq!(needs_sample_clause(sql), sampled, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn a_sampled_query_still_runs() {
+        let (_dir, conn) = fixture("SELECT * FROM range(1000) t(n)");
+
+        let all = rows(
+            &conn,
+            &with_sample("SELECT n FROM data -- every row", 100.0),
+        );
+        let none = rows(&conn, &with_sample("SELECT n FROM data;", 0.0));
+
+        assert_eq!(all.len(), 1000);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn settings_follow_the_functions_memory() {
+        for (memory_mb, memory_limit_mb, threads) in [
+            (128, 76, 1),
+            (1769, 1061, 1),
+            (3008, 1804, 2),
+            (10240, 6144, 6),
+        ] {
+            let settings = DuckDbSettings::for_memory_mb(memory_mb);
+
+            assert_eq!(
+                (settings.memory_limit_mb, settings.threads),
+                (memory_limit_mb, threads),
+                "{} MB",
+                memory_mb
+            );
+            assert_eq!(settings.temp_directory, DEFAULT_SPILL_DIRECTORY);
+        }
+    }
+
+    #[test]
+    fn settings_are_applied_as_set_statements() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = DuckDbSettings {
+            temp_directory: dir.path().to_string_lossy().to_string(),
+            ..DuckDbSettings::for_memory_mb(3008)
+        };
+        assert!(
+            settings
+                .statements()
+                .starts_with("SET memory_limit = '1804MiB'; SET threads = 2;")
+        );
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&settings.statements()).unwrap();
+
+        let setting = |name: &str| -> String {
+            conn.query_row("SELECT current_setting(?)::VARCHAR", [name], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(setting("threads"), "2");
+        assert_eq!(setting("temp_directory"), settings.temp_directory);
+    }
+
+    #[test]
+    fn every_value_type_converts_to_json() {
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = "SELECT
+            true AS boolean,
+            (-8)::TINYINT AS tinyint,
+            (-16)::SMALLINT AS smallint,
+            (-32)::INTEGER AS integer,
+            (-64)::BIGINT AS bigint,
+            170141183460469231731687303715884105727::HUGEINT AS hugeint,
+            8::UTINYINT AS utinyint,
+            16::USMALLINT AS usmallint,
+            32::UINTEGER AS uinteger,
+            18446744073709551615::UBIGINT AS ubigint,
+            1.5::FLOAT AS float,
+            'NaN'::DOUBLE AS nan,
+            12.25::DECIMAL(9,2) AS decimal,
+            12345678901234567890.123456789::DECIMAL(38,9) AS wide_decimal,
+            TIMESTAMP '2024-03-05 06:07:08.123456' AS timestamp,
+            'infinity'::TIMESTAMP AS infinite_timestamp,
+            DATE '2024-03-05' AS date,
+            TIME '06:07:08.5' AS time,
+            INTERVAL '1 month 2 days 3.5 seconds' AS interval,
+            'text' AS varchar,
+            '\\xCA\\xFE'::BLOB AS blob,
+            [1, NULL, 3] AS list,
+            {'a': 1, 'b': 'x'} AS struct,
+            MAP {'k': 2} AS map,
+            'b'::ENUM('a', 'b') AS enum,
+            NULL::INTEGER AS null";
+
+        let result = execute_sql_typed(&conn, sql, NO_LIMITS).unwrap();
+
+        assert_eq!(
+            result.row_objects()[0],
+            json!({
+                "boolean": true,
+                "tinyint": -8,
+                "smallint": -16,
+                "integer": -32,
+                "bigint": -64,
+                "hugeint": "170141183460469231731687303715884105727",
+                "utinyint": 8,
+                "usmallint": 16,
+                "uinteger": 32,
+                "ubigint": 18446744073709551615u64,
+                "float": 1.5,
+                "nan": "NaN",
+                "decimal": 12.25,
+                "wide_decimal": "12345678901234567890.123456789",
+                "timestamp": "2024-03-05 06:07:08.123456",
+                "infinite_timestamp": "infinity",
+                "date": "2024-03-05",
+                "time": "06:07:08.500",
+                "interval": "P1M2DT3.5S",
+                "varchar": "text",
+                "blob": "yv4=",
+                "list": [1, null, 3],
+                "struct": {"a": 1, "b": "x"},
+                "map": {"k": 2},
+                "enum": "b",
+                "null": null
+            })
+        );
+        // Columns keep the order the query selected them in, with DuckDB's own type names
+        let column = |index: usize| {
+            let column = &result.columns[index];
+            (column.name.as_str(), column.duckdb_type.as_str())
+        };
+        assert_eq!(column(0), ("boolean", "BOOLEAN"));
+        assert_eq!(column(12), ("decimal", "DECIMAL(9,2)"));
+        assert_eq!(column(25), ("null", "INTEGER"));
+    }
+
+    #[test]
+    fn paths_with_quotes_spaces_and_unicode_are_read_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+
+        for name in [
+            "it's.parquet",
+            "two words.parquet",
+            "données ✓.parquet",
+            "x'); DROP VIEW data; --.parquet",
+        ] {
+            let path = write_parquet(&conn, &dir, name, "SELECT 7 AS n");
+
+            let schema = get_parquet_schema(&conn, &path).unwrap();
+            assert_eq!(schema[0].name, "n", "{}", name);
+            assert_eq!(parquet_row_count(&conn, &path).unwrap(), 1, "{}", name);
+            register_parquet_view(&conn, PARQUET_VIEW_NAME, &path).unwrap();
+            assert_eq!(rows(&conn, "SELECT n FROM data"), [[json!(7)]], "{}", name);
+        }
+    }
+
+    #[test]
+    fn an_export_lands_at_an_awkward_path() {
+        let (dir, conn) = fixture("SELECT 7 AS n");
+        let out_path = dir.path().join("it's an ✓ export.parquet");
+
+        let exported = export_query_to_parquet(
+            &conn,
+            "SELECT n FROM data",
+            &[PARQUET_VIEW_NAME],
+            &out_path,
+            ExportOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(exported, 1);
+        assert_eq!(
+            parquet_row_count(&conn, &out_path.to_string_lossy()).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn two_registered_views_join() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let orders = write_parquet(
+            &conn,
+            &dir,
+            "orders.parquet",
+            "SELECT * FROM (VALUES (1, 10), (2, 20), (3, 10)) t(id, customer_id)",
+        );
+        let customers = write_parquet(
+            &conn,
+            &dir,
+            "customers.parquet",
+            "SELECT * FROM (VALUES (10, 'Ada'), (20, 'Grace')) t(id, name)",
+        );
+        register_parquet_view(&conn, "orders", &orders).unwrap();
+        register_parquet_glob_view(&conn, "customers", &customers).unwrap();
+
+        let joined = rows(
+            &conn,
+            "SELECT c.name, count(*) FROM orders o JOIN customers c ON o.customer_id = c.id \
+             GROUP BY c.name ORDER BY c.name",
+        );
+
+        assert_eq!(
+            joined,
+            [[json!("Ada"), json!(2)], [json!("Grace"), json!(1)]]
+        );
+    }
+
+    #[test]
+    fn a_view_name_that_isnt_an_alias_is_refused() {
+        let (dir, conn) = fixture("SELECT 1 AS n");
+        let path = dir
+            .path()
+            .join("data.parquet")
+            .to_string_lossy()
+            .to_string();
+
+        for name in ["data; DROP", "Data", "select", "", "data\"x"] {
+            assert!(
+                matches!(
+                    register_parquet_view(&conn, name, &path),
+                    Err(Error::InvalidViewName(_))
+                ),
+                "{}",
+                name
+            );
+        }
+        // The existing view is untouched
+        assert_eq!(rows(&conn, "SELECT n FROM data"), [[json!(1)]]);
+    }
+
+    #[test]
+    fn column_names_with_colons_and_commas_stay_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let path = write_parquet(
+            &conn,
+            &dir,
+            "data.parquet",
+            "SELECT 1 AS \"ratio: a, b\", 'x' AS plain_name, NULL::DATE AS shipped_on",
+        );
+
+        let schema = get_parquet_schema(&conn, &path).unwrap();
+
+        assert_eq!(
+            schema,
+            [
+                ParquetColumn {
+                    name: "ratio: a, b".to_string(),
+                    duckdb_type: "INTEGER".to_string(),
+                    nullable: true,
+                },
+                ParquetColumn {
+                    name: "plain_name".to_string(),
+                    duckdb_type: "VARCHAR".to_string(),
+                    nullable: true,
+                },
+                ParquetColumn {
+                    name: "shipped_on".to_string(),
+                    duckdb_type: "DATE".to_string(),
+                    nullable: true,
+                },
+            ]
+        );
+        assert_eq!(
+            render_schema_for_prompt(&schema),
+            "\"ratio: a, b\": INTEGER, plain_name: VARCHAR, shipped_on: DATE"
+        );
+    }
+
+    // 5000 rows written in row groups of 2048 with snappy: an `id` counting up from 0, and a
+    // `label` cycling through v0 to v2 that is NULL on every tenth row
+    fn row_group_fixture(conn: &Connection, dir: &tempfile::TempDir) -> String {
+        let path = dir
+            .path()
+            .join("groups.parquet")
+            .to_string_lossy()
+            .to_string();
+        conn.execute_batch(&format!(
+            "COPY (SELECT range AS id, \
+             CASE WHEN range % 10 = 0 THEN NULL ELSE 'v' || (range % 3) END AS label \
+             FROM range(5000)) TO {} (FORMAT PARQUET, ROW_GROUP_SIZE 2048, COMPRESSION snappy);",
+            sql_string(&path)
+        ))
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn row_counts_come_from_the_footer_exactly() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let path = row_group_fixture(&conn, &dir);
+
+        assert_eq!(parquet_row_count(&conn, &path).unwrap(), 5000);
+        assert_eq!(
+            parquet_file_stats(&conn, &path).unwrap(),
+            ParquetFileStats {
+                file_bytes: std::fs::metadata(&path).unwrap().len(),
+                row_count: 5000,
+                row_groups: 3,
+                compression: vec!["SNAPPY".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn an_empty_file_counts_no_rows() {
+        let (dir, conn) = fixture("SELECT 1 AS n WHERE false");
+        let path = dir
+            .path()
+            .join("data.parquet")
+            .to_string_lossy()
+            .to_string();
+
+        assert_eq!(parquet_row_count(&conn, &path).unwrap(), 0);
+    }
+
+    #[test]
+    fn the_metadata_report_matches_how_the_fixture_was_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let path = row_group_fixture(&conn, &dir);
+
+        let report = parquet_metadata_report(&conn, &path).unwrap();
+
+        assert_eq!(report.row_count, 5000);
+        let rows: Vec<u64> = report.row_groups.iter().map(|group| group.rows).collect();
+        assert_eq!(rows, [2048, 2048, 904]);
+        assert_eq!(
+            report.compressed_bytes,
+            report
+                .row_groups
+                .iter()
+                .map(|g| g.compressed_bytes)
+                .sum::<u64>()
+        );
+        assert_eq!(
+            report.compressed_bytes,
+            report
+                .columns
+                .iter()
+                .map(|c| c.compressed_bytes)
+                .sum::<u64>()
+        );
+        assert!(report.uncompressed_bytes >= report.compressed_bytes);
+
+        let [id, label] = &report.columns[..] else {
+            panic!("unexpected columns: {:?}", report.columns);
+        };
+        assert_eq!(
+            (id.name.as_str(), id.physical_type.as_str()),
+            ("id", "INT64")
+        );
+        assert_eq!(id.compression, ["SNAPPY"]);
+        assert_eq!(id.null_count, Some(0));
+        assert_eq!(id.row_groups_with_stats, 3);
+        let ranges: Vec<(Option<&str>, Option<&str>)> = id
+            .ranges
+            .iter()
+            .map(|range| (range.min.as_deref(), range.max.as_deref()))
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                (Some("0"), Some("2047")),
+                (Some("2048"), Some("4095")),
+                (Some("4096"), Some("4999"))
+            ]
+        );
+        assert_eq!(label.physical_type, "BYTE_ARRAY");
+        assert_eq!(label.null_count, Some(500));
+        assert!(!label.encodings.is_empty());
+        assert!(
+            label
+                .ranges
+                .iter()
+                .all(|range| (range.min.as_deref(), range.max.as_deref())
+                    == (Some("v0"), Some("v2")))
+        );
+    }
+
+    // A connection whose INSTALL can only look in empty local directories, so falling back
+    // to DuckDB's repository fails the same way with or without internet access
+    fn offline_connection(dir: &tempfile::TempDir) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        for sub in ["installed", "repository"] {
+            std::fs::create_dir(dir.path().join(sub)).unwrap();
+        }
+        conn.execute_batch(&format!(
+            "SET extension_directory = {}; SET custom_extension_repository = {};",
+            sql_string(&dir.path().join("installed").to_string_lossy()),
+            sql_string(&dir.path().join("repository").to_string_lossy())
+        ))
+        .unwrap();
+        conn
+    }
+
+    fn extension_error(result: Result<(), Error>) -> (&'static str, String) {
+        match result {
+            Err(Error::Extension { name, details }) => (name, details),
+            other => panic!("expected an extension error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_bundled_copy_is_loaded_from_the_extension_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = offline_connection(&dir);
+        let bundled = dir.path().join("layer");
+        std::fs::create_dir(&bundled).unwrap();
+        let path = bundled.join("httpfs.duckdb_extension");
+        std::fs::write(&path, b"not a duckdb extension").unwrap();
+
+        let (name, details) =
+            extension_error(load_extension(&conn, Extension::Httpfs, Some(&bundled)));
+
+        // The file was found and handed to LOAD, which rejected it before installing was tried
+        assert_eq!(name, "httpfs");
+        let tried = format!("the bundled copy at {} failed to load", path.display());
+        assert!(details.starts_with(&tried), "{}", details);
+        assert!(details.contains("installing it failed"), "{}", details);
+    }
+
+    #[test]
+    fn a_missing_bundled_copy_names_the_expected_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = offline_connection(&dir);
+        let bundled = dir.path().join("layer");
+        std::fs::create_dir(&bundled).unwrap();
+        // Another extension's file doesn't stand in for the one asked for
+        std::fs::write(bundled.join("json.duckdb_extension"), b"").unwrap();
+
+        let (name, details) =
+            extension_error(load_extension(&conn, Extension::Httpfs, Some(&bundled)));
+
+        assert_eq!(name, "httpfs");
+        let expected = bundled.join("httpfs.duckdb_extension");
+        assert!(
+            details.starts_with(&format!("no bundled copy at {}", expected.display())),
+            "{}",
+            details
+        );
+    }
+
+    #[test]
+    fn without_an_extension_directory_the_error_says_it_is_unset() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = offline_connection(&dir);
+
+        let (_, details) = extension_error(load_extension(&conn, Extension::Httpfs, None));
+
+        assert!(
+            details.starts_with("DUCKDB_EXTENSION_DIR is not set"),
+            "{}",
+            details
+        );
+    }
+
+    #[test]
+    fn an_extension_built_into_the_library_skips_the_bundled_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = offline_connection(&dir);
+        let bundled = dir.path().join("layer");
+        std::fs::create_dir(&bundled).unwrap();
+        std::fs::write(
+            bundled.join("json.duckdb_extension"),
+            b"not a duckdb extension",
+        )
+        .unwrap();
+
+        load_extension(&conn, Extension::Json, Some(&bundled)).unwrap();
+        load_extensions(&conn, &[Extension::Json]).unwrap();
+    }
+
+    #[test]
+    fn the_row_limit_stops_reading_and_marks_the_result_truncated() {
+        let (_dir, conn) = fixture("SELECT range AS id FROM range(100)");
+        let limits = ResultLimits {
+            max_rows: 10,
+            max_bytes: u64::MAX,
+        };
+
+        let result = execute_sql_typed(&conn, "SELECT id FROM data ORDER BY id", limits).unwrap();
+
+        assert!(result.truncated);
+        assert_eq!(
+            result.rows,
+            (0..10).map(|id| vec![json!(id)]).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            result.bytes,
+            result
+                .rows
+                .iter()
+                .map(|row| row_object_bytes(&result.columns, row))
+                .sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn a_result_exactly_at_the_row_limit_is_complete() {
+        let (_dir, conn) = fixture("SELECT range AS id FROM range(10)");
+        let limits = ResultLimits {
+            max_rows: 10,
+            max_bytes: u64::MAX,
+        };
+
+        let result = execute_sql_typed(&conn, "SELECT id FROM data", limits).unwrap();
+
+        assert!(!result.truncated);
+        assert_eq!(result.rows.len(), 10);
+    }
+
+    #[test]
+    fn the_byte_limit_leaves_out_the_row_that_would_go_over() {
+        // Each row is {"text":"<100 characters>"}, counted as 112 bytes with its comma
+        let (_dir, conn) = fixture("SELECT repeat('x', 100) AS text FROM range(50)");
+        let limits = ResultLimits {
+            max_rows: u64::MAX,
+            max_bytes: 1000,
+        };
+
+        let result = execute_sql_typed(&conn, "SELECT text FROM data", limits).unwrap();
+
+        assert!(result.truncated);
+        assert_eq!(result.rows.len(), 8);
+        assert_eq!(result.bytes, 896);
+        assert_eq!(
+            serde_json::to_string(&result.row_objects()).unwrap().len() as u64,
+            // The array's brackets, and a comma between each row but not after the last
+            result.bytes + 2 - 1
+        );
+    }
+
+    #[test]
+    fn a_page_is_cut_short_by_its_byte_budget() {
+        let (_dir, conn) = fixture("SELECT range AS id, repeat('x', 100) AS text FROM range(50)");
+
+        let page = execute_sql_page(&conn, "SELECT * FROM data ORDER BY id", 20, 10, 500).unwrap();
+
+        assert!(page.truncated);
+        let ids: Vec<&serde_json::Value> = page.rows.iter().map(|row| &row[0]).collect();
+        assert_eq!(ids, [&json!(10), &json!(11), &json!(12), &json!(13)]);
+        assert!(page.bytes <= 500);
+    }
+
+    #[test]
+    fn an_exported_files_schema_matches_the_query_projection() {
+        let (dir, conn) = fixture(
+            "SELECT range AS id, 'r' || (range % 3) AS region, (range / 4)::DECIMAL(10, 2) AS price, \
+             DATE '2024-01-01' + range::INTEGER AS sold_on, range % 2 = 0 AS even \
+             FROM range(20)",
+        );
+        let sql = "SELECT region AS area, count(*) AS sales, sum(price) AS revenue, \
+                   max(sold_on) AS last_sale, bool_and(even) AS all_even, list(id ORDER BY id) AS ids, \
+                   avg(id) AS mean_id \
+                   FROM data GROUP BY region ORDER BY area";
+        let out_path = dir.path().join("derived.parquet");
+        let options = ExportOptions {
+            compression: ParquetCompression::Snappy,
+        };
+
+        let exported =
+            export_query_to_parquet(&conn, sql, &[PARQUET_VIEW_NAME], &out_path, options).unwrap();
+
+        let out_path = out_path.to_string_lossy();
+        let written = query_column_types(
+            &conn,
+            &format!("SELECT * FROM read_parquet({})", sql_string(&out_path)),
+        )
+        .unwrap();
+        assert_eq!(written, query_column_types(&conn, sql).unwrap());
+        assert_eq!(
+            written
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "area",
+                "sales",
+                "revenue",
+                "last_sale",
+                "all_even",
+                "ids",
+                "mean_id"
+            ]
+        );
+        assert_eq!(exported, 3);
+        let report = parquet_metadata_report(&conn, &out_path).unwrap();
+        assert!(report.columns.iter().all(|c| c.compression == ["SNAPPY"]));
+    }
+
+    #[test]
+    fn a_refused_export_writes_nothing() {
+        let (dir, conn) = fixture("SELECT 7 AS n");
+        let out_path = dir.path().join("derived.parquet");
+
+        let refused = export_query_to_parquet(
+            &conn,
+            "SELECT * FROM read_csv('/etc/passwd')",
+            &[PARQUET_VIEW_NAME],
+            &out_path,
+            ExportOptions::default(),
+        );
+
+        assert!(refused.is_err());
+        assert!(!out_path.exists());
+    }
 }
@@ -1,118 +1,283 @@
 use aws_sdk_dynamodb::types::AttributeValue;
-use aws_sdk_dynamodb::{Client as DynamoDbClient, Error};
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tracing::error;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Job {
-    pub service: String,
-    pub serviceid: String,
-    pub status: String,
-    pub context: String,
+use std::str::FromStr;
+
+mod api_key;
+mod job;
+mod lease;
+mod listing;
+mod query;
+
+pub use api_key::*;
+pub use job::*;
+pub use lease::*;
+pub use listing::*;
+pub use query::*;
+
+// Where a job is in its life. Every status written to or read from a job item goes through
+// this, so an item with a status this version doesn't know fails to parse here rather than
+// wherever it is next compared.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    #[default]
+    Pending,
+    // Set by the first progress report of a conversion
+    Processing,
+    Success,
+    Failed,
+    Cancelled,
+    Scheduled,
+    // Created by the upload endpoint; the owner's conversion request takes the item over
+    AwaitingUpload,
+}
+
+impl JobStatus {
+    pub const ALL: [JobStatus; 7] = [
+        JobStatus::Pending,
+        JobStatus::Processing,
+        JobStatus::Success,
+        JobStatus::Failed,
+        JobStatus::Cancelled,
+        JobStatus::Scheduled,
+        JobStatus::AwaitingUpload,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Processing => "processing",
+            JobStatus::Success => "success",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::Scheduled => "scheduled",
+            JobStatus::AwaitingUpload => "awaiting_upload",
+        }
+    }
+
+    pub fn attribute(&self) -> AttributeValue {
+        AttributeValue::S(self.as_str().to_string())
+    }
+
+    // Whether a job in this status may be moved to `next`. Success and cancelled are
+    // final; a failed job only leaves that status when it is resubmitted. A pending job
+    // stays pending while a failed attempt waits to be retried.
+    pub fn can_transition_to(self, next: JobStatus) -> bool {
+        use JobStatus::*;
+
+        match self {
+            AwaitingUpload => matches!(next, Pending | Scheduled),
+            Scheduled => matches!(next, Pending | Cancelled),
+            Pending => matches!(
+                next,
+                Pending | Processing | Success | Failed | Cancelled | Scheduled
+            ),
+            Processing => matches!(next, Pending | Success | Failed | Cancelled),
+            Failed => matches!(next, Pending | Scheduled),
+            Success | Cancelled => false,
+        }
+    }
+
+    // Every status a job can be moved to `self` from
+    pub fn predecessors(self) -> Vec<JobStatus> {
+        JobStatus::ALL
+            .into_iter()
+            .filter(|status| status.can_transition_to(self))
+            .collect()
+    }
 }
 
-impl Job {
-    pub fn from_dynamodb_item(
-        item: HashMap<String, AttributeValue>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let service = item
-            .get("service")
-            .and_then(|v| v.as_s().ok())
-            .ok_or("Missing or invalid 'service' field")?
-            .clone();
-
-        let serviceid = item
-            .get("serviceId")
-            .and_then(|v| v.as_s().ok())
-            .ok_or("Missing or invalid 'serviceid' field")?
-            .clone();
-
-        let status = item
-            .get("status")
-            .and_then(|v| v.as_s().ok())
-            .ok_or("Missing or invalid 'status' field")?
-            .clone();
-
-        let context = item
-            .get("context")
-            .and_then(|v| v.as_s().ok())
-            .ok_or("Missing or invalid 'context' field")?
-            .clone();
-
-        Ok(Job {
-            service,
-            serviceid,
-            status,
-            context,
-        })
+impl std::fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("unknown job status {0:?}")]
+pub struct UnknownJobStatus(pub String);
+
+impl FromStr for JobStatus {
+    type Err = UnknownJobStatus;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        JobStatus::ALL
+            .into_iter()
+            .find(|known| known.as_str() == status)
+            .ok_or_else(|| UnknownJobStatus(status.to_string()))
     }
 }
 
-pub async fn update_job_status_to_success(
-    table_name: &str,
-    job_id: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let config = aws_config::load_from_env().await;
-    let dynamodb_client = DynamoDbClient::new(&config);
-
-    let pk = format!("JOB-{}", job_id);
-
-    println!("Job {}: Updating DynamoDB status to success", job_id);
-
-    let result = dynamodb_client
-        .update_item()
-        .table_name(table_name)
-        .key("service", aws_sdk_dynamodb::types::AttributeValue::S(pk))
-        .key(
-            "serviceId",
-            aws_sdk_dynamodb::types::AttributeValue::S(job_id.to_string()),
-        )
-        .update_expression("SET #status = :status")
-        .expression_attribute_names("#status", "status")
-        .expression_attribute_values(
-            ":status",
-            aws_sdk_dynamodb::types::AttributeValue::S("success".to_string()),
-        )
-        .send()
-        .await;
-
-    match result {
-        Ok(_) => {
-            println!(
-                "Job {}: Successfully updated DynamoDB status to success",
-                job_id
-            );
-            Ok(())
+// Every timestamp written to a job item: ISO-8601 UTC to the millisecond with a `Z`
+// suffix, so they sort the same as strings and as times
+pub fn timestamp_now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// How long each kind of item is kept before the table's TTL removes it, in days, each set
+// through its own env variable. A job's retention counts from its last status change, so
+// one still in progress never expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    Job,
+    // Jobs materialized from query results, which can be made again from their parent
+    DerivedJob,
+    QueryAudit,
+    QueryTurn,
+}
+
+impl Retention {
+    pub const ALL: [Retention; 4] = [
+        Retention::Job,
+        Retention::DerivedJob,
+        Retention::QueryAudit,
+        Retention::QueryTurn,
+    ];
+
+    pub fn env_var(&self) -> &'static str {
+        match self {
+            Retention::Job => "JOB_RETENTION_DAYS",
+            Retention::DerivedJob => "DERIVED_JOB_RETENTION_DAYS",
+            Retention::QueryAudit => "QUERY_AUDIT_RETENTION_DAYS",
+            Retention::QueryTurn => "QUERY_TURN_RETENTION_DAYS",
         }
-        Err(e) => {
-            error!("Job {}: Failed to update DynamoDB status: {}", job_id, e);
-            Err(format!("DynamoDB update failed: {}", e).into())
+    }
+
+    fn default_days(&self) -> u64 {
+        match self {
+            Retention::Job => 180,
+            Retention::DerivedJob => 7,
+            Retention::QueryAudit => 90,
+            Retention::QueryTurn => 30,
         }
     }
+
+    // The configured retention, falling back to the default when unset, unparsable or zero
+    pub fn days(&self) -> u64 {
+        self.days_from(std::env::var(self.env_var()).ok().as_deref())
+    }
+
+    fn days_from(&self, configured: Option<&str>) -> u64 {
+        configured
+            .and_then(|days| days.parse::<u64>().ok())
+            .filter(|days| *days > 0)
+            .unwrap_or(self.default_days())
+    }
+
+    // `expires_at` for an item of this kind written at `from`
+    pub fn attribute(&self, from: DateTime<Utc>) -> AttributeValue {
+        AttributeValue::N(expires_at(from, self.days()).to_string())
+    }
 }
 
-pub async fn get_job_by_id(table_name: &str, job_id: &str) -> Result<Option<Job>, Error> {
-    let config = aws_config::load_from_env().await;
-    let dynamodb_client = DynamoDbClient::new(&config);
-    let pk_value = format!("JOB-{}", job_id);
-
-    let request = dynamodb_client
-        .get_item()
-        .table_name(table_name)
-        .key("service", AttributeValue::S(pk_value))
-        .key("serviceId", AttributeValue::S(job_id.to_string()));
-
-    let response = request.send().await?;
-
-    match response.item {
-        Some(item) => match Job::from_dynamodb_item(item) {
-            Ok(job) => Ok(Some(job)),
-            Err(e) => {
-                eprintln!("Error parsing job from DynamoDB item: {}", e);
-                Ok(None)
+// Epoch seconds `days` after `from`, as DynamoDB's TTL reads `expires_at`. Saturates
+// rather than overflowing on an absurd retention.
+pub fn expires_at(from: DateTime<Utc>, days: u64) -> i64 {
+    let days = i64::try_from(days).unwrap_or(i64::MAX);
+    from.timestamp()
+        .saturating_add(days.saturating_mul(SECONDS_PER_DAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_item_expires_its_retention_after_it_was_written() {
+        let written = DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            expires_at(written, 7),
+            written.timestamp() + 7 * SECONDS_PER_DAY
+        );
+        assert_eq!(expires_at(written, 0), written.timestamp());
+        // An absurd retention means never, not an overflow
+        assert_eq!(expires_at(written, u64::MAX), i64::MAX);
+        assert_eq!(
+            Retention::DerivedJob.attribute(written).as_n().unwrap(),
+            &expires_at(written, 7).to_string()
+        );
+    }
+
+    #[test]
+    fn a_retention_falls_back_to_its_default() {
+        for retention in Retention::ALL {
+            let default = retention.default_days();
+            assert_eq!(retention.days_from(None), default);
+            assert_eq!(retention.days_from(Some("0")), default);
+            assert_eq!(retention.days_from(Some("a week")), default);
+            assert_eq!(retention.days_from(Some("-3")), default);
+            assert_eq!(retention.days_from(Some("14")), 14);
+        }
+        assert_eq!(Retention::Job.default_days(), 180);
+        assert_eq!(Retention::DerivedJob.default_days(), 7);
+        assert_eq!(Retention::QueryAudit.default_days(), 90);
+    }
+
+    #[test]
+    fn every_transition_is_allowed_or_refused_as_intended() {
+        use JobStatus::*;
+
+        // Each status with every status it may move to
+        let allowed: [(JobStatus, &[JobStatus]); 7] = [
+            (AwaitingUpload, &[Pending, Scheduled]),
+            (Scheduled, &[Pending, Cancelled]),
+            (
+                Pending,
+                &[Pending, Processing, Success, Failed, Cancelled, Scheduled],
+            ),
+            (Processing, &[Pending, Success, Failed, Cancelled]),
+            (Failed, &[Pending, Scheduled]),
+            (Success, &[]),
+            (Cancelled, &[]),
+        ];
+        assert_eq!(allowed.len(), JobStatus::ALL.len());
+
+        for (from, targets) in allowed {
+            for to in JobStatus::ALL {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    targets.contains(&to),
+                    "{} -> {}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn predecessors_are_the_statuses_that_may_move_to_a_status() {
+        use JobStatus::*;
+
+        assert_eq!(
+            Pending.predecessors(),
+            [Pending, Processing, Failed, Scheduled, AwaitingUpload]
+        );
+        assert_eq!(Processing.predecessors(), [Pending]);
+        assert_eq!(Success.predecessors(), [Pending, Processing]);
+        assert_eq!(Failed.predecessors(), [Pending, Processing]);
+        assert_eq!(Cancelled.predecessors(), [Pending, Processing, Scheduled]);
+        assert_eq!(Scheduled.predecessors(), [Pending, Failed, AwaitingUpload]);
+        assert_eq!(AwaitingUpload.predecessors(), []);
+
+        for status in JobStatus::ALL {
+            for predecessor in status.predecessors() {
+                assert!(predecessor.can_transition_to(status));
             }
-        },
-        None => Ok(None),
+        }
+    }
+
+    #[test]
+    fn statuses_round_trip_through_their_names() {
+        for status in JobStatus::ALL {
+            assert_eq!(status.as_str().parse::<JobStatus>().unwrap(), status);
+        }
+        assert!("done".parse::<JobStatus>().is_err());
     }
 }
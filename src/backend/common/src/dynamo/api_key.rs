@@ -0,0 +1,108 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+use crate::error::Error;
+
+// One stored key, as read from the `APIKEY` items in the job table. Keys are stored as the
+// hex SHA-256 of the key so the table never holds a usable secret.
+#[derive(Debug, Clone)]
+pub struct ApiKeyRecord {
+    pub principal: String,
+    pub revoked: bool,
+    pub valid_until: Option<DateTime<Utc>>,
+    // A `valid_until` that couldn't be read. The key is refused rather than taken to never
+    // expire.
+    pub expiry_unreadable: bool,
+}
+
+pub async fn get_api_key(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    key_hash: &str,
+) -> Result<Option<ApiKeyRecord>, Error> {
+    let response = dynamodb_client
+        .get_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S("APIKEY".to_string()))
+        .key("serviceId", AttributeValue::S(key_hash.to_string()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("GetItem", e))?;
+
+    let Some(item) = response.item else {
+        return Ok(None);
+    };
+
+    let principal = item
+        .get("principal")
+        .and_then(|v| v.as_s().ok())
+        .cloned()
+        .ok_or_else(|| Error::dynamo_response("GetItem", "API key item has no principal"))?;
+
+    let revoked = item
+        .get("revoked")
+        .and_then(|v| v.as_bool().ok())
+        .copied()
+        .unwrap_or(false);
+
+    let (valid_until, expiry_unreadable) = match item.get("valid_until") {
+        None => (None, false),
+        Some(value) => match value
+            .as_s()
+            .ok()
+            .and_then(|valid_until| DateTime::parse_from_rfc3339(valid_until).ok())
+        {
+            Some(valid_until) => (Some(valid_until.with_timezone(&Utc)), false),
+            None => {
+                warn!(principal, "API key item has an unreadable valid_until");
+                (None, true)
+            }
+        },
+    };
+
+    Ok(Some(ApiKeyRecord {
+        principal,
+        revoked,
+        valid_until,
+        expiry_unreadable,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{at, key_table};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn a_key_item_is_read_by_its_hash() {
+        let stub = key_table(Some(json!({
+            "principal": {"S": "team-a"},
+            "revoked": {"BOOL": true},
+            "valid_until": {"S": "2025-01-01T12:00:00Z"}
+        })));
+
+        let record = get_api_key(&stub.dynamodb_client(), "jobs", "abc123")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(record.principal, "team-a");
+        assert!(record.revoked);
+        assert_eq!(record.valid_until, Some(at(12)));
+        let request = stub.operations("GetItem").remove(0).json();
+        assert_eq!(request["Key"]["service"]["S"], "APIKEY");
+        assert_eq!(request["Key"]["serviceId"]["S"], "abc123");
+    }
+
+    #[tokio::test]
+    async fn a_key_item_without_a_principal_is_an_error() {
+        let stub = key_table(Some(json!({"revoked": {"BOOL": false}})));
+
+        let result = get_api_key(&stub.dynamodb_client(), "jobs", "abc123").await;
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,2960 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{
+    AttributeValue, DeleteRequest, KeysAndAttributes, ReturnValue,
+    ReturnValuesOnConditionCheckFailure, WriteRequest,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use tracing::{error, info, warn};
+
+use super::{JOB_ENTITY, JobStatus, Retention, UnknownJobStatus, timestamp_now};
+use crate::column_matching::{ColumnReport, HeaderProblem, HeaderValidation, UnmatchedColumn};
+use crate::creation_types::{ColumnDefinition, ColumnStats, JobProvenance, ProcessingPath};
+use crate::duck_db::{ColumnProfile, ParquetColumn};
+use crate::error::Error;
+use crate::processing_error::ProcessingError;
+use crate::s3::UploadedPart;
+
+// Everything a job item records, as read back by the poller and the query endpoint. Only
+// the key and status are required; the rest is written at different stages of the job,
+// or not at all by older versions, and is left empty when absent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Job {
+    pub service: String,
+    pub serviceid: String,
+    pub status: JobStatus,
+    pub context: String,
+    // API key principal that submitted the job; absent on jobs from before keys existed
+    #[serde(default)]
+    pub created_by: Option<String>,
+    #[serde(default)]
+    pub schema: HashMap<String, String>,
+    #[serde(default)]
+    pub provenance: JobProvenance,
+    #[serde(default)]
+    pub start_after: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+    #[serde(default)]
+    pub started_at: Option<String>,
+    #[serde(default)]
+    pub completed_at: Option<String>,
+    // Last progress report of a running conversion
+    #[serde(default)]
+    pub heartbeat_at: Option<String>,
+    #[serde(default)]
+    pub attempts: Option<u32>,
+    #[serde(default)]
+    pub progress_percent: Option<f64>,
+    #[serde(default)]
+    pub rows_processed: Option<u64>,
+    #[serde(default)]
+    pub row_count: Option<u64>,
+    #[serde(default)]
+    pub output_bytes: Option<u64>,
+    #[serde(default)]
+    pub memory_high_water_bytes: Option<u64>,
+    #[serde(default)]
+    pub unmatched_columns: Vec<UnmatchedColumn>,
+    #[serde(default)]
+    pub ignored_columns: Vec<String>,
+    #[serde(default)]
+    pub header_problems: Vec<HeaderValidation>,
+    #[serde(default)]
+    pub column_stats: HashMap<String, ColumnStats>,
+    #[serde(default)]
+    pub output_bucket: Option<String>,
+    #[serde(default)]
+    pub output_key: Option<String>,
+    // Columns of the output as DESCRIBE reports them, and the ETag of the file they were
+    // read from, so queries can skip reading the schema from the file itself. Jobs that
+    // stored the schema as prompt text, before it was structured, have none.
+    #[serde(default)]
+    pub query_schema: Option<Vec<ParquetColumn>>,
+    #[serde(default)]
+    pub query_schema_etag: Option<String>,
+    #[serde(default)]
+    pub column_profiles: Option<ColumnProfiles>,
+    #[serde(default)]
+    pub error_message: Option<String>,
+    #[serde(default)]
+    pub error_stage: Option<String>,
+    // Set on a job materialized from a query: the job it was queried from and the SQL
+    #[serde(default)]
+    pub parent_job_id: Option<String>,
+    #[serde(default)]
+    pub derived_sql: Option<String>,
+}
+
+// Reads a job item's optional attributes. One that is missing or null is simply absent; one
+// of the wrong type, or a number or JSON that doesn't parse, is treated as absent too but
+// logged, as it means something wrote the item wrong, and one bad attribute shouldn't
+// make the whole job unreadable.
+struct ItemReader<'a> {
+    item: &'a HashMap<String, AttributeValue>,
+    job_id: &'a str,
+}
+
+impl<'a> ItemReader<'a> {
+    fn new(item: &'a HashMap<String, AttributeValue>) -> Self {
+        let job_id = item
+            .get("serviceId")
+            .and_then(|v| v.as_s().ok())
+            .map(String::as_str)
+            .unwrap_or_default();
+        ItemReader { item, job_id }
+    }
+
+    fn get<T>(
+        &self,
+        name: &str,
+        expected: &'static str,
+        read: impl FnOnce(&'a AttributeValue) -> Option<T>,
+    ) -> Option<T> {
+        let value = self.item.get(name)?;
+        if value.is_null() {
+            return None;
+        }
+        let read = read(value);
+        if read.is_none() {
+            warn!(
+                job_id = self.job_id,
+                attribute = name,
+                expected,
+                "Ignoring a job attribute of the wrong type"
+            );
+        }
+        read
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.get(name, "string", |v| v.as_s().ok().cloned())
+    }
+
+    fn number<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name, "number", |v| v.as_n().ok()?.parse().ok())
+    }
+
+    fn map(&self, name: &str) -> Option<&'a HashMap<String, AttributeValue>> {
+        self.get(name, "map", |v| v.as_m().ok())
+    }
+
+    fn list(&self, name: &str) -> Option<&'a Vec<AttributeValue>> {
+        self.get(name, "list", |v| v.as_l().ok())
+    }
+
+    // An attribute stored as JSON text
+    fn json<T: serde::de::DeserializeOwned>(&self, name: &str) -> Option<T> {
+        self.get(name, "JSON string", |v| {
+            serde_json::from_str(v.as_s().ok()?).ok()
+        })
+    }
+}
+
+impl Job {
+    pub fn from_dynamodb_item(
+        item: HashMap<String, AttributeValue>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let reader = ItemReader::new(&item);
+        let text = |name: &str| reader.text(name);
+        let strings = |map: &HashMap<String, AttributeValue>| -> HashMap<String, String> {
+            map.iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_s().ok()?.clone())))
+                .collect()
+        };
+
+        let service = text("service").ok_or("Missing or invalid 'service' field")?;
+        let serviceid = text("serviceId").ok_or("Missing or invalid 'serviceid' field")?;
+        let status = text("status")
+            .ok_or("Missing or invalid 'status' field")?
+            .parse::<JobStatus>()?;
+
+        let schema = reader.map("schema").map(strings).unwrap_or_default();
+
+        let labels = reader.map("labels").map(strings).unwrap_or_default();
+
+        let unmatched_columns = reader
+            .list("unmatched_columns")
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(|entry| {
+                        let fields = entry.as_m().ok()?;
+                        Some(UnmatchedColumn {
+                            column: fields.get("column")?.as_s().ok()?.clone(),
+                            suggestion: fields
+                                .get("suggestion")
+                                .and_then(|v| v.as_s().ok())
+                                .cloned(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let header_problems = reader
+            .list("header_problems")
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(|entry| {
+                        let fields = entry.as_m().ok()?;
+                        Some(HeaderValidation {
+                            header: fields.get("header")?.as_s().ok()?.clone(),
+                            normalized: fields.get("normalized")?.as_s().ok()?.clone(),
+                            problems: fields
+                                .get("problems")?
+                                .as_l()
+                                .ok()?
+                                .iter()
+                                .filter_map(|problem| HeaderProblem::parse(problem.as_s().ok()?))
+                                .collect(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let text_list = |name: &str| -> Vec<String> {
+            reader
+                .list(name)
+                .map(|entries| {
+                    entries
+                        .iter()
+                        .filter_map(|entry| entry.as_s().ok().cloned())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let column_stats = reader
+            .map("column_stats")
+            .map(|stats_map| {
+                stats_map
+                    .iter()
+                    .filter_map(|(column, stats)| {
+                        let stats = stats.as_m().ok()?;
+                        let count = |name: &str| {
+                            stats
+                                .get(name)
+                                .and_then(|v| v.as_n().ok())
+                                .and_then(|n| n.parse::<u64>().ok())
+                                .unwrap_or(0)
+                        };
+                        Some((
+                            column.clone(),
+                            ColumnStats {
+                                empty: count("empty"),
+                                coercion_failures: count("coercion_failures"),
+                                written: count("written"),
+                            },
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Job {
+            service,
+            serviceid,
+            status,
+            context: text("context").unwrap_or_default(),
+            created_by: text("created_by"),
+            schema,
+            provenance: JobProvenance {
+                original_filename: text("original_filename"),
+                submitted_by: text("submitted_by"),
+                labels,
+            },
+            start_after: text("start_after"),
+            created_at: text("created_at"),
+            updated_at: text("updated_at"),
+            started_at: text("started_at"),
+            completed_at: text("completed_at"),
+            heartbeat_at: text("heartbeat_at"),
+            attempts: reader.number("attempts"),
+            progress_percent: reader.number("progress_percent"),
+            rows_processed: reader.number("rows_processed"),
+            row_count: reader.number("row_count"),
+            output_bytes: reader.number("output_bytes"),
+            memory_high_water_bytes: reader.number("memory_high_water_bytes"),
+            unmatched_columns,
+            header_problems,
+            ignored_columns: text_list("ignored_columns"),
+            column_stats,
+            output_bucket: text("output_bucket"),
+            output_key: text("output_key"),
+            query_schema: reader.json("query_schema"),
+            query_schema_etag: text("query_schema_etag"),
+            column_profiles: reader.json("column_profiles"),
+            error_message: text("error_message"),
+            error_stage: text("error_stage"),
+            parent_job_id: text("parent_job_id"),
+            derived_sql: text("derived_sql"),
+        })
+    }
+}
+
+// The attributes and expression of a status update: `status` and `updated_at` set to now,
+// and `expires_at` pushed out to the job retention from now, merged with `extra_attrs`.
+// The status always comes from `status`, while an extra `updated_at` or `expires_at` is
+// kept so it can match another timestamp set in the same update. Names are sorted so the
+// same update always gives the same expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusUpdate {
+    pub expression: String,
+    pub names: HashMap<String, String>,
+    pub values: HashMap<String, AttributeValue>,
+}
+
+pub fn status_update(
+    status: JobStatus,
+    extra_attrs: HashMap<String, AttributeValue>,
+) -> StatusUpdate {
+    let mut attributes: BTreeMap<String, AttributeValue> = extra_attrs.into_iter().collect();
+    attributes.insert("status".to_string(), status.attribute());
+    attributes
+        .entry("updated_at".to_string())
+        .or_insert_with(|| AttributeValue::S(timestamp_now()));
+    attributes
+        .entry("expires_at".to_string())
+        .or_insert_with(|| Retention::Job.attribute(Utc::now()));
+
+    let mut assignments = Vec::with_capacity(attributes.len());
+    let mut names = HashMap::with_capacity(attributes.len());
+    let mut values = HashMap::with_capacity(attributes.len());
+    // Placeholders rather than the names themselves, as `status` is a reserved word
+    for (index, (name, value)) in attributes.into_iter().enumerate() {
+        assignments.push(format!("#a{} = :a{}", index, index));
+        names.insert(format!("#a{}", index), name);
+        values.insert(format!(":a{}", index), value);
+    }
+    StatusUpdate {
+        expression: format!("SET {}", assignments.join(", ")),
+        names,
+        values,
+    }
+}
+
+// Moves the job to `status`, setting `extra_attrs` in the same write. The write only
+// goes through when the job's current status may move to `status`; otherwise, as with a
+// late duplicate delivery finishing a job that already failed or was cancelled, it is
+// logged and skipped, as is an update for a job that no longer exists. Returns whether
+// the write went through, so callers can skip what only follows a real transition.
+pub async fn update_job_status(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    status: JobStatus,
+    extra_attrs: HashMap<String, AttributeValue>,
+) -> Result<bool, Error> {
+    transition_job(
+        dynamodb_client,
+        table_name,
+        job_id,
+        &status.predecessors(),
+        status,
+        extra_attrs,
+    )
+    .await
+}
+
+// The conditional write behind update_job_status, applied only while the job is in one of
+// `from`
+async fn transition_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    from: &[JobStatus],
+    status: JobStatus,
+    extra_attrs: HashMap<String, AttributeValue>,
+) -> Result<bool, Error> {
+    let pk = format!("JOB-{}", job_id);
+    let mut update = status_update(status, extra_attrs);
+
+    // `#current` rather than the update's own placeholder for status, which depends on
+    // the other attributes set
+    let mut placeholders = Vec::with_capacity(from.len());
+    for (index, predecessor) in from.iter().enumerate() {
+        placeholders.push(format!(":from{}", index));
+        update
+            .values
+            .insert(format!(":from{}", index), predecessor.attribute());
+    }
+    update
+        .names
+        .insert("#current".to_string(), "status".to_string());
+
+    info!(
+        job_id,
+        status = status.as_str(),
+        "Updating DynamoDB job status"
+    );
+
+    let result = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(update.expression)
+        .condition_expression(format!("#current IN ({})", placeholders.join(", ")))
+        .set_expression_attribute_names(Some(update.names))
+        .set_expression_attribute_values(Some(update.values))
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => {
+            info!(
+                job_id,
+                status = status.as_str(),
+                "Updated DynamoDB job status"
+            );
+            Ok(true)
+        }
+        Err(e) => match e.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(failed)) => {
+                let current = failed
+                    .item
+                    .as_ref()
+                    .and_then(|item| item.get("status"))
+                    .and_then(|v| v.as_s().ok());
+                match current {
+                    Some(current) => warn!(
+                        job_id,
+                        current = %current,
+                        status = status.as_str(),
+                        "Ignoring an illegal job status transition"
+                    ),
+                    None => warn!(
+                        job_id,
+                        status = status.as_str(),
+                        "Ignoring a status update for a job that doesn't exist"
+                    ),
+                }
+                Ok(false)
+            }
+            _ => {
+                let e = Error::dynamo("UpdateItem", e);
+                error!(job_id, error = %e, "Failed to update DynamoDB job status");
+                Err(e)
+            }
+        },
+    }
+}
+
+// Marks the job done and records where its output is, so the poller can hand out a
+// download link
+pub async fn update_job_status_to_success(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    row_count: u64,
+    output_bucket: &str,
+    output_key: &str,
+) -> Result<bool, Error> {
+    let now = AttributeValue::S(timestamp_now());
+    let extra_attrs = HashMap::from([
+        (
+            "row_count".to_string(),
+            AttributeValue::N(row_count.to_string()),
+        ),
+        (
+            "output_bucket".to_string(),
+            AttributeValue::S(output_bucket.to_string()),
+        ),
+        (
+            "output_key".to_string(),
+            AttributeValue::S(output_key.to_string()),
+        ),
+        (
+            "progress_percent".to_string(),
+            AttributeValue::N("100".to_string()),
+        ),
+        ("completed_at".to_string(), now.clone()),
+        ("updated_at".to_string(), now),
+    ]);
+    update_job_status(
+        dynamodb_client,
+        table_name,
+        job_id,
+        JobStatus::Success,
+        extra_attrs,
+    )
+    .await
+}
+
+// Why and at which stage an attempt failed, with the chain of causes behind it
+fn failure_attrs(
+    error: &ProcessingError,
+    rows_processed: u64,
+    now: &AttributeValue,
+) -> HashMap<String, AttributeValue> {
+    let error_chain = error
+        .chain()
+        .iter()
+        .map(|cause| AttributeValue::S(cause.clone()))
+        .collect();
+    HashMap::from([
+        (
+            "error_message".to_string(),
+            AttributeValue::S(error.summary()),
+        ),
+        (
+            "error_stage".to_string(),
+            AttributeValue::S(error.stage().to_string()),
+        ),
+        ("error_chain".to_string(), AttributeValue::L(error_chain)),
+        (
+            "rows_processed".to_string(),
+            AttributeValue::N(rows_processed.to_string()),
+        ),
+        ("updated_at".to_string(), now.clone()),
+    ])
+}
+
+// Records why and at which stage the job failed for good
+pub async fn update_job_status_to_failed(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    error: &ProcessingError,
+    rows_processed: u64,
+) -> Result<bool, Error> {
+    info!(job_id, stage = error.stage(), "Recording job failure");
+
+    let now = AttributeValue::S(timestamp_now());
+    let mut extra_attrs = failure_attrs(error, rows_processed, &now);
+    extra_attrs.insert("failed_at".to_string(), now.clone());
+    extra_attrs.insert("completed_at".to_string(), now);
+    update_job_status(
+        dynamodb_client,
+        table_name,
+        job_id,
+        JobStatus::Failed,
+        extra_attrs,
+    )
+    .await
+}
+
+// Records an attempt's failure and puts the job back to pending, as its message will be
+// retried. Failing the job here would stop the retry from finishing it. Only a job still
+// pending or processing is written, so a late attempt can't reopen one that has already
+// failed or finished.
+pub async fn record_job_retry(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    error: &ProcessingError,
+    rows_processed: u64,
+) -> Result<bool, Error> {
+    info!(job_id, stage = error.stage(), "Recording failed attempt");
+
+    let now = AttributeValue::S(timestamp_now());
+    transition_job(
+        dynamodb_client,
+        table_name,
+        job_id,
+        &[JobStatus::Pending, JobStatus::Processing],
+        JobStatus::Pending,
+        failure_attrs(error, rows_processed, &now),
+    )
+    .await
+}
+
+// How far a conversion has got, as reported to the poller while it runs
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JobProgress {
+    pub percent: f64,
+    pub rows_processed: u64,
+    pub bytes_read: u64,
+}
+
+// Records the conversion's progress and a heartbeat in one write. A pending job becomes
+// processing with its first report. Nothing is written once the job has left pending and
+// processing, so a report racing a cancel can't undo it.
+pub async fn update_job_progress(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    progress: &JobProgress,
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+    let now = timestamp_now();
+
+    let result = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET #status = :processing, progress_percent = :percent, \
+             rows_processed = :rows, bytes_read = :bytes, heartbeat_at = :now, \
+             updated_at = :now, expires_at = :expires_at",
+        )
+        .condition_expression("#status IN (:pending, :processing)")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":pending", JobStatus::Pending.attribute())
+        .expression_attribute_values(":processing", JobStatus::Processing.attribute())
+        .expression_attribute_values(
+            ":percent",
+            AttributeValue::N(format!("{:.1}", progress.percent.clamp(0.0, 100.0))),
+        )
+        .expression_attribute_values(
+            ":rows",
+            AttributeValue::N(progress.rows_processed.to_string()),
+        )
+        .expression_attribute_values(":bytes", AttributeValue::N(progress.bytes_read.to_string()))
+        .expression_attribute_values(":now", AttributeValue::S(now))
+        .expression_attribute_values(":expires_at", Retention::Job.attribute(Utc::now()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => match e.as_service_error() {
+            Some(service_error) if service_error.is_conditional_check_failed_exception() => {
+                info!(
+                    job_id,
+                    "Skipping progress for a job that is no longer running"
+                );
+                Ok(())
+            }
+            _ => Err(Error::dynamo("UpdateItem", e)),
+        },
+    }
+}
+
+pub async fn record_column_report(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    report: &ColumnReport,
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let unmatched = report
+        .unmatched_columns
+        .iter()
+        .map(|unmatched| {
+            let mut entry = HashMap::new();
+            entry.insert(
+                "column".to_string(),
+                AttributeValue::S(unmatched.column.clone()),
+            );
+            entry.insert(
+                "suggestion".to_string(),
+                match &unmatched.suggestion {
+                    Some(suggestion) => AttributeValue::S(suggestion.clone()),
+                    None => AttributeValue::Null(true),
+                },
+            );
+            AttributeValue::M(entry)
+        })
+        .collect();
+
+    let ignored = report
+        .ignored_columns
+        .iter()
+        .map(|header| AttributeValue::S(header.clone()))
+        .collect();
+
+    let header_problems = report
+        .header_problems
+        .iter()
+        .map(|validation| {
+            let mut entry = HashMap::new();
+            entry.insert(
+                "header".to_string(),
+                AttributeValue::S(validation.header.clone()),
+            );
+            entry.insert(
+                "normalized".to_string(),
+                AttributeValue::S(validation.normalized.clone()),
+            );
+            entry.insert(
+                "problems".to_string(),
+                AttributeValue::L(
+                    validation
+                        .problems
+                        .iter()
+                        .map(|problem| AttributeValue::S(problem.as_str().to_string()))
+                        .collect(),
+                ),
+            );
+            AttributeValue::M(entry)
+        })
+        .collect();
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET unmatched_columns = :unmatched, ignored_columns = :ignored, \
+             header_problems = :header_problems, updated_at = :now",
+        )
+        .expression_attribute_values(":unmatched", AttributeValue::L(unmatched))
+        .expression_attribute_values(":ignored", AttributeValue::L(ignored))
+        .expression_attribute_values(":header_problems", AttributeValue::L(header_problems))
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+// Overwrites the job's schema map with the columns actually written to the parquet file
+pub async fn record_realized_schema(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    column_definitions: &[ColumnDefinition],
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let schema_map: HashMap<String, AttributeValue> = column_definitions
+        .iter()
+        .map(|col| {
+            (
+                col.column.clone(),
+                AttributeValue::S(col.column_type.to_string()),
+            )
+        })
+        .collect();
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression("SET #schema = :schema, updated_at = :now")
+        .expression_attribute_names("#schema", "schema")
+        .expression_attribute_values(":schema", AttributeValue::M(schema_map))
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+// Column profiles for the SQL prompt, computed on a job's first query and reused while the
+// parquet object at `key` still has `etag`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnProfiles {
+    pub key: String,
+    pub etag: String,
+    pub columns: Vec<ColumnProfile>,
+}
+
+// Keeps a wide table's profiles from crowding the job item towards DynamoDB's 400 KB limit
+const MAX_STORED_COLUMN_PROFILES_BYTES: usize = 64 * 1024;
+
+// Stored as a JSON string. Profiles over the size cap are skipped and recomputed by later
+// queries instead.
+pub async fn record_column_profiles(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    profiles: &ColumnProfiles,
+) -> Result<(), Error> {
+    let json = serde_json::to_string(profiles).map_err(|e| Error::DynamoDb {
+        operation: "UpdateItem",
+        message: format!("could not serialize column profiles: {}", e),
+        retryable: false,
+    })?;
+    if json.len() > MAX_STORED_COLUMN_PROFILES_BYTES {
+        warn!(
+            job_id,
+            bytes = json.len(),
+            "Column profiles too large to store"
+        );
+        return Ok(());
+    }
+
+    let pk = format!("JOB-{}", job_id);
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression("SET column_profiles = :profiles")
+        .expression_attribute_values(":profiles", AttributeValue::S(json))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+// Stores the output's columns as JSON, alongside the ETag of the object they describe so a
+// replaced file isn't described by a stale schema
+pub async fn record_query_schema(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    query_schema: &[ParquetColumn],
+    etag: &str,
+) -> Result<(), Error> {
+    let json = serde_json::to_string(query_schema).map_err(|e| Error::DynamoDb {
+        operation: "UpdateItem",
+        message: format!("could not serialize query schema: {}", e),
+        retryable: false,
+    })?;
+    let pk = format!("JOB-{}", job_id);
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression("SET query_schema = :schema, query_schema_etag = :etag")
+        .expression_attribute_values(":schema", AttributeValue::S(json))
+        .expression_attribute_values(":etag", AttributeValue::S(etag.to_string()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+pub async fn record_column_stats(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    column_definitions: &[ColumnDefinition],
+    column_stats: &[ColumnStats],
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let stats_map: HashMap<String, AttributeValue> = column_definitions
+        .iter()
+        .zip(column_stats)
+        .map(|(col, stats)| {
+            let mut entry = HashMap::new();
+            entry.insert(
+                "empty".to_string(),
+                AttributeValue::N(stats.empty.to_string()),
+            );
+            entry.insert(
+                "coercion_failures".to_string(),
+                AttributeValue::N(stats.coercion_failures.to_string()),
+            );
+            entry.insert(
+                "written".to_string(),
+                AttributeValue::N(stats.written.to_string()),
+            );
+            (col.column.clone(), AttributeValue::M(entry))
+        })
+        .collect();
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression("SET column_stats = :stats, updated_at = :now")
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .expression_attribute_values(":stats", AttributeValue::M(stats_map))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+pub async fn record_memory_high_water(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    high_water_bytes: u64,
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression("SET memory_high_water_bytes = :bytes, updated_at = :now")
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .expression_attribute_values(":bytes", AttributeValue::N(high_water_bytes.to_string()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+// Records how the completion notification went. A later successful delivery, e.g. after a
+// retried job succeeds, clears the error left by an earlier attempt.
+pub async fn record_notification_outcome(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    notification_error: Option<&str>,
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+    let now = AttributeValue::S(timestamp_now());
+
+    let request = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()));
+
+    let request = match notification_error {
+        Some(message) => request
+            .update_expression(
+                "SET notification_error = :error, notification_failed_at = :now, \
+                 updated_at = :now",
+            )
+            .expression_attribute_values(":error", AttributeValue::S(message.to_string()))
+            .expression_attribute_values(":now", now),
+        None => request
+            .update_expression(
+                "SET notified_at = :now, updated_at = :now \
+                 REMOVE notification_error, notification_failed_at",
+            )
+            .expression_attribute_values(":now", now),
+    };
+
+    request
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+pub async fn get_job_by_id(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<Option<Job>, Error> {
+    let pk_value = format!("JOB-{}", job_id);
+
+    let request = dynamodb_client
+        .get_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk_value))
+        .key("serviceId", AttributeValue::S(job_id.to_string()));
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("GetItem", e))?;
+
+    match response.item {
+        Some(item) => match Job::from_dynamodb_item(item) {
+            Ok(job) => Ok(Some(job)),
+            Err(e) => {
+                warn!(job_id, error = %e, "Failed to parse job from DynamoDB item");
+                Ok(None)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+// DynamoDB's limit on keys in a single BatchGetItem call
+pub const MAX_BATCH_GET_KEYS: usize = 25;
+
+// Keys the table was too busy to return are asked for again with a growing delay, as AWS
+// recommends; anything still missing after this many rounds fails the whole read
+const BATCH_GET_ATTEMPTS: u32 = 5;
+const BATCH_GET_RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_millis(50);
+
+// Reads the job items for `job_ids`, keyed by job_id. IDs with no job item are simply
+// absent from the map, and repeated IDs are only read once.
+pub async fn batch_get_jobs(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_ids: &[String],
+) -> Result<HashMap<String, HashMap<String, AttributeValue>>, Error> {
+    // BatchGetItem rejects a request that names the same key twice
+    let mut unique_ids: Vec<&String> = Vec::with_capacity(job_ids.len());
+    for job_id in job_ids {
+        if !unique_ids.contains(&job_id) {
+            unique_ids.push(job_id);
+        }
+    }
+
+    let mut items = HashMap::with_capacity(unique_ids.len());
+
+    for chunk in unique_ids.chunks(MAX_BATCH_GET_KEYS) {
+        let keys = chunk
+            .iter()
+            .map(|job_id| {
+                HashMap::from([
+                    (
+                        "service".to_string(),
+                        AttributeValue::S(format!("JOB-{}", job_id)),
+                    ),
+                    (
+                        "serviceId".to_string(),
+                        AttributeValue::S(job_id.to_string()),
+                    ),
+                ])
+            })
+            .collect();
+        let mut pending = KeysAndAttributes::builder()
+            .set_keys(Some(keys))
+            .build()
+            .map_err(|e| Error::dynamo_response("BatchGetItem", e.to_string()))?;
+
+        let mut attempt = 1;
+        loop {
+            let response = dynamodb_client
+                .batch_get_item()
+                .request_items(table_name, pending)
+                .send()
+                .await
+                .map_err(|e| Error::dynamo("BatchGetItem", e))?;
+
+            for item in response
+                .responses
+                .and_then(|mut responses| responses.remove(table_name))
+                .unwrap_or_default()
+            {
+                if let Some(job_id) = item.get("serviceId").and_then(|v| v.as_s().ok()) {
+                    items.insert(job_id.clone(), item);
+                }
+            }
+
+            pending = match response
+                .unprocessed_keys
+                .and_then(|mut unprocessed| unprocessed.remove(table_name))
+                .filter(|unprocessed| !unprocessed.keys().is_empty())
+            {
+                Some(unprocessed) => unprocessed,
+                None => break,
+            };
+
+            if attempt >= BATCH_GET_ATTEMPTS {
+                return Err(Error::dynamo_response(
+                    "BatchGetItem",
+                    format!(
+                        "{} key(s) still unprocessed after {} attempts",
+                        pending.keys().len(),
+                        attempt
+                    ),
+                ));
+            }
+
+            let delay = BATCH_GET_RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+            warn!(
+                attempt,
+                unprocessed = pending.keys().len(),
+                delay_ms = delay.as_millis() as u64,
+                "BatchGetItem left keys unprocessed, retrying"
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    Ok(items)
+}
+
+// A reserved job whose conversion is never requested is swept by the table's TTL
+const UPLOAD_RESERVATION_TTL_SECONDS: i64 = 24 * 60 * 60;
+
+// Scheduled jobs carry a constant `schedule_bucket` so one GSI query, sorted by
+// `start_after`, finds every job that is due. The attribute is removed once a job is
+// dispatched, which also takes it out of the index.
+const SCHEDULE_INDEX: &str = "scheduleIndex";
+const SCHEDULE_BUCKET: &str = "SCHEDULED";
+
+// Reads just the status so the processor can poll for cancellation between batches. A
+// consistent read makes a cancel request visible on the very next check.
+pub async fn get_job_status(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<Option<JobStatus>, Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let response = dynamodb_client
+        .get_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .projection_expression("#status")
+        .expression_attribute_names("#status", "status")
+        .consistent_read(true)
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("GetItem", e))?;
+
+    response
+        .item
+        .as_ref()
+        .and_then(|item| item.get("status"))
+        .and_then(|v| v.as_s().ok())
+        .map(|status| {
+            status
+                .parse()
+                .map_err(|e: UnknownJobStatus| Error::dynamo_response("GetItem", e.to_string()))
+        })
+        .transpose()
+}
+
+// Marks a pending, processing or scheduled job as cancelled. Returns false when the job
+// doesn't exist or has already finished, in which case nothing is changed.
+pub async fn cancel_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<bool, Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    info!(
+        job_id,
+        status = JobStatus::Cancelled.as_str(),
+        "Updating DynamoDB job status"
+    );
+
+    let result = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET #status = :cancelled, cancelled_at = :now, updated_at = :now, \
+             expires_at = :expires_at REMOVE schedule_bucket",
+        )
+        .condition_expression("#status IN (:pending, :processing, :scheduled)")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":cancelled", JobStatus::Cancelled.attribute())
+        .expression_attribute_values(":pending", JobStatus::Pending.attribute())
+        .expression_attribute_values(":processing", JobStatus::Processing.attribute())
+        .expression_attribute_values(":scheduled", JobStatus::Scheduled.attribute())
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .expression_attribute_values(":expires_at", Retention::Job.attribute(Utc::now()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) => match e.as_service_error() {
+            Some(service_error) if service_error.is_conditional_check_failed_exception() => {
+                Ok(false)
+            }
+            _ => {
+                let e = Error::dynamo("UpdateItem", e);
+                error!(job_id, error = %e, "Failed to cancel job");
+                Err(e)
+            }
+        },
+    }
+}
+
+// UTC with whole seconds, so timestamps sort the same as strings and as times
+fn schedule_timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// Puts a job created with status `scheduled` into the schedule index. The queued form of
+// the request is kept on the item for the dispatcher to send when the job is due.
+pub async fn schedule_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    start_after: DateTime<Utc>,
+    request_body: &str,
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET start_after = :start_after, schedule_bucket = :bucket, \
+             scheduled_request = :request, updated_at = :now, expires_at = :expires_at",
+        )
+        // Counted from the start rather than now, so a job scheduled far ahead is still
+        // there when it is due
+        .expression_attribute_values(":expires_at", Retention::Job.attribute(start_after))
+        .expression_attribute_values(
+            ":start_after",
+            AttributeValue::S(schedule_timestamp(start_after)),
+        )
+        .expression_attribute_values(":bucket", AttributeValue::S(SCHEDULE_BUCKET.to_string()))
+        .expression_attribute_values(":request", AttributeValue::S(request_body.to_string()))
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    info!(
+        job_id,
+        start_after = %schedule_timestamp(start_after),
+        "Job scheduled"
+    );
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledJob {
+    pub job_id: String,
+    pub start_after: DateTime<Utc>,
+    pub request_body: String,
+    pub path: ProcessingPath,
+}
+
+// Every scheduled job whose start time is at or before `due_before`, earliest first
+pub async fn get_due_scheduled_jobs(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    due_before: DateTime<Utc>,
+) -> Result<Vec<ScheduledJob>, Error> {
+    let mut jobs = Vec::new();
+    let mut exclusive_start_key = None;
+
+    loop {
+        let response = dynamodb_client
+            .query()
+            .table_name(table_name)
+            .index_name(SCHEDULE_INDEX)
+            .key_condition_expression("schedule_bucket = :bucket AND start_after <= :due")
+            .expression_attribute_values(":bucket", AttributeValue::S(SCHEDULE_BUCKET.to_string()))
+            .expression_attribute_values(":due", AttributeValue::S(schedule_timestamp(due_before)))
+            .set_exclusive_start_key(exclusive_start_key)
+            .send()
+            .await
+            .map_err(|e| Error::dynamo("Query", e))?;
+
+        for item in response.items() {
+            match scheduled_job_from_item(item) {
+                Some(job) => jobs.push(job),
+                None => warn!(
+                    service = ?item.get("service").and_then(|v| v.as_s().ok()),
+                    "Skipping scheduled job item with missing fields"
+                ),
+            }
+        }
+
+        exclusive_start_key = response.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(jobs)
+}
+
+fn scheduled_job_from_item(item: &HashMap<String, AttributeValue>) -> Option<ScheduledJob> {
+    let text = |name: &str| item.get(name).and_then(|v| v.as_s().ok());
+
+    Some(ScheduledJob {
+        job_id: text("serviceId")?.clone(),
+        start_after: DateTime::parse_from_rfc3339(text("start_after")?)
+            .ok()?
+            .with_timezone(&Utc),
+        request_body: text("scheduled_request")?.clone(),
+        path: text("processing_path")
+            .and_then(|path| ProcessingPath::parse(path))
+            .unwrap_or(ProcessingPath::Standard),
+    })
+}
+
+// Moves a due job from scheduled to pending and out of the schedule index. Returns false
+// when the job is no longer scheduled, e.g. it was cancelled or another dispatcher got
+// there first.
+pub async fn claim_scheduled_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<bool, Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let result = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET #status = :pending, updated_at = :now, expires_at = :expires_at \
+             REMOVE schedule_bucket",
+        )
+        .condition_expression("#status = :scheduled")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":pending", JobStatus::Pending.attribute())
+        .expression_attribute_values(":scheduled", JobStatus::Scheduled.attribute())
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .expression_attribute_values(":expires_at", Retention::Job.attribute(Utc::now()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) => match e.as_service_error() {
+            Some(service_error) if service_error.is_conditional_check_failed_exception() => {
+                Ok(false)
+            }
+            _ => Err(Error::dynamo("UpdateItem", e)),
+        },
+    }
+}
+
+// Puts a claimed job back in the schedule after its message couldn't be sent, so the next
+// dispatcher run tries again
+pub async fn reschedule_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET #status = :scheduled, schedule_bucket = :bucket, updated_at = :now, \
+             expires_at = :expires_at",
+        )
+        .condition_expression("#status = :pending")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":pending", JobStatus::Pending.attribute())
+        .expression_attribute_values(":scheduled", JobStatus::Scheduled.attribute())
+        .expression_attribute_values(":bucket", AttributeValue::S(SCHEDULE_BUCKET.to_string()))
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .expression_attribute_values(":expires_at", Retention::Job.attribute(Utc::now()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    Ok(())
+}
+
+// Creates the placeholder item for a job whose CSV hasn't been uploaded yet, so the job_id
+// belongs to `created_by` from the moment the upload URL is handed out
+pub async fn reserve_upload_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    created_by: &str,
+) -> Result<(), Error> {
+    let now = timestamp_now();
+    let expires_at = Utc::now().timestamp() + UPLOAD_RESERVATION_TTL_SECONDS;
+
+    dynamodb_client
+        .put_item()
+        .table_name(table_name)
+        .item("service", AttributeValue::S(format!("JOB-{}", job_id)))
+        .item("serviceId", AttributeValue::S(job_id.to_string()))
+        .item("entity", AttributeValue::S(JOB_ENTITY.to_string()))
+        .item("status", JobStatus::AwaitingUpload.attribute())
+        .item("context", AttributeValue::S(String::new()))
+        .item("created_by", AttributeValue::S(created_by.to_string()))
+        .item("created_at", AttributeValue::S(now.clone()))
+        .item("updated_at", AttributeValue::S(now))
+        .item("expires_at", AttributeValue::N(expires_at.to_string()))
+        .condition_expression("attribute_not_exists(service)")
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("PutItem", e))?;
+
+    info!(job_id, "Reserved job for upload");
+    Ok(())
+}
+
+// A finished job whose parquet was written from a query over another job's output rather
+// than converted from a CSV
+#[derive(Debug, Clone)]
+pub struct DerivedJob {
+    pub job_id: String,
+    pub parent_job_id: String,
+    pub sql: String,
+    pub context: String,
+    pub created_by: String,
+    pub output_bucket: String,
+    pub output_key: String,
+    pub row_count: u64,
+    pub output_bytes: u64,
+}
+
+// Creates the derived job's item already succeeded, as its output is written before the
+// item exists
+pub async fn create_derived_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job: &DerivedJob,
+) -> Result<(), Error> {
+    let now = timestamp_now();
+    dynamodb_client
+        .put_item()
+        .table_name(table_name)
+        .item("service", AttributeValue::S(format!("JOB-{}", job.job_id)))
+        .item("serviceId", AttributeValue::S(job.job_id.clone()))
+        .item("entity", AttributeValue::S(JOB_ENTITY.to_string()))
+        .item("status", JobStatus::Success.attribute())
+        .item("context", AttributeValue::S(job.context.clone()))
+        .item("created_by", AttributeValue::S(job.created_by.clone()))
+        .item(
+            "parent_job_id",
+            AttributeValue::S(job.parent_job_id.clone()),
+        )
+        .item("derived_sql", AttributeValue::S(job.sql.clone()))
+        .item(
+            "output_bucket",
+            AttributeValue::S(job.output_bucket.clone()),
+        )
+        .item("output_key", AttributeValue::S(job.output_key.clone()))
+        .item("row_count", AttributeValue::N(job.row_count.to_string()))
+        .item(
+            "output_bytes",
+            AttributeValue::N(job.output_bytes.to_string()),
+        )
+        .item("created_at", AttributeValue::S(now.clone()))
+        .item("completed_at", AttributeValue::S(now.clone()))
+        .item("updated_at", AttributeValue::S(now))
+        .item("expires_at", Retention::DerivedJob.attribute(Utc::now()))
+        .condition_expression("attribute_not_exists(service)")
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("PutItem", e))?;
+
+    info!(
+        job_id = %job.job_id,
+        parent_job_id = %job.parent_job_id,
+        rows = job.row_count,
+        "Created derived job"
+    );
+    Ok(())
+}
+
+// Replaces the job's labels, removing them when `labels` is empty. Returns false when
+// there is no such job.
+pub async fn update_job_labels(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    labels: &HashMap<String, String>,
+) -> Result<bool, Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let mut request = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        // Without this an unknown job_id would create a stray item holding only labels
+        .condition_expression("attribute_exists(service)")
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()));
+    request = if labels.is_empty() {
+        request.update_expression("SET updated_at = :now REMOVE labels")
+    } else {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| (key.clone(), AttributeValue::S(value.clone())))
+            .collect();
+        request
+            .update_expression("SET labels = :labels, updated_at = :now")
+            .expression_attribute_values(":labels", AttributeValue::M(labels))
+    };
+
+    match request.send().await {
+        Ok(_) => {
+            info!(job_id, labels = labels.len(), "Updated job labels");
+            Ok(true)
+        }
+        Err(e) => match e.as_service_error() {
+            Some(UpdateItemError::ConditionalCheckFailedException(_)) => Ok(false),
+            _ => Err(Error::dynamo("UpdateItem", e)),
+        },
+    }
+}
+
+// DynamoDB's limit on requests in a single BatchWriteItem call
+const MAX_BATCH_WRITE_ITEMS: usize = 25;
+
+// What delete_job removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DeletedJobItems {
+    // False when there was no job item left to delete
+    pub job: bool,
+    pub query_audit: u64,
+    pub saved_queries: u64,
+}
+
+// Deletes every item in the partition `pk`, returning how many there were
+async fn delete_partition(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    pk: &str,
+) -> Result<u64, Error> {
+    let mut deleted = 0;
+    let mut start_key = None;
+
+    loop {
+        let response = dynamodb_client
+            .query()
+            .table_name(table_name)
+            .key_condition_expression("service = :pk")
+            .expression_attribute_values(":pk", AttributeValue::S(pk.to_string()))
+            .projection_expression("service, serviceId")
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await
+            .map_err(|e| Error::dynamo("Query", e))?;
+
+        let keys = response.items.unwrap_or_default();
+        for chunk in keys.chunks(MAX_BATCH_WRITE_ITEMS) {
+            let requests = chunk
+                .iter()
+                .map(|key| {
+                    DeleteRequest::builder()
+                        .set_key(Some(key.clone()))
+                        .build()
+                        .map(|delete| WriteRequest::builder().delete_request(delete).build())
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::dynamo_response("BatchWriteItem", e.to_string()))?;
+            batch_write(dynamodb_client, table_name, requests).await?;
+            deleted += chunk.len() as u64;
+        }
+
+        start_key = response.last_evaluated_key;
+        if start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(deleted)
+}
+
+// Sends one BatchWriteItem, retrying whatever the table was too busy to apply the same way
+// batch_get_jobs does
+async fn batch_write(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    mut pending: Vec<WriteRequest>,
+) -> Result<(), Error> {
+    let mut attempt = 1;
+    loop {
+        let response = dynamodb_client
+            .batch_write_item()
+            .request_items(table_name, pending)
+            .send()
+            .await
+            .map_err(|e| Error::dynamo("BatchWriteItem", e))?;
+
+        pending = match response
+            .unprocessed_items
+            .and_then(|mut unprocessed| unprocessed.remove(table_name))
+            .filter(|unprocessed| !unprocessed.is_empty())
+        {
+            Some(unprocessed) => unprocessed,
+            None => return Ok(()),
+        };
+
+        if attempt >= BATCH_GET_ATTEMPTS {
+            return Err(Error::dynamo_response(
+                "BatchWriteItem",
+                format!(
+                    "{} request(s) still unprocessed after {} attempts",
+                    pending.len(),
+                    attempt
+                ),
+            ));
+        }
+
+        let delay = BATCH_GET_RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+        warn!(
+            attempt,
+            unprocessed = pending.len(),
+            delay_ms = delay.as_millis() as u64,
+            "BatchWriteItem left requests unprocessed, retrying"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Removes a job item along with its query audit history and saved queries. The job item
+// goes last, so a delete that fails partway can be repeated and finish the job off.
+// Conversation turns are keyed by conversation rather than job and are left to their TTL.
+pub async fn delete_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<DeletedJobItems, Error> {
+    let query_audit =
+        delete_partition(dynamodb_client, table_name, &format!("QUERY-{}", job_id)).await?;
+    let saved_queries = delete_partition(
+        dynamodb_client,
+        table_name,
+        &format!("SAVED-QUERY-{}", job_id),
+    )
+    .await?;
+
+    let pk = format!("JOB-{}", job_id);
+    let response = dynamodb_client
+        .delete_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .return_values(ReturnValue::AllOld)
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("DeleteItem", e))?;
+
+    let deleted = DeletedJobItems {
+        job: response.attributes.is_some(),
+        query_audit,
+        saved_queries,
+    };
+    info!(
+        job_id,
+        job = deleted.job,
+        query_audit,
+        saved_queries,
+        "Deleted job"
+    );
+    Ok(deleted)
+}
+
+// How long a submission's idempotency key keeps pointing at its job
+const IDEMPOTENCY_TTL_SECONDS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotencyClaim {
+    // This request owns the key and should go on to create the job
+    Claimed,
+    // An earlier request already used the key for this job
+    Existing(String),
+}
+
+// Ties an idempotency key to a job_id with a conditional put, so when two requests race
+// on the same key exactly one claims it and the other gets the winner's job_id back. An
+// expired mapping the TTL sweeper hasn't removed yet counts as free.
+pub async fn claim_idempotency_key(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    key: &str,
+    job_id: &str,
+) -> Result<IdempotencyClaim, Error> {
+    let pk = format!("IDEMPOTENCY-{}", key);
+    let now = Utc::now().timestamp();
+
+    let result = dynamodb_client
+        .put_item()
+        .table_name(table_name)
+        .item("service", AttributeValue::S(pk.clone()))
+        .item("serviceId", AttributeValue::S(key.to_string()))
+        .item("job_id", AttributeValue::S(job_id.to_string()))
+        .item(
+            "expires_at",
+            AttributeValue::N((now + IDEMPOTENCY_TTL_SECONDS).to_string()),
+        )
+        .condition_expression("attribute_not_exists(service) OR expires_at < :now")
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => return Ok(IdempotencyClaim::Claimed),
+        Err(e) => match e.as_service_error() {
+            Some(service_error) if service_error.is_conditional_check_failed_exception() => {}
+            _ => return Err(Error::dynamo("PutItem", e)),
+        },
+    }
+
+    // Consistent read so the winner's mapping is visible the moment its put succeeded
+    let response = dynamodb_client
+        .get_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(key.to_string()))
+        .projection_expression("job_id")
+        .consistent_read(true)
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("GetItem", e))?;
+
+    let existing = response
+        .item
+        .as_ref()
+        .and_then(|item| item.get("job_id"))
+        .and_then(|v| v.as_s().ok())
+        .cloned()
+        .ok_or_else(|| {
+            Error::dynamo_response("GetItem", "idempotency mapping disappeared after the put")
+        })?;
+
+    info!(job_id = %existing, "Idempotency key already used");
+    Ok(IdempotencyClaim::Existing(existing))
+}
+
+// Frees a key whose submission was rejected, so a corrected retry isn't answered with a
+// job that was never created
+pub async fn release_idempotency_key(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    key: &str,
+) -> Result<(), Error> {
+    dynamodb_client
+        .delete_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(format!("IDEMPOTENCY-{}", key)))
+        .key("serviceId", AttributeValue::S(key.to_string()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("DeleteItem", e))?;
+
+    Ok(())
+}
+
+// Weight of the newest job in the rolling throughput figure
+const THROUGHPUT_EMA_ALPHA: f64 = 0.2;
+
+// Attempts at the read-compute-write cycle before a sample is dropped; losing one sample
+// to a burst of concurrent jobs only makes the average slightly staler
+const THROUGHPUT_UPDATE_ATTEMPTS: u32 = 3;
+
+// Exponential moving average of conversion throughput, seeded with the first sample
+pub fn throughput_ema(previous: Option<f64>, sample: f64) -> f64 {
+    match previous {
+        Some(previous) => THROUGHPUT_EMA_ALPHA * sample + (1.0 - THROUGHPUT_EMA_ALPHA) * previous,
+        None => sample,
+    }
+}
+
+// The rolling MB/s figure for one processing path, or None before any job has finished on it
+pub async fn get_throughput(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    path: ProcessingPath,
+) -> Result<Option<f64>, Error> {
+    let response = dynamodb_client
+        .get_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S("THROUGHPUT".to_string()))
+        .key("serviceId", AttributeValue::S(path.as_str().to_string()))
+        .projection_expression("mb_per_second")
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("GetItem", e))?;
+
+    Ok(response
+        .item
+        .as_ref()
+        .and_then(|item| item.get("mb_per_second"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|mb_per_second| mb_per_second.parse::<f64>().ok()))
+}
+
+// Folds one job's throughput into the rolling average. DynamoDB can't multiply in an update
+// expression, so the new average is computed here and written on condition that the stored
+// one hasn't changed since it was read; a concurrent writer makes us re-read and try again.
+pub async fn record_throughput(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    path: ProcessingPath,
+    job_id: &str,
+    mb_per_second: f64,
+) -> Result<(), Error> {
+    for _ in 0..THROUGHPUT_UPDATE_ATTEMPTS {
+        let response = dynamodb_client
+            .get_item()
+            .table_name(table_name)
+            .key("service", AttributeValue::S("THROUGHPUT".to_string()))
+            .key("serviceId", AttributeValue::S(path.as_str().to_string()))
+            .projection_expression("mb_per_second")
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| Error::dynamo("GetItem", e))?;
+
+        // Compared as the stored string so the condition can't miss on float formatting
+        let previous = response
+            .item
+            .as_ref()
+            .and_then(|item| item.get("mb_per_second"))
+            .and_then(|v| v.as_n().ok())
+            .cloned();
+        let average = throughput_ema(
+            previous
+                .as_ref()
+                .and_then(|previous| previous.parse::<f64>().ok()),
+            mb_per_second,
+        );
+
+        let mut request = dynamodb_client
+            .update_item()
+            .table_name(table_name)
+            .key("service", AttributeValue::S("THROUGHPUT".to_string()))
+            .key("serviceId", AttributeValue::S(path.as_str().to_string()))
+            .update_expression("SET mb_per_second = :average, updated_at = :now ADD samples :one")
+            .expression_attribute_values(":average", AttributeValue::N(average.to_string()))
+            .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()));
+        request = match previous {
+            Some(previous) => request
+                .condition_expression("mb_per_second = :previous")
+                .expression_attribute_values(":previous", AttributeValue::N(previous)),
+            None => request.condition_expression("attribute_not_exists(mb_per_second)"),
+        };
+
+        match request.send().await {
+            Ok(_) => {
+                info!(
+                    job_id,
+                    path = path.as_str(),
+                    sample_mb_per_second = mb_per_second,
+                    average_mb_per_second = average,
+                    "Throughput average updated"
+                );
+                return Ok(());
+            }
+            Err(e) => match e.as_service_error() {
+                Some(service_error) if service_error.is_conditional_check_failed_exception() => {
+                    continue;
+                }
+                _ => return Err(Error::dynamo("UpdateItem", e)),
+            },
+        }
+    }
+
+    warn!(
+        job_id,
+        path = path.as_str(),
+        "Throughput average kept changing underneath us, dropping this sample"
+    );
+    Ok(())
+}
+
+// How far a checkpointed conversion got: the rows it has uploaded to the output's
+// multipart upload, and what it needs to carry on with that upload
+#[derive(Debug, Clone, Default)]
+pub struct ConversionCheckpoint {
+    // Byte offset in the source CSV of the first row not yet uploaded
+    pub byte_offset: u64,
+    pub rows_written: u64,
+    pub upload_id: String,
+    pub parts: Vec<UploadedPart>,
+    // Bytes of the output uploaded so far, and how many row groups they hold
+    pub bytes_written: u64,
+    pub row_groups: usize,
+    // Value counts of the uploaded rows, in column order, so a resumed execution reports
+    // them for the whole file rather than only the rows it read itself
+    pub column_stats: Vec<ColumnStats>,
+}
+
+// Also records which attempt made the progress, copied from the item's attempt counter as
+// the lease holding the job last bumped it
+pub async fn save_job_checkpoint(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    checkpoint: &ConversionCheckpoint,
+) -> Result<(), Error> {
+    let column_stats =
+        serde_json::to_string(&checkpoint.column_stats).map_err(|e| Error::DynamoDb {
+            operation: "UpdateItem",
+            message: format!("could not serialize checkpoint column stats: {}", e),
+            retryable: false,
+        })?;
+    let pk = format!("JOB-{}", job_id);
+
+    let parts = checkpoint
+        .parts
+        .iter()
+        .map(|part| {
+            AttributeValue::M(HashMap::from([
+                (
+                    "number".to_string(),
+                    AttributeValue::N(part.number.to_string()),
+                ),
+                ("etag".to_string(), AttributeValue::S(part.etag.clone())),
+            ]))
+        })
+        .collect();
+
+    dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET checkpoint_offset = :offset, checkpoint_rows = :rows, \
+             checkpoint_upload_id = :upload_id, checkpoint_parts = :parts, \
+             checkpoint_bytes = :bytes, checkpoint_row_groups = :row_groups, \
+             checkpoint_column_stats = :column_stats, checkpoint_attempt = attempts, \
+             updated_at = :now",
+        )
+        .expression_attribute_values(
+            ":offset",
+            AttributeValue::N(checkpoint.byte_offset.to_string()),
+        )
+        .expression_attribute_values(
+            ":rows",
+            AttributeValue::N(checkpoint.rows_written.to_string()),
+        )
+        .expression_attribute_values(
+            ":upload_id",
+            AttributeValue::S(checkpoint.upload_id.clone()),
+        )
+        .expression_attribute_values(":parts", AttributeValue::L(parts))
+        .expression_attribute_values(
+            ":bytes",
+            AttributeValue::N(checkpoint.bytes_written.to_string()),
+        )
+        .expression_attribute_values(
+            ":row_groups",
+            AttributeValue::N(checkpoint.row_groups.to_string()),
+        )
+        .expression_attribute_values(":column_stats", AttributeValue::S(column_stats))
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("UpdateItem", e))?;
+
+    info!(
+        job_id,
+        byte_offset = checkpoint.byte_offset,
+        rows = checkpoint.rows_written,
+        parts = checkpoint.parts.len(),
+        "Checkpoint saved"
+    );
+
+    Ok(())
+}
+
+pub async fn get_job_checkpoint(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<Option<ConversionCheckpoint>, Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let response = dynamodb_client
+        .get_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .projection_expression(
+            "checkpoint_offset, checkpoint_rows, checkpoint_upload_id, checkpoint_parts, \
+             checkpoint_bytes, checkpoint_row_groups, checkpoint_column_stats",
+        )
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("GetItem", e))?;
+
+    let item = match response.item {
+        Some(item) => item,
+        None => return Ok(None),
+    };
+
+    let byte_offset = match item.get("checkpoint_offset").and_then(|v| v.as_n().ok()) {
+        Some(offset) => offset.parse::<u64>().map_err(|e| {
+            Error::dynamo_response("GetItem", format!("invalid checkpoint_offset: {}", e))
+        })?,
+        None => return Ok(None),
+    };
+
+    let number = |name: &str| {
+        item.get(name)
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+
+    let upload_id = item
+        .get("checkpoint_upload_id")
+        .and_then(|v| v.as_s().ok())
+        .cloned()
+        .unwrap_or_default();
+
+    let parts = item
+        .get("checkpoint_parts")
+        .and_then(|v| v.as_l().ok())
+        .map(|parts| {
+            parts
+                .iter()
+                .filter_map(|part| {
+                    let part = part.as_m().ok()?;
+                    Some(UploadedPart {
+                        number: part.get("number")?.as_n().ok()?.parse().ok()?,
+                        etag: part.get("etag")?.as_s().ok()?.clone(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Some(ConversionCheckpoint {
+        byte_offset,
+        rows_written: number("checkpoint_rows"),
+        upload_id,
+        parts,
+        bytes_written: number("checkpoint_bytes"),
+        row_groups: number("checkpoint_row_groups") as usize,
+        column_stats: item
+            .get("checkpoint_column_stats")
+            .and_then(|v| serde_json::from_str(v.as_s().ok()?).ok())
+            .unwrap_or_default(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::column_matching::build_column_report;
+    use crate::dynamo::expires_at;
+    use crate::test_support::{StubEndpoint, StubResponse};
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    fn job_item(attributes: &[(&str, AttributeValue)]) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::from([
+            ("service".to_string(), AttributeValue::S("JOB-job-1".into())),
+            ("serviceId".to_string(), AttributeValue::S("job-1".into())),
+            ("status".to_string(), AttributeValue::S("pending".into())),
+        ]);
+        for (name, value) in attributes {
+            item.insert(name.to_string(), value.clone());
+        }
+        item
+    }
+
+    #[test]
+    fn a_job_with_only_its_key_and_status_reads_with_everything_else_absent() {
+        let job = Job::from_dynamodb_item(job_item(&[])).unwrap();
+
+        assert_eq!(job.serviceid, "job-1");
+        assert_eq!(job.status, JobStatus::Pending);
+        assert_eq!(job.context, "");
+        assert!(job.created_by.is_none());
+        assert!(job.schema.is_empty());
+        assert!(job.provenance.labels.is_empty());
+        assert!(job.provenance.original_filename.is_none());
+        assert!(job.attempts.is_none());
+        assert!(job.progress_percent.is_none());
+        assert!(job.row_count.is_none());
+        assert!(job.unmatched_columns.is_empty());
+        assert!(job.header_problems.is_empty());
+        assert!(job.ignored_columns.is_empty());
+        assert!(job.column_stats.is_empty());
+        assert!(job.query_schema.is_none());
+        assert!(job.column_profiles.is_none());
+        assert!(job.error_message.is_none());
+        assert!(job.parent_job_id.is_none());
+    }
+
+    #[test]
+    fn null_or_mistyped_optional_attributes_read_as_absent() {
+        let job = Job::from_dynamodb_item(job_item(&[
+            ("created_by", AttributeValue::Null(true)),
+            ("context", AttributeValue::N("5".into())),
+            ("attempts", AttributeValue::S("two".into())),
+            ("row_count", AttributeValue::N("not a number".into())),
+            ("schema", AttributeValue::S("id:integer".into())),
+            ("ignored_columns", AttributeValue::Null(true)),
+            ("query_schema", AttributeValue::S("{not json".into())),
+            ("output_key", AttributeValue::S("out/job-1.parquet".into())),
+        ]))
+        .unwrap();
+
+        assert!(job.created_by.is_none());
+        assert_eq!(job.context, "");
+        assert!(job.attempts.is_none());
+        assert!(job.row_count.is_none());
+        assert!(job.schema.is_empty());
+        assert!(job.ignored_columns.is_empty());
+        assert!(job.query_schema.is_none());
+        // A bad attribute doesn't cost the job its good ones
+        assert_eq!(job.output_key.as_deref(), Some("out/job-1.parquet"));
+    }
+
+    #[test]
+    fn partly_written_entries_are_dropped_from_their_lists() {
+        let job = Job::from_dynamodb_item(job_item(&[
+            (
+                "unmatched_columns",
+                AttributeValue::L(vec![
+                    AttributeValue::M(HashMap::from([(
+                        "column".to_string(),
+                        AttributeValue::S("amount".into()),
+                    )])),
+                    AttributeValue::M(HashMap::from([(
+                        "suggestion".to_string(),
+                        AttributeValue::S("total".into()),
+                    )])),
+                ]),
+            ),
+            (
+                "column_stats",
+                AttributeValue::M(HashMap::from([
+                    (
+                        "id".to_string(),
+                        AttributeValue::M(HashMap::from([(
+                            "written".to_string(),
+                            AttributeValue::N("10".into()),
+                        )])),
+                    ),
+                    ("name".to_string(), AttributeValue::S("oops".into())),
+                ])),
+            ),
+        ]))
+        .unwrap();
+
+        assert_eq!(job.unmatched_columns.len(), 1);
+        assert_eq!(job.unmatched_columns[0].column, "amount");
+        assert!(job.unmatched_columns[0].suggestion.is_none());
+        assert_eq!(job.column_stats.len(), 1);
+        assert_eq!(
+            (
+                job.column_stats["id"].empty,
+                job.column_stats["id"].coercion_failures,
+                job.column_stats["id"].written
+            ),
+            (0, 0, 10)
+        );
+    }
+
+    #[test]
+    fn a_job_missing_its_key_or_status_is_unreadable() {
+        for required in ["service", "serviceId", "status"] {
+            let mut item = job_item(&[]);
+            item.remove(required);
+            assert!(Job::from_dynamodb_item(item).is_err(), "{}", required);
+        }
+
+        let unknown_status = job_item(&[("status", AttributeValue::S("paused".into()))]);
+        assert!(Job::from_dynamodb_item(unknown_status).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_job_is_read_by_its_key() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::json(json!({"Item": {
+                "service": {"S": "JOB-job-1"},
+                "serviceId": {"S": "job-1"},
+                "status": {"S": "success"},
+                "created_by": {"S": "team-a"}
+            }}))
+        });
+
+        let job = get_job_by_id(&stub.dynamodb_client(), "jobs", "job-1")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(job.status, JobStatus::Success);
+        assert_eq!(job.created_by.as_deref(), Some("team-a"));
+        let request = stub.operations("GetItem").remove(0).json();
+        assert_eq!(request["TableName"], "jobs");
+        assert_eq!(request["Key"]["service"]["S"], "JOB-job-1");
+        assert_eq!(request["Key"]["serviceId"]["S"], "job-1");
+    }
+
+    #[tokio::test]
+    async fn a_missing_job_is_none() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+
+        let job = get_job_by_id(&stub.dynamodb_client(), "jobs", "job-1")
+            .await
+            .unwrap();
+
+        assert!(job.is_none());
+    }
+
+    #[tokio::test]
+    async fn finishing_a_job_pushes_its_expiry_out_again() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let before = Utc::now();
+
+        update_job_status_to_success(
+            &stub.dynamodb_client(),
+            "jobs",
+            "job-1",
+            1200,
+            "uploads",
+            "parquet/job-1.parquet",
+        )
+        .await
+        .unwrap();
+
+        let request = stub.operations("UpdateItem").remove(0).json();
+        let names = request["ExpressionAttributeNames"].as_object().unwrap();
+        let (placeholder, _) = names
+            .iter()
+            .find(|(_, name)| *name == "expires_at")
+            .unwrap();
+        let expires = request["ExpressionAttributeValues"][placeholder.replacen('#', ":", 1)]["N"]
+            .as_str()
+            .unwrap()
+            .parse::<i64>()
+            .unwrap();
+        let retention = Retention::Job.days();
+        assert!(expires >= expires_at(before, retention));
+        assert!(expires <= expires_at(Utc::now(), retention));
+    }
+
+    #[test]
+    fn every_status_change_refreshes_the_expiry() {
+        for status in JobStatus::ALL {
+            let update = status_update(status, HashMap::new());
+            assert!(
+                update.names.values().any(|name| name == "expires_at"),
+                "{:?}",
+                status
+            );
+        }
+
+        // A derived job keeps the shorter retention it was given
+        let derived = Retention::DerivedJob.attribute(Utc::now());
+        let update = status_update(
+            JobStatus::Success,
+            HashMap::from([("expires_at".to_string(), derived.clone())]),
+        );
+        let (placeholder, _) = update
+            .names
+            .iter()
+            .find(|(_, name)| *name == "expires_at")
+            .unwrap();
+        assert_eq!(update.values[&placeholder.replacen('#', ":", 1)], derived);
+    }
+
+    // The job `item` reads as, through the same GetItem path the poller uses
+    async fn read_job(item: Value) -> Option<Job> {
+        let stub = StubEndpoint::start(move |_| StubResponse::json(json!({"Item": item})));
+        get_job_by_id(&stub.dynamodb_client(), "jobs", "job-1")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_minimal_job_reads_with_everything_else_empty() {
+        let job = read_job(json!({
+            "service": {"S": "JOB-job-1"},
+            "serviceId": {"S": "job-1"},
+            "status": {"S": "pending"}
+        }))
+        .await
+        .unwrap();
+
+        assert_eq!(job.serviceid, "job-1");
+        assert_eq!(job.status, JobStatus::Pending);
+        assert_eq!(job.context, "");
+        assert!(job.schema.is_empty());
+        assert_eq!(job.created_by, None);
+        assert_eq!(job.created_at, None);
+        assert_eq!(job.attempts, None);
+        assert_eq!(job.row_count, None);
+        assert_eq!(job.output_key, None);
+        assert_eq!(job.error_message, None);
+        assert_eq!(job.query_schema, None);
+        assert!(job.column_stats.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_full_job_reads_every_attribute() {
+        let job = read_job(json!({
+            "service": {"S": "JOB-job-1"},
+            "serviceId": {"S": "job-1"},
+            "status": {"S": "success"},
+            "context": {"S": "Monthly sales"},
+            "created_by": {"S": "team-a"},
+            "schema": {"M": {"id": {"S": "integer"}}},
+            "original_filename": {"S": "sales.csv"},
+            "labels": {"M": {"project": {"S": "q3"}}},
+            "created_at": {"S": "2025-01-01T00:00:00.000Z"},
+            "updated_at": {"S": "2025-01-01T00:05:00.000Z"},
+            "completed_at": {"S": "2025-01-01T00:05:00.000Z"},
+            "attempts": {"N": "2"},
+            "progress_percent": {"N": "100"},
+            "row_count": {"N": "1200"},
+            "output_bucket": {"S": "uploads"},
+            "output_key": {"S": "parquet/job-1.parquet"},
+            "column_stats": {"M": {"id": {"M": {
+                "empty": {"N": "1"}, "coercion_failures": {"N": "2"}, "written": {"N": "1197"}
+            }}}},
+            "ignored_columns": {"L": [{"S": "notes"}]},
+            "unmatched_columns": {"L": [{"M": {"column": {"S": "total"}, "suggestion": {"S": "Total"}}}]},
+            "query_schema": {"S": r#"[{"name":"id","duckdb_type":"BIGINT","nullable":true}]"#},
+            "query_schema_etag": {"S": "\"abc\""},
+            "error_message": {"S": "earlier attempt timed out"}
+        }))
+        .await
+        .unwrap();
+
+        assert_eq!(job.context, "Monthly sales");
+        assert_eq!(job.created_by.as_deref(), Some("team-a"));
+        assert_eq!(job.schema["id"], "integer");
+        assert_eq!(
+            job.provenance.original_filename.as_deref(),
+            Some("sales.csv")
+        );
+        assert_eq!(job.provenance.labels["project"], "q3");
+        assert_eq!(job.created_at.as_deref(), Some("2025-01-01T00:00:00.000Z"));
+        assert_eq!(job.updated_at.as_deref(), Some("2025-01-01T00:05:00.000Z"));
+        assert_eq!(job.attempts, Some(2));
+        assert_eq!(job.progress_percent, Some(100.0));
+        assert_eq!(job.row_count, Some(1200));
+        assert_eq!(job.output_key.as_deref(), Some("parquet/job-1.parquet"));
+        assert_eq!(
+            job.column_stats["id"],
+            ColumnStats {
+                empty: 1,
+                coercion_failures: 2,
+                written: 1197
+            }
+        );
+        assert_eq!(job.ignored_columns, ["notes"]);
+        assert_eq!(
+            job.unmatched_columns[0].suggestion.as_deref(),
+            Some("Total")
+        );
+        assert_eq!(job.query_schema.unwrap()[0].duckdb_type, "BIGINT");
+        assert_eq!(job.query_schema_etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            job.error_message.as_deref(),
+            Some("earlier attempt timed out")
+        );
+    }
+
+    #[tokio::test]
+    async fn attributes_of_the_wrong_type_read_as_absent() {
+        let job = read_job(json!({
+            "service": {"S": "JOB-job-1"},
+            "serviceId": {"S": "job-1"},
+            "status": {"S": "failed"},
+            "context": {"N": "1"},
+            "schema": {"S": "id:integer"},
+            "created_at": {"N": "1735689600"},
+            "attempts": {"N": "many"},
+            "row_count": {"S": "1200"},
+            "labels": {"L": []},
+            "query_schema": {"S": "not json"},
+            "output_key": {"NULL": true}
+        }))
+        .await
+        .unwrap();
+
+        // One bad attribute doesn't cost the rest of the job
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.context, "");
+        assert!(job.schema.is_empty());
+        assert_eq!(job.created_at, None);
+        assert_eq!(job.attempts, None);
+        assert_eq!(job.row_count, None);
+        assert!(job.provenance.labels.is_empty());
+        assert_eq!(job.query_schema, None);
+        assert_eq!(job.output_key, None);
+    }
+
+    #[tokio::test]
+    async fn an_item_without_its_key_or_status_is_not_a_job() {
+        for item in [
+            json!({"service": {"S": "JOB-job-1"}, "status": {"S": "pending"}}),
+            json!({"service": {"S": "JOB-job-1"}, "serviceId": {"S": "job-1"}}),
+            json!({
+                "service": {"S": "JOB-job-1"},
+                "serviceId": {"S": "job-1"},
+                "status": {"N": "1"}
+            }),
+        ] {
+            assert!(read_job(item.clone()).await.is_none(), "{}", item);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unknown_status_is_an_error() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::json(json!({"Item": {"status": {"S": "exploded"}}}))
+        });
+
+        let status = get_job_status(&stub.dynamodb_client(), "jobs", "job-1").await;
+
+        assert!(status.is_err());
+        let request = stub.operations("GetItem").remove(0).json();
+        assert_eq!(request["ConsistentRead"], true);
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_finished_job_changes_nothing() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error("ConditionalCheckFailedException", None)
+        });
+
+        let cancelled = cancel_job(&stub.dynamodb_client(), "jobs", "job-1")
+            .await
+            .unwrap();
+
+        assert!(!cancelled);
+    }
+
+    #[tokio::test]
+    async fn a_throttled_write_is_retryable() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error("ProvisionedThroughputExceededException", None)
+        });
+
+        let e = cancel_job(&stub.dynamodb_client(), "jobs", "job-1")
+            .await
+            .unwrap_err();
+
+        assert!(e.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn labels_are_replaced_or_removed() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let client = stub.dynamodb_client();
+        let labels = HashMap::from([("project".to_string(), "q3".to_string())]);
+
+        assert!(
+            update_job_labels(&client, "jobs", "job-1", &labels)
+                .await
+                .unwrap()
+        );
+        assert!(
+            update_job_labels(&client, "jobs", "job-1", &HashMap::new())
+                .await
+                .unwrap()
+        );
+
+        let requests = stub.operations("UpdateItem");
+        let set = requests[0].json();
+        assert_eq!(
+            set["UpdateExpression"],
+            "SET labels = :labels, updated_at = :now"
+        );
+        assert_eq!(
+            set["ExpressionAttributeValues"][":labels"]["M"]["project"]["S"],
+            "q3"
+        );
+        let remove = requests[1].json();
+        assert_eq!(
+            remove["UpdateExpression"],
+            "SET updated_at = :now REMOVE labels"
+        );
+        assert_eq!(remove["ConditionExpression"], "attribute_exists(service)");
+    }
+
+    #[tokio::test]
+    async fn labels_on_a_missing_job_are_not_written() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error("ConditionalCheckFailedException", None)
+        });
+        let labels = HashMap::from([("project".to_string(), "q3".to_string())]);
+
+        let updated = update_job_labels(&stub.dynamodb_client(), "jobs", "job-1", &labels)
+            .await
+            .unwrap();
+
+        assert!(!updated);
+    }
+
+    #[tokio::test]
+    async fn a_status_update_is_conditional_on_the_predecessors() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+
+        let applied = update_job_status(
+            &stub.dynamodb_client(),
+            "jobs",
+            "job-1",
+            JobStatus::Success,
+            HashMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert!(applied);
+        let request = stub.operations("UpdateItem").remove(0).json();
+        assert_eq!(
+            request["ConditionExpression"],
+            "#current IN (:from0, :from1)"
+        );
+        assert_eq!(request["ExpressionAttributeNames"]["#current"], "status");
+        assert_eq!(
+            request["ExpressionAttributeValues"][":from0"]["S"],
+            "pending"
+        );
+        assert_eq!(
+            request["ExpressionAttributeValues"][":from1"]["S"],
+            "processing"
+        );
+        assert_eq!(request["ReturnValuesOnConditionCheckFailure"], "ALL_OLD");
+    }
+
+    #[tokio::test]
+    async fn an_illegal_transition_is_skipped_and_reported() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error(
+                "ConditionalCheckFailedException",
+                Some(json!({"status": {"S": "cancelled"}})),
+            )
+        });
+
+        let applied = update_job_status(
+            &stub.dynamodb_client(),
+            "jobs",
+            "job-1",
+            JobStatus::Success,
+            HashMap::new(),
+        )
+        .await
+        .unwrap();
+
+        assert!(!applied);
+    }
+
+    #[tokio::test]
+    async fn a_retry_only_reopens_a_running_job() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error("ConditionalCheckFailedException", None)
+        });
+        let error = ProcessingError::read("connection reset");
+
+        let applied = record_job_retry(&stub.dynamodb_client(), "jobs", "job-1", &error, 10)
+            .await
+            .unwrap();
+
+        assert!(!applied);
+        let request = stub.operations("UpdateItem").remove(0).json();
+        assert_eq!(
+            request["ConditionExpression"],
+            "#current IN (:from0, :from1)"
+        );
+        assert_eq!(
+            request["ExpressionAttributeValues"][":from0"]["S"],
+            "pending"
+        );
+        assert_eq!(
+            request["ExpressionAttributeValues"][":from1"]["S"],
+            "processing"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_checkpoint_records_the_attempt_that_saved_it() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let checkpoint = ConversionCheckpoint {
+            byte_offset: 4096,
+            rows_written: 120,
+            upload_id: "upload-1".to_string(),
+            parts: vec![UploadedPart {
+                number: 1,
+                etag: "\"etag-1\"".to_string(),
+            }],
+            bytes_written: 8192,
+            row_groups: 2,
+            column_stats: vec![ColumnStats {
+                empty: 3,
+                coercion_failures: 1,
+                written: 116,
+            }],
+        };
+
+        save_job_checkpoint(&stub.dynamodb_client(), "jobs", "job-1", &checkpoint)
+            .await
+            .unwrap();
+
+        let request = stub.operations("UpdateItem").remove(0).json();
+        let expression = request["UpdateExpression"].as_str().unwrap();
+        assert!(
+            expression.contains("checkpoint_attempt = attempts"),
+            "{}",
+            expression
+        );
+        let values = &request["ExpressionAttributeValues"];
+        assert_eq!(values[":offset"]["N"], "4096");
+        assert_eq!(values[":rows"]["N"], "120");
+        assert_eq!(values[":upload_id"]["S"], "upload-1");
+        assert_eq!(values[":parts"]["L"][0]["M"]["number"]["N"], "1");
+        assert_eq!(values[":parts"]["L"][0]["M"]["etag"]["S"], "\"etag-1\"");
+        assert_eq!(values[":bytes"]["N"], "8192");
+        assert_eq!(values[":row_groups"]["N"], "2");
+        assert_eq!(
+            values[":column_stats"]["S"],
+            r#"[{"empty":3,"coercion_failures":1,"written":116}]"#
+        );
+    }
+
+    #[tokio::test]
+    async fn a_saved_checkpoint_reads_back_with_its_value_counts() {
+        let saved = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let checkpoint = ConversionCheckpoint {
+            byte_offset: 4096,
+            rows_written: 120,
+            upload_id: "upload-1".to_string(),
+            column_stats: vec![
+                ColumnStats {
+                    empty: 0,
+                    coercion_failures: 0,
+                    written: 120,
+                },
+                ColumnStats {
+                    empty: 3,
+                    coercion_failures: 1,
+                    written: 116,
+                },
+            ],
+            ..ConversionCheckpoint::default()
+        };
+        save_job_checkpoint(&saved.dynamodb_client(), "jobs", "job-1", &checkpoint)
+            .await
+            .unwrap();
+        let values =
+            saved.operations("UpdateItem").remove(0).json()["ExpressionAttributeValues"].clone();
+
+        let item = json!({
+            "checkpoint_offset": values[":offset"],
+            "checkpoint_rows": values[":rows"],
+            "checkpoint_upload_id": values[":upload_id"],
+            "checkpoint_column_stats": values[":column_stats"]
+        });
+        let stub = StubEndpoint::start(move |_| StubResponse::json(json!({"Item": item})));
+        let read = get_job_checkpoint(&stub.dynamodb_client(), "jobs", "job-1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.column_stats, checkpoint.column_stats);
+
+        // A checkpoint saved before value counts were kept reads back with none
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::json(json!({"Item": {"checkpoint_offset": {"N": "4096"}}}))
+        });
+        let read = get_job_checkpoint(&stub.dynamodb_client(), "jobs", "job-1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(read.column_stats.is_empty());
+    }
+
+    #[tokio::test]
+    async fn header_problems_in_a_column_report_read_back_with_the_job() {
+        let written = Arc::new(Mutex::new(Value::Null));
+        let seen = written.clone();
+        let stub = StubEndpoint::start(move |request| match request.operation() {
+            Some("UpdateItem") => {
+                *seen.lock().unwrap() =
+                    request.json()["ExpressionAttributeValues"][":header_problems"].clone();
+                StubResponse::json(json!({}))
+            }
+            _ => {
+                let mut item = json!({
+                    "service": {"S": "JOB-job-1"},
+                    "serviceId": {"S": "job-1"},
+                    "status": {"S": "success"}
+                });
+                item["header_problems"] = seen.lock().unwrap().clone();
+                StubResponse::json(json!({"Item": item}))
+            }
+        });
+        let client = stub.dynamodb_client();
+        let headers = ["id", " name", "id"].map(String::from);
+        let report = build_column_report(&[], &headers);
+
+        record_column_report(&client, "jobs", "job-1", &report)
+            .await
+            .unwrap();
+        let job = get_job_by_id(&client, "jobs", "job-1")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            *written.lock().unwrap(),
+            json!({"L": [
+                {"M": {
+                    "header": {"S": " name"},
+                    "normalized": {"S": "name"},
+                    "problems": {"L": [{"S": "surrounding_whitespace"}]}
+                }},
+                {"M": {
+                    "header": {"S": "id"},
+                    "normalized": {"S": "id_2"},
+                    "problems": {"L": [{"S": "duplicate"}]}
+                }}
+            ]})
+        );
+        assert_eq!(job.header_problems, report.header_problems);
+    }
+
+    #[test]
+    fn the_throughput_average_starts_at_the_first_sample() {
+        assert_eq!(throughput_ema(None, 12.5), 12.5);
+    }
+
+    #[test]
+    fn each_sample_moves_the_average_a_fifth_of_the_way() {
+        assert!((throughput_ema(Some(10.0), 20.0) - 12.0).abs() < 1e-9);
+        assert!((throughput_ema(Some(10.0), 5.0) - 9.0).abs() < 1e-9);
+
+        // A steady rate is where the average settles
+        let mut average = throughput_ema(None, 50.0);
+        for _ in 0..60 {
+            average = throughput_ema(Some(average), 10.0);
+        }
+        assert!((average - 10.0).abs() < 1e-4);
+    }
+
+    #[tokio::test]
+    async fn the_first_throughput_sample_is_only_written_if_none_exists() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+
+        record_throughput(
+            &stub.dynamodb_client(),
+            "jobs",
+            ProcessingPath::Standard,
+            "job-1",
+            8.0,
+        )
+        .await
+        .unwrap();
+
+        let update = stub.operations("UpdateItem").remove(0).json();
+        assert_eq!(
+            update["ConditionExpression"],
+            "attribute_not_exists(mb_per_second)"
+        );
+        assert_eq!(update["ExpressionAttributeValues"][":average"]["N"], "8");
+    }
+
+    #[tokio::test]
+    async fn a_throughput_update_that_loses_a_race_reads_again() {
+        // The stored average changes from 10 to 20 between the first read and write
+        let stored = Arc::new(Mutex::new("10".to_string()));
+        let seen = stored.clone();
+        let stub = StubEndpoint::start(move |request| match request.operation() {
+            Some("GetItem") => StubResponse::json(json!({"Item": {
+                "mb_per_second": {"N": seen.lock().unwrap().clone()}
+            }})),
+            _ => {
+                let values = &request.json()["ExpressionAttributeValues"];
+                let mut current = seen.lock().unwrap();
+                if values[":previous"]["N"] == "10" {
+                    *current = "20".to_string();
+                    return StubResponse::dynamodb_error("ConditionalCheckFailedException", None);
+                }
+                *current = values[":average"]["N"].as_str().unwrap().to_string();
+                StubResponse::json(json!({}))
+            }
+        });
+
+        record_throughput(
+            &stub.dynamodb_client(),
+            "jobs",
+            ProcessingPath::Standard,
+            "job-1",
+            30.0,
+        )
+        .await
+        .unwrap();
+
+        let updates = stub.operations("UpdateItem");
+        assert_eq!(updates.len(), 2);
+        let retry = updates[1].json();
+        assert_eq!(retry["ConditionExpression"], "mb_per_second = :previous");
+        assert_eq!(retry["ExpressionAttributeValues"][":previous"]["N"], "20");
+        assert_eq!(
+            *stored.lock().unwrap(),
+            throughput_ema(Some(20.0), 30.0).to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_throughput_sample_is_dropped_after_repeated_races() {
+        let stub = StubEndpoint::start(|request| match request.operation() {
+            Some("GetItem") => StubResponse::json(json!({"Item": {
+                "mb_per_second": {"N": "10"}
+            }})),
+            _ => StubResponse::dynamodb_error("ConditionalCheckFailedException", None),
+        });
+
+        let result = record_throughput(
+            &stub.dynamodb_client(),
+            "jobs",
+            ProcessingPath::Standard,
+            "job-1",
+            30.0,
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(
+            stub.operations("UpdateItem").len(),
+            THROUGHPUT_UPDATE_ATTEMPTS as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn only_one_of_two_racing_submissions_claims_an_idempotency_key() {
+        // Stands in for the table: the first put wins and later ones fail their condition
+        let mapping: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let table = mapping.clone();
+        let stub = StubEndpoint::start(move |request| {
+            let body = request.json();
+            let mut mapping = table.lock().unwrap();
+            match request.operation() {
+                Some("PutItem") => {
+                    assert_eq!(
+                        body["ConditionExpression"],
+                        "attribute_not_exists(service) OR expires_at < :now"
+                    );
+                    if mapping.is_some() {
+                        return StubResponse::dynamodb_error(
+                            "ConditionalCheckFailedException",
+                            None,
+                        );
+                    }
+                    *mapping = body["Item"]["job_id"]["S"].as_str().map(String::from);
+                    StubResponse::json(json!({}))
+                }
+                _ => {
+                    assert_eq!(body["ConsistentRead"], true);
+                    StubResponse::json(json!({"Item": {
+                        "job_id": {"S": mapping.clone().unwrap()}
+                    }}))
+                }
+            }
+        });
+        let client = stub.dynamodb_client();
+
+        let (first, second) = tokio::join!(
+            claim_idempotency_key(&client, "jobs", "retry-1", "job-a"),
+            claim_idempotency_key(&client, "jobs", "retry-1", "job-b"),
+        );
+
+        // Whichever put landed first claims the key; the other is told the winner's job
+        let winner = mapping.lock().unwrap().clone().unwrap();
+        for (job_id, claim) in [("job-a", first.unwrap()), ("job-b", second.unwrap())] {
+            if job_id == winner {
+                assert_eq!(claim, IdempotencyClaim::Claimed);
+            } else {
+                assert_eq!(claim, IdempotencyClaim::Existing(winner.clone()));
+            }
+        }
+        assert_eq!(stub.operations("PutItem").len(), 2);
+        assert_eq!(stub.operations("GetItem").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_claimed_key_expires_after_a_day() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+
+        let claim = claim_idempotency_key(&stub.dynamodb_client(), "jobs", "retry-1", "job-a")
+            .await
+            .unwrap();
+
+        assert_eq!(claim, IdempotencyClaim::Claimed);
+        let put = stub.operations("PutItem").remove(0).json();
+        let number = |value: &Value| value["N"].as_str().unwrap().parse::<i64>().unwrap();
+        assert_eq!(put["Item"]["service"]["S"], "IDEMPOTENCY-retry-1");
+        assert_eq!(
+            number(&put["Item"]["expires_at"]) - number(&put["ExpressionAttributeValues"][":now"]),
+            IDEMPOTENCY_TTL_SECONDS
+        );
+    }
+
+    fn scheduled_item(job_id: &str, start_after: &str, path: Option<&str>) -> Value {
+        let mut item = json!({
+            "service": {"S": format!("JOB-{}", job_id)},
+            "serviceId": {"S": job_id},
+            "start_after": {"S": start_after},
+            "scheduled_request": {"S": format!("{{\"job_id\":\"{}\"}}", job_id)}
+        });
+        if let Some(path) = path {
+            item["processing_path"] = json!({"S": path});
+        }
+        item
+    }
+
+    #[tokio::test]
+    async fn due_jobs_are_read_from_the_schedule_index_up_to_the_cutoff() {
+        let stub = StubEndpoint::start(|request| {
+            if request.json().get("ExclusiveStartKey").is_none() {
+                StubResponse::json(json!({
+                    "Items": [
+                        scheduled_item("job-1", "2025-06-01T12:05:00Z", Some("fast")),
+                        scheduled_item("job-2", "2025-06-01T12:10:00Z", None)
+                    ],
+                    "LastEvaluatedKey": {"serviceId": {"S": "job-2"}}
+                }))
+            } else {
+                StubResponse::json(json!({"Items": [
+                    scheduled_item("job-3", "2025-06-01T12:15:00Z", Some("standard"))
+                ]}))
+            }
+        });
+        let due_before = DateTime::parse_from_rfc3339("2025-06-01T12:15:00.750Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let jobs = get_due_scheduled_jobs(&stub.dynamodb_client(), "jobs", due_before)
+            .await
+            .unwrap();
+
+        let ids: Vec<&str> = jobs.iter().map(|job| job.job_id.as_str()).collect();
+        assert_eq!(ids, ["job-1", "job-2", "job-3"]);
+        assert_eq!(jobs[0].path, ProcessingPath::Fast);
+        assert_eq!(jobs[1].path, ProcessingPath::Standard);
+        assert_eq!(jobs[1].request_body, r#"{"job_id":"job-2"}"#);
+
+        let queries = stub.operations("Query");
+        assert_eq!(queries.len(), 2);
+        let first = queries[0].json();
+        assert_eq!(first["IndexName"], SCHEDULE_INDEX);
+        assert_eq!(
+            first["KeyConditionExpression"],
+            "schedule_bucket = :bucket AND start_after <= :due"
+        );
+        assert_eq!(
+            first["ExpressionAttributeValues"][":bucket"]["S"],
+            "SCHEDULED"
+        );
+        // Whole seconds, the same format start times are stored in
+        assert_eq!(
+            first["ExpressionAttributeValues"][":due"]["S"],
+            "2025-06-01T12:15:00Z"
+        );
+        assert_eq!(
+            queries[1].json()["ExclusiveStartKey"]["serviceId"]["S"],
+            "job-2"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_due_item_missing_fields_is_skipped() {
+        let stub = StubEndpoint::start(|_| {
+            let unreadable = scheduled_item("job-2", "not a time", None);
+            let mut no_request = scheduled_item("job-3", "2025-06-01T12:05:00Z", None);
+            no_request
+                .as_object_mut()
+                .unwrap()
+                .remove("scheduled_request");
+            StubResponse::json(json!({"Items": [
+                scheduled_item("job-1", "2025-06-01T12:05:00Z", None),
+                unreadable,
+                no_request
+            ]}))
+        });
+
+        let jobs = get_due_scheduled_jobs(&stub.dynamodb_client(), "jobs", Utc::now())
+            .await
+            .unwrap();
+
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].job_id, "job-1");
+    }
+}
@@ -0,0 +1,457 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue, ReturnValuesOnConditionCheckFailure};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use tracing::warn;
+
+use super::{JobStatus, timestamp_now};
+use crate::error::Error;
+
+// How far apart two Lambdas' clocks are allowed to be. A lease only counts as expired
+// once it is this far past its end, so a contender whose clock runs ahead can't take a
+// lease its holder still believes it has.
+pub const LEASE_CLOCK_SKEW: std::time::Duration = std::time::Duration::from_secs(30);
+
+// A conversion's hold on its job, renewed while it runs and released when it is done
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobLease {
+    // Unique to the invocation holding the lease, so only it can renew or release it
+    pub owner: String,
+    // The job's attempt count including this one
+    pub attempts: u32,
+    // The attempt that last saved a conversion checkpoint; 0 if none has
+    pub checkpoint_attempt: u32,
+    // Epoch milliseconds
+    pub until: i64,
+    // The job's labels as the lease found them, so the output carries any edited since
+    // submission
+    pub labels: HashMap<String, String>,
+}
+
+impl JobLease {
+    // Attempts since the last one that checkpointed progress, this one included. A
+    // checkpointed conversion is redelivered every time an execution runs out of time, so
+    // only the executions that got nothing written count towards the attempt limit.
+    pub fn attempts_without_progress(&self) -> u32 {
+        self.attempts.saturating_sub(self.checkpoint_attempt)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LeaseOutcome {
+    Acquired(JobLease),
+    // Another invocation is converting the job; its lease runs until `until`
+    Held { until: i64 },
+    // The job has left pending and processing, so there is nothing left to convert
+    NotRunnable(String),
+    NotFound,
+}
+
+// When a lease taken or renewed at `now` for `duration` ends, in epoch milliseconds
+pub fn lease_until(now: DateTime<Utc>, duration: std::time::Duration) -> i64 {
+    let millis = i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+    now.timestamp_millis().saturating_add(millis)
+}
+
+// Leases ending before this, in epoch milliseconds, are expired as seen at `now`
+pub fn lease_cutoff(now: DateTime<Utc>) -> i64 {
+    now.timestamp_millis()
+        .saturating_sub(LEASE_CLOCK_SKEW.as_millis() as i64)
+}
+
+// The same comparison acquire_job_lease's condition makes
+pub fn lease_expired(until: i64, now: DateTime<Utc>) -> bool {
+    until < lease_cutoff(now)
+}
+
+// Only a pending or processing job whose lease, if any, ended before the cutoff can be
+// leased. The write's condition is this check on the item as DynamoDB holds it.
+const LEASE_CONDITION: &str = "#status IN (:pending, :processing) \
+     AND (attribute_not_exists(lease_until) OR lease_until < :cutoff)";
+
+// LEASE_CONDITION evaluated on a job read at `now`
+pub fn lease_available(status: JobStatus, until: Option<i64>, now: DateTime<Utc>) -> bool {
+    matches!(status, JobStatus::Pending | JobStatus::Processing)
+        && until.is_none_or(|until| lease_expired(until, now))
+}
+
+// Takes the job for one conversion attempt, unless another invocation holds an unexpired
+// lease on it. Taking the lease bumps the attempt counter in the same write, and the first
+// attempt also records started_at, so queue latency and processing time can be told apart.
+// Only a pending or processing job is leased, which also stops a stray message for an
+// unknown job from creating a half-populated item.
+pub async fn acquire_job_lease(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    lease_duration: std::time::Duration,
+) -> Result<LeaseOutcome, Error> {
+    let pk = format!("JOB-{}", job_id);
+    let owner = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+
+    let result = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression(
+            "SET lease_owner = :owner, lease_until = :until, \
+             started_at = if_not_exists(started_at, :now), updated_at = :now \
+             ADD attempts :one",
+        )
+        .condition_expression(LEASE_CONDITION)
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":pending", JobStatus::Pending.attribute())
+        .expression_attribute_values(":processing", JobStatus::Processing.attribute())
+        .expression_attribute_values(":owner", AttributeValue::S(owner.clone()))
+        .expression_attribute_values(
+            ":until",
+            AttributeValue::N(lease_until(now, lease_duration).to_string()),
+        )
+        .expression_attribute_values(":cutoff", AttributeValue::N(lease_cutoff(now).to_string()))
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+        .expression_attribute_values(":now", AttributeValue::S(timestamp_now()))
+        .return_values(ReturnValue::AllNew)
+        .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+        .send()
+        .await;
+
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            return match e.as_service_error() {
+                Some(UpdateItemError::ConditionalCheckFailedException(failed)) => {
+                    Ok(refused_lease(failed.item.as_ref()))
+                }
+                _ => Err(Error::dynamo("UpdateItem", e)),
+            };
+        }
+    };
+
+    let attributes = response.attributes.unwrap_or_default();
+    let number = |name: &str| {
+        attributes
+            .get(name)
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse::<i64>().ok())
+    };
+    let (Some(attempts), Some(until)) = (number("attempts"), number("lease_until")) else {
+        return Err(Error::dynamo_response(
+            "UpdateItem",
+            "response did not include the attempt count and lease",
+        ));
+    };
+
+    let labels = attributes
+        .get("labels")
+        .and_then(|v| v.as_m().ok())
+        .map(|labels| {
+            labels
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_s().ok()?.clone())))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(LeaseOutcome::Acquired(JobLease {
+        owner,
+        attempts: attempts as u32,
+        checkpoint_attempt: number("checkpoint_attempt").unwrap_or(0) as u32,
+        until,
+        labels,
+    }))
+}
+
+// Why a lease was refused, from the item as it was when the condition failed
+pub fn refused_lease(item: Option<&HashMap<String, AttributeValue>>) -> LeaseOutcome {
+    let Some(item) = item else {
+        return LeaseOutcome::NotFound;
+    };
+    let status = item
+        .get("status")
+        .and_then(|v| v.as_s().ok())
+        .cloned()
+        .unwrap_or_default();
+    let runnable = [JobStatus::Pending, JobStatus::Processing]
+        .iter()
+        .any(|runnable| runnable.as_str() == status);
+    if !runnable {
+        return LeaseOutcome::NotRunnable(status);
+    }
+    let until = item
+        .get("lease_until")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse::<i64>().ok())
+        .unwrap_or_default();
+    LeaseOutcome::Held { until }
+}
+
+// Pushes the lease out to `lease_duration` from now. False when the lease has been lost,
+// as when it lapsed and another invocation took the job.
+pub async fn renew_job_lease(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    lease: &JobLease,
+    lease_duration: std::time::Duration,
+) -> Result<bool, Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let result = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression("SET lease_until = :until")
+        .condition_expression("lease_owner = :owner")
+        .expression_attribute_values(":owner", AttributeValue::S(lease.owner.clone()))
+        .expression_attribute_values(
+            ":until",
+            AttributeValue::N(lease_until(Utc::now(), lease_duration).to_string()),
+        )
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) => match e.as_service_error() {
+            Some(service_error) if service_error.is_conditional_check_failed_exception() => {
+                Ok(false)
+            }
+            _ => Err(Error::dynamo("UpdateItem", e)),
+        },
+    }
+}
+
+// Gives the job up so a retry can take it straight away rather than waiting out the lease.
+// Only the holder's own lease is removed.
+pub async fn release_job_lease(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    lease: &JobLease,
+) -> Result<(), Error> {
+    let pk = format!("JOB-{}", job_id);
+
+    let result = dynamodb_client
+        .update_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(job_id.to_string()))
+        .update_expression("REMOVE lease_owner, lease_until")
+        .condition_expression("lease_owner = :owner")
+        .expression_attribute_values(":owner", AttributeValue::S(lease.owner.clone()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => match e.as_service_error() {
+            Some(service_error) if service_error.is_conditional_check_failed_exception() => {
+                warn!(job_id, "Job lease was already lost when releasing it");
+                Ok(())
+            }
+            _ => Err(Error::dynamo("UpdateItem", e)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubResponse};
+    use serde_json::json;
+
+    fn at_millis(millis: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(millis).unwrap()
+    }
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    #[test]
+    fn a_lease_ends_its_duration_after_it_is_taken() {
+        let until = lease_until(at_millis(NOW), std::time::Duration::from_secs(900));
+
+        assert_eq!(until, NOW + 900_000);
+        assert_eq!(
+            lease_until(at_millis(NOW), std::time::Duration::MAX),
+            i64::MAX
+        );
+    }
+
+    #[test]
+    fn a_lease_only_expires_once_past_the_clock_skew() {
+        let skew = LEASE_CLOCK_SKEW.as_millis() as i64;
+        let now = at_millis(NOW);
+
+        assert_eq!(lease_cutoff(now), NOW - skew);
+        // Still running, just ended, and ended within the skew all count as held
+        assert!(!lease_expired(NOW + 60_000, now));
+        assert!(!lease_expired(NOW, now));
+        assert!(!lease_expired(NOW - skew + 1, now));
+        // Ending exactly at the cutoff is still held, as the condition is strict
+        assert!(!lease_expired(NOW - skew, now));
+        assert!(lease_expired(NOW - skew - 1, now));
+    }
+
+    #[test]
+    fn a_contender_whose_clock_runs_ahead_cannot_take_a_live_lease() {
+        let duration = std::time::Duration::from_secs(60);
+        let holder_clock = at_millis(NOW);
+        let until = lease_until(holder_clock, duration);
+
+        // Up to LEASE_CLOCK_SKEW ahead, the contender still sees the lease as held at the
+        // moment the holder thinks it ends
+        let ahead = holder_clock + chrono::Duration::from_std(duration + LEASE_CLOCK_SKEW).unwrap();
+        assert!(!lease_available(JobStatus::Processing, Some(until), ahead));
+
+        let later = ahead + chrono::Duration::milliseconds(1);
+        assert!(lease_available(JobStatus::Processing, Some(until), later));
+    }
+
+    #[test]
+    fn only_a_running_job_without_a_live_lease_can_be_leased() {
+        let now = at_millis(NOW);
+        let expired = Some(NOW - LEASE_CLOCK_SKEW.as_millis() as i64 - 1);
+        let live = Some(NOW + 1);
+
+        for status in JobStatus::ALL {
+            let runnable = matches!(status, JobStatus::Pending | JobStatus::Processing);
+            assert_eq!(lease_available(status, None, now), runnable, "{}", status);
+            assert_eq!(
+                lease_available(status, expired, now),
+                runnable,
+                "{}",
+                status
+            );
+            assert!(!lease_available(status, live, now), "{}", status);
+        }
+    }
+
+    #[test]
+    fn a_refused_lease_says_why() {
+        let item = |status: &str, until: Option<i64>| {
+            let mut item =
+                HashMap::from([("status".to_string(), AttributeValue::S(status.to_string()))]);
+            if let Some(until) = until {
+                item.insert(
+                    "lease_until".to_string(),
+                    AttributeValue::N(until.to_string()),
+                );
+            }
+            item
+        };
+
+        assert_eq!(refused_lease(None), LeaseOutcome::NotFound);
+        assert_eq!(
+            refused_lease(Some(&item("processing", Some(NOW)))),
+            LeaseOutcome::Held { until: NOW }
+        );
+        assert_eq!(
+            refused_lease(Some(&item("success", None))),
+            LeaseOutcome::NotRunnable("success".to_string())
+        );
+        assert_eq!(
+            refused_lease(Some(&item("cancelled", Some(NOW)))),
+            LeaseOutcome::NotRunnable("cancelled".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn a_lease_is_taken_with_its_condition_and_cutoff() {
+        let stub = StubEndpoint::start(|request| {
+            let values = &request.json()["ExpressionAttributeValues"];
+            StubResponse::json(json!({"Attributes": {
+                "attempts": {"N": "2"},
+                "lease_until": values[":until"].clone(),
+                "labels": {"M": {"project": {"S": "q3"}}}
+            }}))
+        });
+        let duration = std::time::Duration::from_secs(900);
+
+        let outcome = acquire_job_lease(&stub.dynamodb_client(), "jobs", "job-1", duration)
+            .await
+            .unwrap();
+
+        let request = stub.operations("UpdateItem").remove(0).json();
+        assert_eq!(request["ConditionExpression"], LEASE_CONDITION);
+        let values = &request["ExpressionAttributeValues"];
+        let number = |name: &str| values[name]["N"].as_str().unwrap().parse::<i64>().unwrap();
+        // Both come from the same clock reading
+        assert_eq!(
+            number(":until") - number(":cutoff"),
+            (duration + LEASE_CLOCK_SKEW).as_millis() as i64
+        );
+        assert_eq!(values[":pending"]["S"], "pending");
+        assert_eq!(values[":processing"]["S"], "processing");
+
+        let LeaseOutcome::Acquired(lease) = outcome else {
+            panic!("lease was not acquired: {:?}", outcome);
+        };
+        assert_eq!(lease.attempts, 2);
+        assert_eq!(lease.until, number(":until"));
+        assert_eq!(lease.labels["project"], "q3");
+        assert_eq!(values[":owner"]["S"], lease.owner);
+    }
+
+    #[tokio::test]
+    async fn attempts_since_the_last_checkpoint_are_what_count() {
+        let stub = StubEndpoint::start(|request| {
+            let values = &request.json()["ExpressionAttributeValues"];
+            StubResponse::json(json!({"Attributes": {
+                "attempts": {"N": "7"},
+                "checkpoint_attempt": {"N": "6"},
+                "lease_until": values[":until"].clone()
+            }}))
+        });
+
+        let outcome = acquire_job_lease(
+            &stub.dynamodb_client(),
+            "jobs",
+            "job-1",
+            std::time::Duration::from_secs(900),
+        )
+        .await
+        .unwrap();
+
+        let LeaseOutcome::Acquired(lease) = outcome else {
+            panic!("lease was not acquired: {:?}", outcome);
+        };
+        assert_eq!(lease.attempts, 7);
+        assert_eq!(lease.checkpoint_attempt, 6);
+        // The attempt before this one saved progress, so this is the first since
+        assert_eq!(lease.attempts_without_progress(), 1);
+
+        let never_checkpointed = JobLease {
+            checkpoint_attempt: 0,
+            ..lease
+        };
+        assert_eq!(never_checkpointed.attempts_without_progress(), 7);
+    }
+
+    #[tokio::test]
+    async fn a_held_lease_is_reported_with_its_end() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error(
+                "ConditionalCheckFailedException",
+                Some(json!({
+                    "status": {"S": "processing"},
+                    "lease_until": {"N": "1700000000000"}
+                })),
+            )
+        });
+
+        let outcome = acquire_job_lease(
+            &stub.dynamodb_client(),
+            "jobs",
+            "job-1",
+            std::time::Duration::from_secs(900),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(outcome, LeaseOutcome::Held { until: NOW });
+    }
+}
@@ -0,0 +1,649 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::warn;
+
+use super::{Job, JobStatus};
+use crate::error::Error;
+
+// Every job item carries `entity = JOB`, and the byCreatedAt index is keyed on it and
+// `created_at`, so one query lists jobs newest first. Other items in the table have no
+// `entity` and stay out of the index.
+pub const JOB_ENTITY: &str = "JOB";
+const CREATED_AT_INDEX: &str = "byCreatedAt";
+
+pub const DEFAULT_LIST_JOBS_LIMIT: i32 = 25;
+pub const MAX_LIST_JOBS_LIMIT: i32 = 100;
+
+// A filtered page can come back short, or empty, when the status or owner filter drops
+// most of what was read, so up to this many reads are made to fill it
+const LIST_JOBS_READS: u32 = 5;
+
+// What a job listing is narrowed to. The date range is a key condition on `created_at`;
+// status, owner and label are filters applied to what the range reads.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    pub created_by: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub label: Option<LabelFilter>,
+}
+
+// Jobs carrying the label `key`, with `value` when one is given
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelFilter {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl LabelFilter {
+    // Reads `key` or `key:value`; the key can't contain a colon but the value can
+    pub fn parse(text: &str) -> Option<LabelFilter> {
+        let (key, value) = match text.split_once(':') {
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (text, None),
+        };
+        if key.trim().is_empty() {
+            return None;
+        }
+        Some(LabelFilter {
+            key: key.to_string(),
+            value,
+        })
+    }
+}
+
+impl JobFilter {
+    // Ties a cursor to the filter it was made with, as its key is only meaningful to the
+    // same query
+    fn digest(&self) -> String {
+        use sha2::{Digest, Sha256};
+
+        let label = self
+            .label
+            .as_ref()
+            .map(|label| format!("{}={}", label.key, label.value.as_deref().unwrap_or("*")))
+            .unwrap_or_default();
+        let canonical = format!(
+            "{}|{}|{}|{}|{}",
+            self.status
+                .map(|status| status.as_str())
+                .unwrap_or_default(),
+            self.created_by.as_deref().unwrap_or_default(),
+            self.created_after.map(created_at_bound).unwrap_or_default(),
+            self.created_before
+                .map(created_at_bound)
+                .unwrap_or_default(),
+            label,
+        );
+        Sha256::digest(canonical.as_bytes())
+            .iter()
+            .take(8)
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+// Jobs record `created_at` with `timestamp_now`, so bounds are written the same way to
+// compare correctly as strings
+fn created_at_bound(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[derive(Debug)]
+pub struct JobPage {
+    pub jobs: Vec<Job>,
+    // Pass back as `cursor` with the same filter for the next page; None once the
+    // listing is exhausted
+    pub next_cursor: Option<String>,
+    // Items the index read for the page, including those the filters then dropped. Far
+    // more than the page holds means the filters are doing expensive work.
+    pub scanned_count: u64,
+}
+
+// What a cursor holds: where the last page stopped and which filter it belongs to
+#[derive(Debug, Serialize, Deserialize)]
+struct JobCursor {
+    v: u8,
+    id: String,
+    at: String,
+    f: String,
+}
+
+const JOB_CURSOR_VERSION: u8 = 1;
+
+// A requested page size brought within 1..=MAX_LIST_JOBS_LIMIT, the default when absent
+pub fn clamp_list_jobs_limit(limit: Option<i32>) -> i32 {
+    limit
+        .unwrap_or(DEFAULT_LIST_JOBS_LIMIT)
+        .clamp(1, MAX_LIST_JOBS_LIMIT)
+}
+
+// The index's LastEvaluatedKey as an opaque cursor. None when the key isn't one the
+// index would return.
+pub fn encode_job_cursor(
+    last_evaluated_key: &HashMap<String, AttributeValue>,
+    filter: &JobFilter,
+) -> Option<String> {
+    use base64::Engine;
+
+    let text = |name: &str| last_evaluated_key.get(name)?.as_s().ok().cloned();
+    let cursor = JobCursor {
+        v: JOB_CURSOR_VERSION,
+        id: text("serviceId")?,
+        at: text("created_at")?,
+        f: filter.digest(),
+    };
+    let json = serde_json::to_vec(&cursor).ok()?;
+    Some(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json))
+}
+
+// The ExclusiveStartKey a cursor stands for. Only the job's ID and creation time come
+// from the cursor; the rest of the key is rebuilt, and a cursor that doesn't decode, was
+// made for another filter, or points outside the filter's date range is refused.
+pub fn decode_job_cursor(
+    cursor: &str,
+    filter: &JobFilter,
+) -> Result<HashMap<String, AttributeValue>, Error> {
+    use base64::Engine;
+
+    let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| Error::InvalidCursor("not base64"))?;
+    let cursor: JobCursor =
+        serde_json::from_slice(&json).map_err(|_| Error::InvalidCursor("malformed"))?;
+    if cursor.v != JOB_CURSOR_VERSION {
+        return Err(Error::InvalidCursor("unsupported version"));
+    }
+    if cursor.f != filter.digest() {
+        return Err(Error::InvalidCursor("made for a different filter"));
+    }
+    if cursor.id.is_empty() || DateTime::parse_from_rfc3339(&cursor.at).is_err() {
+        return Err(Error::InvalidCursor("malformed"));
+    }
+    let after_range = filter
+        .created_before
+        .is_some_and(|before| cursor.at > created_at_bound(before));
+    let before_range = filter
+        .created_after
+        .is_some_and(|after| cursor.at < created_at_bound(after));
+    if after_range || before_range {
+        return Err(Error::InvalidCursor("outside the filter's date range"));
+    }
+
+    Ok(HashMap::from([
+        (
+            "service".to_string(),
+            AttributeValue::S(format!("JOB-{}", cursor.id)),
+        ),
+        ("serviceId".to_string(), AttributeValue::S(cursor.id)),
+        (
+            "entity".to_string(),
+            AttributeValue::S(JOB_ENTITY.to_string()),
+        ),
+        ("created_at".to_string(), AttributeValue::S(cursor.at)),
+    ]))
+}
+
+// Up to `limit` jobs matching `filter`, newest first, starting after `cursor`. `limit` is
+// clamped to 1..=MAX_LIST_JOBS_LIMIT. Items that don't parse as jobs are logged and left
+// out.
+pub async fn list_jobs(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    filter: &JobFilter,
+    cursor: Option<&str>,
+    limit: Option<i32>,
+) -> Result<JobPage, Error> {
+    let limit = clamp_list_jobs_limit(limit);
+    let mut exclusive_start_key = cursor
+        .map(|cursor| decode_job_cursor(cursor, filter))
+        .transpose()?;
+
+    let mut key_condition = "entity = :entity".to_string();
+    let mut values = HashMap::from([(
+        ":entity".to_string(),
+        AttributeValue::S(JOB_ENTITY.to_string()),
+    )]);
+    match (filter.created_after, filter.created_before) {
+        (Some(after), Some(before)) => {
+            key_condition.push_str(" AND created_at BETWEEN :after AND :before");
+            values.insert(
+                ":after".to_string(),
+                AttributeValue::S(created_at_bound(after)),
+            );
+            values.insert(
+                ":before".to_string(),
+                AttributeValue::S(created_at_bound(before)),
+            );
+        }
+        (Some(after), None) => {
+            key_condition.push_str(" AND created_at >= :after");
+            values.insert(
+                ":after".to_string(),
+                AttributeValue::S(created_at_bound(after)),
+            );
+        }
+        (None, Some(before)) => {
+            key_condition.push_str(" AND created_at <= :before");
+            values.insert(
+                ":before".to_string(),
+                AttributeValue::S(created_at_bound(before)),
+            );
+        }
+        (None, None) => {}
+    }
+
+    let mut filters = Vec::new();
+    let mut names = HashMap::new();
+    if let Some(status) = filter.status {
+        filters.push("#status = :status");
+        names.insert("#status".to_string(), "status".to_string());
+        values.insert(":status".to_string(), status.attribute());
+    }
+    if let Some(created_by) = &filter.created_by {
+        filters.push("created_by = :created_by");
+        values.insert(
+            ":created_by".to_string(),
+            AttributeValue::S(created_by.clone()),
+        );
+    }
+    if let Some(label) = &filter.label {
+        // Label keys are caller-chosen, so the key always goes through a name placeholder
+        names.insert("#label".to_string(), label.key.clone());
+        match &label.value {
+            Some(value) => {
+                filters.push("labels.#label = :label");
+                values.insert(":label".to_string(), AttributeValue::S(value.clone()));
+            }
+            None => filters.push("attribute_exists(labels.#label)"),
+        }
+    }
+    let filter_expression = (!filters.is_empty()).then(|| filters.join(" AND "));
+
+    let mut jobs = Vec::new();
+    let mut last_evaluated_key = None;
+    let mut scanned_count = 0;
+    for _ in 0..LIST_JOBS_READS {
+        // Never more than the page still needs, so the last key read is exactly where
+        // the next page starts
+        let remaining = limit - jobs.len() as i32;
+        let response = dynamodb_client
+            .query()
+            .table_name(table_name)
+            .index_name(CREATED_AT_INDEX)
+            .key_condition_expression(&key_condition)
+            .set_filter_expression(filter_expression.clone())
+            .set_expression_attribute_names((!names.is_empty()).then(|| names.clone()))
+            .set_expression_attribute_values(Some(values.clone()))
+            .scan_index_forward(false)
+            .limit(remaining)
+            .set_exclusive_start_key(exclusive_start_key.take())
+            .send()
+            .await
+            .map_err(|e| Error::dynamo("Query", e))?;
+
+        scanned_count += response.scanned_count.max(0) as u64;
+        for item in response.items.unwrap_or_default() {
+            let service = item.get("service").and_then(|v| v.as_s().ok()).cloned();
+            match Job::from_dynamodb_item(item) {
+                Ok(job) => jobs.push(job),
+                Err(e) => warn!(service = ?service, error = %e, "Skipping unreadable job item"),
+            }
+        }
+
+        last_evaluated_key = response.last_evaluated_key;
+        if last_evaluated_key.is_none() || jobs.len() as i32 >= limit {
+            break;
+        }
+        exclusive_start_key = last_evaluated_key.clone();
+    }
+
+    Ok(JobPage {
+        jobs,
+        next_cursor: last_evaluated_key
+            .as_ref()
+            .and_then(|key| encode_job_cursor(key, filter)),
+        scanned_count,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubResponse};
+    use serde_json::{Value, json};
+
+    #[test]
+    fn a_label_filter_reads_a_key_and_an_optional_value() {
+        let filter = LabelFilter::parse("project:q3-campaign").unwrap();
+        assert_eq!(filter.key, "project");
+        assert_eq!(filter.value.as_deref(), Some("q3-campaign"));
+
+        let filter = LabelFilter::parse("project").unwrap();
+        assert_eq!(filter.value, None);
+
+        // Only the first colon separates the key
+        let filter = LabelFilter::parse("url:http://example.com").unwrap();
+        assert_eq!(filter.key, "url");
+        assert_eq!(filter.value.as_deref(), Some("http://example.com"));
+
+        assert_eq!(LabelFilter::parse(""), None);
+        assert_eq!(LabelFilter::parse(" :value"), None);
+    }
+
+    #[tokio::test]
+    async fn a_label_value_filters_on_the_label() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({"Items": []})));
+        let filter = JobFilter {
+            label: LabelFilter::parse("project:q3-campaign"),
+            ..JobFilter::default()
+        };
+
+        list_jobs(&stub.dynamodb_client(), "jobs", &filter, None, None)
+            .await
+            .unwrap();
+
+        let request = stub.operations("Query").remove(0).json();
+        assert_eq!(request["FilterExpression"], "labels.#label = :label");
+        assert_eq!(request["ExpressionAttributeNames"]["#label"], "project");
+        assert_eq!(
+            request["ExpressionAttributeValues"][":label"]["S"],
+            "q3-campaign"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_label_key_alone_matches_any_value() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({"Items": []})));
+        let filter = JobFilter {
+            status: Some(JobStatus::Success),
+            label: LabelFilter::parse("project"),
+            ..JobFilter::default()
+        };
+
+        list_jobs(&stub.dynamodb_client(), "jobs", &filter, None, None)
+            .await
+            .unwrap();
+
+        let request = stub.operations("Query").remove(0).json();
+        assert_eq!(
+            request["FilterExpression"],
+            "#status = :status AND attribute_exists(labels.#label)"
+        );
+        assert_eq!(request["ExpressionAttributeNames"]["#label"], "project");
+    }
+
+    #[test]
+    fn a_cursor_is_tied_to_its_label_filter() {
+        let key = HashMap::from([
+            (
+                "serviceId".to_string(),
+                AttributeValue::S("job-1".to_string()),
+            ),
+            (
+                "created_at".to_string(),
+                AttributeValue::S("2025-01-01T00:00:00.000Z".to_string()),
+            ),
+        ]);
+        let filter = JobFilter {
+            label: LabelFilter::parse("project:a"),
+            ..JobFilter::default()
+        };
+        let other = JobFilter {
+            label: LabelFilter::parse("project:b"),
+            ..JobFilter::default()
+        };
+
+        let cursor = encode_job_cursor(&key, &filter).unwrap();
+
+        assert!(decode_job_cursor(&cursor, &filter).is_ok());
+        assert!(decode_job_cursor(&cursor, &other).is_err());
+    }
+
+    fn job_item(id: &str, created_at: &str) -> Value {
+        json!({
+            "service": {"S": format!("JOB-{}", id)},
+            "serviceId": {"S": id},
+            "entity": {"S": JOB_ENTITY},
+            "created_at": {"S": created_at},
+            "status": {"S": "success"}
+        })
+    }
+
+    fn last_key(id: &str, created_at: &str) -> Value {
+        json!({
+            "service": {"S": format!("JOB-{}", id)},
+            "serviceId": {"S": id},
+            "entity": {"S": JOB_ENTITY},
+            "created_at": {"S": created_at}
+        })
+    }
+
+    #[test]
+    fn a_page_size_is_clamped() {
+        assert_eq!(clamp_list_jobs_limit(None), DEFAULT_LIST_JOBS_LIMIT);
+        assert_eq!(clamp_list_jobs_limit(Some(10)), 10);
+        assert_eq!(clamp_list_jobs_limit(Some(0)), 1);
+        assert_eq!(clamp_list_jobs_limit(Some(-5)), 1);
+        assert_eq!(
+            clamp_list_jobs_limit(Some(MAX_LIST_JOBS_LIMIT + 1)),
+            MAX_LIST_JOBS_LIMIT
+        );
+    }
+
+    #[test]
+    fn a_cursor_decodes_to_the_key_it_was_made_from() {
+        let key = HashMap::from([
+            (
+                "serviceId".to_string(),
+                AttributeValue::S("job-1".to_string()),
+            ),
+            (
+                "created_at".to_string(),
+                AttributeValue::S("2025-01-01T00:00:00.000Z".to_string()),
+            ),
+        ]);
+        let filter = JobFilter {
+            status: Some(JobStatus::Success),
+            ..JobFilter::default()
+        };
+
+        let cursor = encode_job_cursor(&key, &filter).unwrap();
+        let start = decode_job_cursor(&cursor, &filter).unwrap();
+
+        assert_eq!(start["service"].as_s().unwrap(), "JOB-job-1");
+        assert_eq!(start["serviceId"].as_s().unwrap(), "job-1");
+        assert_eq!(start["entity"].as_s().unwrap(), JOB_ENTITY);
+        assert_eq!(
+            start["created_at"].as_s().unwrap(),
+            "2025-01-01T00:00:00.000Z"
+        );
+        // A key without the index attributes makes no cursor
+        assert_eq!(encode_job_cursor(&HashMap::new(), &filter), None);
+    }
+
+    #[test]
+    fn a_tampered_cursor_is_refused() {
+        use base64::Engine;
+
+        let encode =
+            |json: Value| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json.to_string());
+        let filter = JobFilter::default();
+        let digest = filter.digest();
+        let dated = JobFilter {
+            created_after: Some(
+                DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z")
+                    .unwrap()
+                    .into(),
+            ),
+            ..JobFilter::default()
+        };
+
+        for cursor in [
+            "not base64!".to_string(),
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode("not json"),
+            encode(json!({"v": 2, "id": "job-1", "at": "2025-01-01T00:00:00.000Z", "f": digest})),
+            encode(json!({"v": 1, "id": "job-1", "at": "2025-01-01T00:00:00.000Z", "f": "0000"})),
+            encode(json!({"v": 1, "id": "", "at": "2025-01-01T00:00:00.000Z", "f": digest})),
+            encode(json!({"v": 1, "id": "job-1", "at": "yesterday", "f": digest})),
+        ] {
+            assert!(
+                matches!(
+                    decode_job_cursor(&cursor, &filter),
+                    Err(Error::InvalidCursor(_))
+                ),
+                "{}",
+                cursor
+            );
+        }
+
+        // A cursor from before the filter's range can't be used to read outside it
+        let outside = encode(json!({
+            "v": 1, "id": "job-1", "at": "2025-01-01T00:00:00.000Z", "f": dated.digest()
+        }));
+        assert!(matches!(
+            decode_job_cursor(&outside, &dated),
+            Err(Error::InvalidCursor("outside the filter's date range"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_listing_continues_from_its_cursor() {
+        let stub = StubEndpoint::start(|request| {
+            if request.json()["ExclusiveStartKey"].is_null() {
+                StubResponse::json(json!({
+                    "Items": [job_item("job-2", "2025-01-02T00:00:00.000Z")],
+                    "LastEvaluatedKey": last_key("job-2", "2025-01-02T00:00:00.000Z")
+                }))
+            } else {
+                StubResponse::json(json!({
+                    "Items": [job_item("job-1", "2025-01-01T00:00:00.000Z")]
+                }))
+            }
+        });
+        let client = stub.dynamodb_client();
+        let filter = JobFilter::default();
+
+        let first = list_jobs(&client, "jobs", &filter, None, Some(1))
+            .await
+            .unwrap();
+        let cursor = first.next_cursor.unwrap();
+        let second = list_jobs(&client, "jobs", &filter, Some(&cursor), Some(1))
+            .await
+            .unwrap();
+
+        assert_eq!(first.jobs[0].serviceid, "job-2");
+        assert_eq!(second.jobs[0].serviceid, "job-1");
+        assert_eq!(second.next_cursor, None);
+        let requests = stub.operations("Query");
+        assert_eq!(
+            requests[1].json()["ExclusiveStartKey"],
+            last_key("job-2", "2025-01-02T00:00:00.000Z")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_filtered_page_counts_every_item_it_read() {
+        let stub = StubEndpoint::start(|request| {
+            if request.json()["ExclusiveStartKey"].is_null() {
+                StubResponse::json(json!({
+                    "Items": [job_item("job-2", "2025-01-02T00:00:00.000Z")],
+                    "ScannedCount": 10,
+                    "LastEvaluatedKey": last_key("job-2", "2025-01-02T00:00:00.000Z")
+                }))
+            } else {
+                StubResponse::json(json!({
+                    "Items": [job_item("job-1", "2025-01-01T00:00:00.000Z")],
+                    "ScannedCount": 7
+                }))
+            }
+        });
+        let filter = JobFilter {
+            status: Some(JobStatus::Success),
+            ..JobFilter::default()
+        };
+
+        let page = list_jobs(&stub.dynamodb_client(), "jobs", &filter, None, Some(5))
+            .await
+            .unwrap();
+
+        // The first read came back short, so a second one filled the page
+        assert_eq!(page.jobs.len(), 2);
+        assert_eq!(page.scanned_count, 17);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn a_cursor_for_another_filter_is_refused_before_reading() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({"Items": []})));
+        let key = HashMap::from([
+            (
+                "serviceId".to_string(),
+                AttributeValue::S("job-1".to_string()),
+            ),
+            (
+                "created_at".to_string(),
+                AttributeValue::S("2025-01-01T00:00:00.000Z".to_string()),
+            ),
+        ]);
+        let cursor = encode_job_cursor(&key, &JobFilter::default()).unwrap();
+        let filter = JobFilter {
+            created_by: Some("team-a".to_string()),
+            ..JobFilter::default()
+        };
+
+        let result = list_jobs(
+            &stub.dynamodb_client(),
+            "jobs",
+            &filter,
+            Some(&cursor),
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::InvalidCursor(_))));
+        assert!(stub.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_short_filtered_page_is_filled_by_further_reads() {
+        let stub = StubEndpoint::start(|request| {
+            let body = request.json();
+            if body["ExclusiveStartKey"].is_null() {
+                // The filter dropped everything this read looked at
+                StubResponse::json(json!({
+                    "Items": [],
+                    "LastEvaluatedKey": last_key("job-9", "2025-01-09T00:00:00.000Z")
+                }))
+            } else {
+                StubResponse::json(json!({
+                    "Items": [
+                        job_item("job-2", "2025-01-02T00:00:00.000Z"),
+                        job_item("job-1", "2025-01-01T00:00:00.000Z")
+                    ]
+                }))
+            }
+        });
+
+        let page = list_jobs(
+            &stub.dynamodb_client(),
+            "jobs",
+            &JobFilter::default(),
+            None,
+            Some(500),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(page.jobs.len(), 2);
+        let requests = stub.operations("Query");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].json()["Limit"], MAX_LIST_JOBS_LIMIT);
+        assert_eq!(requests[0].json()["IndexName"], CREATED_AT_INDEX);
+        assert_eq!(requests[0].json()["ScanIndexForward"], false);
+    }
+}
@@ -0,0 +1,681 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::{Retention, timestamp_now};
+use crate::error::Error;
+
+// How long a generated query stays available for fetching later pages of its results
+const GENERATED_QUERY_TTL_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+// The SQL a question was answered with, kept so later pages re-run it instead of asking
+// the model again. Stored apart from the audit log so listing a job's queries doesn't
+// have to skip these.
+#[derive(Debug, Clone)]
+pub struct GeneratedQuery {
+    pub sql: String,
+    pub question: String,
+    // Empty when the caller wrote the SQL themselves
+    pub model_id: String,
+    // The views the SQL reads when it joins several jobs; empty when it reads `data`
+    pub datasets: Vec<QueryDataset>,
+    pub user_authored: bool,
+    // How much of the data an approximate answer's SQL was sampled to, in percent; None for
+    // an exact one
+    pub sample_percent: Option<f64>,
+}
+
+// A job's parquet output as one table of a multi-dataset query
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryDataset {
+    pub alias: String,
+    pub job_id: String,
+}
+
+pub async fn save_generated_query(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    query_id: &str,
+    query: &GeneratedQuery,
+) -> Result<(), Error> {
+    let pk = format!("SAVED-QUERY-{}", job_id);
+    let expires_at = Utc::now().timestamp() + GENERATED_QUERY_TTL_SECONDS;
+
+    let mut request = dynamodb_client
+        .put_item()
+        .table_name(table_name)
+        .item("service", AttributeValue::S(pk))
+        .item("serviceId", AttributeValue::S(query_id.to_string()))
+        .item("sql", AttributeValue::S(query.sql.clone()))
+        .item("question", AttributeValue::S(query.question.clone()))
+        .item("model_id", AttributeValue::S(query.model_id.clone()))
+        .item("user_authored", AttributeValue::Bool(query.user_authored))
+        .item("created_at", AttributeValue::S(timestamp_now()))
+        .item("expires_at", AttributeValue::N(expires_at.to_string()));
+    if !query.datasets.is_empty() {
+        let datasets = query
+            .datasets
+            .iter()
+            .map(|dataset| {
+                AttributeValue::M(HashMap::from([
+                    (
+                        "alias".to_string(),
+                        AttributeValue::S(dataset.alias.clone()),
+                    ),
+                    (
+                        "job_id".to_string(),
+                        AttributeValue::S(dataset.job_id.clone()),
+                    ),
+                ]))
+            })
+            .collect();
+        request = request.item("datasets", AttributeValue::L(datasets));
+    }
+    if let Some(sample_percent) = query.sample_percent {
+        request = request.item(
+            "sample_percent",
+            AttributeValue::N(sample_percent.to_string()),
+        );
+    }
+
+    request
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("PutItem", e))?;
+
+    Ok(())
+}
+
+// None for an unknown query_id, or one of a different job's queries
+pub async fn get_generated_query(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    query_id: &str,
+) -> Result<Option<GeneratedQuery>, Error> {
+    let pk = format!("SAVED-QUERY-{}", job_id);
+
+    let response = dynamodb_client
+        .get_item()
+        .table_name(table_name)
+        .key("service", AttributeValue::S(pk))
+        .key("serviceId", AttributeValue::S(query_id.to_string()))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("GetItem", e))?;
+
+    let item = match response.item {
+        Some(item) => item,
+        None => return Ok(None),
+    };
+
+    let attribute = |name: &str| {
+        item.get(name)
+            .and_then(|v| v.as_s().ok())
+            .cloned()
+            .unwrap_or_default()
+    };
+    let sql = attribute("sql");
+    if sql.is_empty() {
+        return Err(Error::dynamo_response(
+            "GetItem",
+            "generated query has no sql",
+        ));
+    }
+
+    let datasets = item
+        .get("datasets")
+        .and_then(|v| v.as_l().ok())
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| {
+                    let fields = entry.as_m().ok()?;
+                    Some(QueryDataset {
+                        alias: fields.get("alias")?.as_s().ok()?.clone(),
+                        job_id: fields.get("job_id")?.as_s().ok()?.clone(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Some(GeneratedQuery {
+        sql,
+        question: attribute("question"),
+        model_id: attribute("model_id"),
+        datasets,
+        user_authored: item
+            .get("user_authored")
+            .and_then(|v| v.as_bool().ok())
+            .copied()
+            .unwrap_or(false),
+        sample_percent: item
+            .get("sample_percent")
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse::<f64>().ok()),
+    }))
+}
+
+// One generate-query request as the audit log records it, whether it succeeded or not.
+// Fields the request never got far enough to know are left empty.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryAudit {
+    pub message: String,
+    pub principal: Option<String>,
+    pub query_id: Option<String>,
+    pub page: Option<u64>,
+    pub sql: Option<String>,
+    pub row_count: Option<u64>,
+    pub duration_ms: u64,
+    pub model_id: Option<String>,
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    pub estimated_usd: Option<f64>,
+    pub status_code: i64,
+    pub error: Option<String>,
+    // Whether sample rows were allowed into the SQL prompt; unset on entries from before
+    // the flag existed
+    pub sample_rows_enabled: Option<bool>,
+    // Whether the caller sent the SQL instead of a model writing it; unset on entries from
+    // before raw SQL was accepted
+    pub user_authored: Option<bool>,
+    // The model's first query when DuckDB rejected it and `sql` is the repaired one
+    pub failed_sql: Option<String>,
+    // Whether the summary model was called, which is most of a request's cost when it is;
+    // unset on entries from before summaries could be skipped and on requests that failed
+    // first
+    pub summarized: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryAuditRecord {
+    pub id: String,
+    pub recorded_at: String,
+    #[serde(flatten)]
+    pub audit: QueryAudit,
+}
+
+#[derive(Debug)]
+pub struct QueryAuditPage {
+    pub records: Vec<QueryAuditRecord>,
+    // Pass back as `cursor` for the next page; None once the history is exhausted
+    pub next_cursor: Option<String>,
+}
+
+// Appends to the job's query history. Keys sort by time, so listing the partition reads
+// the history in order; the uuid keeps two requests in the same millisecond apart.
+pub async fn record_query_audit(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    audit: &QueryAudit,
+) -> Result<(), Error> {
+    let pk = format!("QUERY-{}", job_id);
+    let sk = format!("{}#{}", timestamp_now(), uuid::Uuid::new_v4());
+
+    let mut request = dynamodb_client
+        .put_item()
+        .table_name(table_name)
+        .item("service", AttributeValue::S(pk))
+        .item("serviceId", AttributeValue::S(sk))
+        .item("message", AttributeValue::S(audit.message.clone()))
+        .item("expires_at", Retention::QueryAudit.attribute(Utc::now()))
+        .item(
+            "duration_ms",
+            AttributeValue::N(audit.duration_ms.to_string()),
+        )
+        .item(
+            "status_code",
+            AttributeValue::N(audit.status_code.to_string()),
+        );
+
+    let capped_sql = |sql: &Option<String>| {
+        sql.as_deref()
+            .map(|sql| cap_text(sql, MAX_STORED_SQL_BYTES))
+    };
+    let sql = capped_sql(&audit.sql);
+    let failed_sql = capped_sql(&audit.failed_sql);
+    let optional_text = [
+        ("principal", &audit.principal),
+        ("query_id", &audit.query_id),
+        ("sql", &sql),
+        ("failed_sql", &failed_sql),
+        ("model_id", &audit.model_id),
+        ("error", &audit.error),
+    ];
+    for (name, value) in optional_text {
+        if let Some(value) = value {
+            request = request.item(name, AttributeValue::S(value.clone()));
+        }
+    }
+    let optional_numbers = [
+        ("page", audit.page),
+        ("row_count", audit.row_count),
+        ("input_tokens", audit.input_tokens),
+        ("output_tokens", audit.output_tokens),
+    ];
+    for (name, value) in optional_numbers {
+        if let Some(value) = value {
+            request = request.item(name, AttributeValue::N(value.to_string()));
+        }
+    }
+    if let Some(estimated_usd) = audit.estimated_usd {
+        request = request.item(
+            "estimated_usd",
+            AttributeValue::N(estimated_usd.to_string()),
+        );
+    }
+    if let Some(sample_rows_enabled) = audit.sample_rows_enabled {
+        request = request.item(
+            "sample_rows_enabled",
+            AttributeValue::Bool(sample_rows_enabled),
+        );
+    }
+    if let Some(user_authored) = audit.user_authored {
+        request = request.item("user_authored", AttributeValue::Bool(user_authored));
+    }
+    if let Some(summarized) = audit.summarized {
+        request = request.item("summarized", AttributeValue::Bool(summarized));
+    }
+
+    request
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("PutItem", e))?;
+
+    Ok(())
+}
+
+// Up to `limit` of the job's audited queries, newest first, starting after `cursor`
+pub async fn list_query_audit(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+    limit: i32,
+    cursor: Option<&str>,
+) -> Result<QueryAuditPage, Error> {
+    let pk = format!("QUERY-{}", job_id);
+
+    let mut request = dynamodb_client
+        .query()
+        .table_name(table_name)
+        .key_condition_expression("service = :pk")
+        .expression_attribute_values(":pk", AttributeValue::S(pk.clone()))
+        .scan_index_forward(false)
+        .limit(limit);
+    if let Some(cursor) = cursor {
+        request = request
+            .exclusive_start_key("service", AttributeValue::S(pk))
+            .exclusive_start_key("serviceId", AttributeValue::S(cursor.to_string()));
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("Query", e))?;
+
+    let records = response
+        .items
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| {
+            let text = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+            let number = |name: &str| {
+                item.get(name)
+                    .and_then(|v| v.as_n().ok())
+                    .and_then(|n| n.parse::<u64>().ok())
+            };
+            let id = text("serviceId").unwrap_or_default();
+            let recorded_at = id.split('#').next().unwrap_or_default().to_string();
+            QueryAuditRecord {
+                audit: QueryAudit {
+                    message: text("message").unwrap_or_default(),
+                    principal: text("principal"),
+                    query_id: text("query_id"),
+                    page: number("page"),
+                    sql: text("sql"),
+                    row_count: number("row_count"),
+                    duration_ms: number("duration_ms").unwrap_or(0),
+                    model_id: text("model_id"),
+                    input_tokens: number("input_tokens"),
+                    output_tokens: number("output_tokens"),
+                    estimated_usd: item
+                        .get("estimated_usd")
+                        .and_then(|v| v.as_n().ok())
+                        .and_then(|n| n.parse::<f64>().ok()),
+                    status_code: number("status_code").unwrap_or(0) as i64,
+                    error: text("error"),
+                    sample_rows_enabled: item
+                        .get("sample_rows_enabled")
+                        .and_then(|v| v.as_bool().ok())
+                        .copied(),
+                    user_authored: item
+                        .get("user_authored")
+                        .and_then(|v| v.as_bool().ok())
+                        .copied(),
+                    failed_sql: text("failed_sql"),
+                    summarized: item
+                        .get("summarized")
+                        .and_then(|v| v.as_bool().ok())
+                        .copied(),
+                },
+                id,
+                recorded_at,
+            }
+        })
+        .collect();
+
+    let next_cursor = response
+        .last_evaluated_key
+        .as_ref()
+        .and_then(|key| key.get("serviceId"))
+        .and_then(|v| v.as_s().ok())
+        .cloned();
+
+    Ok(QueryAuditPage {
+        records,
+        next_cursor,
+    })
+}
+
+// Longest SQL kept on an audit entry or conversation turn, well inside DynamoDB's 400KB
+// item limit however many other attributes the item has
+const MAX_STORED_SQL_BYTES: usize = 16 * 1024;
+// Longest answer or result snippet kept on a conversation turn; enough for a follow-up
+// question to refer back to, not a copy of the results
+const MAX_TURN_TEXT_BYTES: usize = 8 * 1024;
+pub const MAX_RECENT_TURNS: usize = 50;
+
+// `text` cut to at most `max_bytes` on a character boundary, with `...` marking the cut
+pub fn cap_text(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut end = max_bytes.saturating_sub(3);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &text[..end])
+}
+
+// One question and answer in a conversation, kept so a follow-up question can be asked
+// with the earlier ones as context
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct QueryTurn {
+    // The job the question was asked of
+    pub job_id: String,
+    pub question: String,
+    pub sql: Option<String>,
+    pub answer: Option<String>,
+    // A few of the result rows as JSON
+    pub result_snippet: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueryTurnRecord {
+    pub id: String,
+    pub recorded_at: String,
+    #[serde(flatten)]
+    pub turn: QueryTurn,
+}
+
+// The item a turn is stored as, under a sort key that orders the conversation by time.
+// Long text is capped so no turn can outgrow the item limit.
+pub fn query_turn_item(
+    conversation_id: &str,
+    turn_id: &str,
+    turn: &QueryTurn,
+    now: DateTime<Utc>,
+) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        (
+            "service".to_string(),
+            AttributeValue::S(format!("CONVERSATION-{}", conversation_id)),
+        ),
+        (
+            "serviceId".to_string(),
+            AttributeValue::S(turn_id.to_string()),
+        ),
+        ("job_id".to_string(), AttributeValue::S(turn.job_id.clone())),
+        (
+            "question".to_string(),
+            AttributeValue::S(cap_text(&turn.question, MAX_TURN_TEXT_BYTES)),
+        ),
+        (
+            "expires_at".to_string(),
+            Retention::QueryTurn.attribute(now),
+        ),
+    ]);
+    let optional_text = [
+        ("sql", &turn.sql, MAX_STORED_SQL_BYTES),
+        ("answer", &turn.answer, MAX_TURN_TEXT_BYTES),
+        ("result_snippet", &turn.result_snippet, MAX_TURN_TEXT_BYTES),
+    ];
+    for (name, value, max_bytes) in optional_text {
+        if let Some(value) = value {
+            item.insert(
+                name.to_string(),
+                AttributeValue::S(cap_text(value, max_bytes)),
+            );
+        }
+    }
+    item
+}
+
+// A stored turn read back. Only the sort key is needed; anything else missing, as on a
+// turn written before the attribute existed, is left empty.
+pub fn query_turn_record(item: &HashMap<String, AttributeValue>) -> Option<QueryTurnRecord> {
+    let text = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+    let id = text("serviceId")?;
+    let recorded_at = id.split('#').next().unwrap_or_default().to_string();
+    Some(QueryTurnRecord {
+        turn: QueryTurn {
+            job_id: text("job_id").unwrap_or_default(),
+            question: text("question").unwrap_or_default(),
+            sql: text("sql"),
+            answer: text("answer"),
+            result_snippet: text("result_snippet"),
+        },
+        id,
+        recorded_at,
+    })
+}
+
+// Appends a turn to the conversation and returns its ID. Like the audit log, the key is
+// the time and a uuid, so two turns in the same millisecond stay apart.
+pub async fn put_query_turn(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    conversation_id: &str,
+    turn: &QueryTurn,
+) -> Result<String, Error> {
+    let turn_id = format!("{}#{}", timestamp_now(), uuid::Uuid::new_v4());
+
+    dynamodb_client
+        .put_item()
+        .table_name(table_name)
+        .set_item(Some(query_turn_item(
+            conversation_id,
+            &turn_id,
+            turn,
+            Utc::now(),
+        )))
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("PutItem", e))?;
+
+    Ok(turn_id)
+}
+
+// The conversation's last `n` turns, at most MAX_RECENT_TURNS, oldest first as they'd be
+// replayed to the model
+pub async fn get_recent_turns(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    conversation_id: &str,
+    n: usize,
+) -> Result<Vec<QueryTurnRecord>, Error> {
+    let n = n.min(MAX_RECENT_TURNS);
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+
+    let response = dynamodb_client
+        .query()
+        .table_name(table_name)
+        .key_condition_expression("service = :pk")
+        .expression_attribute_values(
+            ":pk",
+            AttributeValue::S(format!("CONVERSATION-{}", conversation_id)),
+        )
+        .scan_index_forward(false)
+        .limit(n as i32)
+        .send()
+        .await
+        .map_err(|e| Error::dynamo("Query", e))?;
+
+    let mut turns: Vec<QueryTurnRecord> = response
+        .items
+        .unwrap_or_default()
+        .iter()
+        .filter_map(query_turn_record)
+        .collect();
+    // Read newest first to get the latest `n`; keys sort by time, so sorting on them puts
+    // the turns back in the order they were asked
+    turns.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(turns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubResponse};
+    use serde_json::{Value, json};
+
+    fn stored_turn(id: &str, question: &str) -> Value {
+        json!({
+            "service": {"S": "CONVERSATION-c1"},
+            "serviceId": {"S": id},
+            "job_id": {"S": "job-1"},
+            "question": {"S": question}
+        })
+    }
+
+    #[test]
+    fn text_is_capped_on_a_character_boundary() {
+        assert_eq!(cap_text("short", 10), "short");
+        assert_eq!(cap_text("abcdefghij", 10), "abcdefghij");
+        assert_eq!(cap_text("abcdefghijk", 10), "abcdefg...");
+        // "é" is two bytes, so the cut backs off to the start of it
+        assert_eq!(cap_text("aéaaaa", 5), "a...");
+        assert!(cap_text(&"é".repeat(100), 51).len() <= 51);
+    }
+
+    #[test]
+    fn a_turn_item_caps_each_text_at_its_own_limit() {
+        let turn = QueryTurn {
+            job_id: "job-1".to_string(),
+            question: "q".repeat(MAX_TURN_TEXT_BYTES * 2),
+            sql: Some("s".repeat(MAX_STORED_SQL_BYTES * 2)),
+            answer: Some("a".repeat(MAX_TURN_TEXT_BYTES + 1)),
+            result_snippet: None,
+        };
+
+        let item = query_turn_item("c1", "2025-01-01T00:00:00.000Z#u", &turn, Utc::now());
+
+        let len = |name: &str| item[name].as_s().unwrap().len();
+        assert_eq!(len("question"), MAX_TURN_TEXT_BYTES);
+        assert_eq!(len("sql"), MAX_STORED_SQL_BYTES);
+        assert_eq!(len("answer"), MAX_TURN_TEXT_BYTES);
+        assert!(!item.contains_key("result_snippet"));
+        assert_eq!(item["service"].as_s().unwrap(), "CONVERSATION-c1");
+        assert!(item.contains_key("expires_at"));
+    }
+
+    #[test]
+    fn a_turn_missing_attributes_is_read_with_them_empty() {
+        let item = HashMap::from([(
+            "serviceId".to_string(),
+            AttributeValue::S("2025-01-01T00:00:00.000Z#u1".to_string()),
+        )]);
+
+        let record = query_turn_record(&item).unwrap();
+
+        assert_eq!(record.recorded_at, "2025-01-01T00:00:00.000Z");
+        assert_eq!(record.turn, QueryTurn::default());
+        assert_eq!(query_turn_record(&HashMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn a_turn_is_stored_under_a_time_ordered_key() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let turn = QueryTurn {
+            job_id: "job-1".to_string(),
+            question: "How many rows?".to_string(),
+            ..QueryTurn::default()
+        };
+
+        let turn_id = put_query_turn(&stub.dynamodb_client(), "jobs", "c1", &turn)
+            .await
+            .unwrap();
+
+        let (recorded_at, unique) = turn_id.split_once('#').unwrap();
+        assert!(DateTime::parse_from_rfc3339(recorded_at).is_ok());
+        assert!(uuid::Uuid::parse_str(unique).is_ok());
+        let item = &stub.operations("PutItem").remove(0).json()["Item"];
+        assert_eq!(item["serviceId"]["S"], turn_id);
+        assert_eq!(item["question"]["S"], "How many rows?");
+    }
+
+    #[tokio::test]
+    async fn recent_turns_come_back_oldest_first() {
+        // The query reads newest first
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::json(json!({"Items": [
+                stored_turn("2025-01-01T00:00:03.000Z#c", "third"),
+                stored_turn("2025-01-01T00:00:02.000Z#b", "second"),
+                {"serviceId": {"N": "1"}},
+                stored_turn("2025-01-01T00:00:01.000Z#a", "first")
+            ]}))
+        });
+
+        let turns = get_recent_turns(&stub.dynamodb_client(), "jobs", "c1", 3)
+            .await
+            .unwrap();
+
+        let questions: Vec<&str> = turns
+            .iter()
+            .map(|turn| turn.turn.question.as_str())
+            .collect();
+        assert_eq!(questions, ["first", "second", "third"]);
+        let request = stub.operations("Query").remove(0).json();
+        assert_eq!(request["ScanIndexForward"], false);
+        assert_eq!(request["Limit"], 3);
+        assert_eq!(
+            request["ExpressionAttributeValues"][":pk"]["S"],
+            "CONVERSATION-c1"
+        );
+    }
+
+    #[tokio::test]
+    async fn recent_turns_are_capped_and_none_reads_nothing() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({"Items": []})));
+        let client = stub.dynamodb_client();
+
+        assert!(
+            get_recent_turns(&client, "jobs", "c1", 0)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(stub.requests().is_empty());
+
+        get_recent_turns(&client, "jobs", "c1", 1000).await.unwrap();
+        let request = stub.operations("Query").remove(0).json();
+        assert_eq!(request["Limit"], MAX_RECENT_TURNS);
+    }
+}
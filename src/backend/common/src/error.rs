@@ -0,0 +1,341 @@
+use arrow::error::ArrowError;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use parquet::errors::ParquetError;
+
+// Service error codes AWS uses for throttling and transient faults; anything else with a
+// 4xx status is a problem with the request itself and will fail the same way again
+const RETRYABLE_ERROR_CODES: &[&str] = &[
+    "ThrottlingException",
+    "Throttling",
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+    "TransactionConflictException",
+    "SlowDown",
+    "RequestTimeout",
+    "InternalError",
+    "ServiceUnavailable",
+];
+
+// What sort of failure a DuckDB error is, read from the type DuckDB starts its message with.
+// Catalog errors, an unknown table or function, are binding failures like an unknown column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuckDbErrorKind {
+    Syntax,
+    Binder,
+    OutOfMemory,
+    Interrupted,
+    Io,
+    Other,
+}
+
+impl DuckDbErrorKind {
+    pub fn of(message: &str) -> Self {
+        let error_type = message
+            .split_once(" Error:")
+            .map(|(error_type, _)| error_type)
+            .unwrap_or("");
+        match error_type {
+            "Parser" => DuckDbErrorKind::Syntax,
+            "Binder" | "Catalog" => DuckDbErrorKind::Binder,
+            "Out of Memory" => DuckDbErrorKind::OutOfMemory,
+            "INTERRUPT" => DuckDbErrorKind::Interrupted,
+            "IO" | "HTTP" => DuckDbErrorKind::Io,
+            _ => DuckDbErrorKind::Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DuckDbErrorKind::Syntax => "syntax",
+            DuckDbErrorKind::Binder => "binder",
+            DuckDbErrorKind::OutOfMemory => "out_of_memory",
+            DuckDbErrorKind::Interrupted => "interrupted",
+            DuckDbErrorKind::Io => "io",
+            DuckDbErrorKind::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("S3 {operation} failed: {message}")]
+    S3 {
+        operation: &'static str,
+        message: String,
+        retryable: bool,
+    },
+    #[error("DynamoDB {operation} failed: {message}")]
+    DynamoDb {
+        operation: &'static str,
+        message: String,
+        retryable: bool,
+    },
+    #[error("CSV parse error on line {line}: {reason}")]
+    CsvParse { line: u64, reason: String },
+    #[error("value {value:?} in column '{column}' could not be coerced to the column type")]
+    TypeCoercion { column: String, value: String },
+    #[error("Parquet write failed: {0}")]
+    ParquetWrite(String),
+    #[error("DuckDB query failed: {message}")]
+    DuckDb {
+        kind: DuckDbErrorKind,
+        message: String,
+    },
+    #[error("query was interrupted after running for {0:?}")]
+    QueryTimeout(std::time::Duration),
+    #[error("configuration error: {0}")]
+    Config(String),
+    #[error("{0:?} can't name a view")]
+    InvalidViewName(String),
+    #[error(transparent)]
+    NotReadOnly(#[from] crate::duck_db::SqlRejection),
+    #[error("DuckDB extension {name} is unavailable: {details}")]
+    Extension { name: &'static str, details: String },
+    #[error("notification delivery failed: {0}")]
+    Notification(String),
+    #[error("invalid cursor: {0}")]
+    InvalidCursor(&'static str),
+}
+
+impl Error {
+    pub fn s3<E>(operation: &'static str, error: SdkError<E>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+    {
+        Error::S3 {
+            operation,
+            retryable: sdk_error_is_retryable(&error),
+            message: DisplayErrorContext(&error).to_string(),
+        }
+    }
+
+    // The object body stream failing part way is a network problem, so always worth a retry
+    pub fn s3_stream<E: std::fmt::Display>(error: E) -> Self {
+        Error::S3 {
+            operation: "GetObject",
+            message: error.to_string(),
+            retryable: true,
+        }
+    }
+
+    pub fn dynamo<E>(operation: &'static str, error: SdkError<E>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+    {
+        Error::DynamoDb {
+            operation,
+            retryable: sdk_error_is_retryable(&error),
+            message: DisplayErrorContext(&error).to_string(),
+        }
+    }
+
+    // For a response that arrived but didn't contain what we asked for
+    pub fn dynamo_response(operation: &'static str, message: impl Into<String>) -> Self {
+        Error::DynamoDb {
+            operation,
+            message: message.into(),
+            retryable: false,
+        }
+    }
+
+    pub fn duckdb_kind(&self) -> Option<DuckDbErrorKind> {
+        match self {
+            Error::DuckDb { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    // The error in words that can go back to a caller. DuckDB's messages quote the SQL they
+    // failed on and the paths of the files they read, and AWS errors name buckets, keys and
+    // request IDs, so only what names the problem is kept from them; the full message is
+    // for the logs.
+    pub fn user_message(&self) -> String {
+        match self {
+            Error::DuckDb { kind, message } => duckdb_user_message(*kind, message),
+            Error::S3 { operation, .. } => format!("S3 {} failed", operation),
+            Error::DynamoDb { operation, .. } => format!("DynamoDB {} failed", operation),
+            other => other.to_string(),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::S3 { retryable, .. } | Error::DynamoDb { retryable, .. } => *retryable,
+            Error::CsvParse { .. }
+            | Error::TypeCoercion { .. }
+            | Error::ParquetWrite(_)
+            | Error::DuckDb { .. }
+            | Error::QueryTimeout(_)
+            | Error::Config(_)
+            | Error::InvalidViewName(_)
+            | Error::NotReadOnly(_)
+            | Error::Extension { .. }
+            | Error::Notification(_)
+            | Error::InvalidCursor(_) => false,
+        }
+    }
+}
+
+impl From<duckdb::Error> for Error {
+    fn from(error: duckdb::Error) -> Self {
+        let message = error.to_string();
+        Error::DuckDb {
+            kind: DuckDbErrorKind::of(&message),
+            message,
+        }
+    }
+}
+
+// The text between `start` and the next `end`
+fn between<'a>(text: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    let (_, rest) = text.split_once(start)?;
+    rest.split_once(end).map(|(inside, _)| inside)
+}
+
+fn duckdb_user_message(kind: DuckDbErrorKind, message: &str) -> String {
+    // The first line says what went wrong; after it come candidates and the SQL itself
+    let first_line = message.lines().next().unwrap_or("");
+    let reason = first_line
+        .split_once(" Error: ")
+        .map(|(_, reason)| reason)
+        .unwrap_or(first_line);
+    match kind {
+        DuckDbErrorKind::Syntax => format!("The query isn't valid SQL: {}", reason),
+        DuckDbErrorKind::Binder => binder_user_message(message, reason),
+        DuckDbErrorKind::OutOfMemory => {
+            "The query needed more memory than is available. Try a narrower question.".to_string()
+        }
+        DuckDbErrorKind::Interrupted => "The query was stopped before it finished.".to_string(),
+        DuckDbErrorKind::Io => "The data for the query couldn't be read.".to_string(),
+        DuckDbErrorKind::Other => "The query failed to run.".to_string(),
+    }
+}
+
+fn binder_user_message(message: &str, reason: &str) -> String {
+    if let Some(column) = between(reason, "Referenced column \"", "\" not found") {
+        let suggestion = message
+            .lines()
+            .find_map(|line| line.strip_prefix("Candidate bindings: "))
+            .and_then(|candidates| between(candidates, "\"", "\""));
+        return match suggestion {
+            Some(suggestion) => format!(
+                "The query referenced a column that doesn't exist: '{}'. Did you mean '{}'?",
+                column, suggestion
+            ),
+            None => format!(
+                "The query referenced a column that doesn't exist: '{}'",
+                column
+            ),
+        };
+    }
+    if let Some(table) = between(reason, "Table with name ", " does not exist") {
+        return format!(
+            "The query referenced a table that doesn't exist: '{}'",
+            table
+        );
+    }
+    if let Some(function) = between(reason, "Function with name ", " does not exist") {
+        return format!(
+            "The query called a function that doesn't exist: '{}'",
+            function
+        );
+    }
+    format!("The query doesn't fit the data: {}", reason)
+}
+
+impl From<ArrowError> for Error {
+    fn from(error: ArrowError) -> Self {
+        Error::ParquetWrite(error.to_string())
+    }
+}
+
+impl From<ParquetError> for Error {
+    fn from(error: ParquetError) -> Self {
+        Error::ParquetWrite(error.to_string())
+    }
+}
+
+pub fn sdk_error_is_retryable<E>(error: &SdkError<E>) -> bool
+where
+    E: ProvideErrorMetadata,
+{
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(service_error) => {
+            let status = service_error.raw().status().as_u16();
+            status == 429
+                || status >= 500
+                || error
+                    .code()
+                    .is_some_and(|code| RETRYABLE_ERROR_CODES.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duckdb_error(message: &str) -> Error {
+        Error::DuckDb {
+            kind: DuckDbErrorKind::of(message),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn aws_errors_keep_their_details_out_of_the_user_message() {
+        let error = Error::S3 {
+            operation: "HeadObject",
+            message: "service error: NoSuchBucket my-bucket (request id ABC123)".to_string(),
+            retryable: false,
+        };
+        assert_eq!(error.user_message(), "S3 HeadObject failed");
+        assert!(error.to_string().contains("my-bucket"));
+
+        let error = Error::dynamo_response("PutItem", "ValidationException on table prod-jobs");
+        assert_eq!(error.user_message(), "DynamoDB PutItem failed");
+    }
+
+    #[test]
+    fn duckdb_errors_are_classified_by_their_type() {
+        assert_eq!(
+            DuckDbErrorKind::of("Parser Error: syntax error at or near \"SELEC\""),
+            DuckDbErrorKind::Syntax
+        );
+        assert_eq!(
+            DuckDbErrorKind::of("Catalog Error: Table with name t does not exist!"),
+            DuckDbErrorKind::Binder
+        );
+        assert_eq!(
+            DuckDbErrorKind::of("INTERRUPT Error: Interrupted!"),
+            DuckDbErrorKind::Interrupted
+        );
+        assert_eq!(
+            DuckDbErrorKind::of("something else"),
+            DuckDbErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn duckdb_user_messages_drop_the_sql_and_paths() {
+        let error = duckdb_error(
+            "Binder Error: Referenced column \"totl\" not found in FROM clause!\n\
+             Candidate bindings: \"total\", \"tax\"\n\
+             LINE 1: SELECT totl FROM '/tmp/scratch/abc.parquet'",
+        );
+        assert_eq!(
+            error.user_message(),
+            "The query referenced a column that doesn't exist: 'totl'. Did you mean 'total'?"
+        );
+
+        let error = duckdb_error("IO Error: No files found that match /tmp/x/*");
+        assert_eq!(
+            error.user_message(),
+            "The data for the query couldn't be read."
+        );
+    }
+}
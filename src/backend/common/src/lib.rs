@@ -1,11 +1,34 @@
+pub mod auth;
+pub mod bedrock;
+pub mod chart_shape;
+pub mod column_matching;
 pub mod cors;
 pub mod creation_parsing;
 pub mod creation_types;
+pub mod creation_validation;
 pub mod duck_db;
 pub mod dynamo;
+pub mod error;
+pub mod logging;
+pub mod memory;
+pub mod metrics;
+pub mod notifications;
+pub mod parquet_cache;
 pub mod parquet_creation;
 pub mod parquet_creation_processor;
 pub mod parquet_query;
+pub mod processing_error;
+pub mod progress;
+pub mod query_events;
 pub mod query_prompts;
+pub mod query_result;
 pub mod s3;
+pub mod sqs;
 pub mod test_creation_processor;
+// Not behind cfg(test), so the lambdas' own tests can stub their AWS clients too
+#[doc(hidden)]
+pub mod test_support;
+pub mod tmp_manager;
+pub mod type_inference;
+pub mod warm_duckdb;
+pub mod xray;
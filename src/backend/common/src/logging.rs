@@ -0,0 +1,98 @@
+use std::env;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::xray::XRayLayer;
+
+// Longest excerpt of a request body or payload that debug logs will include
+pub const MAX_LOGGED_BODY_CHARS: usize = 256;
+
+// Sets up JSON logging for a lambda so CloudWatch can filter on fields such as job_id.
+// LOG_LEVEL overrides the default `info` level; payload dumps only appear at `debug`.
+// The X-Ray exporter is added when TRACE_EXPORTER=xray so local runs don't need a daemon.
+pub fn init_tracing() {
+    let fmt_layer = fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_target(false)
+        .without_time();
+
+    let level = env::var("LOG_LEVEL")
+        .ok()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::INFO);
+
+    let xray_layer = match env::var("TRACE_EXPORTER").as_deref() {
+        Ok("xray") => match XRayLayer::from_env() {
+            Ok(layer) => Some(layer),
+            Err(e) => {
+                eprintln!("X-Ray exporter disabled: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(level)
+        .with(fmt_layer)
+        .with(xray_layer)
+        .init();
+}
+
+// Keeps the start of a body so logs show its shape without copying user data wholesale
+pub fn redact(body: &str) -> String {
+    match body.char_indices().nth(MAX_LOGGED_BODY_CHARS) {
+        Some((end, _)) => format!(
+            "{}... ({} more bytes redacted)",
+            &body[..end],
+            body.len() - end
+        ),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_short_body_is_logged_whole() {
+        let body = "a".repeat(MAX_LOGGED_BODY_CHARS);
+
+        assert_eq!(redact(&body), body);
+        assert_eq!(redact(""), "");
+    }
+
+    #[test]
+    fn a_long_body_keeps_its_start_and_counts_the_rest() {
+        let body = format!("{}{}", "a".repeat(MAX_LOGGED_BODY_CHARS), "secret");
+
+        assert_eq!(
+            redact(&body),
+            format!(
+                "{}... (6 more bytes redacted)",
+                "a".repeat(MAX_LOGGED_BODY_CHARS)
+            )
+        );
+    }
+
+    #[test]
+    fn truncation_counts_characters_and_never_splits_one() {
+        // Two bytes each, so a byte-based cut would land inside a character
+        let body = "é".repeat(MAX_LOGGED_BODY_CHARS + 3);
+
+        let redacted = redact(&body);
+
+        assert_eq!(
+            redacted,
+            format!(
+                "{}... (6 more bytes redacted)",
+                "é".repeat(MAX_LOGGED_BODY_CHARS)
+            )
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+// Used when AWS_LAMBDA_FUNCTION_MEMORY_SIZE isn't set, e.g. running locally
+const DEFAULT_MEMORY_MB: usize = 3008;
+
+// Share of the function's memory the pipeline may hold in flight; the rest covers the
+// runtime, the S3 read buffer and allocator overhead
+const IN_FLIGHT_BUDGET_PERCENT: usize = 60;
+
+// Allocated bytes above this share of the function's memory trigger smaller batches
+const HIGH_WATER_PERCENT: usize = 75;
+
+const MIN_ROWS_PER_BATCH: usize = 50_000;
+
+// The memory the function is configured with, in MB
+pub fn function_memory_mb() -> usize {
+    env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")
+        .ok()
+        .and_then(|size| size.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MEMORY_MB)
+}
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+// Wraps the system allocator to keep a running count of live heap bytes. A binary opts in
+// with `#[global_allocator]`; without it `allocated_bytes` stays at zero and the governor
+// falls back to the in-flight estimates alone.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            record_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+fn record_alloc(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+}
+
+pub fn allocated_bytes() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+// Highest allocated_bytes seen since the last reset_peak_allocated
+pub fn peak_allocated_bytes() -> usize {
+    PEAK_ALLOCATED.load(Ordering::Relaxed)
+}
+
+// Warm invocations share the process, so each job starts its peak from the current usage
+pub fn reset_peak_allocated() {
+    PEAK_ALLOCATED.store(allocated_bytes(), Ordering::Relaxed);
+}
+
+// Keeps the reader and writer from outgrowing the Lambda together. The reader asks for
+// room before handing a batch over and waits while batches queued for the writer plus the
+// writer's own buffer are over budget; once the heap crosses the high-water mark, batches
+// get smaller for the rest of the job.
+#[derive(Debug)]
+pub struct MemoryGovernor {
+    budget_bytes: usize,
+    high_water_bytes: usize,
+    rows_per_batch: AtomicUsize,
+    queued_bytes: AtomicUsize,
+    writer_bytes: AtomicUsize,
+    peak_in_flight_bytes: AtomicUsize,
+    released: Notify,
+}
+
+impl MemoryGovernor {
+    pub fn new(memory_bytes: usize, rows_per_batch: usize) -> Self {
+        MemoryGovernor {
+            budget_bytes: memory_bytes / 100 * IN_FLIGHT_BUDGET_PERCENT,
+            high_water_bytes: memory_bytes / 100 * HIGH_WATER_PERCENT,
+            rows_per_batch: AtomicUsize::new(rows_per_batch),
+            queued_bytes: AtomicUsize::new(0),
+            writer_bytes: AtomicUsize::new(0),
+            peak_in_flight_bytes: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn from_env(rows_per_batch: usize) -> Self {
+        Self::new(function_memory_mb() * 1024 * 1024, rows_per_batch)
+    }
+
+    pub fn rows_per_batch(&self) -> usize {
+        self.rows_per_batch.load(Ordering::Relaxed)
+    }
+
+    // Called by the reader after each batch. Halves the batch size while the heap is over
+    // the high-water mark; it never grows back, since the rows that caused the spike are
+    // likely to keep coming.
+    pub fn adapt(&self, job_id: &str) {
+        let allocated = allocated_bytes();
+        if allocated < self.high_water_bytes {
+            return;
+        }
+
+        let before = self.rows_per_batch();
+        let after = (before / 2).max(MIN_ROWS_PER_BATCH);
+        if after == before {
+            return;
+        }
+
+        self.rows_per_batch.store(after, Ordering::Relaxed);
+        warn!(
+            job_id,
+            allocated_bytes = allocated,
+            high_water_bytes = self.high_water_bytes,
+            rows_per_batch_before = before,
+            rows_per_batch_after = after,
+            "Memory above high-water mark, shrinking batches"
+        );
+    }
+
+    // Waits until a batch of `bytes` fits in the budget, then counts it as queued. A batch
+    // is always let through when nothing is queued, as waiting couldn't free anything.
+    pub async fn reserve(&self, bytes: usize, job_id: &str) {
+        let mut paused = false;
+        loop {
+            let released = self.released.notified();
+            let queued = self.queued_bytes.load(Ordering::Acquire);
+            let in_flight = queued + self.writer_bytes.load(Ordering::Acquire);
+
+            if queued == 0 || in_flight + bytes <= self.budget_bytes {
+                break;
+            }
+
+            if !paused {
+                info!(
+                    job_id,
+                    in_flight_bytes = in_flight,
+                    batch_bytes = bytes,
+                    budget_bytes = self.budget_bytes,
+                    "In-flight memory over budget, pausing reader"
+                );
+                paused = true;
+            }
+            released.await;
+        }
+
+        if paused {
+            info!(
+                job_id,
+                in_flight_bytes = self.in_flight_bytes(),
+                "Resuming reader"
+            );
+        }
+
+        self.queued_bytes.fetch_add(bytes, Ordering::AcqRel);
+        self.record_in_flight();
+    }
+
+    // Called by the writer once a queued batch has been handed to the parquet writer
+    pub fn release(&self, bytes: usize) {
+        self.queued_bytes.fetch_sub(bytes, Ordering::AcqRel);
+        self.released.notify_waiters();
+    }
+
+    // The writer reports how much it is holding itself: the encoded output so far plus
+    // the row group it is still building
+    pub fn set_writer_bytes(&self, bytes: usize) {
+        let before = self.writer_bytes.swap(bytes, Ordering::AcqRel);
+        self.record_in_flight();
+        if bytes < before {
+            self.released.notify_waiters();
+        }
+    }
+
+    pub fn in_flight_bytes(&self) -> usize {
+        self.queued_bytes.load(Ordering::Acquire) + self.writer_bytes.load(Ordering::Acquire)
+    }
+
+    // The larger of the measured heap peak and the in-flight peak, so the figure is still
+    // meaningful when the counting allocator isn't installed
+    pub fn high_water_mark(&self) -> usize {
+        peak_allocated_bytes().max(self.peak_in_flight_bytes.load(Ordering::Relaxed))
+    }
+
+    fn record_in_flight(&self) {
+        self.peak_in_flight_bytes
+            .fetch_max(self.in_flight_bytes(), Ordering::Relaxed);
+    }
+}
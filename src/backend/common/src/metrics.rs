@@ -0,0 +1,195 @@
+use serde_json::{Map, Value, json};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const METRICS_NAMESPACE: &str = "BeyondCSV";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricUnit {
+    Count,
+    Bytes,
+    Milliseconds,
+}
+
+impl MetricUnit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricUnit::Count => "Count",
+            MetricUnit::Bytes => "Bytes",
+            MetricUnit::Milliseconds => "Milliseconds",
+        }
+    }
+}
+
+// Collects metrics for one job or request and prints them as a CloudWatch Embedded
+// Metric Format line. Lambda ships stdout to CloudWatch Logs, which turns the line into
+// metrics under the `function` and `dataset` dimensions without any extra API calls.
+#[derive(Debug, Clone)]
+pub struct MetricsLogger {
+    dimensions: Vec<(String, String)>,
+    metrics: Vec<(String, f64, MetricUnit)>,
+}
+
+impl MetricsLogger {
+    pub fn new(function: &str, dataset: &str) -> Self {
+        MetricsLogger {
+            dimensions: vec![
+                ("function".to_string(), function.to_string()),
+                ("dataset".to_string(), dataset.to_string()),
+            ],
+            metrics: Vec::new(),
+        }
+    }
+
+    // EMF rejects a document that defines the same metric twice, so a repeated name
+    // replaces the earlier value
+    pub fn put_metric(&mut self, name: &str, value: f64, unit: MetricUnit) {
+        match self
+            .metrics
+            .iter_mut()
+            .find(|(existing, _, _)| existing == name)
+        {
+            Some(metric) => {
+                metric.1 = value;
+                metric.2 = unit;
+            }
+            None => self.metrics.push((name.to_string(), value, unit)),
+        }
+    }
+
+    pub fn put_count(&mut self, name: &str, value: u64) {
+        self.put_metric(name, value as f64, MetricUnit::Count);
+    }
+
+    pub fn put_bytes(&mut self, name: &str, value: u64) {
+        self.put_metric(name, value as f64, MetricUnit::Bytes);
+    }
+
+    pub fn put_duration(&mut self, name: &str, duration: Duration) {
+        self.put_metric(
+            name,
+            duration.as_secs_f64() * 1000.0,
+            MetricUnit::Milliseconds,
+        );
+    }
+
+    pub fn to_emf(&self, timestamp_ms: u64) -> Value {
+        let dimension_names: Vec<&str> = self
+            .dimensions
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        let metric_definitions: Vec<Value> = self
+            .metrics
+            .iter()
+            .map(|(name, _, unit)| json!({ "Name": name, "Unit": unit.as_str() }))
+            .collect();
+
+        let mut document = Map::new();
+        document.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": timestamp_ms,
+                "CloudWatchMetrics": [{
+                    "Namespace": METRICS_NAMESPACE,
+                    "Dimensions": [dimension_names],
+                    "Metrics": metric_definitions
+                }]
+            }),
+        );
+
+        for (name, value) in &self.dimensions {
+            document.insert(name.clone(), Value::String(value.clone()));
+        }
+        for (name, value, _) in &self.metrics {
+            document.insert(name.clone(), json!(value));
+        }
+
+        Value::Object(document)
+    }
+
+    // Prints everything collected so far and starts a fresh set of metrics
+    pub fn flush(&mut self) {
+        if self.metrics.is_empty() {
+            return;
+        }
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+        // Written straight to stdout rather than through tracing: CloudWatch only extracts
+        // metrics from log lines that are the bare EMF document
+        println!("{}", self.to_emf(timestamp_ms));
+        self.metrics.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_document_declares_its_namespace_dimensions_and_units() {
+        let mut metrics = MetricsLogger::new("parquet-creation-processor", "job-1");
+        metrics.put_count("RowsProcessed", 1200);
+        metrics.put_bytes("BytesRead", 4096);
+        metrics.put_duration("JobDuration", Duration::from_millis(1500));
+
+        let document = metrics.to_emf(1_700_000_000_000);
+
+        assert_eq!(
+            document["_aws"],
+            json!({
+                "Timestamp": 1_700_000_000_000u64,
+                "CloudWatchMetrics": [{
+                    "Namespace": "BeyondCSV",
+                    "Dimensions": [["function", "dataset"]],
+                    "Metrics": [
+                        {"Name": "RowsProcessed", "Unit": "Count"},
+                        {"Name": "BytesRead", "Unit": "Bytes"},
+                        {"Name": "JobDuration", "Unit": "Milliseconds"}
+                    ]
+                }]
+            })
+        );
+        // Every dimension and metric named above has its value at the top level
+        assert_eq!(document["function"], "parquet-creation-processor");
+        assert_eq!(document["dataset"], "job-1");
+        assert_eq!(document["RowsProcessed"], 1200.0);
+        assert_eq!(document["BytesRead"], 4096.0);
+        assert_eq!(document["JobDuration"], 1500.0);
+    }
+
+    #[test]
+    fn a_repeated_metric_is_defined_once_with_its_last_value() {
+        let mut metrics = MetricsLogger::new("poller", "job-1");
+        metrics.put_count("JobsFailed", 0);
+        metrics.put_count("JobsFailed", 1);
+
+        let document = metrics.to_emf(0);
+
+        let definitions = document["_aws"]["CloudWatchMetrics"][0]["Metrics"]
+            .as_array()
+            .unwrap();
+        assert_eq!(definitions.len(), 1);
+        assert_eq!(document["JobsFailed"], 1.0);
+    }
+
+    #[test]
+    fn flushing_starts_a_fresh_set_of_metrics() {
+        let mut metrics = MetricsLogger::new("poller", "job-1");
+        metrics.put_count("Polls", 1);
+
+        metrics.flush();
+
+        let document = metrics.to_emf(0);
+        assert_eq!(
+            document["_aws"]["CloudWatchMetrics"][0]["Metrics"],
+            json!([])
+        );
+        assert!(document.get("Polls").is_none());
+        // The dimensions carry over to the next set
+        assert_eq!(document["dataset"], "job-1");
+    }
+}
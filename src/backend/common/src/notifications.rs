@@ -0,0 +1,185 @@
+use aws_sdk_sns::Client as SnsClient;
+use aws_sdk_sns::error::DisplayErrorContext;
+use aws_sdk_sns::types::MessageAttributeValue;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::creation_types::NotifySettings;
+use crate::dynamo::JobStatus;
+use crate::error::Error;
+use crate::processing_error::ProcessingError;
+
+pub const SIGNATURE_HEADER: &str = "X-BeyondCSV-Signature";
+
+// Webhook deliveries are retried on timeouts, 429s and 5xx responses; any other status
+// means the receiver rejected the event and will do so again
+const WEBHOOK_ATTEMPTS: u32 = 3;
+const WEBHOOK_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What a caller is told when their job reaches a final status. Events are delivered at
+// least once, so receivers should expect the occasional repeat for the same job_id.
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionEvent {
+    pub job_id: String,
+    pub status: JobStatus,
+    pub output_key: Option<String>,
+    pub row_count: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl CompletionEvent {
+    pub fn succeeded(job_id: &str, output_key: &str, row_count: u64) -> Self {
+        CompletionEvent {
+            job_id: job_id.to_string(),
+            status: JobStatus::Success,
+            output_key: Some(output_key.to_string()),
+            row_count: Some(row_count),
+            error: None,
+        }
+    }
+
+    pub fn failed(job_id: &str, error: &ProcessingError) -> Self {
+        CompletionEvent {
+            job_id: job_id.to_string(),
+            status: JobStatus::Failed,
+            output_key: None,
+            row_count: None,
+            error: Some(error.summary()),
+        }
+    }
+}
+
+// Hex HMAC-SHA256 of the exact body sent, in the `sha256=<hex>` form receivers compare
+// against
+pub fn sign_payload(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(body);
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", signature)
+}
+
+// Sends the event to every target in `settings`, trying each one even if another fails.
+// Any failures are returned together for the caller to record on the job.
+pub async fn send_completion_notification(
+    settings: &NotifySettings,
+    event: &CompletionEvent,
+) -> Result<(), Error> {
+    let body = serde_json::to_string(event)
+        .map_err(|e| Error::Notification(format!("could not serialize event: {}", e)))?;
+
+    let mut failures = Vec::new();
+
+    if let Some(topic_arn) = &settings.sns_topic_arn {
+        let published = publish_to_topic(topic_arn, event.status.as_str(), &body).await;
+        if let Err(e) = published {
+            failures.push(format!("SNS: {}", e));
+        }
+    }
+
+    if let Some(webhook_url) = &settings.webhook_url {
+        let posted = post_to_webhook(webhook_url, &body).await;
+        if let Err(e) = posted {
+            failures.push(format!("webhook: {}", e));
+        }
+    }
+
+    if failures.is_empty() {
+        info!(job_id = %event.job_id, status = event.status.as_str(), "Sent completion notification");
+        Ok(())
+    } else {
+        Err(Error::Notification(failures.join("; ")))
+    }
+}
+
+// The SDK's own retry policy covers throttling and transient faults
+async fn publish_to_topic(topic_arn: &str, status: &str, body: &str) -> Result<(), String> {
+    let config = aws_config::load_from_env().await;
+    let sns_client = SnsClient::new(&config);
+
+    // Lets subscribers filter on the outcome without parsing the message
+    let status_attribute = MessageAttributeValue::builder()
+        .data_type("String")
+        .string_value(status)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    sns_client
+        .publish()
+        .topic_arn(topic_arn)
+        .message(body)
+        .message_attributes("status", status_attribute)
+        .send()
+        .await
+        .map_err(|e| DisplayErrorContext(&e).to_string())?;
+
+    Ok(())
+}
+
+fn http_client() -> Result<&'static reqwest::Client, String> {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        // A redirect could carry the signed event somewhere the caller never configured
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| e.to_string())?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+async fn post_to_webhook(webhook_url: &str, body: &str) -> Result<(), String> {
+    // Never send an unsigned event; receivers have no other way to trust it
+    let secret = std::env::var("WEBHOOK_SIGNING_SECRET")
+        .map_err(|_| "WEBHOOK_SIGNING_SECRET is not set".to_string())?;
+    let signature = sign_payload(secret.as_bytes(), body.as_bytes());
+    let client = http_client()?;
+
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .post(webhook_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.to_string())
+            .send()
+            .await;
+
+        let (message, retryable) = match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                (
+                    format!("receiver responded {}", status),
+                    status.as_u16() == 429 || status.is_server_error(),
+                )
+            }
+            Err(e) => (e.to_string(), e.is_timeout() || e.is_connect()),
+        };
+
+        if !retryable || attempt >= WEBHOOK_ATTEMPTS {
+            return Err(format!("{} after {} attempt(s)", message, attempt));
+        }
+
+        let delay = WEBHOOK_RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+        warn!(
+            attempt,
+            delay_ms = delay.as_millis() as u64,
+            error = %message,
+            "Webhook delivery failed, retrying"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
@@ -0,0 +1,420 @@
+use aws_sdk_s3::Client as S3Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::error::Error;
+use crate::s3::SourceObject;
+
+// /tmp is 512 MB by default on Lambda; the rest is left for DuckDB's spill files
+pub const DEFAULT_CACHE_BUDGET_BYTES: u64 = 400 * 1024 * 1024;
+
+const CACHE_DIR: &str = "/tmp/parquet-cache";
+const PARQUET_EXTENSION: &str = "parquet";
+const META_EXTENSION: &str = "meta";
+const PARTIAL_EXTENSION: &str = "partial";
+
+// Sidecar written next to each cached file once its download has finished, so a file
+// without one is never trusted
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct CacheMeta {
+    key: String,
+    etag: String,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct CachedParquet {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub hit: bool,
+    // Cached files removed to make room for this one
+    pub evicted: usize,
+}
+
+// Parquet files downloaded by earlier invocations of a warm container. Entries are keyed
+// by a hash of the S3 key, as different jobs' outputs can share a file name, and are
+// evicted least recently used first to keep the directory within `budget_bytes`.
+#[derive(Debug, Clone)]
+pub struct ParquetCache {
+    dir: PathBuf,
+    budget_bytes: u64,
+}
+
+impl ParquetCache {
+    pub fn new(dir: impl Into<PathBuf>, budget_bytes: u64) -> Self {
+        ParquetCache {
+            dir: dir.into(),
+            budget_bytes,
+        }
+    }
+
+    // Budget from PARQUET_CACHE_BUDGET_BYTES, falling back to the default when unset or
+    // unparsable
+    pub fn from_env() -> Self {
+        let budget_bytes = std::env::var("PARQUET_CACHE_BUDGET_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_CACHE_BUDGET_BYTES);
+        ParquetCache::new(CACHE_DIR, budget_bytes)
+    }
+
+    pub fn budget_bytes(&self) -> u64 {
+        self.budget_bytes
+    }
+
+    // Deletes downloads that never finished. A failed download removes its own, but one cut
+    // off by a timeout is left behind, holding space eviction doesn't count. Only call this
+    // when no download is running. Returns how many were removed.
+    pub fn remove_partials(&self) -> usize {
+        let Ok(read_dir) = fs::read_dir(&self.dir) else {
+            return 0;
+        };
+        read_dir
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == PARTIAL_EXTENSION)
+            })
+            .filter(|path| fs::remove_file(path).is_ok())
+            .count()
+    }
+
+    fn entry_path(&self, key: &str, extension: &str) -> PathBuf {
+        let digest = Sha256::digest(key.as_bytes());
+        let name: String = digest
+            .iter()
+            .take(16)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.dir.join(format!("{}.{}", name, extension))
+    }
+
+    // The cached copy of `key`, if there is one for exactly this version of the object.
+    // A hit counts as a use for eviction; a stale entry is removed.
+    pub fn lookup(&self, key: &str, etag: &str, bytes: u64) -> Option<PathBuf> {
+        let path = self.entry_path(key, PARQUET_EXTENSION);
+        let meta_path = self.entry_path(key, META_EXTENSION);
+
+        let meta: CacheMeta = serde_json::from_slice(&fs::read(&meta_path).ok()?).ok()?;
+        let on_disk = fs::metadata(&path).ok().map(|metadata| metadata.len());
+
+        let expected = CacheMeta {
+            key: key.to_string(),
+            etag: etag.to_string(),
+            bytes,
+        };
+        if meta != expected || on_disk != Some(bytes) {
+            info!(key, cached_etag = %meta.etag, etag, "Cached parquet is stale");
+            self.remove_entry(&path);
+            return None;
+        }
+
+        touch(&path);
+        Some(path)
+    }
+
+    // Deletes least recently used entries until `incoming_bytes` more fits in the budget.
+    // Returns how many were evicted. An incoming file bigger than the whole budget clears
+    // the cache but is still allowed in; the query needs it either way.
+    pub fn make_room(&self, incoming_bytes: u64) -> usize {
+        let mut entries = self.entries();
+        // Oldest first
+        entries.sort_by_key(|(_, _, used)| *used);
+
+        let mut total: u64 = entries.iter().map(|(_, bytes, _)| bytes).sum();
+        let mut evicted = 0;
+        for (path, bytes, _) in entries {
+            if total + incoming_bytes <= self.budget_bytes {
+                break;
+            }
+            self.remove_entry(&path);
+            total = total.saturating_sub(bytes);
+            evicted += 1;
+        }
+
+        if incoming_bytes > self.budget_bytes {
+            warn!(
+                incoming_bytes,
+                budget_bytes = self.budget_bytes,
+                "Parquet file is larger than the cache budget"
+            );
+        }
+        evicted
+    }
+
+    // Every cached parquet file with its size and when it was last used
+    fn entries(&self) -> Vec<(PathBuf, u64, SystemTime)> {
+        let Ok(read_dir) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        read_dir
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == PARQUET_EXTENSION)
+            })
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                Some((path, metadata.len(), used))
+            })
+            .collect()
+    }
+
+    fn remove_entry(&self, path: &Path) {
+        // The sidecar goes first so a half-removed entry can't look valid
+        let _ = fs::remove_file(path.with_extension(META_EXTENSION));
+        let _ = fs::remove_file(path);
+    }
+
+    // Moves a finished download into place and records what it is a copy of
+    fn commit(
+        &self,
+        key: &str,
+        partial: &Path,
+        etag: &str,
+        bytes: u64,
+    ) -> std::io::Result<PathBuf> {
+        let path = self.entry_path(key, PARQUET_EXTENSION);
+        fs::rename(partial, &path)?;
+        let meta = CacheMeta {
+            key: key.to_string(),
+            etag: etag.to_string(),
+            bytes,
+        };
+        fs::write(
+            self.entry_path(key, META_EXTENSION),
+            serde_json::to_vec(&meta)?,
+        )?;
+        Ok(path)
+    }
+}
+
+// Bumps the modified time, which is what eviction orders by
+fn touch(path: &Path) {
+    let touched = fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = touched {
+        warn!(path = %path.display(), error = %e, "Failed to mark cached parquet as used");
+    }
+}
+
+fn cache_io_error(error: std::io::Error) -> Error {
+    Error::S3 {
+        operation: "GetObject",
+        message: format!("could not write parquet to the cache: {}", error),
+        retryable: false,
+    }
+}
+
+// Returns a local copy of s3://bucket/key, downloading it only when the cache has no copy
+// of `object`, the version the caller just looked up. The download is pinned to its ETag,
+// so an object replaced mid-download fails instead of being cached wrongly.
+pub async fn fetch_cached_parquet(
+    s3_client: &S3Client,
+    cache: &ParquetCache,
+    bucket: &str,
+    key: &str,
+    object: &SourceObject,
+) -> Result<CachedParquet, Error> {
+    let etag = object.etag.clone().unwrap_or_default();
+    let bytes = object.bytes.max(0) as u64;
+
+    if let Some(path) = cache.lookup(key, &etag, bytes) {
+        return Ok(CachedParquet {
+            path,
+            bytes,
+            hit: true,
+            evicted: 0,
+        });
+    }
+
+    fs::create_dir_all(&cache.dir).map_err(cache_io_error)?;
+    let evicted = cache.make_room(bytes);
+    if evicted > 0 {
+        info!(evicted, "Evicted cached parquet files to make room");
+    }
+
+    let mut request = s3_client.get_object().bucket(bucket).key(key);
+    if !etag.is_empty() {
+        request = request.if_match(&etag);
+    }
+    let output = request
+        .send()
+        .await
+        .map_err(|e| Error::s3("GetObject", e))?;
+
+    let partial = cache.entry_path(key, PARTIAL_EXTENSION);
+    let downloaded = async {
+        let mut file = tokio::fs::File::create(&partial)
+            .await
+            .map_err(cache_io_error)?;
+        let mut byte_stream = output.body;
+        while let Some(chunk) = byte_stream.try_next().await.map_err(Error::s3_stream)? {
+            file.write_all(&chunk).await.map_err(cache_io_error)?;
+        }
+        file.flush().await.map_err(cache_io_error)
+    }
+    .await;
+    if let Err(e) = downloaded {
+        // Leaving the partial file behind would hold /tmp space that eviction can't see
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    let path = cache
+        .commit(key, &partial, &etag, bytes)
+        .map_err(cache_io_error)?;
+
+    Ok(CachedParquet {
+        path,
+        bytes,
+        hit: false,
+        evicted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubResponse};
+
+    // Answers every GetObject with the ETag it was pinned to as the body, so each version of
+    // an object has contents of its own and a length the test knows
+    fn s3_stub() -> StubEndpoint {
+        StubEndpoint::start(|request| {
+            let etag = request.header("if-match").unwrap_or_default();
+            StubResponse::bytes(200, etag.as_bytes().to_vec()).with_header("ETag", etag)
+        })
+    }
+
+    fn object(etag: &str) -> SourceObject {
+        SourceObject {
+            bytes: etag.len() as i64,
+            etag: Some(etag.to_string()),
+        }
+    }
+
+    fn downloads(stub: &StubEndpoint) -> Vec<String> {
+        stub.requests()
+            .iter()
+            .filter(|request| request.method == "GET")
+            .map(|request| request.target.clone())
+            .collect()
+    }
+
+    fn set_used(path: &Path, seconds: u64) {
+        let used = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(used))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_second_fetch_of_the_same_version_is_a_hit() {
+        let stub = s3_stub();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ParquetCache::new(dir.path(), 1024);
+        let object = object("\"v1\"");
+
+        let first =
+            fetch_cached_parquet(&stub.s3_client(), &cache, "uploads", "a.parquet", &object)
+                .await
+                .unwrap();
+        let second =
+            fetch_cached_parquet(&stub.s3_client(), &cache, "uploads", "a.parquet", &object)
+                .await
+                .unwrap();
+
+        assert!(!first.hit);
+        assert!(second.hit);
+        assert_eq!(second.path, first.path);
+        assert_eq!(fs::read(&second.path).unwrap(), b"\"v1\"");
+        assert_eq!(downloads(&stub), ["/uploads/a.parquet?x-id=GetObject"]);
+    }
+
+    #[tokio::test]
+    async fn a_changed_etag_downloads_the_new_version() {
+        let stub = s3_stub();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ParquetCache::new(dir.path(), 1024);
+        let s3_client = stub.s3_client();
+        fetch_cached_parquet(
+            &s3_client,
+            &cache,
+            "uploads",
+            "a.parquet",
+            &object("\"v1\""),
+        )
+        .await
+        .unwrap();
+
+        let replaced = fetch_cached_parquet(
+            &s3_client,
+            &cache,
+            "uploads",
+            "a.parquet",
+            &object("\"v2-longer\""),
+        )
+        .await
+        .unwrap();
+
+        assert!(!replaced.hit);
+        assert_eq!(fs::read(&replaced.path).unwrap(), b"\"v2-longer\"");
+        let requests = stub.requests();
+        let pinned: Vec<Option<&str>> = requests
+            .iter()
+            .map(|request| request.header("if-match"))
+            .collect();
+        assert_eq!(pinned, [Some("\"v1\""), Some("\"v2-longer\"")]);
+        // The old version is gone, not left beside the new one
+        assert_eq!(cache.entries().len(), 1);
+        assert!(cache.lookup("a.parquet", "\"v1\"", 4).is_none());
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_file_is_evicted_to_stay_within_the_budget() {
+        let stub = s3_stub();
+        let dir = tempfile::tempdir().unwrap();
+        // Room for three of the six byte objects
+        let cache = ParquetCache::new(dir.path(), 20);
+        let s3_client = stub.s3_client();
+        let six_bytes = object("\"0001\"");
+        let mut paths = Vec::new();
+        for (used, key) in ["a", "b", "c"].iter().enumerate() {
+            let cached = fetch_cached_parquet(&s3_client, &cache, "uploads", key, &six_bytes)
+                .await
+                .unwrap();
+            set_used(&cached.path, used as u64 + 1);
+            paths.push(cached.path);
+        }
+        // Reading `a` again leaves `b` the least recently used
+        let reread = fetch_cached_parquet(&s3_client, &cache, "uploads", "a", &six_bytes)
+            .await
+            .unwrap();
+        assert!(reread.hit);
+
+        let added = fetch_cached_parquet(&s3_client, &cache, "uploads", "d", &six_bytes)
+            .await
+            .unwrap();
+
+        assert_eq!(added.evicted, 1);
+        assert!(paths[0].exists());
+        assert!(!paths[1].exists());
+        assert!(paths[2].exists());
+        let total: u64 = cache.entries().iter().map(|(_, bytes, _)| bytes).sum();
+        assert_eq!(total, 18);
+        assert!(cache.lookup("b", "\"0001\"", 6).is_none());
+    }
+}
@@ -1,15 +1,78 @@
 use aws_sdk_dynamodb::types::AttributeValue;
 use aws_sdk_dynamodb::{Client as DynamoClient, Error as DynamoError};
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 
+use crate::creation_types::{JobProvenance, NotifySettings, ProcessingPath};
+use crate::dynamo::{JOB_ENTITY, JobStatus, Retention, timestamp_now};
+use crate::s3::SourceObject;
+
+// SQS can hold a message back for at most 15 minutes
+pub const MAX_SQS_DELAY_SECONDS: i64 = 15 * 60;
+
+// When a submitted job gets queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartPlan {
+    Now,
+    // Queued now with an SQS delay
+    Delay(i32),
+    // Too far off for an SQS delay; the dispatcher queues it once it comes within range
+    Scheduled,
+}
+
+impl StartPlan {
+    pub fn delay_seconds(&self) -> Option<i32> {
+        match self {
+            StartPlan::Delay(seconds) => Some(*seconds),
+            StartPlan::Now | StartPlan::Scheduled => None,
+        }
+    }
+}
+
+pub fn plan_start(start_after: Option<DateTime<Utc>>, now: DateTime<Utc>) -> StartPlan {
+    let Some(start_after) = start_after else {
+        return StartPlan::Now;
+    };
+
+    match (start_after - now).num_seconds() {
+        delay if delay <= 0 => StartPlan::Now,
+        delay if delay <= MAX_SQS_DELAY_SECONDS => StartPlan::Delay(delay as i32),
+        _ => StartPlan::Scheduled,
+    }
+}
+
+// Shortest and longest estimate we'll show; anything past an hour is too rough to be useful
+const MIN_ESTIMATED_SECONDS: u64 = 1;
+const MAX_ESTIMATED_SECONDS: u64 = 60 * 60;
+
+// Seconds a file of `source_bytes` should take at the rolling throughput, or None when
+// there is no history to go on
+pub fn estimate_seconds(source_bytes: i64, mb_per_second: Option<f64>) -> Option<u64> {
+    let mb_per_second = mb_per_second.filter(|rate| rate.is_finite() && *rate > 0.0)?;
+    let megabytes = source_bytes.max(0) as f64 / (1024.0 * 1024.0);
+    let seconds = (megabytes / mb_per_second).ceil() as u64;
+    Some(seconds.clamp(MIN_ESTIMATED_SECONDS, MAX_ESTIMATED_SECONDS))
+}
+
+// Creates the job item, failing with ConditionalCheckFailedException if the job already
+// exists so a repeated submission can't start a second conversion of the same job. A job
+// the same caller reserved for upload is replaced, and with `resubmit` so is a failed job,
+// which also clears its error and attempt fields.
+#[allow(clippy::too_many_arguments)]
 pub async fn put_job_status(
     dynamo_client: &DynamoClient,
     table_name: &str,
     service: &str,
     service_id: &str,
-    status: &str,
+    status: JobStatus,
     context: &str,
     schema: &HashMap<String, String>,
+    source: &SourceObject,
+    provenance: &JobProvenance,
+    created_by: &str,
+    notify: Option<&NotifySettings>,
+    path: ProcessingPath,
+    resubmit: bool,
 ) -> Result<(), DynamoError> {
     let mut item = HashMap::new();
 
@@ -21,7 +84,11 @@ pub async fn put_job_status(
         "serviceId".to_string(),
         AttributeValue::S(service_id.to_string()),
     );
-    item.insert("status".to_string(), AttributeValue::S(status.to_string()));
+    item.insert(
+        "entity".to_string(),
+        AttributeValue::S(JOB_ENTITY.to_string()),
+    );
+    item.insert("status".to_string(), status.attribute());
     item.insert(
         "context".to_string(),
         AttributeValue::S(context.to_string()),
@@ -31,13 +98,156 @@ pub async fn put_job_status(
         .map(|(k, v)| (k.clone(), AttributeValue::S(v.clone())))
         .collect();
     item.insert("schema".to_string(), AttributeValue::M(schema_map));
+    item.insert(
+        "source_bytes".to_string(),
+        AttributeValue::N(source.bytes.to_string()),
+    );
+    if let Some(etag) = &source.etag {
+        item.insert("source_etag".to_string(), AttributeValue::S(etag.clone()));
+    }
+    if let Some(original_filename) = &provenance.original_filename {
+        item.insert(
+            "original_filename".to_string(),
+            AttributeValue::S(original_filename.clone()),
+        );
+    }
+    if let Some(submitted_by) = &provenance.submitted_by {
+        item.insert(
+            "submitted_by".to_string(),
+            AttributeValue::S(submitted_by.clone()),
+        );
+    }
+    if !provenance.labels.is_empty() {
+        let labels = provenance
+            .labels
+            .iter()
+            .map(|(k, v)| (k.clone(), AttributeValue::S(v.clone())))
+            .collect();
+        item.insert("labels".to_string(), AttributeValue::M(labels));
+    }
+    item.insert(
+        "created_by".to_string(),
+        AttributeValue::S(created_by.to_string()),
+    );
+    if let Some(notify) = notify {
+        let mut targets = HashMap::new();
+        if let Some(topic_arn) = &notify.sns_topic_arn {
+            targets.insert(
+                "sns_topic_arn".to_string(),
+                AttributeValue::S(topic_arn.clone()),
+            );
+        }
+        if let Some(webhook_url) = &notify.webhook_url {
+            targets.insert(
+                "webhook_url".to_string(),
+                AttributeValue::S(webhook_url.clone()),
+            );
+        }
+        item.insert("notify".to_string(), AttributeValue::M(targets));
+    }
+    item.insert(
+        "processing_path".to_string(),
+        AttributeValue::S(path.as_str().to_string()),
+    );
+    let now = timestamp_now();
+    item.insert("created_at".to_string(), AttributeValue::S(now.clone()));
+    item.insert("updated_at".to_string(), AttributeValue::S(now));
+    item.insert(
+        "expires_at".to_string(),
+        Retention::Job.attribute(Utc::now()),
+    );
 
-    dynamo_client
+    let mut request = dynamo_client
         .put_item()
         .table_name(table_name)
-        .set_item(Some(item))
-        .send()
-        .await?;
+        .set_item(Some(item));
+
+    // A job reserved by the upload endpoint is taken over by its owner's conversion request
+    let mut condition = "attribute_not_exists(service) \
+                         OR (#status = :awaiting_upload AND created_by = :created_by)"
+        .to_string();
+    if resubmit {
+        condition.push_str(" OR #status = :failed");
+        request = request.expression_attribute_values(":failed", JobStatus::Failed.attribute());
+    }
+
+    request = request
+        .condition_expression(condition)
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":awaiting_upload", JobStatus::AwaitingUpload.attribute())
+        .expression_attribute_values(":created_by", AttributeValue::S(created_by.to_string()));
+
+    request.send().await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: i64 = 1024 * 1024;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn plan_in(seconds: i64) -> StartPlan {
+        plan_start(Some(now() + chrono::Duration::seconds(seconds)), now())
+    }
+
+    #[test]
+    fn a_job_without_a_start_time_or_already_due_starts_now() {
+        assert_eq!(plan_start(None, now()), StartPlan::Now);
+        assert_eq!(plan_in(0), StartPlan::Now);
+        assert_eq!(plan_in(-3600), StartPlan::Now);
+        assert_eq!(plan_in(0).delay_seconds(), None);
+    }
+
+    #[test]
+    fn a_start_up_to_fifteen_minutes_away_is_an_sqs_delay() {
+        assert_eq!(plan_in(1), StartPlan::Delay(1));
+        assert_eq!(plan_in(MAX_SQS_DELAY_SECONDS), StartPlan::Delay(900));
+        assert_eq!(plan_in(MAX_SQS_DELAY_SECONDS).delay_seconds(), Some(900));
+    }
+
+    #[test]
+    fn a_start_past_fifteen_minutes_is_left_to_the_dispatcher() {
+        assert_eq!(plan_in(MAX_SQS_DELAY_SECONDS + 1), StartPlan::Scheduled);
+        assert_eq!(plan_in(7 * 24 * 3600), StartPlan::Scheduled);
+        assert_eq!(plan_in(MAX_SQS_DELAY_SECONDS + 1).delay_seconds(), None);
+
+        // Part of a second past the limit still fits, as the delay is whole seconds
+        let just_over = now()
+            + chrono::Duration::seconds(MAX_SQS_DELAY_SECONDS)
+            + chrono::Duration::milliseconds(500);
+        assert_eq!(plan_start(Some(just_over), now()), StartPlan::Delay(900));
+    }
+
+    #[test]
+    fn there_is_no_estimate_without_a_usable_throughput() {
+        assert_eq!(estimate_seconds(10 * MB, None), None);
+        assert_eq!(estimate_seconds(10 * MB, Some(0.0)), None);
+        assert_eq!(estimate_seconds(10 * MB, Some(-2.0)), None);
+        assert_eq!(estimate_seconds(10 * MB, Some(f64::NAN)), None);
+        assert_eq!(estimate_seconds(10 * MB, Some(f64::INFINITY)), None);
+    }
+
+    #[test]
+    fn an_estimate_rounds_up_to_whole_seconds() {
+        assert_eq!(estimate_seconds(10 * MB, Some(2.0)), Some(5));
+        assert_eq!(estimate_seconds(10 * MB, Some(3.0)), Some(4));
+    }
+
+    #[test]
+    fn an_estimate_stays_between_a_second_and_an_hour() {
+        assert_eq!(estimate_seconds(0, Some(5.0)), Some(MIN_ESTIMATED_SECONDS));
+        assert_eq!(estimate_seconds(-1, Some(5.0)), Some(MIN_ESTIMATED_SECONDS));
+        assert_eq!(
+            estimate_seconds(100 * 1024 * MB, Some(0.5)),
+            Some(MAX_ESTIMATED_SECONDS)
+        );
+    }
+}
@@ -1,22 +1,48 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
 use aws_sdk_s3::Client as S3Client;
 use std::sync::Arc;
-use tokio::io::AsyncBufReadExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
 use tokio::sync::mpsc;
 use tokio::task;
-use tracing::error;
+use tracing::{Instrument, error, field, info, info_span, warn};
 
 use arrow::array::ArrayRef;
 use arrow::datatypes::{Field, Schema};
 use arrow::record_batch::RecordBatch;
 use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use parquet::file::FOOTER_SIZE;
+use parquet::file::metadata::{
+    FileMetaData, KeyValue, ParquetMetaData, ParquetMetaDataReader, ParquetMetaDataWriter,
+    RowGroupMetaData,
+};
 use parquet::file::properties::{EnabledStatistics, WriterProperties};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::column_matching::{build_column_report, remaining_headers_as_string_columns};
 use crate::creation_parsing::{parse_boolean, parse_date_to_days, parse_datetime_to_nanos};
-use crate::creation_types::{ColumnDefinition, DataType};
-use crate::s3::upload_to_s3;
+use crate::creation_types::{
+    ColumnDefinition, ColumnStats, ConversionOptions, DataType, JobProvenance, ProcessingPath,
+    query_schema,
+};
+use crate::duck_db::ParquetColumn;
+use crate::dynamo::{
+    ConversionCheckpoint, JobStatus, get_job_checkpoint, get_job_status, record_column_report,
+    record_column_stats, record_realized_schema, save_job_checkpoint,
+};
+use crate::error::Error;
+use crate::memory::{MemoryGovernor, reset_peak_allocated};
+use crate::processing_error::ProcessingError;
+use crate::progress::ProgressReporter;
+use crate::s3::{
+    abort_multipart_upload, complete_multipart_upload, create_multipart_upload, delete_from_s3,
+    parquet_footer_key, upload_object, upload_part,
+};
 
 // Optimized constants for 2.6GB memory utilization
+// Starting batch size; the memory governor shrinks it if the heap runs hot
 const ROWS_PER_BATCH: usize = 3_500_000;
 const S3_CHUNK_SIZE: usize = 512 * 1024 * 1024; // 512MB read buffer
 const MAX_BATCH_MEMORY: usize = 1800 * 1024 * 1024; // 1.8GB per batch
@@ -24,9 +50,29 @@ const CHANNEL_BUFFER_SIZE: usize = 8;
 const STRING_POOL_SIZE: usize = 50000; // Larger string pool for deduplication
 const PARQUET_BUFFER_SIZE: usize = 512 * 1024 * 1024;
 
+// Fast path for small files: buffers sized for a few MB keep a low-memory Lambda
+// comfortable, and reading and writing share one task instead of spawning a reader
+const FAST_ROWS_PER_BATCH: usize = 100_000;
+const FAST_READ_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+const FAST_PARQUET_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+// Files this large can't be converted inside a single Lambda execution, so they are
+// uploaded a few row groups at a time with a checkpoint after each upload
+const CHECKPOINT_THRESHOLD_BYTES: i64 = 5 * 1024 * 1024 * 1024;
+const BATCHES_PER_CHECKPOINT: usize = 2;
+// S3 takes no part smaller than this but the last one
+const MIN_UPLOAD_PART_BYTES: usize = 5 * 1024 * 1024;
+const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+const HEADER_PROBE_BYTES: i64 = 64 * 1024;
+
+// How often the reader offers its progress; the reporter decides whether it is written
+const PROGRESS_CHECK_ROWS: usize = 10_000;
+
 #[derive(Debug, Clone)]
 pub enum FieldValue {
     Null,
+    // A non-empty value that couldn't be coerced to the column type; written as null
+    Unparseable,
     String(String),
     Integer(i64),
     Float(f64),
@@ -37,6 +83,47 @@ pub enum FieldValue {
 
 pub type OptimizedRow = Vec<FieldValue>;
 
+// What a successful conversion did, for metrics
+#[derive(Debug, Clone, Default)]
+pub struct ConversionSummary {
+    // Rows in the output, including those uploaded by earlier executions of a resumed job
+    pub rows_written: u64,
+    pub bytes_read: u64,
+    pub rejected_values: u64,
+    pub read_duration: Duration,
+    pub write_duration: Duration,
+    pub memory_high_water_bytes: u64,
+    // Where the parquet was written
+    pub output_key: String,
+    // The columns written, as DESCRIBE would report them
+    pub query_schema: Vec<ParquetColumn>,
+    // ETag of the written parquet, which pins the stored schema to this version of it
+    pub output_etag: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct ReadSummary {
+    bytes_read: u64,
+    rejected_values: u64,
+}
+
+#[derive(Debug, Default)]
+struct WriteSummary {
+    rows_written: u64,
+    output_etag: Option<String>,
+}
+
+#[derive(Debug)]
+struct OffsetBatch {
+    batch: RecordBatch,
+    // Byte offset in the source CSV just past the last row in this batch
+    end_offset: u64,
+    // Reserved with the memory governor until the writer has consumed the batch
+    memory_bytes: usize,
+    // Value counts of the rows in this batch, which a checkpoint covering them saves
+    column_stats: Vec<ColumnStats>,
+}
+
 #[derive(Debug)]
 struct BatchBuilder {
     rows: Vec<OptimizedRow>,
@@ -66,25 +153,85 @@ impl BatchBuilder {
         }
     }
 
-    fn is_full(&self) -> bool {
-        self.rows.len() >= ROWS_PER_BATCH || self.estimated_size >= MAX_BATCH_MEMORY
+    fn is_full(&self, rows_per_batch: usize) -> bool {
+        self.rows.len() >= rows_per_batch || self.estimated_size >= MAX_BATCH_MEMORY
     }
 }
 
+// Batch and checkpoint sizes for one conversion. They come from the path's constants;
+// tests shrink them so a handful of rows go through the same batching and checkpointing
+// as a multi-gigabyte file.
+#[derive(Debug, Clone, Copy)]
+struct ConversionLimits {
+    rows_per_batch: usize,
+    checkpoint_threshold_bytes: i64,
+    batches_per_checkpoint: usize,
+    min_part_bytes: usize,
+}
+
+impl ConversionLimits {
+    fn for_path(path: ProcessingPath) -> Self {
+        ConversionLimits {
+            rows_per_batch: rows_per_batch(path),
+            checkpoint_threshold_bytes: CHECKPOINT_THRESHOLD_BYTES,
+            batches_per_checkpoint: BATCHES_PER_CHECKPOINT,
+            min_part_bytes: MIN_UPLOAD_PART_BYTES,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn stream_csv_to_parquet_optimized(
     bucket: &str,
     key: &str,
     column_definitions: &[ColumnDefinition],
+    options: &ConversionOptions,
     output_key: &str,
     job_id: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    rows_processed: Arc<AtomicU64>,
+    path: ProcessingPath,
+    provenance: &JobProvenance,
+) -> Result<ConversionSummary, ProcessingError> {
     let config = aws_config::load_from_env().await;
     let s3_client = S3Client::new(&config);
 
-    println!(
-        "Job {}: Starting optimized streaming from S3: bucket={}, key={}",
-        job_id, bucket, key
-    );
+    convert_csv_to_parquet(
+        &s3_client,
+        bucket,
+        key,
+        column_definitions,
+        options,
+        output_key,
+        job_id,
+        dynamodb_client,
+        table_name,
+        rows_processed,
+        path,
+        provenance,
+        ConversionLimits::for_path(path),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn convert_csv_to_parquet(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    column_definitions: &[ColumnDefinition],
+    options: &ConversionOptions,
+    output_key: &str,
+    job_id: &str,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    rows_processed: Arc<AtomicU64>,
+    path: ProcessingPath,
+    provenance: &JobProvenance,
+    limits: ConversionLimits,
+) -> Result<ConversionSummary, ProcessingError> {
+    info!(job_id, bucket, key, "Starting optimized streaming from S3");
 
     // Get file size for progress tracking
     let head_response = s3_client
@@ -92,18 +239,65 @@ pub async fn stream_csv_to_parquet_optimized(
         .bucket(bucket)
         .key(key)
         .send()
-        .await?;
+        .await
+        .map_err(|e| ProcessingError::read(Error::s3("HeadObject", e)))?;
     let content_length = head_response.content_length().unwrap_or(0);
 
-    println!(
-        "Job {}: File size: {:.2} MB",
-        job_id,
-        content_length as f64 / (1024.0 * 1024.0)
-    );
+    info!(job_id, bytes = content_length, "Fetched source file size");
+
+    // The fast path only ever sees small files, so there is nothing worth resuming
+    let needs_checkpoint =
+        path == ProcessingPath::Standard && content_length >= limits.checkpoint_threshold_bytes;
+    let checkpoint = if needs_checkpoint {
+        let existing = get_job_checkpoint(dynamodb_client, table_name, job_id)
+            .await
+            .map_err(ProcessingError::dynamo)?;
+        if let Some(existing) = &existing {
+            info!(
+                job_id,
+                byte_offset = existing.byte_offset,
+                rows = existing.rows_written,
+                parts = existing.parts.len(),
+                "Resuming from checkpoint"
+            );
+        }
+        Some(existing.unwrap_or_default())
+    } else {
+        None
+    };
+
+    reset_peak_allocated();
+    let governor = Arc::new(MemoryGovernor::from_env(limits.rows_per_batch));
 
-    let (batch_tx, batch_rx) = mpsc::channel::<RecordBatch>(CHANNEL_BUFFER_SIZE);
+    check_cancelled(dynamodb_client, table_name, job_id).await?;
 
-    let column_definitions = Arc::new(column_definitions.to_vec());
+    // The processor reports its own failure through the channel so the writer never
+    // uploads a file built from a partially read CSV
+    let (batch_tx, batch_rx) =
+        mpsc::channel::<Result<OffsetBatch, ProcessingError>>(CHANNEL_BUFFER_SIZE);
+
+    let mut column_definitions = column_definitions.to_vec();
+    if options.include_remaining_as_string {
+        let header_line = read_header_line(s3_client, bucket, key)
+            .await
+            .map_err(ProcessingError::read)?;
+        let headers = parse_csv_line(&header_line).map_err(ProcessingError::parse)?;
+
+        let remaining = remaining_headers_as_string_columns(&column_definitions, &headers);
+        if !remaining.is_empty() {
+            info!(
+                job_id,
+                columns = remaining.len(),
+                "Adding unmapped CSV headers as string columns"
+            );
+            column_definitions.extend(remaining);
+            record_realized_schema(dynamodb_client, table_name, job_id, &column_definitions)
+                .await
+                .map_err(ProcessingError::dynamo)?;
+        }
+    }
+
+    let column_definitions = Arc::new(column_definitions);
     let job_id = Arc::new(job_id.to_string());
 
     let fields: Vec<Field> = column_definitions
@@ -111,18 +305,55 @@ pub async fn stream_csv_to_parquet_optimized(
         .map(|col| Field::new(&col.column, col.column_type.to_arrow_type(), true))
         .collect();
     let schema = Arc::new(Schema::new(fields));
+    let query_schema = query_schema(&column_definitions);
+
+    let props = parquet_writer_properties(provenance);
+
+    let output = match checkpoint {
+        Some(checkpoint) => Some(
+            MultipartParquet::open(
+                s3_client,
+                bucket,
+                output_key,
+                checkpoint,
+                schema.clone(),
+                props.clone(),
+                &job_id,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    let resume_offset = output.as_ref().map_or(0, MultipartParquet::resume_offset);
+    let resume_stats = output
+        .as_ref()
+        .map(MultipartParquet::resume_column_stats)
+        .unwrap_or_default();
 
-    // Spawn CSV processor task
-    let processor_handle = {
+    // CSV processor task
+    let read_task = {
         let s3_client = s3_client.clone();
         let bucket = bucket.to_string();
         let key = key.to_string();
         let column_definitions = column_definitions.clone();
         let schema = schema.clone();
         let job_id = job_id.clone();
+        let dynamodb_client = dynamodb_client.clone();
+        let table_name = table_name.to_string();
+        let options = options.clone();
+        let error_tx = batch_tx.clone();
+        let governor = governor.clone();
+        let read_span = info_span!("s3_read", rows = field::Empty, bytes = field::Empty);
 
-        task::spawn(async move {
-            if let Err(e) = process_csv_optimized(
+        async move {
+            let mut progress = ProgressReporter::new(
+                dynamodb_client.clone(),
+                &table_name,
+                &job_id,
+                content_length.max(0) as u64,
+            );
+            let read_start = std::time::Instant::now();
+            match process_csv_optimized(
                 s3_client,
                 &bucket,
                 &key,
@@ -130,50 +361,234 @@ pub async fn stream_csv_to_parquet_optimized(
                 &column_definitions,
                 schema,
                 &job_id,
+                &dynamodb_client,
+                &table_name,
+                &options,
+                resume_offset,
+                resume_stats,
+                &rows_processed,
+                &governor,
+                path,
+                &mut progress,
             )
             .await
             {
-                error!("Job {}: CSV processor failed: {}", job_id, e);
+                Ok(read_summary) => Some((read_summary, read_start.elapsed())),
+                Err(e) => {
+                    if !e.is_cancelled() {
+                        error!(
+                            job_id = %job_id,
+                            stage = e.stage(),
+                            error = %e,
+                            "CSV processor failed"
+                        );
+                    }
+                    let _ = error_tx.send(Err(e)).await;
+                    None
+                }
             }
-        })
+        }
+        .instrument(read_span)
     };
 
-    // Main thread: Parquet writer
-    let write_result =
-        write_parquet_optimized(batch_rx, bucket, output_key, schema.clone(), &job_id).await;
+    // Parquet writer
+    let write_start = std::time::Instant::now();
+    let write_task = async {
+        match output {
+            Some(output) => {
+                write_parquet_checkpointed(
+                    batch_rx,
+                    s3_client,
+                    bucket,
+                    output,
+                    schema.clone(),
+                    dynamodb_client,
+                    table_name,
+                    limits,
+                    &job_id,
+                    &governor,
+                    props,
+                )
+                .instrument(info_span!("parquet_write", checkpointed = true))
+                .await
+            }
+            None => {
+                write_parquet_optimized(
+                    batch_rx,
+                    s3_client,
+                    bucket,
+                    output_key,
+                    schema.clone(),
+                    &job_id,
+                    &governor,
+                    path,
+                    props,
+                )
+                .instrument(info_span!("parquet_write", checkpointed = false))
+                .await
+            }
+        }
+    };
 
-    processor_handle.await?;
+    let (read_result, write_result) = match path {
+        ProcessingPath::Standard => {
+            let processor_handle = task::spawn(read_task);
+            let write_result = write_task.await;
+            (
+                processor_handle.await.map_err(ProcessingError::parse)?,
+                write_result,
+            )
+        }
+        ProcessingPath::Fast => tokio::join!(read_task, write_task),
+    };
+    let write_duration = write_start.elapsed();
+
+    let write_summary = write_result?;
 
-    write_result
+    // A failed read always reaches the writer through the channel, so this is only
+    // missing if the writer somehow finished without seeing it
+    let (read_summary, read_duration) =
+        read_result.ok_or_else(|| ProcessingError::read("CSV processor did not complete"))?;
+
+    Ok(ConversionSummary {
+        rows_written: write_summary.rows_written,
+        bytes_read: read_summary.bytes_read,
+        rejected_values: read_summary.rejected_values,
+        read_duration,
+        write_duration,
+        memory_high_water_bytes: governor.high_water_mark() as u64,
+        output_key: output_key.to_string(),
+        query_schema,
+        output_etag: write_summary.output_etag,
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_csv_optimized(
     s3_client: S3Client,
     bucket: &str,
     key: &str,
-    batch_tx: mpsc::Sender<RecordBatch>,
+    batch_tx: mpsc::Sender<Result<OffsetBatch, ProcessingError>>,
     column_definitions: &[ColumnDefinition],
     schema: Arc<Schema>,
     job_id: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let response = s3_client
-        .get_object()
-        .bucket(bucket)
-        .key(key)
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    options: &ConversionOptions,
+    resume_offset: u64,
+    resume_stats: Vec<ColumnStats>,
+    rows_processed: &AtomicU64,
+    governor: &MemoryGovernor,
+    path: ProcessingPath,
+    progress: &mut ProgressReporter,
+) -> Result<ReadSummary, ProcessingError> {
+    // When resuming, start one byte early: the first line read is then either just the
+    // newline ending the last checkpointed row or the tail of a partial row, and
+    // discarding it leaves the reader on a line boundary either way
+    let range_start = resume_offset.saturating_sub(1);
+
+    let mut request = s3_client.get_object().bucket(bucket).key(key);
+    if resume_offset > 0 {
+        request = request.range(format!("bytes={}-", range_start));
+    }
+    let response = request
         .send()
-        .await?;
+        .await
+        .map_err(|e| ProcessingError::read(Error::s3("GetObject", e)))?;
 
     let byte_stream = response.body.into_async_read();
-    let buf_reader = tokio::io::BufReader::with_capacity(S3_CHUNK_SIZE, byte_stream);
+    let read_buffer_size = match path {
+        ProcessingPath::Standard => S3_CHUNK_SIZE,
+        ProcessingPath::Fast => FAST_READ_BUFFER_SIZE,
+    };
+    let mut buf_reader = tokio::io::BufReader::with_capacity(read_buffer_size, byte_stream);
 
-    let mut lines = buf_reader.lines();
+    let mut line = String::new();
+    let mut position = range_start;
 
-    let header_line = match lines.next_line().await? {
-        Some(line) => line,
-        None => return Err("Empty CSV file".into()),
+    let header_line = if resume_offset == 0 {
+        if !read_next_line(&mut buf_reader, &mut line, &mut position)
+            .await
+            .map_err(ProcessingError::read)?
+        {
+            return Err(ProcessingError::parse(Error::CsvParse {
+                line: 1,
+                reason: "empty CSV file".to_string(),
+            }));
+        }
+        line.clone()
+    } else {
+        read_next_line(&mut buf_reader, &mut line, &mut position)
+            .await
+            .map_err(ProcessingError::read)?;
+        read_header_line(&s3_client, bucket, key)
+            .await
+            .map_err(ProcessingError::read)?
     };
 
-    let headers = parse_csv_line(&header_line)?;
+    let headers = parse_csv_line(&header_line).map_err(ProcessingError::parse)?;
+
+    let column_report = build_column_report(column_definitions, &headers);
+    for unmatched in &column_report.unmatched_columns {
+        match &unmatched.suggestion {
+            Some(suggestion) => warn!(
+                job_id,
+                column = %unmatched.column,
+                suggestion = %suggestion,
+                "Schema column not found in CSV headers"
+            ),
+            None => warn!(
+                job_id,
+                column = %unmatched.column,
+                "Schema column not found in CSV headers"
+            ),
+        }
+    }
+
+    if !column_report.ignored_columns.is_empty() {
+        info!(
+            job_id,
+            ignored_columns = %column_report.ignored_columns.join(", "),
+            "Skipped CSV headers not in the schema"
+        );
+    }
+
+    for validation in &column_report.header_problems {
+        let problems: Vec<&str> = validation.problems.iter().map(|p| p.as_str()).collect();
+        warn!(
+            job_id,
+            header = %validation.header,
+            normalized = %validation.normalized,
+            problems = %problems.join(", "),
+            "CSV header may break queries"
+        );
+    }
+
+    // Always written so a retry that fixed the mapping clears the previous report
+    record_column_report(dynamodb_client, table_name, job_id, &column_report)
+        .await
+        .map_err(ProcessingError::dynamo)?;
+
+    if options.require_all_columns && !column_report.unmatched_columns.is_empty() {
+        let missing: Vec<&str> = column_report
+            .unmatched_columns
+            .iter()
+            .map(|unmatched| unmatched.column.as_str())
+            .collect();
+        return Err(ProcessingError::parse(format!(
+            "Schema columns not found in CSV headers: {}",
+            missing.join(", ")
+        )));
+    }
+    // The first report moves the job to processing before any rows are read
+    progress
+        .report(
+            position,
+            rows_processed.load(Ordering::Relaxed),
+            position - range_start,
+        )
+        .await;
+
     let header_map: HashMap<String, usize> = headers
         .iter()
         .enumerate()
@@ -187,40 +602,80 @@ async fn process_csv_optimized(
         .collect();
 
     // Process records in batches
-    let mut batch_builder = BatchBuilder::new(ROWS_PER_BATCH);
+    let mut batch_builder = BatchBuilder::new(governor.rows_per_batch());
     let mut total_rows = 0;
+    // A resumed conversion carries on from the counts of the rows already uploaded
+    let mut column_stats = resume_stats;
+    column_stats.resize(column_definitions.len(), ColumnStats::default());
     let start_time = std::time::Instant::now();
 
-    while let Some(line) = lines.next_line().await? {
+    while read_next_line(&mut buf_reader, &mut line, &mut position)
+        .await
+        .map_err(ProcessingError::read)?
+    {
         if line.trim().is_empty() {
             continue;
         }
 
-        let fields = parse_csv_line(&line)?;
+        let fields = parse_csv_line(&line).map_err(ProcessingError::parse)?;
 
         // Parse row directly into typed values
-        let row = parse_row_from_fields(&fields, &header_map, &column_map)?;
+        let row = parse_row_from_fields(&fields, &header_map, &column_map)
+            .map_err(ProcessingError::parse)?;
         batch_builder.add_row(row);
         total_rows += 1;
+        rows_processed.fetch_add(1, Ordering::Relaxed);
+
+        if total_rows % PROGRESS_CHECK_ROWS == 0 {
+            progress
+                .report(
+                    position,
+                    rows_processed.load(Ordering::Relaxed),
+                    position - range_start,
+                )
+                .await;
+        }
 
         // Send batch when full
-        if batch_builder.is_full() {
-            let batch = create_record_batch_optimized(
-                &batch_builder.rows,
-                column_definitions,
-                schema.clone(),
-            )?;
+        if batch_builder.is_full(governor.rows_per_batch()) {
+            let (batch, batch_stats) = info_span!("batch_build", rows = batch_builder.rows.len())
+                .in_scope(|| {
+                    create_record_batch_optimized(
+                        &batch_builder.rows,
+                        column_definitions,
+                        schema.clone(),
+                    )
+                })
+                .map_err(ProcessingError::parse)?;
+            merge_column_stats(&mut column_stats, &batch_stats);
 
-            if batch_tx.send(batch).await.is_err() {
+            let memory_bytes = batch.get_array_memory_size();
+            governor.reserve(memory_bytes, job_id).await;
+
+            let offset_batch = OffsetBatch {
+                batch,
+                end_offset: position,
+                memory_bytes,
+                column_stats: batch_stats,
+            };
+            if batch_tx.send(Ok(offset_batch)).await.is_err() {
                 break;
             }
+            governor.adapt(job_id);
+
+            // Batches are millions of rows, so a status read per batch costs nothing next
+            // to the work it can save
+            check_cancelled(dynamodb_client, table_name, job_id).await?;
 
             if total_rows % 100_000 == 0 {
-                let elapsed = start_time.elapsed().as_secs_f64();
-                let throughput = (total_rows as f64 / elapsed) / 1000.0;
-                println!(
-                    "Job {}: Processed {} rows, {:.1}K rows/s",
-                    job_id, total_rows, throughput
+                let elapsed = start_time.elapsed();
+                let rows_per_second = total_rows as f64 / elapsed.as_secs_f64();
+                info!(
+                    job_id,
+                    rows = total_rows,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    rows_per_second = rows_per_second as u64,
+                    "Processed rows"
                 );
             }
 
@@ -229,24 +684,157 @@ async fn process_csv_optimized(
     }
 
     if !batch_builder.rows.is_empty() {
-        let batch =
-            create_record_batch_optimized(&batch_builder.rows, column_definitions, schema.clone())?;
-        let _ = batch_tx.send(batch).await;
+        // The writer closes and uploads as soon as it gets the last batch, so this is the
+        // final point at which a cancel request can still stop the output being written
+        check_cancelled(dynamodb_client, table_name, job_id).await?;
+
+        let (batch, batch_stats) = info_span!("batch_build", rows = batch_builder.rows.len())
+            .in_scope(|| {
+                create_record_batch_optimized(
+                    &batch_builder.rows,
+                    column_definitions,
+                    schema.clone(),
+                )
+            })
+            .map_err(ProcessingError::parse)?;
+        merge_column_stats(&mut column_stats, &batch_stats);
+
+        let memory_bytes = batch.get_array_memory_size();
+        governor.reserve(memory_bytes, job_id).await;
+        let _ = batch_tx
+            .send(Ok(OffsetBatch {
+                batch,
+                end_offset: position,
+                memory_bytes,
+                column_stats: batch_stats,
+            }))
+            .await;
     }
 
-    let total_time = start_time.elapsed().as_secs_f64();
-    println!(
-        "Job {}: Finished processing {} rows in {:.2}s, avg: {:.1}K rows/s",
+    let total_time = start_time.elapsed();
+    let current_span = tracing::Span::current();
+    current_span.record("rows", total_rows);
+    current_span.record("bytes", position - range_start);
+    info!(
         job_id,
-        total_rows,
-        total_time,
-        (total_rows as f64 / total_time) / 1000.0
+        rows = total_rows,
+        elapsed_ms = total_time.as_millis() as u64,
+        rows_per_second = (total_rows as f64 / total_time.as_secs_f64()) as u64,
+        "Finished processing CSV"
     );
 
-    Ok(())
+    for (col, stats) in column_definitions.iter().zip(&column_stats) {
+        if stats.coercion_failures > 0 {
+            warn!(
+                job_id,
+                column = %col.column,
+                column_type = %col.column_type,
+                coercion_failures = stats.coercion_failures,
+                "Values could not be parsed as the column type"
+            );
+        }
+    }
+
+    record_column_stats(
+        dynamodb_client,
+        table_name,
+        job_id,
+        column_definitions,
+        &column_stats,
+    )
+    .await
+    .map_err(ProcessingError::dynamo)?;
+
+    Ok(ReadSummary {
+        bytes_read: position - range_start,
+        rejected_values: column_stats
+            .iter()
+            .map(|stats| stats.coercion_failures)
+            .sum(),
+    })
 }
 
-fn parse_csv_line(line: &str) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+// A failed status read is only logged: missing one check shouldn't fail a conversion
+// that is otherwise going fine
+async fn check_cancelled(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    job_id: &str,
+) -> Result<(), ProcessingError> {
+    match get_job_status(dynamodb_client, table_name, job_id).await {
+        Ok(Some(JobStatus::Cancelled)) => {
+            info!(job_id, "Job was cancelled, stopping conversion");
+            Err(ProcessingError::cancelled("job was cancelled by the user"))
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            warn!(job_id, error = %e, "Could not check whether the job was cancelled");
+            Ok(())
+        }
+    }
+}
+
+fn merge_column_stats(totals: &mut [ColumnStats], batch_stats: &[ColumnStats]) {
+    for (total, batch) in totals.iter_mut().zip(batch_stats) {
+        total.merge(batch);
+    }
+}
+
+// Reads the next line into `line` without its terminator, advancing `position` by the raw
+// bytes consumed so batch boundaries can be mapped back to offsets in the source object
+async fn read_next_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+    position: &mut u64,
+) -> Result<bool, Error> {
+    line.clear();
+    let bytes_read = reader.read_line(line).await.map_err(Error::s3_stream)?;
+    if bytes_read == 0 {
+        return Ok(false);
+    }
+    *position += bytes_read as u64;
+
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(true)
+}
+
+async fn read_header_line(s3_client: &S3Client, bucket: &str, key: &str) -> Result<String, Error> {
+    let response = s3_client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .range(format!("bytes=0-{}", HEADER_PROBE_BYTES - 1))
+        .send()
+        .await
+        .map_err(|e| Error::s3("GetObject", e))?;
+    let bytes = response
+        .body
+        .collect()
+        .await
+        .map_err(Error::s3_stream)?
+        .into_bytes();
+
+    let header_end = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| Error::CsvParse {
+            line: 1,
+            reason: "header row not found within the first 64KB of the file".to_string(),
+        })?;
+    let header = std::str::from_utf8(&bytes[..header_end]).map_err(|e| Error::CsvParse {
+        line: 1,
+        reason: e.to_string(),
+    })?;
+
+    Ok(header.trim_end_matches('\r').to_string())
+}
+
+fn parse_csv_line(line: &str) -> Result<Vec<String>, Error> {
     let mut fields = Vec::new();
     let mut field = String::new();
     let mut in_quotes = false;
@@ -278,89 +866,101 @@ fn parse_row_from_fields(
     fields: &[String],
     header_map: &HashMap<String, usize>,
     column_map: &HashMap<String, (usize, &ColumnDefinition)>,
-) -> Result<OptimizedRow, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<OptimizedRow, Error> {
     let mut row = vec![FieldValue::Null; column_map.len()];
 
     for (col_name, &(output_idx, col_def)) in column_map.iter() {
-        if let Some(&csv_idx) = header_map.get(col_name) {
-            if let Some(field) = fields.get(csv_idx) {
-                let value = if field.trim().is_empty() {
-                    FieldValue::Null
-                } else {
-                    parse_field_value(field.trim(), &col_def.column_type)?
-                };
-                row[output_idx] = value;
-            }
+        if let Some(&csv_idx) = header_map.get(col_name)
+            && let Some(field) = fields.get(csv_idx)
+        {
+            let value = if field.trim().is_empty() {
+                FieldValue::Null
+            } else {
+                parse_field_value(field.trim(), &col_def.column_type)?
+            };
+            row[output_idx] = value;
         }
     }
 
     Ok(row)
 }
 
-fn parse_field_value(
-    field: &str,
-    data_type: &DataType,
-) -> Result<FieldValue, Box<dyn std::error::Error + Send + Sync>> {
+fn parse_field_value(field: &str, data_type: &DataType) -> Result<FieldValue, Error> {
     Ok(match data_type {
         DataType::String => FieldValue::String(field.to_string()),
         DataType::Integer => match field.parse::<i64>() {
             Ok(v) => FieldValue::Integer(v),
-            Err(_) => FieldValue::Null,
+            Err(_) => FieldValue::Unparseable,
         },
         DataType::Float => match field.parse::<f64>() {
             Ok(v) => FieldValue::Float(v),
-            Err(_) => FieldValue::Null,
+            Err(_) => FieldValue::Unparseable,
         },
         DataType::Boolean => match parse_boolean(field) {
             Some(v) => FieldValue::Boolean(v),
-            None => FieldValue::Null,
+            None => FieldValue::Unparseable,
         },
         DataType::Date => match parse_date_to_days(field) {
             Some(v) => FieldValue::Date(v),
-            None => FieldValue::Null,
+            None => FieldValue::Unparseable,
         },
         DataType::DateTime | DataType::Timestamp => match parse_datetime_to_nanos(field) {
             Some(v) => FieldValue::Timestamp(v),
-            None => FieldValue::Null,
+            None => FieldValue::Unparseable,
         },
     })
 }
 
+// Every value occupies a full FieldValue slot in the row whatever its variant, so wide rows
+// of short strings cost far more than their text; strings add their heap buffer on top
 fn estimate_row_size(row: &OptimizedRow) -> usize {
-    row.iter()
+    let string_bytes: usize = row
+        .iter()
         .map(|v| match v {
-            FieldValue::Null => 1,
-            FieldValue::String(s) => s.len() + 24,
-            FieldValue::Integer(_) => 8,
-            FieldValue::Float(_) => 8,
-            FieldValue::Boolean(_) => 1,
-            FieldValue::Date(_) => 4,
-            FieldValue::Timestamp(_) => 8,
+            FieldValue::String(s) => s.capacity(),
+            _ => 0,
         })
-        .sum()
+        .sum();
+
+    std::mem::size_of::<OptimizedRow>()
+        + row.capacity() * std::mem::size_of::<FieldValue>()
+        + string_bytes
 }
 
 fn create_record_batch_optimized(
     rows: &[OptimizedRow],
     column_definitions: &[ColumnDefinition],
     schema: Arc<Schema>,
-) -> Result<RecordBatch, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<(RecordBatch, Vec<ColumnStats>), Error> {
     if rows.is_empty() {
-        return Err("No data to convert".into());
+        return Err(Error::ParquetWrite("no data to convert".to_string()));
     }
 
-    let arrays = create_arrays_optimized(rows, column_definitions)?;
-    Ok(RecordBatch::try_new(schema, arrays)?)
+    let (arrays, column_stats) = create_arrays_optimized(rows, column_definitions)?;
+    Ok((RecordBatch::try_new(schema, arrays)?, column_stats))
+}
+
+fn count_column_values(rows: &[OptimizedRow], col_idx: usize) -> ColumnStats {
+    let mut stats = ColumnStats::default();
+    for row in rows {
+        match &row[col_idx] {
+            FieldValue::Null => stats.empty += 1,
+            FieldValue::Unparseable => stats.coercion_failures += 1,
+            _ => stats.written += 1,
+        }
+    }
+    stats
 }
 
 fn create_arrays_optimized(
     rows: &[OptimizedRow],
     column_definitions: &[ColumnDefinition],
-) -> Result<Vec<ArrayRef>, Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<(Vec<ArrayRef>, Vec<ColumnStats>), Error> {
     column_definitions
         .iter()
         .enumerate()
         .map(|(col_idx, col_def)| {
+            let stats = count_column_values(rows, col_idx);
             let array: ArrayRef = match &col_def.column_type {
                 DataType::String => {
                     // Estimate better capacity for string columns
@@ -436,21 +1036,31 @@ fn create_arrays_optimized(
                     Arc::new(builder.finish())
                 }
             };
-            Ok(array)
+            Ok((array, stats))
         })
-        .collect()
+        .collect::<Result<Vec<_>, Error>>()
+        .map(|columns| columns.into_iter().unzip())
 }
 
-async fn write_parquet_optimized(
-    mut batch_rx: mpsc::Receiver<RecordBatch>,
-    bucket: &str,
-    output_key: &str,
-    schema: Arc<Schema>,
-    job_id: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut buffer = Vec::with_capacity(PARQUET_BUFFER_SIZE); // 512MB initial
+// `original_filename` and the job's labels go into the file's key-value metadata so the
+// upload it came from and how it was tagged are still known wherever the parquet file gets
+// copied. Labels are one JSON object with sorted keys, so the same labels always write the
+// same metadata.
+fn parquet_writer_properties(provenance: &JobProvenance) -> WriterProperties {
+    let mut entries = Vec::new();
+    if let Some(name) = &provenance.original_filename {
+        entries.push(KeyValue::new("original_filename".to_string(), name.clone()));
+    }
+    if !provenance.labels.is_empty() {
+        let labels: BTreeMap<&String, &String> = provenance.labels.iter().collect();
+        entries.push(KeyValue::new(
+            "labels".to_string(),
+            serde_json::to_string(&labels).unwrap_or_default(),
+        ));
+    }
+    let key_value_metadata = (!entries.is_empty()).then_some(entries);
 
-    let props = WriterProperties::builder()
+    WriterProperties::builder()
         .set_compression(parquet::basic::Compression::SNAPPY)
         .set_write_batch_size(ROWS_PER_BATCH)
         .set_data_page_size_limit(16 * 1024 * 1024) // 16MB pages for larger batches
@@ -458,41 +1068,1161 @@ async fn write_parquet_optimized(
         .set_max_row_group_size(3_500_000) // Match batch size
         .set_column_index_truncate_length(Some(64))
         .set_statistics_enabled(EnabledStatistics::Chunk)
-        .build();
+        .set_key_value_metadata(key_value_metadata)
+        .build()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn write_parquet_optimized(
+    mut batch_rx: mpsc::Receiver<Result<OffsetBatch, ProcessingError>>,
+    s3_client: &S3Client,
+    bucket: &str,
+    output_key: &str,
+    schema: Arc<Schema>,
+    job_id: &str,
+    governor: &MemoryGovernor,
+    path: ProcessingPath,
+    props: WriterProperties,
+) -> Result<WriteSummary, ProcessingError> {
+    let mut buffer = Vec::with_capacity(match path {
+        ProcessingPath::Standard => PARQUET_BUFFER_SIZE, // 512MB initial
+        ProcessingPath::Fast => FAST_PARQUET_BUFFER_SIZE,
+    });
 
     let mut batches_written = 0;
+    let mut rows_written = 0;
     let start_time = std::time::Instant::now();
 
     // Create writer in a scope so it's dropped before we use buffer
     {
-        let mut writer = ArrowWriter::try_new(&mut buffer, schema, Some(props))?;
+        let mut writer = ArrowWriter::try_new(&mut buffer, schema, Some(props))
+            .map_err(ProcessingError::write)?;
 
-        while let Some(batch) = batch_rx.recv().await {
-            writer.write(&batch)?;
+        while let Some(offset_batch) = batch_rx.recv().await {
+            let offset_batch = offset_batch?;
+            writer
+                .write(&offset_batch.batch)
+                .map_err(ProcessingError::write)?;
+            governor.release(offset_batch.memory_bytes);
+            governor.set_writer_bytes(writer.bytes_written() + writer.memory_size());
             batches_written += 1;
+            rows_written += offset_batch.batch.num_rows() as u64;
 
             if batches_written % 5 == 0 {
-                println!("Job {}: Written {} batches", job_id, batches_written);
+                info!(job_id, batches = batches_written, "Written batches");
             }
         }
 
-        writer.close()?;
+        // A header-only CSV sends no batches; closing still writes a valid file with the
+        // schema and zero rows, which queries can read like any other
+        if batches_written == 0 {
+            info!(job_id, "No data rows, writing schema-only parquet");
+        }
+
+        writer.close().map_err(ProcessingError::write)?;
     } // writer is dropped here, releasing the mutable borrow on buffer
 
-    println!(
-        "Job {}: Writing complete - {} batches, uploading {:.2} MB to S3",
+    info!(
         job_id,
-        batches_written,
-        buffer.len() as f64 / (1024.0 * 1024.0)
+        batches = batches_written,
+        bytes = buffer.len(),
+        "Writing complete, uploading to S3"
     );
 
-    upload_to_s3(bucket, output_key, buffer, job_id).await?;
+    let upload_span = info_span!("s3_upload", bytes = buffer.len());
+    let output_etag = upload_object(s3_client, bucket, output_key, buffer, job_id)
+        .instrument(upload_span)
+        .await
+        .map_err(ProcessingError::upload)?;
+
+    info!(
+        job_id,
+        elapsed_ms = start_time.elapsed().as_millis() as u64,
+        "Upload completed"
+    );
+
+    Ok(WriteSummary {
+        rows_written,
+        output_etag,
+    })
+}
+
+fn rows_per_batch(path: ProcessingPath) -> usize {
+    match path {
+        ProcessingPath::Standard => ROWS_PER_BATCH,
+        ProcessingPath::Fast => FAST_ROWS_PER_BATCH,
+    }
+}
+
+// Writes each group of `batches_per_checkpoint` batches as row groups of the output's
+// multipart upload, saving the source offset once they are uploaded so a redelivered
+// message can pick up where this execution stopped
+#[allow(clippy::too_many_arguments)]
+async fn write_parquet_checkpointed(
+    mut batch_rx: mpsc::Receiver<Result<OffsetBatch, ProcessingError>>,
+    s3_client: &S3Client,
+    bucket: &str,
+    mut output: MultipartParquet,
+    schema: Arc<Schema>,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    limits: ConversionLimits,
+    job_id: &str,
+    governor: &MemoryGovernor,
+    props: WriterProperties,
+) -> Result<WriteSummary, ProcessingError> {
+    let start_time = std::time::Instant::now();
+
+    let written: Result<(), ProcessingError> = async {
+        let mut writer: Option<ArrowWriter<Vec<u8>>> = None;
+        let mut batches_in_chunk = 0;
+        let mut chunk_stats = vec![ColumnStats::default(); schema.fields().len()];
+        let mut last_offset = output.resume_offset();
+
+        while let Some(offset_batch) = batch_rx.recv().await {
+            let offset_batch = offset_batch?;
+            let chunk_writer = match writer.as_mut() {
+                Some(chunk_writer) => chunk_writer,
+                None => writer.insert(
+                    ArrowWriter::try_new(
+                        Vec::with_capacity(PARQUET_BUFFER_SIZE),
+                        schema.clone(),
+                        Some(props.clone()),
+                    )
+                    .map_err(ProcessingError::write)?,
+                ),
+            };
+
+            chunk_writer
+                .write(&offset_batch.batch)
+                .map_err(ProcessingError::write)?;
+            governor.release(offset_batch.memory_bytes);
+            governor.set_writer_bytes(
+                chunk_writer.bytes_written() + chunk_writer.memory_size() + output.pending_bytes(),
+            );
+            batches_in_chunk += 1;
+            merge_column_stats(&mut chunk_stats, &offset_batch.column_stats);
+            last_offset = offset_batch.end_offset;
+
+            if batches_in_chunk >= limits.batches_per_checkpoint {
+                if let Some(chunk_writer) = writer.take() {
+                    let chunk = chunk_writer.into_inner().map_err(ProcessingError::write)?;
+                    output
+                        .append(&chunk, last_offset, &chunk_stats)
+                        .map_err(ProcessingError::write)?;
+                    chunk_stats.fill(ColumnStats::default());
+                    // Too little for a part of its own waits for the next chunk
+                    if output.pending_bytes() >= limits.min_part_bytes {
+                        output
+                            .checkpoint(s3_client, bucket, dynamodb_client, table_name, job_id)
+                            .await?;
+                    }
+                    governor.set_writer_bytes(output.pending_bytes());
+                }
+                batches_in_chunk = 0;
+            }
+        }
+
+        if let Some(chunk_writer) = writer.take() {
+            let chunk = chunk_writer.into_inner().map_err(ProcessingError::write)?;
+            output
+                .append(&chunk, last_offset, &chunk_stats)
+                .map_err(ProcessingError::write)?;
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = written {
+        // A cancelled job's output is never read, so its upload goes with it. After any
+        // other failure the upload is kept for the retry to carry on with.
+        if e.is_cancelled() {
+            output.abort(s3_client, bucket, job_id).await;
+        }
+        return Err(e);
+    }
+
+    let rows_written = output.rows_written();
+    let output_etag = output.complete(s3_client, bucket, job_id).await?;
 
-    let total_time = start_time.elapsed().as_secs_f64();
-    println!(
-        "Job {}: Upload completed in {:.2}s total",
-        job_id, total_time
+    info!(
+        job_id,
+        rows = rows_written,
+        elapsed_ms = start_time.elapsed().as_millis() as u64,
+        "Checkpointed write complete"
     );
 
-    Ok(())
+    Ok(WriteSummary {
+        rows_written,
+        output_etag,
+    })
+}
+
+// The output of a checkpointed conversion: one parquet file built through a multipart
+// upload that outlives any one execution. The rows of each checkpoint are written as a
+// parquet file of their own, whose column chunks are appended to the upload as they are.
+// Its row groups, moved to where those chunks land, join the footer the upload ends with.
+// That footer so far is kept beside the output, so an execution resuming from the
+// checkpoint can finish the same file.
+struct MultipartParquet {
+    key: String,
+    // The upload and what is in it, as last saved
+    checkpoint: ConversionCheckpoint,
+    // The footer's schema and key-value metadata, and every row group appended so far
+    file_metadata: FileMetaData,
+    row_groups: Vec<RowGroupMetaData>,
+    // Appended since the last checkpoint and not uploaded yet, with its rows, their value
+    // counts and the source offset just past them
+    pending: Vec<u8>,
+    pending_rows: u64,
+    pending_stats: Vec<ColumnStats>,
+    pending_offset: u64,
+}
+
+impl MultipartParquet {
+    // Carries on with the upload the checkpoint recorded, or starts a new one
+    async fn open(
+        s3_client: &S3Client,
+        bucket: &str,
+        key: &str,
+        mut checkpoint: ConversionCheckpoint,
+        schema: Arc<Schema>,
+        props: WriterProperties,
+        job_id: &str,
+    ) -> Result<Self, ProcessingError> {
+        let columns = schema.fields().len();
+        // A file with no rows has the footer every chunk's row groups are added to
+        let empty = ArrowWriter::try_new(Vec::new(), schema, Some(props))
+            .and_then(ArrowWriter::into_inner)
+            .map_err(ProcessingError::write)?;
+        let file_metadata = decode_footer(&empty)
+            .map_err(ProcessingError::write)?
+            .file_metadata()
+            .clone();
+
+        let footer_key = parquet_footer_key(key);
+        let mut row_groups = Vec::new();
+        if !checkpoint.parts.is_empty() {
+            match read_object(s3_client, bucket, &footer_key)
+                .await
+                .map_err(ProcessingError::read)?
+            {
+                Some(footer) => {
+                    // The footer is saved ahead of the checkpoint, so it may also hold the
+                    // row groups of a part the checkpoint never recorded. That part is
+                    // uploaded again under the same number.
+                    row_groups = decode_footer(&footer)
+                        .map_err(ProcessingError::read)?
+                        .row_groups()
+                        .to_vec();
+                    if row_groups.len() < checkpoint.row_groups {
+                        return Err(ProcessingError::read(format!(
+                            "saved footer has {} row groups, the checkpoint {}",
+                            row_groups.len(),
+                            checkpoint.row_groups
+                        )));
+                    }
+                    row_groups.truncate(checkpoint.row_groups);
+                }
+                None => {
+                    // Only completing the upload removes the footer, so an earlier
+                    // execution finished the file but stopped before the job was marked
+                    // done. The upload can't be added to any more.
+                    warn!(
+                        job_id,
+                        "Checkpointed upload was already completed, converting from the start"
+                    );
+                    checkpoint = ConversionCheckpoint::default();
+                }
+            }
+        }
+
+        if checkpoint.parts.is_empty() {
+            checkpoint.upload_id = create_multipart_upload(s3_client, bucket, key)
+                .await
+                .map_err(ProcessingError::upload)?;
+        }
+        // A checkpoint saved before value counts were kept has none to carry on from
+        checkpoint
+            .column_stats
+            .resize(columns, ColumnStats::default());
+
+        Ok(MultipartParquet {
+            key: key.to_string(),
+            pending_offset: checkpoint.byte_offset,
+            checkpoint,
+            file_metadata,
+            row_groups,
+            pending: Vec::new(),
+            pending_rows: 0,
+            pending_stats: vec![ColumnStats::default(); columns],
+        })
+    }
+
+    fn resume_offset(&self) -> u64 {
+        self.checkpoint.byte_offset
+    }
+
+    fn resume_column_stats(&self) -> Vec<ColumnStats> {
+        self.checkpoint.column_stats.clone()
+    }
+
+    fn rows_written(&self) -> u64 {
+        self.checkpoint.rows_written + self.pending_rows
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Adds the row groups of `chunk`, a whole parquet file written with the output's schema
+    // from the rows before `end_offset`, whose value counts are `column_stats`
+    fn append(
+        &mut self,
+        chunk: &[u8],
+        end_offset: u64,
+        column_stats: &[ColumnStats],
+    ) -> Result<(), ParquetError> {
+        let metadata = decode_footer(chunk)?;
+
+        // Only the output's first bytes are its magic number; a later chunk's is dropped
+        let start = self.checkpoint.bytes_written + self.pending.len() as u64;
+        let skip = if start == 0 { 0 } else { PARQUET_MAGIC.len() };
+        let data_end = metadata
+            .row_groups()
+            .iter()
+            .flat_map(|row_group| row_group.columns())
+            .map(|column| {
+                let (offset, length) = column.byte_range();
+                offset + length
+            })
+            .max()
+            .map_or(skip, |end| end as usize);
+
+        let shift = start as i64 - skip as i64;
+        for row_group in metadata.row_groups() {
+            let ordinal = self.row_groups.len();
+            self.row_groups
+                .push(moved_row_group(row_group, shift, ordinal)?);
+        }
+        self.pending.extend_from_slice(&chunk[skip..data_end]);
+        self.pending_rows += metadata.file_metadata().num_rows().max(0) as u64;
+        merge_column_stats(&mut self.pending_stats, column_stats);
+        self.pending_offset = end_offset;
+        Ok(())
+    }
+
+    // Uploads what was appended since the last checkpoint as the next part, then saves the
+    // footer and the checkpoint that go with it
+    async fn checkpoint(
+        &mut self,
+        s3_client: &S3Client,
+        bucket: &str,
+        dynamodb_client: &DynamoDbClient,
+        table_name: &str,
+        job_id: &str,
+    ) -> Result<(), ProcessingError> {
+        let bytes = self.pending.len() as u64;
+        let number = self.checkpoint.parts.len() as i32 + 1;
+        let data = std::mem::take(&mut self.pending);
+        let part = upload_part(
+            s3_client,
+            bucket,
+            &self.key,
+            &self.checkpoint.upload_id,
+            number,
+            data,
+        )
+        .instrument(info_span!("s3_upload", part = number, bytes))
+        .await
+        .map_err(ProcessingError::upload)?;
+
+        let footer = self.footer().map_err(ProcessingError::write)?;
+        upload_object(
+            s3_client,
+            bucket,
+            &parquet_footer_key(&self.key),
+            footer,
+            job_id,
+        )
+        .await
+        .map_err(ProcessingError::upload)?;
+
+        self.checkpoint.parts.push(part);
+        self.checkpoint.bytes_written += bytes;
+        self.checkpoint.row_groups = self.row_groups.len();
+        self.checkpoint.rows_written += std::mem::take(&mut self.pending_rows);
+        merge_column_stats(&mut self.checkpoint.column_stats, &self.pending_stats);
+        self.pending_stats.fill(ColumnStats::default());
+        self.checkpoint.byte_offset = self.pending_offset;
+
+        save_job_checkpoint(dynamodb_client, table_name, job_id, &self.checkpoint)
+            .instrument(info_span!(
+                "dynamo_update",
+                checkpoint_offset = self.checkpoint.byte_offset
+            ))
+            .await
+            .map_err(ProcessingError::dynamo)
+    }
+
+    // The footer over every row group so far, as it ends a parquet file
+    fn footer(&self) -> Result<Vec<u8>, ParquetError> {
+        let metadata = ParquetMetaData::new(self.file_metadata.clone(), self.row_groups.clone());
+        let mut footer = Vec::new();
+        ParquetMetaDataWriter::new(&mut footer, &metadata).finish()?;
+        Ok(footer)
+    }
+
+    // Uploads the rest of the file and its footer as the last part, which S3 lets be any
+    // size, and completes the upload. Returns the output's ETag.
+    async fn complete(
+        mut self,
+        s3_client: &S3Client,
+        bucket: &str,
+        job_id: &str,
+    ) -> Result<Option<String>, ProcessingError> {
+        let mut last_part = std::mem::take(&mut self.pending);
+        // With no rows at all the file is its magic number and a footer of no row groups
+        if self.checkpoint.bytes_written == 0 && last_part.is_empty() {
+            last_part.extend_from_slice(PARQUET_MAGIC);
+        }
+        last_part.extend(self.footer().map_err(ProcessingError::write)?);
+
+        let bytes = last_part.len();
+        let number = self.checkpoint.parts.len() as i32 + 1;
+        let part = upload_part(
+            s3_client,
+            bucket,
+            &self.key,
+            &self.checkpoint.upload_id,
+            number,
+            last_part,
+        )
+        .instrument(info_span!("s3_upload", part = number, bytes))
+        .await
+        .map_err(ProcessingError::upload)?;
+        self.checkpoint.parts.push(part);
+
+        let output_etag = complete_multipart_upload(
+            s3_client,
+            bucket,
+            &self.key,
+            &self.checkpoint.upload_id,
+            &self.checkpoint.parts,
+        )
+        .await
+        .map_err(ProcessingError::upload)?;
+
+        // Best effort: deleting the job's parquet takes a footer left behind with it
+        let footer_key = parquet_footer_key(&self.key);
+        if let Err(e) = delete_from_s3(s3_client, bucket, &[footer_key], job_id).await {
+            warn!(job_id, error = %e, "Could not delete the saved footer");
+        }
+
+        Ok(output_etag)
+    }
+
+    // Best effort: whatever isn't removed here goes with the bucket's lifecycle rule for
+    // unfinished uploads, or with the job's parquet when the job is deleted
+    async fn abort(&self, s3_client: &S3Client, bucket: &str, job_id: &str) {
+        if let Err(e) =
+            abort_multipart_upload(s3_client, bucket, &self.key, &self.checkpoint.upload_id).await
+        {
+            warn!(job_id, error = %e, "Could not abort the cancelled job's upload");
+        }
+        let footer_key = parquet_footer_key(&self.key);
+        if let Err(e) = delete_from_s3(s3_client, bucket, &[footer_key], job_id).await {
+            warn!(job_id, error = %e, "Could not delete the cancelled job's saved footer");
+        }
+    }
+}
+
+// The metadata in the footer that ends `file`, a parquet file or a footer on its own
+fn decode_footer(file: &[u8]) -> Result<ParquetMetaData, ParquetError> {
+    let corrupt = || ParquetError::General("Invalid Parquet file. Corrupt footer".to_string());
+    let tail = file.len().checked_sub(FOOTER_SIZE).ok_or_else(corrupt)?;
+    if &file[tail + 4..] != PARQUET_MAGIC {
+        return Err(corrupt());
+    }
+    let length = u32::from_le_bytes([file[tail], file[tail + 1], file[tail + 2], file[tail + 3]]);
+    let start = tail.checked_sub(length as usize).ok_or_else(corrupt)?;
+    ParquetMetaDataReader::decode_metadata(&file[start..tail])
+}
+
+// `row_group` as the `ordinal`th row group of a file it sits `shift` bytes further into.
+// Page indexes and bloom filters are written after the row groups and aren't carried over,
+// so the moved row group doesn't point at any.
+fn moved_row_group(
+    row_group: &RowGroupMetaData,
+    shift: i64,
+    ordinal: usize,
+) -> Result<RowGroupMetaData, ParquetError> {
+    let columns = row_group
+        .columns()
+        .iter()
+        .map(|column| {
+            column
+                .clone()
+                .into_builder()
+                .set_data_page_offset(column.data_page_offset() + shift)
+                .set_dictionary_page_offset(column.dictionary_page_offset().map(|o| o + shift))
+                .set_index_page_offset(column.index_page_offset().map(|o| o + shift))
+                .set_offset_index_offset(None)
+                .set_offset_index_length(None)
+                .set_column_index_offset(None)
+                .set_column_index_length(None)
+                .set_bloom_filter_offset(None)
+                .set_bloom_filter_length(None)
+                .build()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut moved = row_group
+        .clone()
+        .into_builder()
+        .set_column_metadata(columns)
+        .set_ordinal(ordinal as i16);
+    if let Some(offset) = row_group.file_offset() {
+        moved = moved.set_file_offset(offset + shift);
+    }
+    moved.build()
+}
+
+// The object's bytes, or None when there is no object at the key
+async fn read_object(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    let response = match s3_client.get_object().bucket(bucket).key(key).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return match e.as_service_error() {
+                Some(service_error) if service_error.is_no_such_key() => Ok(None),
+                _ => Err(Error::s3("GetObject", e)),
+            };
+        }
+    };
+    let bytes = response
+        .body
+        .collect()
+        .await
+        .map_err(Error::s3_stream)?
+        .into_bytes();
+    Ok(Some(bytes.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubRequest, StubResponse};
+    use arrow::compute::concat_batches;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use serde_json::{Value, json};
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    const BUCKET: &str = "uploads";
+    const SOURCE_KEY: &str = "uploads/job-1.csv";
+    const OUTPUT_KEY: &str = "parquet/job-1.parquet";
+
+    // Both line endings, blank lines, a quoted comma, an empty value and no newline after
+    // the last row, so a resumed read has to find its way past each of them. The scores
+    // that aren't numbers fall in different checkpoints.
+    const SOURCE_CSV: &str = "id,name,score\r\n\
+        1,alpha,1.5\r\n\
+        2,\"beta, the second\",2.5\n\
+        \n\
+        3,gamma,3.5\r\n\
+        4,delta,\n\
+        5,epsilon,n/a\n\
+        \r\n\
+        6,zeta,6.5\n\
+        7,\"eta\",7.5\r\n\
+        8,theta,8.5\n\
+        9,iota,9.5\n\
+        10,kappa,ten\n\
+        11,lambda,11.5";
+    const SOURCE_ROWS: usize = 11;
+
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    // Two rows a batch and two batches a checkpoint, so the rows above are uploaded as
+    // three parts with a checkpoint after each, and the footer as a fourth
+    const CHECKPOINTED: ConversionLimits = ConversionLimits {
+        rows_per_batch: 2,
+        checkpoint_threshold_bytes: 0,
+        batches_per_checkpoint: 2,
+        min_part_bytes: 0,
+    };
+    const STRAIGHT_THROUGH: ConversionLimits = ConversionLimits {
+        rows_per_batch: 2,
+        checkpoint_threshold_bytes: i64::MAX,
+        batches_per_checkpoint: 2,
+        min_part_bytes: 0,
+    };
+
+    // The value of one query parameter of a request target; a bare flag such as `uploads`
+    // has an empty one
+    fn query_param<'a>(target: &'a str, name: &str) -> Option<&'a str> {
+        let (_, query) = target.split_once('?')?;
+        query.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key == name).then_some(value)
+        })
+    }
+
+    // An in-memory bucket answering HeadObject, GetObject with or without a range,
+    // PutObject, DeleteObject and the multipart upload calls; objects are addressed by
+    // path as /bucket/key. A completed upload joins the parts it names, in order.
+    fn s3_stub(objects: Objects) -> StubEndpoint {
+        let uploads: Mutex<BTreeMap<String, BTreeMap<i32, Vec<u8>>>> = Mutex::default();
+        StubEndpoint::start(move |request| {
+            let path = request.target.split('?').next().unwrap_or_default();
+            let key = path
+                .trim_start_matches('/')
+                .split_once('/')
+                .map_or("", |(_, key)| key)
+                .to_string();
+            let mut objects = objects.lock().unwrap();
+            let mut uploads = uploads.lock().unwrap();
+            let upload_id = query_param(&request.target, "uploadId");
+
+            match (request.method.as_str(), upload_id) {
+                ("POST", None) if query_param(&request.target, "uploads").is_some() => {
+                    let upload_id = format!("upload-{}", uploads.len() + 1);
+                    uploads.insert(upload_id.clone(), BTreeMap::new());
+                    StubResponse::xml(&format!(
+                        "<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key>\
+                         <UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                        BUCKET, key, upload_id
+                    ))
+                }
+                ("PUT", Some(upload_id)) => {
+                    let number: i32 = query_param(&request.target, "partNumber")
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    let Some(parts) = uploads.get_mut(upload_id) else {
+                        return StubResponse::bytes(404, Vec::new());
+                    };
+                    parts.insert(number, request.body.clone());
+                    StubResponse::bytes(200, Vec::new())
+                        .with_header("ETag", format!("\"{}-{}\"", upload_id, number))
+                }
+                ("POST", Some(upload_id)) => {
+                    let Some(parts) = uploads.remove(upload_id) else {
+                        return StubResponse::bytes(404, Vec::new());
+                    };
+                    let named: Vec<i32> = String::from_utf8_lossy(&request.body)
+                        .split("<PartNumber>")
+                        .skip(1)
+                        .filter_map(|rest| rest.split_once("</PartNumber>"))
+                        .map(|(number, _)| number.parse().unwrap())
+                        .collect();
+                    let object = named.iter().flat_map(|number| parts[number].clone());
+                    objects.insert(key.clone(), object.collect());
+                    StubResponse::xml(&format!(
+                        "<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key>\
+                         <ETag>\"{}-complete\"</ETag></CompleteMultipartUploadResult>",
+                        BUCKET, key, upload_id
+                    ))
+                }
+                ("DELETE", Some(upload_id)) => {
+                    uploads.remove(upload_id);
+                    StubResponse::bytes(204, Vec::new())
+                }
+                ("DELETE", None) => {
+                    objects.remove(&key);
+                    StubResponse::bytes(204, Vec::new())
+                }
+                ("PUT", None) => {
+                    objects.insert(key, request.body.clone());
+                    StubResponse::bytes(200, Vec::new()).with_header("ETag", "\"stub\"")
+                }
+                ("HEAD", None) => match objects.get(&key) {
+                    Some(object) => StubResponse::bytes(200, Vec::new())
+                        .with_header("Content-Length", object.len().to_string()),
+                    None => StubResponse::bytes(404, Vec::new()),
+                },
+                ("GET", None) => match (objects.get(&key), request.header("range")) {
+                    (Some(object), Some(range)) => {
+                        let (start, end) = byte_range(range, object.len());
+                        StubResponse::bytes(206, object[start..end].to_vec()).with_header(
+                            "Content-Range",
+                            format!("bytes {}-{}/{}", start, end - 1, object.len()),
+                        )
+                    }
+                    (Some(object), None) => StubResponse::bytes(200, object.clone()),
+                    (None, _) => StubResponse {
+                        status: 404,
+                        ..StubResponse::xml(
+                            "<Error><Code>NoSuchKey</Code><Message>no such key</Message></Error>",
+                        )
+                    },
+                },
+                _ => StubResponse::bytes(404, Vec::new()),
+            }
+        })
+    }
+
+    // The half-open byte span of a `bytes=start-` or `bytes=start-end` range
+    fn byte_range(range: &str, len: usize) -> (usize, usize) {
+        let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+        let start: usize = start.parse().unwrap();
+        let end = end.parse::<usize>().map_or(len, |end| (end + 1).min(len));
+        (start, end)
+    }
+
+    // The job as the conversion sees it: always processing, with whatever checkpoint was
+    // saved last. Once `saves_left` runs out every save fails, as if the execution died
+    // after uploading a part but before recording it.
+    #[derive(Default)]
+    struct JobItem {
+        checkpoint: Option<Value>,
+        saves_left: Option<usize>,
+        // The value counts the conversion recorded for the job
+        column_stats: Option<Value>,
+        // How many cancellation checks see the job still processing before the user
+        // cancels it; None never cancels
+        checks_before_cancel: Option<usize>,
+        // How many cancellation checks the conversion made
+        status_checks: usize,
+    }
+
+    fn dynamodb_stub(job: Arc<Mutex<JobItem>>) -> StubEndpoint {
+        StubEndpoint::start(move |request| {
+            let mut job = job.lock().unwrap();
+            let body = request.json();
+            let expression = body["UpdateExpression"].as_str().unwrap_or_default();
+            let saves_checkpoint = expression.contains("checkpoint_offset");
+
+            match request.operation() {
+                Some("GetItem") if body["ProjectionExpression"] == "#status" => {
+                    job.status_checks += 1;
+                    let status = match job.checks_before_cancel {
+                        Some(checks) if job.status_checks > checks => "cancelled",
+                        _ => "processing",
+                    };
+                    StubResponse::json(json!({"Item": {"status": {"S": status}}}))
+                }
+                Some("GetItem") => {
+                    let mut item = job.checkpoint.clone().unwrap_or_else(|| json!({}));
+                    item["status"] = json!({"S": "processing"});
+                    StubResponse::json(json!({"Item": item}))
+                }
+                Some("UpdateItem") if saves_checkpoint => {
+                    if job.saves_left == Some(0) {
+                        return StubResponse::dynamodb_error("InternalServerError", None);
+                    }
+                    job.saves_left = job.saves_left.map(|saves| saves - 1);
+                    let values = &body["ExpressionAttributeValues"];
+                    job.checkpoint = Some(json!({
+                        "checkpoint_offset": values[":offset"],
+                        "checkpoint_rows": values[":rows"],
+                        "checkpoint_upload_id": values[":upload_id"],
+                        "checkpoint_parts": values[":parts"],
+                        "checkpoint_bytes": values[":bytes"],
+                        "checkpoint_row_groups": values[":row_groups"],
+                        "checkpoint_column_stats": values[":column_stats"]
+                    }));
+                    StubResponse::json(json!({}))
+                }
+                Some("UpdateItem") if expression.starts_with("SET column_stats") => {
+                    job.column_stats = Some(body["ExpressionAttributeValues"][":stats"].clone());
+                    StubResponse::json(json!({}))
+                }
+                _ => StubResponse::json(json!({})),
+            }
+        })
+    }
+
+    fn source_objects(csv: &str) -> Objects {
+        let mut objects = BTreeMap::new();
+        objects.insert(SOURCE_KEY.to_string(), csv.as_bytes().to_vec());
+        Arc::new(Mutex::new(objects))
+    }
+
+    async fn convert(
+        s3: &StubEndpoint,
+        dynamodb: &StubEndpoint,
+        limits: ConversionLimits,
+    ) -> Result<ConversionSummary, ProcessingError> {
+        let columns = vec![
+            ColumnDefinition {
+                column: "id".to_string(),
+                column_type: DataType::Integer,
+            },
+            ColumnDefinition {
+                column: "name".to_string(),
+                column_type: DataType::String,
+            },
+            ColumnDefinition {
+                column: "score".to_string(),
+                column_type: DataType::Float,
+            },
+        ];
+        convert_csv_to_parquet(
+            &s3.s3_client(),
+            BUCKET,
+            SOURCE_KEY,
+            &columns,
+            &ConversionOptions::default(),
+            OUTPUT_KEY,
+            "job-1",
+            &dynamodb.dynamodb_client(),
+            "jobs",
+            Arc::new(AtomicU64::new(0)),
+            ProcessingPath::Standard,
+            &JobProvenance::default(),
+            limits,
+        )
+        .await
+    }
+
+    // The output's reader, which checks the footer against the file as it opens it
+    fn output_reader(objects: &Objects) -> ParquetRecordBatchReaderBuilder<std::fs::File> {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&objects.lock().unwrap()[OUTPUT_KEY])
+            .unwrap();
+        ParquetRecordBatchReaderBuilder::try_new(file).unwrap()
+    }
+
+    // Every row of the output, in order, as one batch
+    fn read_rows(objects: &Objects) -> RecordBatch {
+        let reader = output_reader(objects).build().unwrap();
+        let batches: Vec<RecordBatch> = reader.map(|batch| batch.unwrap()).collect();
+        concat_batches(&batches[0].schema(), &batches).unwrap()
+    }
+
+    // The rows written, and the value counts recorded for them
+    async fn convert_straight_through() -> (RecordBatch, Value) {
+        let objects = source_objects(SOURCE_CSV);
+        let s3 = s3_stub(objects.clone());
+        let job = Arc::new(Mutex::new(JobItem::default()));
+        let dynamodb = dynamodb_stub(job.clone());
+
+        convert(&s3, &dynamodb, STRAIGHT_THROUGH).await.unwrap();
+        let column_stats = job.lock().unwrap().column_stats.clone().unwrap();
+        (read_rows(&objects), column_stats)
+    }
+
+    fn is_upload_part(request: &StubRequest) -> bool {
+        request.method == "PUT" && query_param(&request.target, "partNumber").is_some()
+    }
+
+    #[tokio::test]
+    async fn a_checkpointed_conversion_writes_the_same_rows_as_a_single_file() {
+        let (expected, _) = convert_straight_through().await;
+        assert_eq!(expected.num_rows(), SOURCE_ROWS);
+
+        let objects = source_objects(SOURCE_CSV);
+        let s3 = s3_stub(objects.clone());
+        let dynamodb = dynamodb_stub(Arc::default());
+
+        let summary = convert(&s3, &dynamodb, CHECKPOINTED).await.unwrap();
+
+        assert_eq!(summary.output_key, OUTPUT_KEY);
+        assert_eq!(summary.rows_written, SOURCE_ROWS as u64);
+        assert_eq!(
+            summary.output_etag.as_deref(),
+            Some("\"upload-1-complete\"")
+        );
+        assert_eq!(read_rows(&objects), expected);
+
+        // One upload of a part per checkpoint and one for the footer, joined into a file
+        // with a row group per checkpoint
+        let requests = s3.requests();
+        assert_eq!(requests.iter().filter(|r| is_upload_part(r)).count(), 4);
+        assert_eq!(output_reader(&objects).metadata().num_row_groups(), 3);
+        assert!(
+            !objects
+                .lock()
+                .unwrap()
+                .contains_key(&parquet_footer_key(OUTPUT_KEY))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_checkpointed_conversion_of_no_rows_writes_an_empty_file() {
+        let objects = source_objects("id,name,score\n");
+        let s3 = s3_stub(objects.clone());
+        let dynamodb = dynamodb_stub(Arc::default());
+
+        let summary = convert(&s3, &dynamodb, CHECKPOINTED).await.unwrap();
+
+        assert_eq!(summary.rows_written, 0);
+        let reader = output_reader(&objects);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+        let columns: Vec<&str> = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().as_str())
+            .collect();
+        assert_eq!(columns, ["id", "name", "score"]);
+    }
+
+    #[tokio::test]
+    async fn a_conversion_killed_between_parts_resumes_the_same_upload() {
+        let (expected, expected_stats) = convert_straight_through().await;
+        assert_eq!(
+            expected_stats["M"]["score"]["M"],
+            json!({
+                "empty": {"N": "1"},
+                "coercion_failures": {"N": "2"},
+                "written": {"N": "8"}
+            })
+        );
+
+        for saved_parts in 0..3 {
+            let objects = source_objects(SOURCE_CSV);
+            let job = Arc::new(Mutex::new(JobItem {
+                saves_left: Some(saved_parts),
+                ..JobItem::default()
+            }));
+            let s3 = s3_stub(objects.clone());
+            let dynamodb = dynamodb_stub(job.clone());
+
+            let killed = convert(&s3, &dynamodb, CHECKPOINTED).await;
+            assert!(killed.is_err(), "killed after {} parts", saved_parts);
+
+            let resume_offset = {
+                let mut job = job.lock().unwrap();
+                job.saves_left = None;
+                job.checkpoint.as_ref().map(|checkpoint| {
+                    checkpoint["checkpoint_offset"]["N"]
+                        .as_str()
+                        .unwrap()
+                        .parse::<u64>()
+                        .unwrap()
+                })
+            };
+            let requests_before = s3.requests().len();
+
+            let summary = convert(&s3, &dynamodb, CHECKPOINTED).await.unwrap();
+
+            assert_eq!(
+                summary.rows_written, SOURCE_ROWS as u64,
+                "killed after {} parts",
+                saved_parts
+            );
+            assert_eq!(
+                read_rows(&objects),
+                expected,
+                "killed after {} parts",
+                saved_parts
+            );
+
+            // The counts cover the rows uploaded before the kill as well as those after
+            assert_eq!(
+                summary.rejected_values, 2,
+                "killed after {} parts",
+                saved_parts
+            );
+            assert_eq!(
+                job.lock().unwrap().column_stats.as_ref(),
+                Some(&expected_stats),
+                "killed after {} parts",
+                saved_parts
+            );
+
+            // Once a part is checkpointed the resumed execution finishes the same upload;
+            // before that it has nothing to carry on with and starts its own
+            let upload = if resume_offset.is_some() {
+                "upload-1"
+            } else {
+                "upload-2"
+            };
+            assert_eq!(
+                summary.output_etag,
+                Some(format!("\"{}-complete\"", upload)),
+                "killed after {} parts",
+                saved_parts
+            );
+
+            // The resumed execution reads the source from one byte before the checkpoint,
+            // and the header from the start of the file
+            let requests = s3.requests();
+            let ranges: Vec<String> = requests[requests_before..]
+                .iter()
+                .filter(|request| request.method == "GET")
+                .filter_map(|request| request.header("range").map(str::to_string))
+                .collect();
+            match resume_offset {
+                Some(offset) => assert_eq!(
+                    ranges,
+                    vec![
+                        format!("bytes={}-", offset - 1),
+                        "bytes=0-65535".to_string()
+                    ]
+                ),
+                None => assert!(ranges.is_empty()),
+            }
+        }
+    }
+
+    // The recorded counts for one column as (empty, coercion failures, written)
+    fn recorded_counts(column_stats: &Value, column: &str) -> (u64, u64, u64) {
+        let counts = &column_stats["M"][column]["M"];
+        let count = |name: &str| counts[name]["N"].as_str().unwrap().parse().unwrap();
+        (count("empty"), count("coercion_failures"), count("written"))
+    }
+
+    #[tokio::test]
+    async fn value_counts_match_a_fixture_with_known_proportions_of_bad_values() {
+        // A tenth of the ids aren't numbers, an eighth of the names are empty, and a
+        // quarter of the scores are empty with another 150 of them not numbers
+        let mut csv = String::from("id,name,score\n");
+        for i in 0..1000 {
+            let id = if i % 10 == 9 {
+                format!("x{}", i)
+            } else {
+                i.to_string()
+            };
+            let name = if i % 8 == 0 {
+                String::new()
+            } else {
+                format!("n{}", i)
+            };
+            let score = if i % 4 == 0 {
+                String::new()
+            } else if i % 5 == 0 {
+                "bad".to_string()
+            } else {
+                format!("{}.5", i)
+            };
+            csv.push_str(&format!("{},{},{}\n", id, name, score));
+        }
+        let objects = source_objects(&csv);
+        let s3 = s3_stub(objects.clone());
+        let job = Arc::new(Mutex::new(JobItem::default()));
+        let dynamodb = dynamodb_stub(job.clone());
+
+        // Batches big enough that the job isn't checked for cancellation five hundred times
+        let limits = ConversionLimits {
+            rows_per_batch: 250,
+            ..STRAIGHT_THROUGH
+        };
+        let summary = convert(&s3, &dynamodb, limits).await.unwrap();
+
+        let column_stats = job.lock().unwrap().column_stats.clone().unwrap();
+        assert_eq!(recorded_counts(&column_stats, "id"), (0, 100, 900));
+        assert_eq!(recorded_counts(&column_stats, "name"), (125, 0, 875));
+        assert_eq!(recorded_counts(&column_stats, "score"), (250, 150, 600));
+        assert_eq!(summary.rows_written, 1000);
+        assert_eq!(summary.rejected_values, 250);
+
+        // Empty and rejected values alike are written as nulls
+        let rows = read_rows(&objects);
+        let nulls: Vec<usize> = rows.columns().iter().map(|c| c.null_count()).collect();
+        assert_eq!(nulls, [100, 125, 400]);
+    }
+
+    // A checkpointed conversion of the usual rows, with the user cancelling once the
+    // conversion has checked `checks_before_cancel` times
+    async fn convert_cancelling_after(
+        checks_before_cancel: usize,
+    ) -> (
+        Result<ConversionSummary, ProcessingError>,
+        Objects,
+        StubEndpoint,
+        usize,
+    ) {
+        let objects = source_objects(SOURCE_CSV);
+        let s3 = s3_stub(objects.clone());
+        let job = Arc::new(Mutex::new(JobItem {
+            checks_before_cancel: Some(checks_before_cancel),
+            ..JobItem::default()
+        }));
+        let dynamodb = dynamodb_stub(job.clone());
+
+        let converted = convert(&s3, &dynamodb, CHECKPOINTED).await;
+        let status_checks = job.lock().unwrap().status_checks;
+        (converted, objects, s3, status_checks)
+    }
+
+    fn aborted_uploads(s3: &StubEndpoint) -> usize {
+        s3.requests()
+            .iter()
+            .filter(|request| {
+                request.method == "DELETE" && query_param(&request.target, "uploadId").is_some()
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn a_job_cancelled_before_the_header_is_read_reads_nothing() {
+        let (converted, objects, s3, status_checks) = convert_cancelling_after(0).await;
+
+        assert!(converted.unwrap_err().is_cancelled());
+        assert_eq!(status_checks, 1);
+        assert!(s3.requests().iter().all(|request| request.method != "GET"));
+        assert!(!objects.lock().unwrap().contains_key(OUTPUT_KEY));
+    }
+
+    #[tokio::test]
+    async fn a_job_cancelled_mid_stream_stops_and_aborts_its_upload() {
+        // Cancelled after the first batch, before the second is checked
+        let (converted, objects, s3, status_checks) = convert_cancelling_after(2).await;
+
+        assert!(converted.unwrap_err().is_cancelled());
+        assert_eq!(status_checks, 3);
+        // A part the writer uploaded before the cancel goes with the aborted upload
+        assert_eq!(aborted_uploads(&s3), 1);
+        let objects = objects.lock().unwrap();
+        assert!(!objects.contains_key(OUTPUT_KEY));
+        assert!(!objects.contains_key(&parquet_footer_key(OUTPUT_KEY)));
+    }
+
+    #[tokio::test]
+    async fn a_cancel_after_the_last_check_is_too_late_to_stop_the_output() {
+        // The initial check, one per full batch of two and one before the last row
+        let checks = 1 + SOURCE_ROWS / 2 + 1;
+
+        let (converted, objects, s3, status_checks) = convert_cancelling_after(checks).await;
+
+        assert_eq!(converted.unwrap().rows_written, SOURCE_ROWS as u64);
+        assert_eq!(status_checks, checks);
+        assert_eq!(aborted_uploads(&s3), 0);
+        assert!(objects.lock().unwrap().contains_key(OUTPUT_KEY));
+    }
+
+    #[tokio::test]
+    async fn a_header_only_file_converts_to_an_empty_file_either_way() {
+        // With and without a newline after the header, converted in one upload and with
+        // checkpoints
+        for csv in ["id,name,score\r\n", "id,name,score"] {
+            for limits in [STRAIGHT_THROUGH, CHECKPOINTED] {
+                let objects = source_objects(csv);
+                let s3 = s3_stub(objects.clone());
+                let job = Arc::new(Mutex::new(JobItem::default()));
+                let dynamodb = dynamodb_stub(job.clone());
+
+                let summary = convert(&s3, &dynamodb, limits).await.unwrap();
+
+                assert_eq!(summary.rows_written, 0, "{:?}", csv);
+                assert_eq!(summary.rejected_values, 0, "{:?}", csv);
+                let reader = output_reader(&objects);
+                assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+                assert_eq!(reader.schema().fields().len(), 3);
+                let column_stats = job.lock().unwrap().column_stats.clone().unwrap();
+                assert_eq!(recorded_counts(&column_stats, "score"), (0, 0, 0));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_zero_byte_file_fails_without_writing_anything() {
+        let objects = source_objects("");
+        let s3 = s3_stub(objects.clone());
+        let dynamodb = dynamodb_stub(Arc::default());
+
+        let error = convert(&s3, &dynamodb, STRAIGHT_THROUGH).await.unwrap_err();
+
+        assert_eq!(error.stage(), "parse");
+        assert!(error.to_string().contains("empty CSV file"), "{}", error);
+        assert!(!error.is_retryable());
+        assert!(!objects.lock().unwrap().contains_key(OUTPUT_KEY));
+    }
 }
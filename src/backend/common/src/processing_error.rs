@@ -0,0 +1,160 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::error::Error as CommonError;
+
+// Longest summary we attach to a dead-lettered message; SQS attributes share the
+// 256KB message limit with the body, so keep this small
+const MAX_SUMMARY_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessingStage {
+    Read,
+    Parse,
+    Write,
+    Upload,
+    Dynamo,
+    Retries,
+    Cancelled,
+}
+
+impl ProcessingStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessingStage::Read => "read",
+            ProcessingStage::Parse => "parse",
+            ProcessingStage::Write => "write",
+            ProcessingStage::Upload => "upload",
+            ProcessingStage::Dynamo => "dynamo",
+            ProcessingStage::Retries => "retries",
+            ProcessingStage::Cancelled => "cancelled",
+        }
+    }
+
+    // Used when the error isn't a common::error::Error that can classify itself: I/O
+    // stages usually fail transiently, bad input and exhausted retries never recover
+    fn retryable_by_default(&self) -> bool {
+        match self {
+            ProcessingStage::Read | ProcessingStage::Upload | ProcessingStage::Dynamo => true,
+            ProcessingStage::Parse
+            | ProcessingStage::Write
+            | ProcessingStage::Retries
+            | ProcessingStage::Cancelled => false,
+        }
+    }
+}
+
+// A conversion failure tagged with the pipeline stage it happened in, carrying the error
+// chain (outermost error first) and whether another attempt could succeed
+#[derive(Debug, Clone)]
+pub struct ProcessingError {
+    stage: ProcessingStage,
+    chain: Vec<String>,
+    retryable: bool,
+}
+
+impl ProcessingError {
+    pub fn new<E: Into<Box<dyn Error + Send + Sync>>>(stage: ProcessingStage, error: E) -> Self {
+        let error = error.into();
+        let retryable = match error.downcast_ref::<CommonError>() {
+            Some(common_error) => common_error.is_retryable(),
+            None => stage.retryable_by_default(),
+        };
+
+        ProcessingError {
+            stage,
+            chain: error_chain(&*error),
+            retryable,
+        }
+    }
+
+    pub fn read<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self::new(ProcessingStage::Read, error)
+    }
+
+    pub fn parse<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self::new(ProcessingStage::Parse, error)
+    }
+
+    pub fn write<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self::new(ProcessingStage::Write, error)
+    }
+
+    pub fn upload<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self::new(ProcessingStage::Upload, error)
+    }
+
+    pub fn dynamo<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self::new(ProcessingStage::Dynamo, error)
+    }
+
+    pub fn retries<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self::new(ProcessingStage::Retries, error)
+    }
+
+    pub fn cancelled<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> Self {
+        Self::new(ProcessingStage::Cancelled, error)
+    }
+
+    pub fn stage(&self) -> &'static str {
+        self.stage.as_str()
+    }
+
+    pub fn chain(&self) -> &[String] {
+        &self.chain
+    }
+
+    // A cancelled job stopped because the user asked it to, not because anything failed
+    pub fn is_cancelled(&self) -> bool {
+        self.stage == ProcessingStage::Cancelled
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+
+    // The same failure once the job has been failed for good, so no later delivery of its
+    // message is waited for
+    pub fn into_final(self) -> Self {
+        ProcessingError {
+            retryable: false,
+            ..self
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let summary = self.to_string();
+        if summary.len() <= MAX_SUMMARY_LENGTH {
+            return summary;
+        }
+
+        let mut end = MAX_SUMMARY_LENGTH;
+        while !summary.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}...", &summary[..end])
+    }
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} stage failed: {}",
+            self.stage(),
+            self.chain().join(": ")
+        )
+    }
+}
+
+impl Error for ProcessingError {}
+
+pub fn error_chain(error: &(dyn Error + 'static)) -> Vec<String> {
+    let mut chain = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(cause) = source {
+        chain.push(cause.to_string());
+        source = cause.source();
+    }
+    chain
+}
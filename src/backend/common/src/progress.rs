@@ -0,0 +1,198 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::dynamo::{JobProgress, update_job_progress};
+
+// A report is only written once this long has passed since the last write...
+const MIN_WRITE_INTERVAL: Duration = Duration::from_secs(5);
+// ...or once the percentage has moved this far, so a fast job writes at most about a
+// hundred times
+const MIN_PERCENT_STEP: f64 = 1.0;
+
+// When the last report was written and what it said, deciding whether the next is worth
+// a write
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProgressThrottle {
+    last_write: Option<(Instant, f64)>,
+}
+
+impl ProgressThrottle {
+    // The first report is always written, which is what moves the job to processing
+    pub fn should_write(&self, now: Instant, percent: f64) -> bool {
+        match self.last_write {
+            None => true,
+            Some((written_at, written_percent)) => {
+                now.saturating_duration_since(written_at) >= MIN_WRITE_INTERVAL
+                    || (percent - written_percent).abs() >= MIN_PERCENT_STEP
+            }
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, percent: f64) {
+        self.last_write = Some((now, percent));
+    }
+}
+
+// Reports a conversion's progress to the job item, skipping reports too close to the last
+// one. A failed write is logged and the conversion carries on; progress is only ever
+// informational.
+#[derive(Debug)]
+pub struct ProgressReporter {
+    dynamodb_client: DynamoDbClient,
+    table_name: String,
+    job_id: String,
+    // Size of the source file; 0 when S3 didn't say, which leaves the percentage at 0
+    total_bytes: u64,
+    throttle: ProgressThrottle,
+}
+
+impl ProgressReporter {
+    pub fn new(
+        dynamodb_client: DynamoDbClient,
+        table_name: &str,
+        job_id: &str,
+        total_bytes: u64,
+    ) -> Self {
+        ProgressReporter {
+            dynamodb_client,
+            table_name: table_name.to_string(),
+            job_id: job_id.to_string(),
+            total_bytes,
+            throttle: ProgressThrottle::default(),
+        }
+    }
+
+    // Progress at `offset` bytes into the source. The percentage counts from the start of
+    // the file, so a resumed conversion carries on from where its checkpoint left off.
+    pub fn progress(&self, offset: u64, rows_processed: u64, bytes_read: u64) -> JobProgress {
+        let percent = if self.total_bytes == 0 {
+            0.0
+        } else {
+            (offset as f64 * 100.0 / self.total_bytes as f64).min(100.0)
+        };
+        JobProgress {
+            percent,
+            rows_processed,
+            bytes_read,
+        }
+    }
+
+    // Writes the progress unless the throttle skips it, returning whether it was written
+    pub async fn report(&mut self, offset: u64, rows_processed: u64, bytes_read: u64) -> bool {
+        let progress = self.progress(offset, rows_processed, bytes_read);
+        let now = Instant::now();
+        if !self.throttle.should_write(now, progress.percent) {
+            return false;
+        }
+
+        // Recorded before the write, so a failing table isn't hit again on the next row
+        self.throttle.record(now, progress.percent);
+        if let Err(e) = update_job_progress(
+            &self.dynamodb_client,
+            &self.table_name,
+            &self.job_id,
+            &progress,
+        )
+        .await
+        {
+            warn!(job_id = %self.job_id, error = %e, "Failed to record job progress");
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubResponse};
+    use serde_json::json;
+
+    // How many of `updates`, each a millisecond offset and a percentage, the throttle writes
+    fn writes(updates: impl IntoIterator<Item = (u64, f64)>) -> usize {
+        let start = Instant::now();
+        let mut throttle = ProgressThrottle::default();
+        let mut written = 0;
+        for (millis, percent) in updates {
+            let now = start + Duration::from_millis(millis);
+            if throttle.should_write(now, percent) {
+                throttle.record(now, percent);
+                written += 1;
+            }
+        }
+        written
+    }
+
+    #[test]
+    fn a_fast_job_writes_once_per_percentage_point() {
+        // 400 updates a quarter point apart, all within a second
+        let updates = (0..=400).map(|step| (step * 2, step as f64 * 0.25));
+
+        assert_eq!(writes(updates), 101);
+    }
+
+    #[test]
+    fn a_slow_job_writes_every_five_seconds() {
+        // An update every 100ms for a minute, barely moving
+        let updates = (0..=600).map(|step| (step * 100, step as f64 * 0.0005));
+
+        assert_eq!(writes(updates), 13);
+    }
+
+    #[test]
+    fn the_first_update_is_always_written() {
+        assert_eq!(writes([(0, 0.0)]), 1);
+        assert_eq!(writes([(0, 0.0), (1, 0.0), (2, 0.5)]), 1);
+    }
+
+    #[tokio::test]
+    async fn a_reporter_writes_only_the_updates_the_throttle_lets_through() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let mut reporter = ProgressReporter::new(stub.dynamodb_client(), "jobs", "job-1", 1000);
+
+        // A tenth of a point per report, quickly enough that the interval never passes
+        let mut reported = 0;
+        for offset in 0..=50 {
+            if reporter.report(offset, offset * 10, offset).await {
+                reported += 1;
+            }
+        }
+
+        let updates = stub.operations("UpdateItem");
+        assert_eq!(reported, 6);
+        assert_eq!(updates.len(), 6);
+        let percents: Vec<serde_json::Value> = updates
+            .iter()
+            .map(|update| update.json()["ExpressionAttributeValues"][":percent"]["N"].clone())
+            .collect();
+        assert_eq!(
+            percents,
+            ["0.0", "1.0", "2.0", "3.0", "4.0", "5.0"].map(|percent| json!(percent))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failing_write_still_counts_against_the_throttle() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::dynamodb_error("ProvisionedThroughputExceededException", None)
+        });
+        let mut reporter = ProgressReporter::new(stub.dynamodb_client(), "jobs", "job-1", 400);
+
+        for offset in 0..=3 {
+            reporter.report(offset, 0, offset).await;
+        }
+
+        assert_eq!(stub.operations("UpdateItem").len(), 1);
+    }
+
+    #[test]
+    fn an_unknown_size_leaves_the_percentage_at_zero() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let unknown = ProgressReporter::new(stub.dynamodb_client(), "jobs", "job-1", 0);
+        let known = ProgressReporter::new(stub.dynamodb_client(), "jobs", "job-1", 200);
+
+        assert_eq!(unknown.progress(150, 1, 150).percent, 0.0);
+        assert_eq!(known.progress(150, 1, 150).percent, 75.0);
+        assert_eq!(known.progress(250, 1, 250).percent, 100.0);
+    }
+}
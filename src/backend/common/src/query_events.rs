@@ -0,0 +1,164 @@
+use serde::Serialize;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+// A stage a generate-query request has reached, sent ahead of the answer when the response
+// is streamed. `Result` is always the last event of a stream and carries what the buffered
+// endpoint would have returned.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum QueryEvent {
+    // Getting the parquet files ready, which is a download unless they are cached or big
+    // enough to be read in place
+    Downloading {
+        datasets: usize,
+    },
+    GeneratingSql,
+    // The SQL that is about to run, once it has passed the read-only check
+    Sql {
+        sql: String,
+    },
+    Executing,
+    Summarizing,
+    Result {
+        status_code: i64,
+        body: serde_json::Value,
+    },
+}
+
+impl QueryEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueryEvent::Downloading { .. } => "downloading",
+            QueryEvent::GeneratingSql => "generating_sql",
+            QueryEvent::Sql { .. } => "sql",
+            QueryEvent::Executing => "executing",
+            QueryEvent::Summarizing => "summarizing",
+            QueryEvent::Result { .. } => "result",
+        }
+    }
+}
+
+// The event as one server-sent event. The data line repeats the name, so a client reading
+// the body as plain chunks doesn't need the `event:` line.
+pub fn sse_frame(event: &QueryEvent) -> String {
+    let data = serde_json::to_string(event).unwrap_or_else(|_| "{}".to_string());
+    format!("event: {}\ndata: {}\n\n", event.name(), data)
+}
+
+// Where a request reports its progress. A buffered request has nowhere to send events, so
+// they are dropped; a streamed one queues them, in order, for the response body.
+#[derive(Debug, Clone, Default)]
+pub struct QueryEvents {
+    sender: Option<UnboundedSender<QueryEvent>>,
+}
+
+impl QueryEvents {
+    pub fn buffered() -> Self {
+        QueryEvents { sender: None }
+    }
+
+    // The receiver ends once every clone of the returned QueryEvents is dropped
+    pub fn streamed() -> (Self, UnboundedReceiver<QueryEvent>) {
+        let (sender, receiver) = unbounded_channel();
+        (
+            QueryEvents {
+                sender: Some(sender),
+            },
+            receiver,
+        )
+    }
+
+    // A client that hung up has closed the receiver; the request still runs to the end so
+    // it is audited, but there is no one left to tell
+    pub fn emit(&self, event: QueryEvent) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // One of each, in the order a request that gets as far as summarizing sends them
+    fn every_stage() -> Vec<QueryEvent> {
+        vec![
+            QueryEvent::Downloading { datasets: 2 },
+            QueryEvent::GeneratingSql,
+            QueryEvent::Sql {
+                sql: "SELECT count(*) FROM data".to_string(),
+            },
+            QueryEvent::Executing,
+            QueryEvent::Summarizing,
+            QueryEvent::Result {
+                status_code: 200,
+                body: json!({"rows": []}),
+            },
+        ]
+    }
+
+    #[test]
+    fn each_event_serializes_under_its_name() {
+        let serialized: Vec<serde_json::Value> = every_stage()
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap())
+            .collect();
+
+        assert_eq!(
+            serialized,
+            [
+                json!({"event": "downloading", "datasets": 2}),
+                json!({"event": "generating_sql"}),
+                json!({"event": "sql", "sql": "SELECT count(*) FROM data"}),
+                json!({"event": "executing"}),
+                json!({"event": "summarizing"}),
+                json!({"event": "result", "status_code": 200, "body": {"rows": []}}),
+            ]
+        );
+        for (event, value) in every_stage().iter().zip(&serialized) {
+            assert_eq!(value["event"], event.name());
+        }
+    }
+
+    #[test]
+    fn a_frame_is_one_server_sent_event() {
+        let frame = sse_frame(&QueryEvent::Sql {
+            sql: "SELECT 1\nFROM data".to_string(),
+        });
+
+        // The newline in the SQL is escaped, so it can't end the data line early
+        assert_eq!(
+            frame,
+            "event: sql\ndata: {\"event\":\"sql\",\"sql\":\"SELECT 1\\nFROM data\"}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn streamed_events_arrive_in_the_order_they_were_emitted() {
+        let (events, mut receiver) = QueryEvents::streamed();
+        let worker = events.clone();
+
+        for event in every_stage() {
+            worker.emit(event);
+        }
+        drop(worker);
+        drop(events);
+
+        let mut received = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            received.push(event);
+        }
+        assert_eq!(received, every_stage());
+    }
+
+    #[test]
+    fn emitting_with_no_one_listening_is_harmless() {
+        QueryEvents::buffered().emit(QueryEvent::Executing);
+
+        let (events, receiver) = QueryEvents::streamed();
+        drop(receiver);
+        events.emit(QueryEvent::Executing);
+    }
+}
@@ -0,0 +1,270 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, NaiveDate, NaiveTime};
+use duckdb::types::{TimeUnit, Value};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use serde::Serialize;
+use serde_json::{Map, Number, Value as JsonValue};
+
+// A result column's name and its type as DuckDB's DESCRIBE reports it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ColumnMeta {
+    pub name: String,
+    pub duckdb_type: String,
+}
+
+// Rows of a query in the order DuckDB returned them, each value in its column's position.
+// `truncated` is set when the query had more rows than the limits let through, and `bytes`
+// is what the kept rows come to as `row_objects` JSON.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct QueryResult {
+    pub columns: Vec<ColumnMeta>,
+    pub rows: Vec<Vec<JsonValue>>,
+    pub truncated: bool,
+    pub bytes: u64,
+}
+
+// Most a result may hold once it is built. Rows stop being read at whichever is reached
+// first, so a wide or text-heavy result can't fill the function's memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResultLimits {
+    pub max_rows: u64,
+    pub max_bytes: u64,
+}
+
+// Roughly what one row adds to the response as an object: each value's JSON with its
+// column name, quotes, colon and comma, and the braces
+pub fn row_object_bytes(columns: &[ColumnMeta], row: &[JsonValue]) -> u64 {
+    let fields: usize = columns
+        .iter()
+        .zip(row)
+        .map(|(column, value)| column.name.len() + value.to_string().len() + 4)
+        .sum();
+    (fields + 2) as u64
+}
+
+impl QueryResult {
+    // Each row as an object keyed by column name, the shape the API has always returned
+    pub fn row_objects(&self) -> Vec<JsonValue> {
+        self.rows
+            .iter()
+            .map(|row| {
+                let object: Map<String, JsonValue> = self
+                    .columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, value)| (column.name.clone(), value.clone()))
+                    .collect();
+                JsonValue::Object(object)
+            })
+            .collect()
+    }
+}
+
+fn float(value: f64) -> JsonValue {
+    // JSON has no NaN or infinity, so those stay readable as text
+    match Number::from_f64(value) {
+        Some(number) => JsonValue::Number(number),
+        None => JsonValue::String(value.to_string()),
+    }
+}
+
+// A decimal is a number when a double holds it exactly, and text when it would lose digits
+fn decimal(value: Decimal) -> JsonValue {
+    let exact = value
+        .to_f64()
+        .filter(|double| Decimal::from_f64(*double) == Some(value.normalize()));
+    match exact {
+        Some(double) => float(double),
+        None => JsonValue::String(value.normalize().to_string()),
+    }
+}
+
+// DuckDB's infinite dates and timestamps are the type's largest magnitude
+fn infinity(positive: bool) -> JsonValue {
+    JsonValue::String(if positive { "infinity" } else { "-infinity" }.to_string())
+}
+
+// `YYYY-MM-DD HH:MM:SS[.fraction]` in UTC, as DuckDB prints a timestamp. Out-of-range
+// timestamps come back as their raw count.
+fn timestamp(unit: TimeUnit, value: i64) -> JsonValue {
+    if value == i64::MAX || value == -i64::MAX {
+        return infinity(value > 0);
+    }
+    let date_time = match unit {
+        TimeUnit::Second => DateTime::from_timestamp(value, 0),
+        TimeUnit::Millisecond => DateTime::from_timestamp_millis(value),
+        TimeUnit::Microsecond => DateTime::from_timestamp_micros(value),
+        TimeUnit::Nanosecond => Some(DateTime::from_timestamp_nanos(value)),
+    };
+    match date_time {
+        Some(date_time) => JsonValue::String(date_time.naive_utc().to_string()),
+        None => JsonValue::String(value.to_string()),
+    }
+}
+
+// Days since 1970-01-01 as `YYYY-MM-DD`
+fn date(days: i32) -> JsonValue {
+    if days == i32::MAX || days == -i32::MAX {
+        return infinity(days > 0);
+    }
+    let date = NaiveDate::from_ymd_opt(1970, 1, 1)
+        .and_then(|epoch| epoch.checked_add_signed(chrono::Duration::days(days.into())));
+    match date {
+        Some(date) => JsonValue::String(date.format("%Y-%m-%d").to_string()),
+        None => JsonValue::String(days.to_string()),
+    }
+}
+
+// Time since midnight as `HH:MM:SS[.fraction]`
+fn time(unit: TimeUnit, value: i64) -> JsonValue {
+    let nanos = match unit {
+        TimeUnit::Second => value.saturating_mul(1_000_000_000),
+        TimeUnit::Millisecond => value.saturating_mul(1_000_000),
+        TimeUnit::Microsecond => value.saturating_mul(1000),
+        TimeUnit::Nanosecond => value,
+    };
+    let time = u32::try_from(nanos.div_euclid(1_000_000_000))
+        .ok()
+        .and_then(|seconds| {
+            NaiveTime::from_num_seconds_from_midnight_opt(
+                seconds,
+                nanos.rem_euclid(1_000_000_000) as u32,
+            )
+        });
+    match time {
+        Some(time) => JsonValue::String(time.to_string()),
+        None => JsonValue::String(value.to_string()),
+    }
+}
+
+// An ISO 8601 duration, such as `P1M2DT3.5S`, since an interval's months and days don't
+// have a fixed length to add up
+fn interval(months: i32, days: i32, nanos: i64) -> JsonValue {
+    let seconds = nanos as f64 / 1_000_000_000.0;
+    JsonValue::String(format!("P{}M{}DT{}S", months, days, seconds))
+}
+
+// A map key as JSON object keys have to be, text
+fn map_key(key: &Value) -> String {
+    match json_value(key) {
+        JsonValue::String(text) => text,
+        other => other.to_string(),
+    }
+}
+
+// One DuckDB value as JSON. Integers, floats and booleans stay numbers and booleans;
+// integers too big for 64 bits, and decimals a double can't hold exactly, become text
+// rather than losing digits. Dates and times are text in DuckDB's own format,
+// blobs are base64, and lists, structs and maps nest.
+pub fn json_value(value: &Value) -> JsonValue {
+    match value {
+        Value::Null => JsonValue::Null,
+        Value::Boolean(value) => JsonValue::Bool(*value),
+        Value::TinyInt(value) => JsonValue::from(*value),
+        Value::SmallInt(value) => JsonValue::from(*value),
+        Value::Int(value) => JsonValue::from(*value),
+        Value::BigInt(value) => JsonValue::from(*value),
+        Value::HugeInt(value) => match i64::try_from(*value) {
+            Ok(value) => JsonValue::from(value),
+            Err(_) => JsonValue::String(value.to_string()),
+        },
+        Value::UTinyInt(value) => JsonValue::from(*value),
+        Value::USmallInt(value) => JsonValue::from(*value),
+        Value::UInt(value) => JsonValue::from(*value),
+        Value::UBigInt(value) => JsonValue::from(*value),
+        Value::Float(value) => float(f64::from(*value)),
+        Value::Double(value) => float(*value),
+        Value::Decimal(value) => decimal(*value),
+        Value::Timestamp(unit, value) => timestamp(*unit, *value),
+        Value::Text(value) => JsonValue::String(value.clone()),
+        Value::Blob(bytes) => JsonValue::String(BASE64.encode(bytes)),
+        Value::Date32(days) => date(*days),
+        Value::Time64(unit, value) => time(*unit, *value),
+        Value::Interval {
+            months,
+            days,
+            nanos,
+        } => interval(*months, *days, *nanos),
+        Value::List(values) | Value::Array(values) => {
+            JsonValue::Array(values.iter().map(json_value).collect())
+        }
+        Value::Enum(value) => JsonValue::String(value.clone()),
+        Value::Struct(fields) => JsonValue::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), json_value(value)))
+                .collect(),
+        ),
+        Value::Map(entries) => JsonValue::Object(
+            entries
+                .iter()
+                .map(|(key, value)| (map_key(key), json_value(value)))
+                .collect(),
+        ),
+        Value::Union(value) => json_value(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn a_decimal_is_a_number_only_when_a_double_holds_it_exactly() {
+        assert_eq!(
+            json_value(&Value::Decimal(Decimal::new(1225, 2))),
+            json!(12.25)
+        );
+        assert_eq!(
+            json_value(&Value::Decimal(Decimal::new(1_000_000_000_000_000_001, 1))),
+            json!("100000000000000000.1")
+        );
+        assert_eq!(
+            json_value(&Value::Decimal(Decimal::new(1500, 3))),
+            json!(1.5)
+        );
+    }
+
+    #[test]
+    fn timestamps_are_read_in_their_unit() {
+        for (unit, value) in [
+            (TimeUnit::Second, 1_709_618_828),
+            (TimeUnit::Millisecond, 1_709_618_828_000),
+            (TimeUnit::Microsecond, 1_709_618_828_000_000),
+            (TimeUnit::Nanosecond, 1_709_618_828_000_000_000),
+        ] {
+            assert_eq!(
+                json_value(&Value::Timestamp(unit, value)),
+                json!("2024-03-05 06:07:08"),
+                "{:?}",
+                unit
+            );
+        }
+        assert_eq!(
+            json_value(&Value::Timestamp(TimeUnit::Microsecond, -i64::MAX)),
+            json!("-infinity")
+        );
+    }
+
+    #[test]
+    fn dates_and_times_out_of_range_keep_their_raw_value() {
+        assert_eq!(json_value(&Value::Date32(-i32::MAX)), json!("-infinity"));
+        assert_eq!(
+            json_value(&Value::Date32(i32::MAX - 1)),
+            json!("2147483646")
+        );
+        assert_eq!(
+            json_value(&Value::Time64(TimeUnit::Second, 90_000)),
+            json!("90000")
+        );
+    }
+
+    #[test]
+    fn infinite_floats_are_text() {
+        assert_eq!(json_value(&Value::Double(f64::INFINITY)), json!("inf"));
+        assert_eq!(json_value(&Value::Float(-0.5)), json!(-0.5));
+    }
+}
@@ -1,32 +1,528 @@
 use aws_sdk_s3::Client as S3Client;
-use lambda_runtime::Error;
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
+use std::time::Duration;
+use tracing::info;
 
+use crate::error::Error;
+
+// Where a job's CSV is uploaded. The upload endpoint signs URLs for this key, and a
+// conversion request without an s3_key reads the file from it.
+pub const UPLOAD_PREFIX: &str = "csvUpload/";
+
+pub fn upload_key(job_id: &str) -> String {
+    format!("{}{}.csv", UPLOAD_PREFIX, job_id)
+}
+
+// Where a job's parquet is written
+pub const PARQUET_PREFIX: &str = "parquet/";
+
+pub fn parquet_key(job_id: &str) -> String {
+    format!("{}{}.parquet", PARQUET_PREFIX, job_id)
+}
+
+// Where a checkpointed conversion keeps the footer of the row groups it has uploaded so
+// far while it builds `output_key`. It shares the output's name as a prefix, so deleting
+// the job's parquet takes it too.
+pub fn parquet_footer_key(output_key: &str) -> String {
+    format!("{}.footer", output_key)
+}
+
+// Where a parquet materialized from a query over `parent_job_id` is written, under the
+// parent so a job's derived datasets sit together
+pub const DERIVED_PREFIX: &str = "derived/";
+
+pub fn derived_key(parent_job_id: &str, job_id: &str) -> String {
+    format!("{}{}/{}.parquet", DERIVED_PREFIX, parent_job_id, job_id)
+}
+
+// Size and version of an uploaded source file, recorded on the job so a later run can
+// tell whether the file changed underneath it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceObject {
+    pub bytes: i64,
+    pub etag: Option<String>,
+}
+
+// Returns None when there is no object at the key
+pub async fn head_source_object(bucket: &str, key: &str) -> Result<Option<SourceObject>, Error> {
+    let config = aws_config::load_from_env().await;
+    let s3_client = S3Client::new(&config);
+
+    head_object(&s3_client, bucket, key).await
+}
+
+// Same as head_source_object, with a client the caller already holds
+pub async fn head_object(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+) -> Result<Option<SourceObject>, Error> {
+    match s3_client.head_object().bucket(bucket).key(key).send().await {
+        Ok(output) => Ok(Some(SourceObject {
+            bytes: output.content_length().unwrap_or(0),
+            etag: output.e_tag().map(|etag| etag.to_string()),
+        })),
+        Err(e) => match e.as_service_error() {
+            Some(service_error) if service_error.is_not_found() => Ok(None),
+            _ => Err(Error::s3("HeadObject", e)),
+        },
+    }
+}
+
+// Returns the uploaded object's ETag
 pub async fn upload_to_s3(
     bucket: &str,
     key: &str,
     parquet_data: Vec<u8>,
     job_id: &str,
-) -> Result<(), Error> {
+) -> Result<Option<String>, Error> {
     let config = aws_config::load_from_env().await;
     let s3_client = S3Client::new(&config);
 
-    println!(
-        "Job {}: Uploading parquet to S3: bucket={}, key={}, size={:.2} MB",
+    upload_object(&s3_client, bucket, key, parquet_data, job_id).await
+}
+
+// Same as upload_to_s3, with a client the caller already holds
+pub async fn upload_object(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    parquet_data: Vec<u8>,
+    job_id: &str,
+) -> Result<Option<String>, Error> {
+    info!(
         job_id,
         bucket,
         key,
-        parquet_data.len() as f64 / (1024.0 * 1024.0)
+        bytes = parquet_data.len(),
+        "Uploading parquet to S3"
     );
 
-    s3_client
+    let output = s3_client
         .put_object()
         .bucket(bucket)
         .key(key)
         .body(parquet_data.into())
         .content_type("application/octet-stream")
         .send()
-        .await?;
+        .await
+        .map_err(|e| Error::s3("PutObject", e))?;
+
+    info!(job_id, key, "Uploaded parquet to S3");
+    Ok(output.e_tag().map(|etag| etag.to_string()))
+}
+
+// Removes output written before a job stopped. Objects that are already gone count as
+// deleted, so this is safe to repeat.
+pub async fn delete_from_s3(
+    s3_client: &S3Client,
+    bucket: &str,
+    keys: &[String],
+    job_id: &str,
+) -> Result<(), Error> {
+    for key in keys {
+        s3_client
+            .delete_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| Error::s3("DeleteObject", e))?;
+    }
+
+    info!(
+        job_id,
+        bucket,
+        objects = keys.len(),
+        "Deleted partial output from S3"
+    );
+    Ok(())
+}
+
+// One uploaded part of a multipart upload, as completing the upload has to name it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadedPart {
+    pub number: i32,
+    pub etag: String,
+}
+
+// Starts a multipart upload to `key` and returns its upload ID
+pub async fn create_multipart_upload(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+) -> Result<String, Error> {
+    let output = s3_client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .content_type("application/octet-stream")
+        .send()
+        .await
+        .map_err(|e| Error::s3("CreateMultipartUpload", e))?;
+
+    output
+        .upload_id()
+        .map(str::to_string)
+        .ok_or_else(|| Error::S3 {
+            operation: "CreateMultipartUpload",
+            message: "response had no upload ID".to_string(),
+            retryable: true,
+        })
+}
+
+// Uploading a part number again replaces what was uploaded under it before, so a part can
+// be retried or rewritten until the upload is completed
+pub async fn upload_part(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    number: i32,
+    data: Vec<u8>,
+) -> Result<UploadedPart, Error> {
+    let bytes = data.len();
+    let output = s3_client
+        .upload_part()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .part_number(number)
+        .body(data.into())
+        .send()
+        .await
+        .map_err(|e| Error::s3("UploadPart", e))?;
+
+    info!(key, part = number, bytes, "Uploaded part to S3");
+    let etag = output.e_tag().ok_or_else(|| Error::S3 {
+        operation: "UploadPart",
+        message: format!("part {} was uploaded without an ETag", number),
+        retryable: true,
+    })?;
+    Ok(UploadedPart {
+        number,
+        etag: etag.to_string(),
+    })
+}
+
+// Joins `parts`, in order, into the object and returns its ETag. Parts uploaded but not
+// named here are dropped.
+pub async fn complete_multipart_upload(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    parts: &[UploadedPart],
+) -> Result<Option<String>, Error> {
+    let completed = CompletedMultipartUpload::builder()
+        .set_parts(Some(
+            parts
+                .iter()
+                .map(|part| {
+                    CompletedPart::builder()
+                        .part_number(part.number)
+                        .e_tag(&part.etag)
+                        .build()
+                })
+                .collect(),
+        ))
+        .build();
+
+    let output = s3_client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(completed)
+        .send()
+        .await
+        .map_err(|e| Error::s3("CompleteMultipartUpload", e))?;
+
+    info!(key, parts = parts.len(), "Completed multipart upload");
+    Ok(output.e_tag().map(|etag| etag.to_string()))
+}
 
-    println!("Job {}: Successfully uploaded parquet file", job_id);
+// Drops the upload and every part uploaded to it
+pub async fn abort_multipart_upload(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<(), Error> {
+    s3_client
+        .abort_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .send()
+        .await
+        .map_err(|e| Error::s3("AbortMultipartUpload", e))?;
+
+    info!(key, "Aborted multipart upload");
     Ok(())
 }
+
+// Deletes every object whose key starts with `prefix`, a listed page at a time, and
+// returns how many were deleted. A page holds at most 1000 keys, which is also the most
+// DeleteObjects takes.
+pub async fn delete_prefix(s3_client: &S3Client, bucket: &str, prefix: &str) -> Result<u64, Error> {
+    let mut deleted = 0;
+    let mut continuation_token = None;
+
+    loop {
+        let page = s3_client
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await
+            .map_err(|e| Error::s3("ListObjectsV2", e))?;
+
+        let objects = page
+            .contents()
+            .iter()
+            .filter_map(|object| object.key())
+            .map(|key| ObjectIdentifier::builder().key(key).build())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::S3 {
+                operation: "DeleteObjects",
+                message: e.to_string(),
+                retryable: false,
+            })?;
+
+        if !objects.is_empty() {
+            let count = objects.len() as u64;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(true)
+                .build()
+                .map_err(|e| Error::S3 {
+                    operation: "DeleteObjects",
+                    message: e.to_string(),
+                    retryable: false,
+                })?;
+            let output = s3_client
+                .delete_objects()
+                .bucket(bucket)
+                .delete(delete)
+                .send()
+                .await
+                .map_err(|e| Error::s3("DeleteObjects", e))?;
+
+            // The call succeeds even when single keys fail; those are only reported here
+            if let Some(failed) = output.errors().first() {
+                return Err(Error::S3 {
+                    operation: "DeleteObjects",
+                    message: format!(
+                        "{} of {} object(s) not deleted, first {}: {}",
+                        output.errors().len(),
+                        count,
+                        failed.key().unwrap_or_default(),
+                        failed.message().unwrap_or_default()
+                    ),
+                    retryable: true,
+                });
+            }
+            deleted += count;
+        }
+
+        continuation_token = page
+            .next_continuation_token()
+            .filter(|_| page.is_truncated().unwrap_or(false))
+            .map(|token| token.to_string());
+        if continuation_token.is_none() {
+            break;
+        }
+    }
+
+    info!(bucket, prefix, objects = deleted, "Deleted objects from S3");
+    Ok(deleted)
+}
+
+// A time-limited GET link to one object. The link is signed with the Lambda's role
+// credentials, so it also stops working once those expire, whichever comes first.
+pub async fn presign_get_url(
+    bucket: &str,
+    key: &str,
+    expires_in: Duration,
+) -> Result<String, Error> {
+    let config = aws_config::load_from_env().await;
+    let s3_client = S3Client::new(&config);
+
+    let presigning = PresigningConfig::expires_in(expires_in)
+        .map_err(|e| Error::Config(format!("invalid presigned URL expiry: {}", e)))?;
+
+    let request = s3_client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .presigned(presigning)
+        .await
+        .map_err(|e| Error::s3("GetObject", e))?;
+
+    Ok(request.uri().to_string())
+}
+
+// A time-limited PUT link for uploading one object. Content-Type and Content-Length are
+// part of the signature, so the upload must send exactly the declared values.
+pub async fn presign_put_url(
+    bucket: &str,
+    key: &str,
+    content_type: &str,
+    content_length: i64,
+    expires_in: Duration,
+) -> Result<String, Error> {
+    let config = aws_config::load_from_env().await;
+    let s3_client = S3Client::new(&config);
+
+    let presigning = PresigningConfig::expires_in(expires_in)
+        .map_err(|e| Error::Config(format!("invalid presigned URL expiry: {}", e)))?;
+
+    let request = s3_client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type(content_type)
+        .content_length(content_length)
+        .presigned(presigning)
+        .await
+        .map_err(|e| Error::s3("PutObject", e))?;
+
+    Ok(request.uri().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubRequest, StubResponse};
+
+    // A ListObjectsV2 page; a token means more pages follow
+    fn listed_page(keys: &[&str], next_token: Option<&str>) -> StubResponse {
+        let contents: String = keys
+            .iter()
+            .map(|key| format!("<Contents><Key>{}</Key><Size>1</Size></Contents>", key))
+            .collect();
+        let next = next_token.map_or(String::new(), |token| {
+            format!("<NextContinuationToken>{}</NextContinuationToken>", token)
+        });
+        StubResponse::xml(&format!(
+            "<ListBucketResult><Name>uploads</Name><KeyCount>{}</KeyCount>\
+             <IsTruncated>{}</IsTruncated>{}{}</ListBucketResult>",
+            keys.len(),
+            next_token.is_some(),
+            next,
+            contents
+        ))
+    }
+
+    // The keys named in a DeleteObjects request body
+    fn deleted_keys(request: &StubRequest) -> Vec<String> {
+        String::from_utf8_lossy(&request.body)
+            .split("<Key>")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("</Key>"))
+            .map(|(key, _)| key.to_string())
+            .collect()
+    }
+
+    fn is_delete(request: &StubRequest) -> bool {
+        request.method == "POST" && request.target.contains("delete")
+    }
+
+    #[tokio::test]
+    async fn deleting_a_prefix_follows_every_listed_page() {
+        let stub = StubEndpoint::start(|request| {
+            if is_delete(request) {
+                StubResponse::xml("<DeleteResult></DeleteResult>")
+            } else if request.target.contains("continuation-token=page-2") {
+                listed_page(&["parquet/job-1/part-00002.parquet"], None)
+            } else {
+                listed_page(
+                    &[
+                        "parquet/job-1/part-00000.parquet",
+                        "parquet/job-1/part-00001.parquet",
+                    ],
+                    Some("page-2"),
+                )
+            }
+        });
+
+        let deleted = delete_prefix(&stub.s3_client(), "uploads", "parquet/job-1/")
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, 3);
+        let requests = stub.requests();
+        let listings: Vec<&StubRequest> = requests
+            .iter()
+            .filter(|request| request.method == "GET")
+            .collect();
+        assert_eq!(listings.len(), 2);
+        assert!(
+            listings
+                .iter()
+                .all(|request| request.target.contains("prefix=parquet%2Fjob-1%2F"))
+        );
+        let deletes: Vec<Vec<String>> = requests
+            .iter()
+            .filter(|request| is_delete(request))
+            .map(deleted_keys)
+            .collect();
+        assert_eq!(
+            deletes,
+            vec![
+                vec![
+                    "parquet/job-1/part-00000.parquet",
+                    "parquet/job-1/part-00001.parquet",
+                ],
+                vec!["parquet/job-1/part-00002.parquet"],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn an_empty_prefix_deletes_nothing() {
+        let stub = StubEndpoint::start(|_| listed_page(&[], None));
+
+        let deleted = delete_prefix(&stub.s3_client(), "uploads", "parquet/job-1/")
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, 0);
+        assert!(!stub.requests().iter().any(is_delete));
+    }
+
+    #[tokio::test]
+    async fn keys_that_fail_to_delete_are_a_retryable_error() {
+        let stub = StubEndpoint::start(|request| {
+            if is_delete(request) {
+                StubResponse::xml(
+                    "<DeleteResult><Error><Key>parquet/job-1.parquet</Key>\
+                     <Code>InternalError</Code><Message>try again</Message></Error>\
+                     </DeleteResult>",
+                )
+            } else {
+                listed_page(&["parquet/job-1.parquet"], None)
+            }
+        });
+
+        let error = delete_prefix(&stub.s3_client(), "uploads", "parquet/job-1")
+            .await
+            .unwrap_err();
+
+        match error {
+            Error::S3 {
+                operation,
+                message,
+                retryable,
+            } => {
+                assert_eq!(operation, "DeleteObjects");
+                assert!(message.contains("parquet/job-1.parquet"), "{}", message);
+                assert!(retryable);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::Client as SqsClient;
+use aws_sdk_sqs::error::BuildError;
+use aws_sdk_sqs::types::MessageAttributeValue;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::dynamo::{JobLease, renew_job_lease};
+
+// How often the in-flight message has its visibility extended, and by how much.
+// The extension is comfortably longer than the interval so a single slow
+// ChangeMessageVisibility call can't let the message reappear on the queue.
+pub const VISIBILITY_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(180);
+pub const VISIBILITY_EXTENSION_SECONDS: i32 = 600;
+
+// The job lease covers the same window as the message's visibility, so a second Lambda
+// can't get the job any sooner than it could get the message
+pub const JOB_LEASE_DURATION: Duration = Duration::from_secs(VISIBILITY_EXTENSION_SECONDS as u64);
+
+// Keeps both the message and the job's lease held while the conversion runs
+pub fn spawn_visibility_heartbeat(
+    sqs_client: SqsClient,
+    queue_url: String,
+    receipt_handle: String,
+    dynamodb_client: DynamoDbClient,
+    table_name: String,
+    job_id: String,
+    lease: JobLease,
+) -> JoinHandle<()> {
+    let hold = Arc::new(MessageHold {
+        sqs_client,
+        queue_url,
+        receipt_handle,
+        dynamodb_client,
+        table_name,
+        job_id,
+        lease,
+    });
+    spawn_heartbeat(VISIBILITY_HEARTBEAT_INTERVAL, move || {
+        let hold = hold.clone();
+        async move { hold.extend().await }
+    })
+}
+
+// Runs `beat` every `interval` until the task is aborted, starting one interval from now
+fn spawn_heartbeat<F, Fut>(interval: Duration, mut beat: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick completes immediately; the message was only just received
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            beat().await;
+        }
+    })
+}
+
+// The in-flight message and job lease one conversion holds
+struct MessageHold {
+    sqs_client: SqsClient,
+    queue_url: String,
+    receipt_handle: String,
+    dynamodb_client: DynamoDbClient,
+    table_name: String,
+    job_id: String,
+    lease: JobLease,
+}
+
+impl MessageHold {
+    // One heartbeat. A failure to extend either is logged and the next beat tries again.
+    async fn extend(&self) {
+        let job_id = &self.job_id;
+
+        match self
+            .sqs_client
+            .change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(&self.receipt_handle)
+            .visibility_timeout(VISIBILITY_EXTENSION_SECONDS)
+            .send()
+            .await
+        {
+            Ok(_) => info!(
+                job_id,
+                extension_seconds = VISIBILITY_EXTENSION_SECONDS,
+                "Extended message visibility"
+            ),
+            Err(e) => error!(job_id, error = %e, "Failed to extend message visibility"),
+        }
+
+        match renew_job_lease(
+            &self.dynamodb_client,
+            &self.table_name,
+            job_id,
+            &self.lease,
+            JOB_LEASE_DURATION,
+        )
+        .await
+        {
+            Ok(true) => info!(job_id, "Renewed job lease"),
+            Ok(false) => error!(job_id, "Job lease was lost to another invocation"),
+            Err(e) => error!(job_id, error = %e, "Failed to renew job lease"),
+        }
+    }
+}
+
+pub fn string_message_attribute(value: &str) -> Result<MessageAttributeValue, BuildError> {
+    MessageAttributeValue::builder()
+        .data_type("String")
+        .string_value(value)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{StubEndpoint, StubResponse};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn hold(stub: &StubEndpoint) -> MessageHold {
+        MessageHold {
+            sqs_client: stub.sqs_client(),
+            queue_url: "https://sqs.us-east-1.amazonaws.com/123/jobs".to_string(),
+            receipt_handle: "receipt-1".to_string(),
+            dynamodb_client: stub.dynamodb_client(),
+            table_name: "jobs".to_string(),
+            job_id: "job-1".to_string(),
+            lease: JobLease {
+                owner: "owner-1".to_string(),
+                attempts: 1,
+                checkpoint_attempt: 0,
+                until: 0,
+                labels: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn an_extension_outlasts_a_missed_beat() {
+        // A beat that fails still leaves the message hidden until the one after it
+        let extension = Duration::from_secs(VISIBILITY_EXTENSION_SECONDS as u64);
+        assert!(extension > VISIBILITY_HEARTBEAT_INTERVAL * 2);
+        assert_eq!(JOB_LEASE_DURATION, extension);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_heartbeat_beats_every_interval_until_aborted() {
+        let beats = Arc::new(AtomicU32::new(0));
+        let counted = beats.clone();
+        let heartbeat = spawn_heartbeat(VISIBILITY_HEARTBEAT_INTERVAL, move || {
+            let counted = counted.clone();
+            async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let second = Duration::from_secs(1);
+
+        // Nothing straight away, as the message was only just received
+        tokio::time::sleep(VISIBILITY_HEARTBEAT_INTERVAL - second).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 0);
+
+        tokio::time::sleep(second * 2).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(VISIBILITY_HEARTBEAT_INTERVAL * 2).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 3);
+
+        heartbeat.abort();
+        tokio::time::sleep(VISIBILITY_HEARTBEAT_INTERVAL * 3).await;
+        assert_eq!(beats.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_slow_beat_does_not_delay_the_next_ones() {
+        let beats = Arc::new(AtomicU32::new(0));
+        let counted = beats.clone();
+        let _heartbeat = spawn_heartbeat(VISIBILITY_HEARTBEAT_INTERVAL, move || {
+            let counted = counted.clone();
+            async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(VISIBILITY_HEARTBEAT_INTERVAL / 2).await;
+            }
+        });
+
+        tokio::time::sleep(VISIBILITY_HEARTBEAT_INTERVAL * 4 + Duration::from_secs(1)).await;
+
+        assert_eq!(beats.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn a_beat_extends_the_message_and_renews_the_lease() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+
+        hold(&stub).extend().await;
+
+        let visibility = stub.operations("ChangeMessageVisibility").remove(0).json();
+        assert_eq!(visibility["ReceiptHandle"], "receipt-1");
+        assert_eq!(
+            visibility["VisibilityTimeout"],
+            VISIBILITY_EXTENSION_SECONDS
+        );
+        let renewal = stub.operations("UpdateItem").remove(0).json();
+        assert_eq!(renewal["ConditionExpression"], "lease_owner = :owner");
+        assert_eq!(
+            renewal["ExpressionAttributeValues"][":owner"]["S"],
+            "owner-1"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_lease_is_renewed_even_when_the_extension_fails() {
+        let stub = StubEndpoint::start(|request| match request.operation() {
+            Some("ChangeMessageVisibility") => StubResponse {
+                status: 400,
+                ..StubResponse::json(json!({
+                    "__type": "com.amazonaws.sqs#ReceiptHandleIsInvalid",
+                    "message": "stubbed failure"
+                }))
+            },
+            _ => StubResponse::json(json!({})),
+        });
+
+        hold(&stub).extend().await;
+
+        assert_eq!(stub.operations("ChangeMessageVisibility").len(), 1);
+        assert_eq!(stub.operations("UpdateItem").len(), 1);
+    }
+}
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tokio::io::AsyncBufReadExt;
 use tokio::sync::mpsc;
 use tokio::task;
-use tracing::error;
+use tracing::{error, info};
 
 use arrow::array::ArrayRef;
 use arrow::datatypes::{Field, Schema};
@@ -79,14 +79,11 @@ pub async fn stream_csv_to_parquet_optimized(
     column_definitions: &[ColumnDefinition],
     output_key: &str,
     job_id: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
     let config = aws_config::load_from_env().await;
     let s3_client = S3Client::new(&config);
 
-    println!(
-        "Job {}: Starting optimized streaming from S3: bucket={}, key={}",
-        job_id, bucket, key
-    );
+    info!(job_id, bucket, key, "Starting optimized streaming from S3");
 
     // Get file size for progress tracking
     let head_response = s3_client
@@ -97,11 +94,7 @@ pub async fn stream_csv_to_parquet_optimized(
         .await?;
     let content_length = head_response.content_length().unwrap_or(0);
 
-    println!(
-        "Job {}: File size: {:.2} MB",
-        job_id,
-        content_length as f64 / (1024.0 * 1024.0)
-    );
+    info!(job_id, bytes = content_length, "Fetched source file size");
 
     // Create channels
     let (batch_tx, batch_rx) = mpsc::channel::<RecordBatch>(CHANNEL_BUFFER_SIZE);
@@ -138,7 +131,7 @@ pub async fn stream_csv_to_parquet_optimized(
             )
             .await
             {
-                error!("Job {}: CSV processor failed: {}", job_id, e);
+                error!(job_id = %job_id, error = %e, "CSV processor failed");
             }
         })
     };
@@ -224,11 +217,14 @@ async fn process_csv_optimized(
             }
 
             if total_rows % 100_000 == 0 {
-                let elapsed = start_time.elapsed().as_secs_f64();
-                let throughput = (total_rows as f64 / elapsed) / 1000.0;
-                println!(
-                    "Job {}: Processed {} rows, {:.1}K rows/s",
-                    job_id, total_rows, throughput
+                let elapsed = start_time.elapsed();
+                let rows_per_second = total_rows as f64 / elapsed.as_secs_f64();
+                info!(
+                    job_id,
+                    rows = total_rows,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    rows_per_second = rows_per_second as u64,
+                    "Processed rows"
                 );
             }
 
@@ -243,13 +239,13 @@ async fn process_csv_optimized(
         let _ = batch_tx.send(batch).await;
     }
 
-    let total_time = start_time.elapsed().as_secs_f64();
-    println!(
-        "Job {}: Finished processing {} rows in {:.2}s, avg: {:.1}K rows/s",
+    let total_time = start_time.elapsed();
+    info!(
         job_id,
-        total_rows,
-        total_time,
-        (total_rows as f64 / total_time) / 1000.0
+        rows = total_rows,
+        elapsed_ms = total_time.as_millis() as u64,
+        rows_per_second = (total_rows as f64 / total_time.as_secs_f64()) as u64,
+        "Finished processing CSV"
     );
 
     Ok(())
@@ -293,15 +289,15 @@ fn parse_row_from_fields(
     let mut row = vec![FieldValue::Null; column_map.len()];
 
     for (col_name, &(output_idx, col_def)) in column_map.iter() {
-        if let Some(&csv_idx) = header_map.get(col_name) {
-            if let Some(field) = fields.get(csv_idx) {
-                let value = if field.trim().is_empty() {
-                    FieldValue::Null
-                } else {
-                    parse_field_value(field.trim(), &col_def.column_type)?
-                };
-                row[output_idx] = value;
-            }
+        if let Some(&csv_idx) = header_map.get(col_name)
+            && let Some(field) = fields.get(csv_idx)
+        {
+            let value = if field.trim().is_empty() {
+                FieldValue::Null
+            } else {
+                parse_field_value(field.trim(), &col_def.column_type)?
+            };
+            row[output_idx] = value;
         }
     }
 
@@ -458,7 +454,7 @@ async fn write_parquet_optimized(
     output_key: &str,
     schema: Arc<Schema>,
     job_id: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
     let mut buffer = Vec::with_capacity(PARQUET_BUFFER_SIZE); // 512MB initial
 
     let props = WriterProperties::builder()
@@ -472,6 +468,7 @@ async fn write_parquet_optimized(
         .build();
 
     let mut batches_written = 0;
+    let mut rows_written = 0;
     let start_time = std::time::Instant::now();
 
     // Create writer in a scope so it's dropped before we use buffer
@@ -481,29 +478,30 @@ async fn write_parquet_optimized(
         while let Some(batch) = batch_rx.recv().await {
             writer.write(&batch)?;
             batches_written += 1;
+            rows_written += batch.num_rows() as u64;
 
             if batches_written % 5 == 0 {
-                println!("Job {}: Written {} batches", job_id, batches_written);
+                info!(job_id, batches = batches_written, "Written batches");
             }
         }
 
         writer.close()?;
     } // writer is dropped here, releasing the mutable borrow on buffer
 
-    println!(
-        "Job {}: Writing complete - {} batches, uploading {:.2} MB to S3",
+    info!(
         job_id,
-        batches_written,
-        buffer.len() as f64 / (1024.0 * 1024.0)
+        batches = batches_written,
+        bytes = buffer.len(),
+        "Writing complete, uploading to S3"
     );
 
     upload_to_s3(bucket, output_key, buffer, job_id).await?;
 
-    let total_time = start_time.elapsed().as_secs_f64();
-    println!(
-        "Job {}: Upload completed in {:.2}s total",
-        job_id, total_time
+    info!(
+        job_id,
+        elapsed_ms = start_time.elapsed().as_millis() as u64,
+        "Upload completed"
     );
 
-    Ok(())
+    Ok(rows_written)
 }
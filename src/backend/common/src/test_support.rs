@@ -0,0 +1,308 @@
+// A stand-in AWS endpoint for unit tests. It answers on a loopback port with whatever the
+// test's handler returns and keeps every request it saw, so helpers that take an SDK
+// client can be exercised with a real client, without credentials or a network.
+use aws_sdk_bedrockruntime::Client as BedrockClient;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_sqs::Client as SqsClient;
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+type Handler = dyn Fn(&StubRequest) -> StubResponse + Send + Sync;
+
+#[derive(Debug, Clone)]
+pub struct StubRequest {
+    pub method: String,
+    // The request target as sent, path and query string
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl StubRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The JSON protocol's operation name, e.g. "UpdateItem" from DynamoDB_20120810.UpdateItem
+    pub fn operation(&self) -> Option<&str> {
+        self.header("x-amz-target")
+            .and_then(|target| target.rsplit('.').next())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StubResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    // Sent after Content-Type; a Content-Length here replaces the body's own, as a HEAD
+    // response needs
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl StubResponse {
+    pub fn json(body: Value) -> Self {
+        StubResponse {
+            status: 200,
+            content_type: "application/x-amz-json-1.0",
+            headers: Vec::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    pub fn bytes(status: u16, body: Vec<u8>) -> Self {
+        StubResponse {
+            status,
+            content_type: "application/octet-stream",
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    // An S3-style XML body such as a ListObjectsV2 page
+    pub fn xml(body: &str) -> Self {
+        StubResponse {
+            content_type: "application/xml",
+            ..StubResponse::bytes(200, body.as_bytes().to_vec())
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    // A DynamoDB-style error such as ConditionalCheckFailedException, optionally with the
+    // item the condition saw
+    pub fn dynamodb_error(error_type: &str, item: Option<Value>) -> Self {
+        let mut body = serde_json::json!({
+            "__type": format!("com.amazonaws.dynamodb.v20120810#{}", error_type),
+            "message": "stubbed failure"
+        });
+        if let Some(item) = item {
+            body["Item"] = item;
+        }
+        StubResponse {
+            status: 400,
+            content_type: "application/x-amz-json-1.0",
+            headers: Vec::new(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+}
+
+pub struct StubEndpoint {
+    url: String,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+}
+
+impl StubEndpoint {
+    pub fn start(handler: impl Fn(&StubRequest) -> StubResponse + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind stub endpoint");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                let seen = seen.clone();
+                std::thread::spawn(move || serve_connection(stream, handler.as_ref(), &seen));
+            }
+        });
+
+        StubEndpoint { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // Requests for one JSON protocol operation, in the order they arrived
+    pub fn operations(&self, operation: &str) -> Vec<StubRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.operation() == Some(operation))
+            .collect()
+    }
+
+    pub fn dynamodb_client(&self) -> DynamoDbClient {
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(aws_sdk_dynamodb::config::BehaviorVersion::latest())
+            .region(aws_sdk_dynamodb::config::Region::new("us-east-1"))
+            .endpoint_url(&self.url)
+            .credentials_provider(aws_sdk_dynamodb::config::Credentials::new(
+                "test", "test", None, None, "stub",
+            ))
+            .retry_config(aws_sdk_dynamodb::config::retry::RetryConfig::disabled())
+            .build();
+        DynamoDbClient::from_conf(config)
+    }
+
+    pub fn sqs_client(&self) -> SqsClient {
+        let config = aws_sdk_sqs::Config::builder()
+            .behavior_version(aws_sdk_sqs::config::BehaviorVersion::latest())
+            .region(aws_sdk_sqs::config::Region::new("us-east-1"))
+            .endpoint_url(&self.url)
+            .credentials_provider(aws_sdk_sqs::config::Credentials::new(
+                "test", "test", None, None, "stub",
+            ))
+            .retry_config(aws_sdk_sqs::config::retry::RetryConfig::disabled())
+            .build();
+        SqsClient::from_conf(config)
+    }
+
+    // Retries are left to `with_bedrock_retries`, as in `bedrock::bedrock_client`
+    pub fn bedrock_client(&self) -> BedrockClient {
+        let config = aws_sdk_bedrockruntime::Config::builder()
+            .behavior_version(aws_sdk_bedrockruntime::config::BehaviorVersion::latest())
+            .region(aws_sdk_bedrockruntime::config::Region::new("us-east-1"))
+            .endpoint_url(&self.url)
+            .credentials_provider(aws_sdk_bedrockruntime::config::Credentials::new(
+                "test", "test", None, None, "stub",
+            ))
+            .retry_config(aws_sdk_bedrockruntime::config::retry::RetryConfig::disabled())
+            .build();
+        BedrockClient::from_conf(config)
+    }
+
+    // Objects are addressed by path, http://host/bucket/key, so any bucket name works
+    pub fn s3_client(&self) -> S3Client {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .endpoint_url(&self.url)
+            .force_path_style(true)
+            .credentials_provider(aws_sdk_s3::config::Credentials::new(
+                "test", "test", None, None, "stub",
+            ))
+            .retry_config(aws_sdk_s3::config::retry::RetryConfig::disabled())
+            .build();
+        S3Client::from_conf(config)
+    }
+}
+
+// Answers requests on one connection until the client closes it
+fn serve_connection(stream: TcpStream, handler: &Handler, seen: &Mutex<Vec<StubRequest>>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+
+    while let Some(request) = read_request(&mut reader, &mut writer) {
+        let response = handler(&request);
+        seen.lock().unwrap().push(request);
+
+        let mut head = format!(
+            "HTTP/1.1 {} Stub\r\nContent-Type: {}\r\n",
+            response.status, response.content_type
+        );
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !response
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+        }
+        head.push_str("\r\n");
+        if writer.write_all(head.as_bytes()).is_err() || writer.write_all(&response.body).is_err() {
+            return;
+        }
+    }
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>, writer: &mut TcpStream) -> Option<StubRequest> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
+    }
+    let mut request_parts = request_line.split_whitespace();
+    let method = request_parts.next()?.to_string();
+    let target = request_parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    };
+
+    if header("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue")) {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").ok()?;
+    }
+
+    let mut body = Vec::new();
+    if header("transfer-encoding").is_some_and(|encoding| encoding.contains("chunked")) {
+        loop {
+            let mut size_line = String::new();
+            reader.read_line(&mut size_line).ok()?;
+            let size_text = size_line.trim().split(';').next().unwrap_or("0");
+            let size = usize::from_str_radix(size_text, 16).ok()?;
+            if size == 0 {
+                // Trailers, if any, end with an empty line
+                loop {
+                    let mut trailer = String::new();
+                    reader.read_line(&mut trailer).ok()?;
+                    if trailer.trim().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = header("content-length").and_then(|length| length.parse().ok()) {
+        body = vec![0; length];
+        reader.read_exact(&mut body).ok()?;
+    }
+
+    Some(StubRequest {
+        method,
+        target,
+        headers,
+        body,
+    })
+}
+
+// The API key tests' clock: `hour` o'clock on 2025-01-01
+pub fn at(hour: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2025, 1, 1, hour, 0, 0).unwrap()
+}
+
+// A key table holding one item, returned for whichever key is asked for
+pub fn key_table(item: Option<Value>) -> StubEndpoint {
+    StubEndpoint::start(move |_| match &item {
+        Some(item) => StubResponse::json(json!({"Item": item})),
+        None => StubResponse::json(json!({})),
+    })
+}
@@ -0,0 +1,167 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+// Lambda's /tmp unless the function is configured with more ephemeral storage
+pub const DEFAULT_TMP_CAPACITY_BYTES: u64 = 512 * 1024 * 1024;
+
+const INVOCATIONS_DIR: &str = "/tmp/invocations";
+
+// Scratch space for one invocation: DuckDB's spill files and anything else that shouldn't
+// outlive the request. It all goes in a directory of the invocation's own, removed when the
+// manager is dropped, so early returns and errors clean up as well. The parquet cache sits
+// beside it and is left alone; its files are meant to outlive the invocation.
+#[derive(Debug)]
+pub struct TmpManager {
+    dir: PathBuf,
+}
+
+impl TmpManager {
+    pub fn for_invocation(request_id: &str) -> std::io::Result<Self> {
+        TmpManager::in_root(INVOCATIONS_DIR, request_id)
+    }
+
+    // Clears out what earlier invocations left under `root` before making this one's
+    // directory. Only one invocation runs in a container at a time, so anything there is
+    // from one that timed out or ran out of memory before it could clean up.
+    pub fn in_root(root: impl AsRef<Path>, request_id: &str) -> std::io::Result<Self> {
+        let root = root.as_ref();
+        if let Ok(entries) = fs::read_dir(root) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let path = entry.path();
+                match fs::remove_dir_all(&path) {
+                    Ok(()) => info!(path = %path.display(), "Removed stale invocation files"),
+                    Err(e) => {
+                        warn!(path = %path.display(), error = %e, "Failed to remove stale invocation files")
+                    }
+                }
+            }
+        }
+
+        // Request IDs are UUIDs, but nothing else should be able to reach outside the root
+        let name: String = request_id
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect();
+        let dir = root.join(if name.is_empty() { "invocation" } else { &name });
+        fs::create_dir_all(&dir)?;
+        Ok(TmpManager { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for TmpManager {
+    fn drop(&mut self) {
+        let bytes = directory_bytes(&self.dir);
+        match fs::remove_dir_all(&self.dir) {
+            Ok(()) if bytes > 0 => info!(bytes, "Removed the invocation's tmp files"),
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                warn!(dir = %self.dir.display(), error = %e, "Failed to remove invocation files")
+            }
+        }
+    }
+}
+
+fn directory_bytes(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => directory_bytes(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+// Size of /tmp from TMP_CAPACITY_BYTES, falling back to the Lambda default when unset or
+// unparsable
+pub fn tmp_capacity_bytes() -> u64 {
+    std::env::var("TMP_CAPACITY_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TMP_CAPACITY_BYTES)
+}
+
+// How much an invocation may spill: whatever /tmp has beyond the parquet cache's budget, so
+// a full cache and a large spill can't between them fill the disk
+pub fn scratch_budget_bytes(cache_budget_bytes: u64) -> u64 {
+    tmp_capacity_bytes().saturating_sub(cache_budget_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parquet_cache::{ParquetCache, fetch_cached_parquet};
+    use crate::s3::SourceObject;
+    use crate::test_support::{StubEndpoint, StubResponse};
+
+    const PARQUET_BYTES: usize = 1000;
+    const SPILL_BYTES: usize = 2000;
+    const CACHE_BUDGET_BYTES: u64 = 3000;
+
+    // What the cached parquet files come to, leaving out their small sidecars
+    fn cached_parquet_bytes(cache_dir: &Path) -> u64 {
+        fs::read_dir(cache_dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|e| e == "parquet"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn repeated_invocations_over_distinct_jobs_stay_within_the_budget() {
+        let stub = StubEndpoint::start(|_| StubResponse::bytes(200, vec![b'p'; PARQUET_BYTES]));
+        let s3_client = stub.s3_client();
+        let root = tempfile::tempdir().unwrap();
+        let invocations = root.path().join("invocations");
+        let cache_dir = root.path().join("parquet-cache");
+        let cache = ParquetCache::new(&cache_dir, CACHE_BUDGET_BYTES);
+
+        for job in 0..20 {
+            let request_id = format!("request-{}", job);
+            let scratch = TmpManager::in_root(&invocations, &request_id).unwrap();
+            let object = SourceObject {
+                bytes: PARQUET_BYTES as i64,
+                etag: Some(format!("\"etag-{}\"", job)),
+            };
+            let key = format!("parquet/job-{}/data.parquet", job);
+            fetch_cached_parquet(&s3_client, &cache, "uploads", &key, &object)
+                .await
+                .unwrap();
+            fs::write(scratch.dir().join("spill.tmp"), vec![0; SPILL_BYTES]).unwrap();
+
+            // A timed out invocation never drops its manager; the next one clears up after it
+            if job % 5 == 4 {
+                std::mem::forget(scratch);
+            }
+
+            // Only the invocation running now has scratch files
+            assert_eq!(directory_bytes(&invocations), SPILL_BYTES as u64);
+            assert!(cached_parquet_bytes(&cache_dir) <= CACHE_BUDGET_BYTES);
+        }
+
+        let last = TmpManager::in_root(&invocations, "request-final").unwrap();
+        drop(last);
+        assert_eq!(directory_bytes(&invocations), 0);
+        assert_eq!(cached_parquet_bytes(&cache_dir), CACHE_BUDGET_BYTES);
+    }
+
+    #[test]
+    fn a_request_id_cant_reach_outside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+
+        let scratch = TmpManager::in_root(root.path(), "../../etc").unwrap();
+
+        assert_eq!(scratch.dir(), root.path().join("etc"));
+    }
+}
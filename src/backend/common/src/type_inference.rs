@@ -0,0 +1,159 @@
+use serde::Serialize;
+
+use crate::creation_parsing::{parse_boolean, parse_date_to_days, parse_datetime_to_nanos};
+use crate::creation_types::DataType;
+
+// Most specific first. Integer sits ahead of DateTime because plain integers also parse
+// as unix timestamps, and Boolean ahead of Integer so a 0/1 column reads as a flag.
+const CANDIDATE_TYPES: [DataType; 5] = [
+    DataType::Boolean,
+    DataType::Integer,
+    DataType::Float,
+    DataType::Date,
+    DataType::DateTime,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CandidateFailures {
+    #[serde(rename = "type")]
+    pub data_type: DataType,
+    pub failures: usize,
+}
+
+// The suggested type for a column plus how many sampled values each candidate type
+// rejected, so a caller can show why a column fell back to string
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TypeSuggestion {
+    pub suggested: DataType,
+    pub failures: Vec<CandidateFailures>,
+}
+
+// Empty values are ignored: they become nulls whatever the type, so they say nothing
+// about which type fits. A column with no non-empty values is suggested as string.
+pub fn infer_column_type(values: &[&str]) -> TypeSuggestion {
+    let non_empty: Vec<&str> = values
+        .iter()
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .collect();
+
+    let failures: Vec<CandidateFailures> = CANDIDATE_TYPES
+        .iter()
+        .map(|data_type| CandidateFailures {
+            data_type: data_type.clone(),
+            failures: non_empty
+                .iter()
+                .filter(|value| !parses_as(value, data_type))
+                .count(),
+        })
+        .collect();
+
+    let suggested = if non_empty.is_empty() {
+        DataType::String
+    } else {
+        failures
+            .iter()
+            .find(|candidate| candidate.failures == 0)
+            .map_or(DataType::String, |candidate| candidate.data_type.clone())
+    };
+
+    TypeSuggestion {
+        suggested,
+        failures,
+    }
+}
+
+// Mirrors the coercion the processor applies, so a suggested type converts the sampled
+// values without any coercion failures
+fn parses_as(value: &str, data_type: &DataType) -> bool {
+    match data_type {
+        DataType::String => true,
+        DataType::Integer => value.parse::<i64>().is_ok(),
+        DataType::Float => value.parse::<f64>().is_ok(),
+        DataType::Boolean => parse_boolean(value).is_some(),
+        DataType::Date => parse_date_to_days(value).is_some(),
+        DataType::DateTime | DataType::Timestamp => parse_datetime_to_nanos(value).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn failures(suggestion: &TypeSuggestion, data_type: DataType) -> usize {
+        suggestion
+            .failures
+            .iter()
+            .find(|candidate| candidate.data_type == data_type)
+            .unwrap()
+            .failures
+    }
+
+    #[test]
+    fn one_stray_value_in_an_integer_column_falls_back_to_string() {
+        let suggestion = infer_column_type(&["12", "7", "N/A", "40", "3"]);
+
+        assert_eq!(suggestion.suggested, DataType::String);
+        assert_eq!(failures(&suggestion, DataType::Integer), 1);
+        assert_eq!(failures(&suggestion, DataType::Float), 1);
+        assert_eq!(failures(&suggestion, DataType::Boolean), 5);
+        assert_eq!(failures(&suggestion, DataType::Date), 5);
+    }
+
+    #[test]
+    fn empty_values_say_nothing_about_the_type() {
+        let suggestion = infer_column_type(&["12", "", "  ", "40"]);
+
+        assert_eq!(suggestion.suggested, DataType::Integer);
+        assert_eq!(failures(&suggestion, DataType::Integer), 0);
+        assert_eq!(infer_column_type(&["", " "]).suggested, DataType::String);
+        assert_eq!(infer_column_type(&[]).suggested, DataType::String);
+    }
+
+    #[test]
+    fn dates_in_two_formats_are_still_dates() {
+        let suggestion = infer_column_type(&["2024-03-05", "03/06/2024", "2024-03-07"]);
+
+        assert_eq!(suggestion.suggested, DataType::Date);
+        assert_eq!(failures(&suggestion, DataType::Date), 0);
+        assert_eq!(failures(&suggestion, DataType::Integer), 3);
+    }
+
+    #[test]
+    fn the_most_specific_type_without_failures_is_suggested() {
+        assert_eq!(
+            infer_column_type(&["0", "1", "1"]).suggested,
+            DataType::Boolean
+        );
+        assert_eq!(
+            infer_column_type(&["0", "1", "2"]).suggested,
+            DataType::Integer
+        );
+        assert_eq!(
+            infer_column_type(&["1", "2.5", "-3"]).suggested,
+            DataType::Float
+        );
+        assert_eq!(
+            infer_column_type(&["north", "south"]).suggested,
+            DataType::String
+        );
+    }
+
+    #[test]
+    fn a_suggestion_serializes_with_its_failures_per_type() {
+        let suggestion = infer_column_type(&["1", "2", "x"]);
+
+        let value = serde_json::to_value(&suggestion).unwrap();
+
+        assert_eq!(value["suggested"], "string");
+        assert_eq!(
+            value["failures"][1],
+            json!({"type": "integer", "failures": 1})
+        );
+        assert_eq!(
+            value["failures"].as_array().unwrap().len(),
+            CANDIDATE_TYPES.len()
+        );
+    }
+}
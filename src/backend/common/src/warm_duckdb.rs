@@ -0,0 +1,199 @@
+use duckdb::Connection;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use tracing::{info, warn};
+
+use crate::duck_db::{drop_view, register_parquet_view, setup_duckdb_connection};
+use crate::error::Error;
+
+// The database a warm container keeps between invocations, and the file each of its views
+// reads. Settings, loaded extensions and views belong to the database, so an invocation
+// asking about the same parquet as the last one finds it all in place.
+struct WarmDatabase {
+    conn: Connection,
+    views: HashMap<String, String>,
+}
+
+type WarmSlot = Mutex<Option<WarmDatabase>>;
+
+fn warm_slot() -> &'static WarmSlot {
+    static SLOT: OnceLock<WarmSlot> = OnceLock::new();
+    SLOT.get_or_init(|| Mutex::new(None))
+}
+
+// A panic while the slot was locked may have left the database half set up, so it is
+// dropped and the next connection starts over
+fn lock_slot() -> MutexGuard<'static, Option<WarmDatabase>> {
+    let slot = warm_slot();
+    match slot.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            warn!("Recreating the DuckDB database after a panic");
+            let mut guard = poisoned.into_inner();
+            *guard = None;
+            slot.clear_poison();
+            guard
+        }
+    }
+}
+
+// A connection of the invocation's own to the container's database, opening the database
+// on first use, and whether the database was already open. Views other than `views` are
+// dropped first, so nothing an earlier caller registered can be read by this one. Only one
+// invocation runs in a container at a time.
+pub fn warm_connection(views: &[&str]) -> Result<(Connection, bool), Error> {
+    let mut slot = lock_slot();
+    let reused = slot.is_some();
+    let database = match slot.as_mut() {
+        Some(database) => database,
+        None => slot.insert(WarmDatabase {
+            conn: setup_duckdb_connection()?,
+            views: HashMap::new(),
+        }),
+    };
+
+    let stale: Vec<String> = database
+        .views
+        .keys()
+        .filter(|name| !views.contains(&name.as_str()))
+        .cloned()
+        .collect();
+    for name in stale {
+        drop_view(&database.conn, &name)?;
+        database.views.remove(&name);
+    }
+
+    Ok((database.conn.try_clone()?, reused))
+}
+
+// Points the view `name` at the file unless the warm database already has it pointing
+// there, and returns whether it had to. Once `reset_warm_database` has dropped the
+// database, views are still registered on `conn` but no longer recorded.
+pub fn register_warm_view(conn: &Connection, name: &str, file_path: &str) -> Result<bool, Error> {
+    let mut slot = lock_slot();
+    let registered = slot
+        .as_ref()
+        .and_then(|database| database.views.get(name))
+        .is_some_and(|path| path == file_path);
+    if registered {
+        return Ok(false);
+    }
+
+    register_parquet_view(conn, name, file_path)?;
+    if let Some(database) = slot.as_mut() {
+        database
+            .views
+            .insert(name.to_string(), file_path.to_string());
+    }
+    Ok(true)
+}
+
+// Drops the warm database, for when a file its views read has been evicted from the
+// parquet cache. The next connection opens a new one.
+pub fn reset_warm_database() {
+    if lock_slot().take().is_some() {
+        info!("Reset the warm DuckDB database");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The warm database is one per process, so tests that use it take turns
+    static WARM_TESTS: Mutex<()> = Mutex::new(());
+
+    fn take_turn() -> MutexGuard<'static, ()> {
+        let turn = WARM_TESTS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset_warm_database();
+        turn
+    }
+
+    // A parquet file holding the single value `n`
+    fn parquet(dir: &tempfile::TempDir, name: &str, n: i64) -> String {
+        let path = dir.path().join(name).to_string_lossy().to_string();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "COPY (SELECT {} AS n) TO '{}' (FORMAT PARQUET);",
+            n, path
+        ))
+        .unwrap();
+        path
+    }
+
+    fn read_n(conn: &Connection, view: &str) -> Result<i64, duckdb::Error> {
+        conn.query_row(&format!("SELECT n FROM {}", view), [], |row| row.get(0))
+    }
+
+    #[test]
+    fn a_second_invocation_on_the_same_file_skips_registration() {
+        let _turn = take_turn();
+        let dir = tempfile::tempdir().unwrap();
+        let first_file = parquet(&dir, "first.parquet", 1);
+
+        let (conn, reused) = warm_connection(&["data"]).unwrap();
+        assert!(!reused);
+        assert!(register_warm_view(&conn, "data", &first_file).unwrap());
+        drop(conn);
+
+        let (conn, reused) = warm_connection(&["data"]).unwrap();
+        assert!(reused);
+        assert!(!register_warm_view(&conn, "data", &first_file).unwrap());
+        assert_eq!(read_n(&conn, "data").unwrap(), 1);
+
+        // Another file under the same name is registered over the old one
+        let second_file = parquet(&dir, "second.parquet", 2);
+        assert!(register_warm_view(&conn, "data", &second_file).unwrap());
+        assert_eq!(read_n(&conn, "data").unwrap(), 2);
+    }
+
+    #[test]
+    fn views_another_invocation_registered_are_dropped() {
+        let _turn = take_turn();
+        let dir = tempfile::tempdir().unwrap();
+        let file = parquet(&dir, "orders.parquet", 1);
+        let (conn, _) = warm_connection(&["orders"]).unwrap();
+        register_warm_view(&conn, "orders", &file).unwrap();
+        drop(conn);
+
+        let (conn, reused) = warm_connection(&["data"]).unwrap();
+
+        assert!(reused);
+        assert!(read_n(&conn, "orders").is_err());
+    }
+
+    #[test]
+    fn a_reset_opens_a_new_database() {
+        let _turn = take_turn();
+        let dir = tempfile::tempdir().unwrap();
+        let file = parquet(&dir, "data.parquet", 1);
+        let (conn, _) = warm_connection(&["data"]).unwrap();
+        register_warm_view(&conn, "data", &file).unwrap();
+        drop(conn);
+
+        reset_warm_database();
+        let (conn, reused) = warm_connection(&["data"]).unwrap();
+
+        assert!(!reused);
+        assert!(register_warm_view(&conn, "data", &file).unwrap());
+    }
+
+    #[test]
+    fn a_panic_while_locked_recreates_the_database() {
+        let _turn = take_turn();
+        let (conn, _) = warm_connection(&[]).unwrap();
+        drop(conn);
+
+        let panicked = std::thread::spawn(|| {
+            let _slot = lock_slot();
+            panic!("setting up the database failed");
+        })
+        .join();
+        assert!(panicked.is_err());
+
+        let (_conn, reused) = warm_connection(&[]).unwrap();
+        assert!(!reused);
+    }
+}
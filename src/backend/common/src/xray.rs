@@ -0,0 +1,299 @@
+use serde_json::{Map, Value, json};
+use std::env;
+use std::net::UdpSocket;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::Subscriber;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+// SQS system attribute that carries the X-Ray trace header between producer and consumer
+pub const TRACE_HEADER_ATTRIBUTE: &str = "AWSTraceHeader";
+
+// Spans recording a field with this name start a trace; every span below them is sent
+// to X-Ray as a subsegment of that trace
+pub const TRACE_HEADER_FIELD: &str = "xray_trace";
+
+const DEFAULT_DAEMON_ADDRESS: &str = "127.0.0.1:2000";
+const SEGMENT_HEADER: &str = "{\"format\":\"json\",\"version\":1}";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceHeader {
+    pub root: String,
+    pub parent: Option<String>,
+    pub sampled: bool,
+}
+
+impl TraceHeader {
+    // Parses `Root=1-...;Parent=...;Sampled=1`, ignoring keys we don't use such as Lineage
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut root = None;
+        let mut parent = None;
+        let mut sampled = false;
+
+        for part in header.split(';') {
+            match part.trim().split_once('=') {
+                Some(("Root", value)) => root = Some(value.to_string()),
+                Some(("Parent", value)) => parent = Some(value.to_string()),
+                Some(("Sampled", value)) => sampled = value == "1",
+                _ => {}
+            }
+        }
+
+        root.map(|root| TraceHeader {
+            root,
+            parent,
+            sampled,
+        })
+    }
+
+    pub fn to_header(&self) -> String {
+        let mut header = format!("Root={}", self.root);
+        if let Some(parent) = &self.parent {
+            header.push_str(&format!(";Parent={}", parent));
+        }
+        header.push_str(if self.sampled {
+            ";Sampled=1"
+        } else {
+            ";Sampled=0"
+        });
+        header
+    }
+}
+
+struct SpanData {
+    subsegment_id: String,
+    start_time: f64,
+    fields: Map<String, Value>,
+    trace: Option<TraceHeader>,
+}
+
+struct FieldVisitor<'a> {
+    fields: &'a mut Map<String, Value>,
+    trace: &'a mut Option<TraceHeader>,
+}
+
+impl FieldVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.fields.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == TRACE_HEADER_FIELD {
+            *self.trace = TraceHeader::parse(value);
+            return;
+        }
+        self.insert(field, json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+// Sends each closed span as an X-Ray subsegment to the daemon Lambda runs alongside the
+// function (AWS_XRAY_DAEMON_ADDRESS). Spans outside a traced span are ignored.
+pub struct XRayLayer {
+    socket: UdpSocket,
+    daemon_address: String,
+}
+
+impl XRayLayer {
+    pub fn from_env() -> std::io::Result<Self> {
+        let daemon_address = env::var("AWS_XRAY_DAEMON_ADDRESS")
+            .unwrap_or_else(|_| DEFAULT_DAEMON_ADDRESS.to_string());
+
+        Ok(XRayLayer {
+            socket: UdpSocket::bind("0.0.0.0:0")?,
+            daemon_address,
+        })
+    }
+
+    fn send(&self, document: &Value) {
+        let payload = format!("{}\n{}", SEGMENT_HEADER, document);
+        if let Err(e) = self
+            .socket
+            .send_to(payload.as_bytes(), &self.daemon_address)
+        {
+            // Logging through tracing here would re-enter this layer
+            eprintln!("Failed to send X-Ray subsegment: {}", e);
+        }
+    }
+}
+
+impl<S> Layer<S> for XRayLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = Map::new();
+        let mut trace = None;
+        attrs.record(&mut FieldVisitor {
+            fields: &mut fields,
+            trace: &mut trace,
+        });
+
+        span.extensions_mut().insert(SpanData {
+            subsegment_id: new_subsegment_id(),
+            start_time: epoch_seconds(),
+            fields,
+            trace,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut FieldVisitor {
+                fields: &mut data.fields,
+                trace: &mut data.trace,
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+
+        // A span that starts a trace hangs off the caller's segment; anything below it
+        // hangs off its nearest enclosing span
+        let (trace, parent_id) = match &data.trace {
+            Some(trace) => (Some(trace.clone()), trace.parent.clone()),
+            None => {
+                let mut trace = None;
+                let mut parent_id = None;
+                for ancestor in span.scope().skip(1) {
+                    let extensions = ancestor.extensions();
+                    let Some(ancestor_data) = extensions.get::<SpanData>() else {
+                        continue;
+                    };
+                    if parent_id.is_none() {
+                        parent_id = Some(ancestor_data.subsegment_id.clone());
+                    }
+                    if let Some(ancestor_trace) = &ancestor_data.trace {
+                        trace = Some(ancestor_trace.clone());
+                        break;
+                    }
+                }
+                (trace, parent_id)
+            }
+        };
+
+        let (Some(trace), Some(parent_id)) = (trace, parent_id) else {
+            return;
+        };
+        if !trace.sampled {
+            return;
+        }
+
+        self.send(&json!({
+            "name": span.name(),
+            "id": data.subsegment_id,
+            "trace_id": trace.root,
+            "parent_id": parent_id,
+            "type": "subsegment",
+            "start_time": data.start_time,
+            "end_time": epoch_seconds(),
+            "metadata": { "default": data.fields }
+        }));
+    }
+}
+
+fn new_subsegment_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
+fn epoch_seconds() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "1-5759e988-bd862e3fe1be46a994272793";
+
+    #[test]
+    fn a_full_header_survives_a_round_trip() {
+        let header = format!("Root={};Parent=53995c3f42cd8ad8;Sampled=1", ROOT);
+
+        let parsed = TraceHeader::parse(&header).unwrap();
+
+        assert_eq!(
+            parsed,
+            TraceHeader {
+                root: ROOT.to_string(),
+                parent: Some("53995c3f42cd8ad8".to_string()),
+                sampled: true,
+            }
+        );
+        assert_eq!(parsed.to_header(), header);
+    }
+
+    #[test]
+    fn missing_optional_parts_read_as_an_unsampled_root() {
+        let parsed = TraceHeader::parse(&format!("Root={}", ROOT)).unwrap();
+
+        assert_eq!(parsed.parent, None);
+        assert!(!parsed.sampled);
+        assert_eq!(parsed.to_header(), format!("Root={};Sampled=0", ROOT));
+    }
+
+    #[test]
+    fn unused_keys_and_spacing_are_ignored() {
+        let parsed = TraceHeader::parse(&format!(
+            "Root={}; Lineage=a87bd80c:1|68fd508a:5 ;Sampled=1",
+            ROOT
+        ))
+        .unwrap();
+
+        assert_eq!(parsed.root, ROOT);
+        assert!(parsed.sampled);
+        assert_eq!(parsed.to_header(), format!("Root={};Sampled=1", ROOT));
+    }
+
+    #[test]
+    fn a_header_without_a_root_is_not_a_trace() {
+        assert_eq!(TraceHeader::parse(""), None);
+        assert_eq!(
+            TraceHeader::parse("Parent=53995c3f42cd8ad8;Sampled=1"),
+            None
+        );
+        assert_eq!(TraceHeader::parse("not a trace header"), None);
+        // Only the exact key counts
+        assert_eq!(TraceHeader::parse(&format!("root={}", ROOT)), None);
+    }
+}
@@ -0,0 +1,125 @@
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use chrono::Utc;
+use common::auth::authorize;
+use common::cors::create_cors_response;
+use common::creation_validation::parse_upload_request;
+use common::dynamo::{JobStatus, reserve_upload_job};
+use common::logging::init_tracing;
+use common::s3::{presign_put_url, upload_key};
+use lambda_runtime::{Error, LambdaEvent, run, service_fn};
+use serde_json::json;
+use std::env;
+use std::time::Duration;
+use tracing::{error, info};
+
+// S3 rejects a single PUT above 5 GiB, so a larger cap couldn't be honoured anyway
+const MAX_UPLOAD_BYTES: i64 = 5 * 1024 * 1024 * 1024;
+
+const DEFAULT_UPLOAD_URL_EXPIRY_SECONDS: u64 = 15 * 60;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    init_tracing();
+
+    run(service_fn(function_handler)).await
+}
+
+// Reserves a job_id for the caller and returns a URL to upload its CSV to. The conversion
+// request that follows only needs the job_id; the file is read from the reserved key.
+async fn function_handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if event.payload.http_method == "OPTIONS" {
+        return Ok(create_cors_response(200, None));
+    }
+
+    let table_name = env::var("DYNAMODB_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let dynamodb_client = DynamoDbClient::new(&config);
+
+    let principal = match authorize(&event.payload, &dynamodb_client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    let bucket_name = env::var("S3_UPLOAD_BUCKET_NAME")?;
+    let max_bytes = env::var("UPLOAD_MAX_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse::<i64>().ok())
+        .unwrap_or(MAX_UPLOAD_BYTES)
+        .min(MAX_UPLOAD_BYTES);
+    let expiry_seconds = env::var("UPLOAD_URL_EXPIRY_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_UPLOAD_URL_EXPIRY_SECONDS);
+
+    let body = event.payload.body.unwrap_or_default();
+    let request = match parse_upload_request(&body, max_bytes) {
+        Ok(request) => request,
+        Err(errors) => {
+            info!(errors = errors.len(), "Rejected invalid upload request");
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Invalid upload request", "details": errors}).to_string()),
+            ));
+        }
+    };
+
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let s3_key = upload_key(&job_id);
+
+    if let Err(e) = reserve_upload_job(&dynamodb_client, &table_name, &job_id, &principal.id).await
+    {
+        error!(job_id = %job_id, error = %e, "Failed to reserve job");
+        return Ok(create_cors_response(
+            500,
+            Some(json!({"error": "Internal server error"}).to_string()),
+        ));
+    }
+
+    let upload_url = match presign_put_url(
+        &bucket_name,
+        &s3_key,
+        &request.content_type,
+        request.content_length,
+        Duration::from_secs(expiry_seconds),
+    )
+    .await
+    {
+        Ok(url) => url,
+        Err(e) => {
+            // The reservation expires on its own through the table's TTL
+            error!(job_id = %job_id, error = %e, "Failed to presign upload");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            ));
+        }
+    };
+
+    let expires_at = Utc::now() + chrono::Duration::seconds(expiry_seconds as i64);
+
+    info!(
+        job_id = %job_id,
+        content_length = request.content_length,
+        "Issued upload URL"
+    );
+
+    let response_body = json!({
+        "job_id": job_id,
+        "status": JobStatus::AwaitingUpload,
+        "s3_key": s3_key,
+        "upload_url": upload_url,
+        "method": "PUT",
+        // Both are signed into the URL and must be sent exactly as given
+        "headers": {
+            "Content-Type": request.content_type,
+            "Content-Length": request.content_length.to_string()
+        },
+        "expires_at": expires_at.to_rfc3339()
+    });
+
+    Ok(create_cors_response(200, Some(response_body.to_string())))
+}
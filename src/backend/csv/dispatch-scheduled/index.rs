@@ -0,0 +1,89 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::Client as SqsClient;
+use chrono::{Duration, Utc};
+use common::{
+    creation_types::ProcessingPath,
+    dynamo::{claim_scheduled_job, get_due_scheduled_jobs, reschedule_job},
+    logging::init_tracing,
+    parquet_creation::{MAX_SQS_DELAY_SECONDS, plan_start},
+};
+use lambda_runtime::{Error, LambdaEvent, service_fn};
+use std::env;
+use tracing::{error, info};
+
+// The EventBridge schedule's event carries nothing we need
+#[derive(serde::Deserialize, Debug)]
+struct ScheduledEvent {}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    init_tracing();
+
+    let handler = service_fn(handler);
+    lambda_runtime::run(handler).await?;
+    Ok(())
+}
+
+// Queues every scheduled job that starts within SQS's delay limit, with a delay covering
+// whatever is left until its start time. Runs more often than that limit, so no job is
+// picked up late.
+async fn handler(_event: LambdaEvent<ScheduledEvent>) -> Result<(), Error> {
+    let table_name = env::var("DYNAMODB_NAME")?;
+    let queue_url = env::var("PARQUET_QUEUE_URL")?;
+    let fast_queue_url = env::var("PARQUET_FAST_QUEUE_URL")?;
+
+    let config = aws_config::load_from_env().await;
+    let sqs_client = SqsClient::new(&config);
+    let dynamodb_client = DynamoDbClient::new(&config);
+
+    let now = Utc::now();
+    let due_before = now + Duration::seconds(MAX_SQS_DELAY_SECONDS);
+    let jobs = get_due_scheduled_jobs(&dynamodb_client, &table_name, due_before).await?;
+
+    let mut dispatched = 0;
+    let mut failed = 0;
+
+    for job in jobs {
+        // Claiming first means a job cancelled since the query, or taken by an overlapping
+        // run, is never queued
+        if !claim_scheduled_job(&dynamodb_client, &table_name, &job.job_id).await? {
+            info!(job_id = %job.job_id, "Scheduled job no longer waiting, skipping");
+            continue;
+        }
+
+        let queue_url = match job.path {
+            ProcessingPath::Standard => &queue_url,
+            ProcessingPath::Fast => &fast_queue_url,
+        };
+        let delay_seconds = plan_start(Some(job.start_after), now).delay_seconds();
+
+        match sqs_client
+            .send_message()
+            .queue_url(queue_url)
+            .message_body(&job.request_body)
+            .set_delay_seconds(delay_seconds)
+            .send()
+            .await
+        {
+            Ok(_) => {
+                dispatched += 1;
+                info!(
+                    job_id = %job.job_id,
+                    start_after = %job.start_after,
+                    delay_seconds = delay_seconds.unwrap_or(0),
+                    "Dispatched scheduled job"
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                error!(job_id = %job.job_id, error = %e, "Failed to queue scheduled job");
+                if let Err(e) = reschedule_job(&dynamodb_client, &table_name, &job.job_id).await {
+                    error!(job_id = %job.job_id, error = %e, "Failed to reschedule job");
+                }
+            }
+        }
+    }
+
+    info!(dispatched, failed, "Scheduled job dispatch complete");
+    Ok(())
+}
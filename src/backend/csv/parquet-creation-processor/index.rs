@@ -1,85 +1,827 @@
-use aws_lambda_events::{event::sqs::SqsEvent, sqs::SqsMessage};
+use aws_lambda_events::{
+    event::sqs::{BatchItemFailure, SqsBatchResponse, SqsEvent},
+    sqs::SqsMessage,
+};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_sqs::Client as SqsClient;
 use common::{
-    creation_types::ColumnDefinition, dynamo::update_job_status_to_success,
-    parquet_creation_processor::stream_csv_to_parquet_optimized,
+    creation_types::{JobProvenance, ParquetCreationRequest, ProcessingPath},
+    dynamo::{
+        JobLease, LeaseOutcome, acquire_job_lease, record_job_retry, record_memory_high_water,
+        record_notification_outcome, record_query_schema, record_throughput, release_job_lease,
+        update_job_status_to_failed, update_job_status_to_success,
+    },
+    logging::{init_tracing, redact},
+    memory::CountingAllocator,
+    metrics::MetricsLogger,
+    notifications::{CompletionEvent, send_completion_notification},
+    parquet_creation_processor::{ConversionSummary, stream_csv_to_parquet_optimized},
+    processing_error::ProcessingError,
+    s3::parquet_key,
+    sqs::{JOB_LEASE_DURATION, spawn_visibility_heartbeat, string_message_attribute},
+    xray::TRACE_HEADER_ATTRIBUTE,
 };
 use lambda_runtime::{Error, LambdaEvent, service_fn};
 use std::env;
-use tracing::error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{Instrument, debug, error, field, info, info_span, warn};
 
-#[derive(serde::Deserialize, Debug)]
-struct ParquetCreationRequest {
-    payload: Vec<ColumnDefinition>,
-    s3_key: String,
-    job_id: String,
-}
+// Must match the redrive policy on the queue: on the last delivery we forward the message
+// to the DLQ ourselves so it carries the failure details. It is well above the attempt
+// limit because every execution a checkpointed conversion runs out of time in is another
+// delivery of the same message.
+const DEFAULT_MAX_RECEIVE_COUNT: u32 = 20;
+
+// Unlike the receive count, the attempt counter lives on the job item, so it also covers
+// messages redriven out of the DLQ and duplicate submissions of the same job. Attempts
+// that saved a checkpoint don't count, so this limits failures rather than executions.
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+const METRICS_FUNCTION_NAME: &str = "parquet-creation-processor";
+
+// Lets the memory governor see real heap usage rather than only its own estimates
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .without_time()
-        .init();
+    init_tracing();
 
     let handler = service_fn(handler);
     lambda_runtime::run(handler).await?;
     Ok(())
 }
 
-async fn handler(event: LambdaEvent<SqsEvent>) -> Result<(), Error> {
-    println!("{:?}", event);
-    let bucket_name = env::var("S3_UPLOAD_BUCKET_NAME")?;
-    let table_name = env::var("DYNAMODB_NAME")?;
+async fn handler(event: LambdaEvent<SqsEvent>) -> Result<SqsBatchResponse, Error> {
+    debug!(records = event.payload.records.len(), "Received SQS event");
+    let settings = ProcessorSettings {
+        bucket_name: env::var("S3_UPLOAD_BUCKET_NAME")?,
+        table_name: env::var("DYNAMODB_NAME")?,
+        queue_url: env::var("PARQUET_QUEUE_URL")?,
+        dlq_url: env::var("PARQUET_DLQ_URL")?,
+        max_receive_count: env::var("PARQUET_MAX_RECEIVE_COUNT")
+            .ok()
+            .and_then(|count| count.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_MAX_RECEIVE_COUNT),
+        max_attempts: env::var("PARQUET_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|count| count.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_MAX_ATTEMPTS),
+    };
+
+    let config = aws_config::load_from_env().await;
+    let sqs_client = SqsClient::new(&config);
+    let dynamodb_client = DynamoDbClient::new(&config);
+
+    Ok(process_records(
+        event.payload.records,
+        event.context.xray_trace_id,
+        &settings,
+        &sqs_client,
+        &dynamodb_client,
+    )
+    .await)
+}
+
+struct ProcessorSettings {
+    bucket_name: String,
+    table_name: String,
+    queue_url: String,
+    dlq_url: String,
+    max_receive_count: u32,
+    max_attempts: u32,
+}
+
+// Processes each message in turn, reporting the ones to be redelivered
+async fn process_records(
+    records: Vec<SqsMessage>,
+    invocation_trace: Option<String>,
+    settings: &ProcessorSettings,
+    sqs_client: &SqsClient,
+    dynamodb_client: &DynamoDbClient,
+) -> SqsBatchResponse {
+    let mut batch_item_failures = Vec::new();
+
+    for record in records {
+        let message_id = record.message_id.clone().unwrap_or_default();
+        debug!(
+            message_id = %message_id,
+            body = %redact(record.body.as_deref().unwrap_or_default()),
+            "Received SQS message"
+        );
+
+        // Continue the submission lambda's trace when the message carries it
+        let trace_header = record
+            .attributes
+            .get(TRACE_HEADER_ATTRIBUTE)
+            .cloned()
+            .or_else(|| invocation_trace.clone())
+            .unwrap_or_default();
+        let message_span = info_span!(
+            "process_message",
+            message_id = %message_id,
+            job_id = field::Empty,
+            xray_trace = %trace_header
+        );
 
-    for record in event.payload.records {
-        if let Err(e) = process_sqs_message(&record, &bucket_name, &table_name).await {
+        if let Err(e) = process_sqs_message(
+            &record,
+            &settings.bucket_name,
+            dynamodb_client,
+            &settings.table_name,
+            sqs_client,
+            &settings.queue_url,
+            settings.max_attempts,
+        )
+        .instrument(message_span)
+        .await
+        {
             error!(
-                "Failed to process SQS message {}: {}",
-                record.message_id.unwrap_or_default(),
-                e
+                message_id = %message_id,
+                stage = e.stage(),
+                retryable = e.is_retryable(),
+                error = %e,
+                "Failed to process SQS message"
             );
-            continue;
+
+            let receive_count = record
+                .attributes
+                .get("ApproximateReceiveCount")
+                .and_then(|count| count.parse::<u32>().ok())
+                .unwrap_or(1);
+
+            // Bad input fails the same way every time, so don't wait out the redrive policy
+            if !e.is_retryable() || receive_count >= settings.max_receive_count {
+                match forward_to_dead_letter_queue(sqs_client, &settings.dlq_url, &record, &e).await
+                {
+                    // Reporting success lets Lambda delete the original now the DLQ has a copy
+                    Ok(_) => continue,
+                    Err(dlq_error) => error!(
+                        message_id = %message_id,
+                        error = %dlq_error,
+                        "Failed to forward SQS message to the DLQ"
+                    ),
+                }
+            }
+
+            batch_item_failures.push(BatchItemFailure {
+                item_identifier: message_id,
+            });
         }
     }
+
+    SqsBatchResponse {
+        batch_item_failures,
+    }
+}
+
+async fn forward_to_dead_letter_queue(
+    sqs_client: &SqsClient,
+    dlq_url: &str,
+    record: &SqsMessage,
+    processing_error: &ProcessingError,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let body = record.body.clone().unwrap_or_else(|| "{}".to_string());
+
+    sqs_client
+        .send_message()
+        .queue_url(dlq_url)
+        .message_body(body)
+        .message_attributes(
+            "error_stage",
+            string_message_attribute(processing_error.stage())?,
+        )
+        .message_attributes(
+            "error_summary",
+            string_message_attribute(&processing_error.summary())?,
+        )
+        .send()
+        .await?;
+
     Ok(())
 }
 
 async fn process_sqs_message(
     record: &SqsMessage,
     bucket_name: &str,
+    dynamodb_client: &DynamoDbClient,
     table_name: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let body = record.body.as_ref().ok_or("SQS message has no body")?;
+    sqs_client: &SqsClient,
+    queue_url: &str,
+    max_attempts: u32,
+) -> Result<(), ProcessingError> {
+    let body = record
+        .body
+        .as_ref()
+        .ok_or_else(|| ProcessingError::parse("SQS message has no body"))?;
+
+    let request: ParquetCreationRequest = serde_json::from_str(body).map_err(|e| {
+        ProcessingError::parse(format!("Failed to parse JSON from SQS message: {}", e))
+    })?;
+
+    tracing::Span::current().record("job_id", request.job_id.as_str());
+
+    let outcome = acquire_job_lease(
+        dynamodb_client,
+        table_name,
+        &request.job_id,
+        JOB_LEASE_DURATION,
+    )
+    .await
+    .map_err(ProcessingError::dynamo)?;
+    let lease = match outcome {
+        LeaseOutcome::Acquired(lease) => lease,
+        // A visibility-timeout race handed the message out twice. Failing it puts it back
+        // on the queue without touching the job, so if the holder fails, the job is picked
+        // up again once its lease has expired.
+        LeaseOutcome::Held { until } => {
+            warn!(
+                job_id = %request.job_id,
+                lease_until = until,
+                "Job is being converted by another invocation"
+            );
+            return Err(ProcessingError::dynamo(
+                "job is leased by another invocation",
+            ));
+        }
+        LeaseOutcome::NotRunnable(status) => {
+            info!(
+                job_id = %request.job_id,
+                status = %status,
+                "Job is no longer pending, not converting"
+            );
+            return Ok(());
+        }
+        LeaseOutcome::NotFound => {
+            return Err(ProcessingError::parse("no job exists for this message"));
+        }
+    };
+
+    let result = process_leased_job(
+        record,
+        &request,
+        &lease,
+        bucket_name,
+        dynamodb_client,
+        table_name,
+        sqs_client,
+        queue_url,
+        max_attempts,
+    )
+    .await;
+
+    // Best effort: a lease that isn't released still expires on its own
+    if let Err(e) = release_job_lease(dynamodb_client, table_name, &request.job_id, &lease).await {
+        warn!(job_id = %request.job_id, error = %e, "Failed to release job lease");
+    }
+
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_leased_job(
+    record: &SqsMessage,
+    request: &ParquetCreationRequest,
+    lease: &JobLease,
+    bucket_name: &str,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    sqs_client: &SqsClient,
+    queue_url: &str,
+    max_attempts: u32,
+) -> Result<(), ProcessingError> {
+    let mut metrics = MetricsLogger::new(METRICS_FUNCTION_NAME, &request.job_id);
+    // A checkpointed conversion that timed out after saving progress is resuming, not
+    // failing again
+    let attempts = lease.attempts_without_progress();
+
+    if exceeds_max_attempts(attempts, max_attempts) {
+        info!(
+            job_id = %request.job_id,
+            attempts,
+            max_attempts,
+            "Attempt exceeds the maximum, not reprocessing"
+        );
+        let e = ProcessingError::retries(format!(
+            "max attempts exceeded ({} of {})",
+            attempts, max_attempts
+        ));
+        let failed =
+            update_job_status_to_failed(dynamodb_client, table_name, &request.job_id, &e, 0)
+                .await
+                .map_err(ProcessingError::dynamo)?;
+        // A job that already finished some other way has nothing to announce
+        if failed {
+            notify_completion(
+                dynamodb_client,
+                table_name,
+                request,
+                CompletionEvent::failed(&request.job_id, &e),
+            )
+            .await;
+        }
+        metrics.put_count("JobsSucceeded", 0);
+        metrics.put_count("JobsFailed", 1);
+        metrics.flush();
+        // Returning success removes the message so it isn't retried any further
+        return Ok(());
+    }
+
+    // From here on the job is known, so every failure is recorded against it and the
+    // poller can report it instead of leaving the job pending forever
+    let rows_processed = Arc::new(AtomicU64::new(0));
+    let start_time = std::time::Instant::now();
+
+    let result = convert_job(
+        record,
+        request,
+        lease,
+        bucket_name,
+        dynamodb_client,
+        table_name,
+        sqs_client,
+        queue_url,
+        rows_processed.clone(),
+    )
+    .await;
+
+    metrics.put_count("RowsProcessed", rows_processed.load(Ordering::Relaxed));
+    metrics.put_duration("JobDuration", start_time.elapsed());
+    match &result {
+        Ok(summary) => {
+            metrics.put_bytes("BytesRead", summary.bytes_read);
+            metrics.put_count("RejectedValues", summary.rejected_values);
+            metrics.put_duration("ReadDuration", summary.read_duration);
+            metrics.put_duration("WriteDuration", summary.write_duration);
+            metrics.put_bytes("MemoryHighWater", summary.memory_high_water_bytes);
+            metrics.put_count("JobsSucceeded", 1);
+            metrics.put_count("JobsFailed", 0);
+            metrics.put_count("JobsCancelled", 0);
+        }
+        Err(e) if e.is_cancelled() => {
+            metrics.put_count("JobsSucceeded", 0);
+            metrics.put_count("JobsFailed", 0);
+            metrics.put_count("JobsCancelled", 1);
+        }
+        Err(_) => {
+            metrics.put_count("JobsSucceeded", 0);
+            metrics.put_count("JobsFailed", 1);
+            metrics.put_count("JobsCancelled", 0);
+        }
+    }
+    metrics.flush();
+
+    match &result {
+        // The cancel request already set the job's final status; returning success
+        // removes the message so the conversion isn't picked up again
+        Err(e) if e.is_cancelled() => {
+            info!(
+                job_id = %request.job_id,
+                rows = rows_processed.load(Ordering::Relaxed),
+                "Conversion cancelled"
+            );
+            return Ok(());
+        }
+        Err(e) => {
+            // A job whose message will be retried stays pending, as a failed job can't
+            // be finished by a later attempt
+            let final_failure = !e.is_retryable() || attempts >= max_attempts;
+            let rows_processed = rows_processed.load(Ordering::Relaxed);
+            let recorded = if final_failure {
+                update_job_status_to_failed(
+                    dynamodb_client,
+                    table_name,
+                    &request.job_id,
+                    e,
+                    rows_processed,
+                )
+                .instrument(info_span!("dynamo_update", status = "failed"))
+                .await
+            } else {
+                record_job_retry(
+                    dynamodb_client,
+                    table_name,
+                    &request.job_id,
+                    e,
+                    rows_processed,
+                )
+                .instrument(info_span!("dynamo_update", status = "pending"))
+                .await
+            };
+            if let Err(record_error) = &recorded {
+                error!(
+                    job_id = %request.job_id,
+                    error = %record_error,
+                    "Could not record failure"
+                );
+            }
+
+            // Only announce a failure the job won't recover from; a retried attempt may
+            // still succeed. A job that had already left for another status, such as one
+            // cancelled mid-conversion, was not failed by this attempt.
+            if final_failure && !matches!(recorded, Ok(false)) {
+                notify_completion(
+                    dynamodb_client,
+                    table_name,
+                    request,
+                    CompletionEvent::failed(&request.job_id, e),
+                )
+                .await;
+            }
+
+            // The queue allows more deliveries than the job has attempts, so a job failed
+            // for good sends its message to the DLQ now instead of waiting them out
+            if final_failure {
+                return Err(e.clone().into_final());
+            }
+        }
+        Ok(_) => {}
+    }
+
+    result.map(|_| ())
+}
+
+// Delivery problems are recorded on the job rather than failing it; the conversion itself
+// has already finished either way
+async fn notify_completion(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    request: &ParquetCreationRequest,
+    event: CompletionEvent,
+) {
+    let Some(settings) = &request.notify else {
+        return;
+    };
 
-    let request: ParquetCreationRequest = serde_json::from_str(body)
-        .map_err(|e| format!("Failed to parse JSON from SQS message: {}", e))?;
+    let outcome = send_completion_notification(settings, &event).await;
+    if let Err(e) = &outcome {
+        warn!(job_id = %request.job_id, error = %e, "Failed to send completion notification");
+    }
+
+    let notification_error = outcome.err().map(|e| e.to_string());
+    if let Err(e) = record_notification_outcome(
+        dynamodb_client,
+        table_name,
+        &request.job_id,
+        notification_error.as_deref(),
+    )
+    .await
+    {
+        warn!(job_id = %request.job_id, error = %e, "Failed to record notification outcome");
+    }
+}
+
+fn exceeds_max_attempts(attempts: u32, max_attempts: u32) -> bool {
+    attempts > max_attempts
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn convert_job(
+    record: &SqsMessage,
+    request: &ParquetCreationRequest,
+    lease: &JobLease,
+    bucket_name: &str,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    sqs_client: &SqsClient,
+    queue_url: &str,
+    rows_processed: Arc<AtomicU64>,
+) -> Result<ConversionSummary, ProcessingError> {
+    let receipt_handle = record
+        .receipt_handle
+        .as_ref()
+        .ok_or_else(|| ProcessingError::read("SQS message has no receipt handle"))?;
 
-    println!(
-        "Processing job {} with {} columns using multithreaded approach",
-        request.job_id,
-        request.payload.len()
+    let path = ProcessingPath::from_env();
+
+    info!(
+        job_id = %request.job_id,
+        columns = request.payload.len(),
+        path = path.as_str(),
+        "Processing job"
     );
 
     let start_time = std::time::Instant::now();
 
-    let parquet_key = format!("parquet/{}.parquet", request.job_id);
+    let parquet_key = parquet_key(&request.job_id);
 
-    stream_csv_to_parquet_optimized(
+    // Keep the message invisible and the job leased while we work, so neither SQS nor a
+    // redelivered message hands the job to a second Lambda
+    let heartbeat = spawn_visibility_heartbeat(
+        sqs_client.clone(),
+        queue_url.to_string(),
+        receipt_handle.clone(),
+        dynamodb_client.clone(),
+        table_name.to_string(),
+        request.job_id.clone(),
+        lease.clone(),
+    );
+
+    // Labels may have been edited since the job was submitted; the lease read the current ones
+    let provenance = JobProvenance {
+        labels: lease.labels.clone(),
+        ..request.provenance.clone()
+    };
+    let conversion_result = stream_csv_to_parquet_optimized(
         bucket_name,
         &request.s3_key,
         &request.payload,
+        &request.options,
         &parquet_key,
         &request.job_id,
+        dynamodb_client,
+        table_name,
+        rows_processed,
+        path,
+        &provenance,
     )
-    .await?;
+    .await;
+
+    heartbeat.abort();
+    let summary = conversion_result?;
 
-    println!(
-        "Job {} converted to Parquet using multithreading in {:.2} seconds",
-        request.job_id,
-        start_time.elapsed().as_secs_f64()
+    info!(
+        job_id = %request.job_id,
+        elapsed_ms = start_time.elapsed().as_millis() as u64,
+        memory_high_water_bytes = summary.memory_high_water_bytes,
+        "Converted to Parquet"
     );
 
-    update_job_status_to_success(table_name, &request.job_id).await?;
+    record_memory_high_water(
+        dynamodb_client,
+        table_name,
+        &request.job_id,
+        summary.memory_high_water_bytes,
+    )
+    .await
+    .map_err(ProcessingError::dynamo)?;
+
+    // Written before the job reports success so the first query can use it. Queries read
+    // the schema from the file when it's missing, so a failure here is only logged.
+    if let Some(etag) = &summary.output_etag {
+        let recorded = record_query_schema(
+            dynamodb_client,
+            table_name,
+            &request.job_id,
+            &summary.query_schema,
+            etag,
+        )
+        .await;
+        if let Err(e) = recorded {
+            warn!(job_id = %request.job_id, error = %e, "Failed to record query schema");
+        }
+    }
 
-    Ok(())
+    let succeeded = update_job_status_to_success(
+        dynamodb_client,
+        table_name,
+        &request.job_id,
+        summary.rows_written,
+        bucket_name,
+        &summary.output_key,
+    )
+    .instrument(info_span!("dynamo_update", status = "success"))
+    .await
+    .map_err(ProcessingError::dynamo)?;
+    // The job was cancelled, failed by another attempt or deleted while this one ran.
+    // Its status stands, so there's no throughput sample or success to announce, and the
+    // message is done with like a cancelled one.
+    if !succeeded {
+        return Err(ProcessingError::cancelled(
+            "job left processing before the conversion finished",
+        ));
+    }
+
+    // Feeds the submission endpoint's completion estimate. The job has already succeeded,
+    // so a failure here is only logged.
+    let elapsed = start_time.elapsed().as_secs_f64();
+    if summary.bytes_read > 0 && elapsed > 0.0 {
+        let mb_per_second = summary.bytes_read as f64 / (1024.0 * 1024.0) / elapsed;
+        if let Err(e) = record_throughput(
+            dynamodb_client,
+            table_name,
+            path,
+            &request.job_id,
+            mb_per_second,
+        )
+        .await
+        {
+            warn!(job_id = %request.job_id, error = %e, "Failed to record throughput");
+        }
+    }
+
+    notify_completion(
+        dynamodb_client,
+        table_name,
+        request,
+        CompletionEvent::succeeded(&request.job_id, &summary.output_key, summary.rows_written),
+    )
+    .await;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::test_support::{StubEndpoint, StubResponse};
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+
+    const MAX_ATTEMPTS: u32 = 3;
+    const DLQ_URL: &str = "https://sqs.us-east-1.amazonaws.com/123/parquet-dlq";
+
+    fn settings() -> ProcessorSettings {
+        ProcessorSettings {
+            bucket_name: "uploads".to_string(),
+            table_name: "jobs".to_string(),
+            queue_url: "https://sqs.us-east-1.amazonaws.com/123/parquet".to_string(),
+            dlq_url: DLQ_URL.to_string(),
+            max_receive_count: DEFAULT_MAX_RECEIVE_COUNT,
+            max_attempts: MAX_ATTEMPTS,
+        }
+    }
+
+    // A stub whose lease reports `attempts` and which accepts every other write
+    fn stub(attempts: u32) -> StubEndpoint {
+        StubEndpoint::start(move |request| match request.operation() {
+            Some("UpdateItem")
+                if request.json()["UpdateExpression"]
+                    .as_str()
+                    .is_some_and(|expression| expression.contains("ADD attempts")) =>
+            {
+                let values = &request.json()["ExpressionAttributeValues"];
+                StubResponse::json(json!({"Attributes": {
+                    "attempts": {"N": attempts.to_string()},
+                    "lease_until": values[":until"].clone()
+                }}))
+            }
+            Some("SendMessage") => StubResponse::json(json!({"MessageId": "dlq-1"})),
+            _ => StubResponse::json(json!({})),
+        })
+    }
+
+    // Without a receipt handle the conversion can't keep its message invisible, so it
+    // fails at once with a retryable read error
+    fn message() -> SqsMessage {
+        SqsMessage {
+            message_id: Some("message-1".to_string()),
+            body: Some(
+                json!({"payload": [], "s3_key": "uploads/sales.csv", "job_id": "job-1"})
+                    .to_string(),
+            ),
+            attributes: HashMap::from([("ApproximateReceiveCount".to_string(), "1".to_string())]),
+            ..Default::default()
+        }
+    }
+
+    // The statuses the job was moved to, in order
+    fn statuses_written(stub: &StubEndpoint) -> Vec<String> {
+        stub.operations("UpdateItem")
+            .into_iter()
+            .filter_map(|request| {
+                let request = request.json();
+                let names = request["ExpressionAttributeNames"].as_object()?.clone();
+                let (placeholder, _) = names
+                    .iter()
+                    .find(|(placeholder, name)| *name == "status" && *placeholder != "#current")?;
+                let value =
+                    &request["ExpressionAttributeValues"][placeholder.replacen('#', ":", 1)];
+                Some(value["S"].as_str()?.to_string())
+            })
+            .collect()
+    }
+
+    fn failed_ids(response: &SqsBatchResponse) -> Vec<&str> {
+        response
+            .batch_item_failures
+            .iter()
+            .map(|failure| failure.item_identifier.as_str())
+            .collect()
+    }
+
+    fn dlq_messages(stub: &StubEndpoint) -> Vec<Value> {
+        stub.operations("SendMessage")
+            .into_iter()
+            .map(|request| request.json())
+            .collect()
+    }
+
+    #[test]
+    fn the_attempt_limit_itself_is_still_allowed() {
+        assert!(!exceeds_max_attempts(MAX_ATTEMPTS - 1, MAX_ATTEMPTS));
+        assert!(!exceeds_max_attempts(MAX_ATTEMPTS, MAX_ATTEMPTS));
+        assert!(exceeds_max_attempts(MAX_ATTEMPTS + 1, MAX_ATTEMPTS));
+    }
+
+    #[tokio::test]
+    async fn an_early_failed_attempt_is_left_for_redelivery() {
+        let stub = stub(1);
+
+        let response = process_records(
+            vec![message()],
+            None,
+            &settings(),
+            &stub.sqs_client(),
+            &stub.dynamodb_client(),
+        )
+        .await;
+
+        assert_eq!(failed_ids(&response), ["message-1"]);
+        assert!(dlq_messages(&stub).is_empty());
+        assert_eq!(statuses_written(&stub), ["pending"]);
+    }
+
+    #[tokio::test]
+    async fn the_last_allowed_attempt_fails_the_job_and_goes_to_the_dlq() {
+        let stub = stub(MAX_ATTEMPTS);
+
+        let response = process_records(
+            vec![message()],
+            None,
+            &settings(),
+            &stub.sqs_client(),
+            &stub.dynamodb_client(),
+        )
+        .await;
+
+        // Forwarded, so the original is reported done rather than redelivered
+        assert!(failed_ids(&response).is_empty());
+        assert_eq!(statuses_written(&stub), ["failed"]);
+        let forwarded = dlq_messages(&stub);
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0]["QueueUrl"], DLQ_URL);
+        assert_eq!(forwarded[0]["MessageBody"], message().body.unwrap());
+        assert_eq!(
+            forwarded[0]["MessageAttributes"]["error_stage"]["StringValue"],
+            "read"
+        );
+    }
+
+    #[tokio::test]
+    async fn an_attempt_past_the_limit_fails_the_job_without_converting() {
+        let stub = stub(MAX_ATTEMPTS + 1);
+
+        let response = process_records(
+            vec![message()],
+            None,
+            &settings(),
+            &stub.sqs_client(),
+            &stub.dynamodb_client(),
+        )
+        .await;
+
+        // The job's failure is recorded, so the message is simply removed
+        assert!(failed_ids(&response).is_empty());
+        assert!(dlq_messages(&stub).is_empty());
+        assert_eq!(statuses_written(&stub), ["failed"]);
+    }
+
+    #[tokio::test]
+    async fn a_message_for_no_job_goes_straight_to_the_dlq() {
+        let stub = StubEndpoint::start(|request| match request.operation() {
+            Some("SendMessage") => StubResponse::json(json!({"MessageId": "dlq-1"})),
+            _ => StubResponse::dynamodb_error("ConditionalCheckFailedException", None),
+        });
+
+        let response = process_records(
+            vec![message()],
+            None,
+            &settings(),
+            &stub.sqs_client(),
+            &stub.dynamodb_client(),
+        )
+        .await;
+
+        assert!(failed_ids(&response).is_empty());
+        let forwarded = dlq_messages(&stub);
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(
+            forwarded[0]["MessageAttributes"]["error_stage"]["StringValue"],
+            "parse"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_retryable_failure_on_the_last_delivery_goes_to_the_dlq() {
+        let stub = stub(1);
+        let mut record = message();
+        record.attributes.insert(
+            "ApproximateReceiveCount".to_string(),
+            DEFAULT_MAX_RECEIVE_COUNT.to_string(),
+        );
+
+        let response = process_records(
+            vec![record],
+            None,
+            &settings(),
+            &stub.sqs_client(),
+            &stub.dynamodb_client(),
+        )
+        .await;
+
+        assert!(failed_ids(&response).is_empty());
+        assert_eq!(dlq_messages(&stub).len(), 1);
+    }
 }
@@ -1,28 +1,108 @@
 use aws_config::BehaviorVersion;
 use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
-use aws_sdk_dynamodb::Client as DynamoClient;
+use aws_sdk_dynamodb::{Client as DynamoClient, Error as DynamoError};
+use aws_sdk_s3::Client as S3Client;
 use aws_sdk_sqs::Client as SqsClient;
+use aws_sdk_sqs::types::{
+    MessageSystemAttributeNameForSends, MessageSystemAttributeValue, SendMessageBatchRequestEntry,
+};
+use chrono::{DateTime, Utc};
+use common::auth::{Principal, authorize};
 use common::cors::create_cors_response;
-use common::parquet_creation::put_job_status;
+use common::creation_types::{ParquetCreationRequest, ProcessingPath};
+use common::creation_validation::{
+    check_idempotency_key, parse_creation_request, validate_creation_request,
+};
+use common::dynamo::{
+    IdempotencyClaim, JobStatus, claim_idempotency_key, delete_job, get_job_status, get_throughput,
+    release_idempotency_key, schedule_job,
+};
+use common::logging::{init_tracing, redact};
+use common::parquet_creation::{StartPlan, estimate_seconds, plan_start, put_job_status};
+use common::s3::{SourceObject, head_object};
 use lambda_runtime::{Error, LambdaEvent, service_fn};
-use serde_json::json;
+use serde_json::{Map, Value, json};
 use std::collections::HashMap;
 use std::env;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
 
-#[derive(serde::Deserialize, Debug)]
-struct ParquetCreationRequest {
-    job_id: String,
-    context_text: String,
-    #[serde(default)]
-    schema: HashMap<String, String>,
+// How long `wait_for_upload` keeps looking for the source file before giving up
+const UPLOAD_WAIT_ATTEMPTS: u32 = 10;
+const UPLOAD_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Files below this size go to the fast queue unless PARQUET_FAST_PATH_MAX_BYTES says otherwise
+const DEFAULT_FAST_PATH_MAX_BYTES: i64 = 10 * 1024 * 1024;
+
+// Most entries one batch submission may carry; each one costs an S3 and a DynamoDB call
+// inside the API Gateway timeout
+const MAX_BATCH_SIZE: usize = 100;
+
+// SQS limit on entries per SendMessageBatch call
+const SQS_BATCH_LIMIT: usize = 10;
+
+struct Submission {
+    dynamo_client: DynamoClient,
+    sqs_client: SqsClient,
+    s3_client: S3Client,
+    dynamo_name: String,
+    bucket_name: String,
+    queue_url: String,
+    fast_queue_url: String,
+    fast_path_max_bytes: i64,
+    trace_header: Option<MessageSystemAttributeValue>,
+    principal: Principal,
+}
+
+impl Submission {
+    fn queue_for(&self, path: ProcessingPath) -> &str {
+        match path {
+            ProcessingPath::Standard => &self.queue_url,
+            ProcessingPath::Fast => &self.fast_queue_url,
+        }
+    }
+}
+
+// A job that passed every check and now has a pending job item
+struct AcceptedJob {
+    request: ParquetCreationRequest,
+    path: ProcessingPath,
+    source: SourceObject,
+    start: StartPlan,
+}
+
+// Why a job wasn't accepted: the status a single submission answers with, the error, and
+// any extra fields that explain it
+struct Rejection {
+    status: i64,
+    error: &'static str,
+    details: Map<String, Value>,
+}
+
+enum Outcome {
+    // Boxed as it dwarfs the other variants
+    Accepted(Box<AcceptedJob>),
+    // The idempotency key was used before; carries the job_id it created
+    Replayed(String),
+    Rejected(Rejection),
+}
+
+impl Rejection {
+    fn new(status: i64, error: &'static str, details: Value) -> Self {
+        Rejection {
+            status,
+            error,
+            details: match details {
+                Value::Object(details) => details,
+                _ => Map::new(),
+            },
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .without_time()
-        .init();
+    init_tracing();
 
     let handler = service_fn(handler);
     lambda_runtime::run(handler).await?;
@@ -36,46 +116,706 @@ async fn handler(
         return Ok(create_cors_response(200, None));
     }
 
+    let dynamo_name = env::var("DYNAMODB_NAME")?;
     let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let dynamo_client = DynamoClient::new(&config);
 
-    let dynamo_name = env::var("DYNAMODB_NAME")?;
-    let queue_url = env::var("PARQUET_QUEUE_URL")?;
+    let principal = match authorize(&event.payload, &dynamo_client, &dynamo_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
 
-    let sqs_client = SqsClient::new(&config);
-    let dynamo_client = DynamoClient::new(&config);
+    // Lets the processor continue this invocation's trace once it picks the message up
+    let trace_header = event
+        .context
+        .xray_trace_id
+        .map(|trace_id| {
+            MessageSystemAttributeValue::builder()
+                .data_type("String")
+                .string_value(trace_id)
+                .build()
+        })
+        .transpose()?;
+
+    let submission = Submission {
+        dynamo_client,
+        sqs_client: SqsClient::new(&config),
+        s3_client: S3Client::new(&config),
+        dynamo_name,
+        bucket_name: env::var("S3_UPLOAD_BUCKET_NAME")?,
+        queue_url: env::var("PARQUET_QUEUE_URL")?,
+        fast_queue_url: env::var("PARQUET_FAST_QUEUE_URL")?,
+        fast_path_max_bytes: env::var("PARQUET_FAST_PATH_MAX_BYTES")
+            .ok()
+            .and_then(|bytes| bytes.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_FAST_PATH_MAX_BYTES),
+        trace_header,
+        principal,
+    };
+
+    let idempotency_header = event
+        .payload
+        .headers
+        .get("idempotency-key")
+        .map(|key| key.to_str().unwrap_or_default().to_string());
 
     let body = event.payload.body.unwrap_or_default();
+    debug!(body = %redact(&body), "Received job submission");
+
+    // A JSON array is a batch of conversions; anything else is a single request
+    if let Ok(Value::Array(entries)) = serde_json::from_str::<Value>(&body) {
+        return submit_batch(&submission, entries).await;
+    }
+
+    let mut request = match parse_creation_request(&body) {
+        Ok(request) => request,
+        Err(errors) => {
+            info!(errors = errors.len(), "Rejected invalid job submission");
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Invalid conversion request", "details": errors}).to_string()),
+            ));
+        }
+    };
+
+    if let Some(key) = idempotency_header {
+        if let Err(message) = check_idempotency_key(&key) {
+            return Ok(create_cors_response(
+                400,
+                Some(
+                    json!({
+                        "error": "Invalid conversion request",
+                        "details": [{"field": "Idempotency-Key", "message": message}]
+                    })
+                    .to_string(),
+                ),
+            ));
+        }
+        request.idempotency_key = Some(key);
+    }
+
+    let job = match accept_job(&submission, request).await? {
+        Outcome::Accepted(job) => *job,
+        Outcome::Replayed(job_id) => {
+            return Ok(create_cors_response(
+                200,
+                Some(json!({"job_id": job_id, "replayed": true}).to_string()),
+            ));
+        }
+        Outcome::Rejected(rejection) => {
+            let mut body = rejection.details;
+            body.insert("error".to_string(), json!(rejection.error));
+            return Ok(create_cors_response(
+                rejection.status,
+                Some(Value::Object(body).to_string()),
+            ));
+        }
+    };
+
+    let throughput = read_throughput(&submission, job.path).await;
+    let estimated_seconds = estimate_seconds(job.source.bytes, throughput);
+
+    if job.start == StartPlan::Scheduled {
+        return Ok(create_cors_response(
+            200,
+            Some(
+                json!({
+                    "job_id": job.request.job_id,
+                    "status": JobStatus::Scheduled,
+                    "start_after": job.request.start_after,
+                    "estimated_seconds": estimated_seconds
+                })
+                .to_string(),
+            ),
+        ));
+    }
 
-    let request: ParquetCreationRequest = serde_json::from_str(&body)
-        .map_err(|e| lambda_runtime::Error::from(format!("Failed to parse JSON: {}", e)))?;
+    // Queue the parsed request rather than the raw body so the processor only ever sees
+    // the canonical shape it was validated as
+    let message_body = serde_json::to_string(&job.request)?;
 
-    sqs_client
+    if let Err(e) = submission
+        .sqs_client
         .send_message()
-        .queue_url(&queue_url)
-        .message_body(body)
+        .queue_url(submission.queue_for(job.path))
+        .message_body(message_body)
+        .set_delay_seconds(job.start.delay_seconds())
+        .set_message_system_attributes(submission.trace_header.clone().map(|header| {
+            HashMap::from([(MessageSystemAttributeNameForSends::AwsTraceHeader, header)])
+        }))
         .send()
-        .await?;
+        .await
+    {
+        abandon_job(&submission, &job).await;
+        return Err(e.into());
+    }
+
+    info!(
+        job_id = %job.request.job_id,
+        path = job.path.as_str(),
+        delay_seconds = job.start.delay_seconds().unwrap_or(0),
+        "Queued parquet conversion"
+    );
+
+    Ok(create_cors_response(
+        200,
+        Some(
+            json!({
+                "job_id": job.request.job_id,
+                "status": "queued",
+                "start_after": job.request.start_after,
+                "estimated_seconds": estimated_seconds
+            })
+            .to_string(),
+        ),
+    ))
+}
+
+// Claims the request's idempotency key, if it has one, before creating the job. A key that
+// ends up not creating a job is released again so it can't point at nothing.
+async fn accept_job(
+    submission: &Submission,
+    request: ParquetCreationRequest,
+) -> Result<Outcome, Error> {
+    let Some(key) = request.idempotency_key.clone() else {
+        return create_job(submission, request).await;
+    };
+
+    if let IdempotencyClaim::Existing(job_id) = claim_idempotency_key(
+        &submission.dynamo_client,
+        &submission.dynamo_name,
+        &key,
+        &request.job_id,
+    )
+    .await?
+    {
+        return Ok(Outcome::Replayed(job_id));
+    }
+
+    let outcome = create_job(submission, request).await;
+    if !matches!(outcome, Ok(Outcome::Accepted(_))) {
+        release_key(submission, &key).await;
+    }
+    outcome
+}
+
+// Checks the source file and creates the pending job item. Errors are AWS failures;
+// anything wrong with the request itself comes back as a rejection.
+async fn create_job(
+    submission: &Submission,
+    request: ParquetCreationRequest,
+) -> Result<Outcome, Error> {
+    // Catch a submission sent before its upload finished here, rather than as an S3 404
+    // deep inside the processor
+    let source = match find_source_object(
+        &submission.s3_client,
+        &submission.bucket_name,
+        &request.s3_key,
+        request.wait_for_upload,
+    )
+    .await?
+    {
+        Some(source) if source.bytes > 0 => source,
+        Some(_) => {
+            return Ok(Outcome::Rejected(Rejection::new(
+                409,
+                "Source file is empty",
+                json!({"s3_key": request.s3_key}),
+            )));
+        }
+        None => {
+            return Ok(Outcome::Rejected(Rejection::new(
+                404,
+                "Source file not found",
+                json!({"s3_key": request.s3_key}),
+            )));
+        }
+    };
+
+    // Small files get their own queue and processor so they don't sit behind large ones
+    let path = if source.bytes < submission.fast_path_max_bytes {
+        ProcessingPath::Fast
+    } else {
+        ProcessingPath::Standard
+    };
+
+    // Validation already checked the format
+    let start_after = request
+        .start_after
+        .as_deref()
+        .and_then(|start_after| DateTime::parse_from_rfc3339(start_after).ok())
+        .map(|start_after| start_after.with_timezone(&Utc));
+    let start = plan_start(start_after, Utc::now());
+    let status = match start {
+        StartPlan::Scheduled => JobStatus::Scheduled,
+        StartPlan::Now | StartPlan::Delay(_) => JobStatus::Pending,
+    };
 
     let service = format!("JOB-{}", request.job_id);
 
-    put_job_status(
-        &dynamo_client,
-        &dynamo_name,
+    // The job item is written first: its condition is what stops a duplicate submission
+    // from queueing a second conversion racing on the same output key
+    match put_job_status(
+        &submission.dynamo_client,
+        &submission.dynamo_name,
         &service,
         &request.job_id,
-        "pending",
+        status,
         &request.context_text,
         &request.schema,
+        &source,
+        &request.provenance,
+        &submission.principal.id,
+        request.notify.as_ref(),
+        path,
+        request.resubmit,
     )
-    .await?;
+    .await
+    {
+        Ok(()) => {}
+        Err(DynamoError::ConditionalCheckFailedException(_)) => {
+            let status = get_job_status(
+                &submission.dynamo_client,
+                &submission.dynamo_name,
+                &request.job_id,
+            )
+            .await?;
+            info!(
+                job_id = %request.job_id,
+                status = ?status,
+                "Rejected duplicate job submission"
+            );
+            return Ok(Outcome::Rejected(Rejection::new(
+                409,
+                "Job already exists",
+                json!({"job_id": request.job_id, "status": status}),
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if let (StartPlan::Scheduled, Some(start_after)) = (start, start_after) {
+        let request_body = serde_json::to_string(&request)?;
+        if let Err(e) = schedule_job(
+            &submission.dynamo_client,
+            &submission.dynamo_name,
+            &request.job_id,
+            start_after,
+            &request_body,
+        )
+        .await
+        {
+            // Out of the schedule index the job would never be dispatched
+            if let Err(delete_error) = delete_job(
+                &submission.dynamo_client,
+                &submission.dynamo_name,
+                &request.job_id,
+            )
+            .await
+            {
+                error!(
+                    job_id = %request.job_id,
+                    error = %delete_error,
+                    "Failed to remove job that couldn't be scheduled"
+                );
+            }
+            return Err(e.into());
+        }
+    }
+
+    Ok(Outcome::Accepted(Box::new(AcceptedJob {
+        request,
+        path,
+        source,
+        start,
+    })))
+}
+
+// Undoes a job that was created but couldn't be queued: without its message it would sit
+// pending forever, and its idempotency key would replay a job that never runs
+async fn abandon_job(submission: &Submission, job: &AcceptedJob) {
+    if let Err(e) = delete_job(
+        &submission.dynamo_client,
+        &submission.dynamo_name,
+        &job.request.job_id,
+    )
+    .await
+    {
+        error!(
+            job_id = %job.request.job_id,
+            error = %e,
+            "Failed to remove job that couldn't be queued"
+        );
+    }
+    if let Some(key) = &job.request.idempotency_key {
+        release_key(submission, key).await;
+    }
+}
+
+async fn release_key(submission: &Submission, key: &str) {
+    if let Err(e) =
+        release_idempotency_key(&submission.dynamo_client, &submission.dynamo_name, key).await
+    {
+        error!(error = %e, "Failed to release idempotency key");
+    }
+}
+
+// Validates and queues each entry on its own, so one bad entry only rejects itself. The
+// response lists every entry in order with whether it was accepted and, if not, why.
+async fn submit_batch(
+    submission: &Submission,
+    entries: Vec<Value>,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if entries.is_empty() || entries.len() > MAX_BATCH_SIZE {
+        return Ok(create_cors_response(
+            400,
+            Some(
+                json!({
+                    "error": "Invalid batch submission",
+                    "details": format!("a batch must contain 1 to {} entries", MAX_BATCH_SIZE)
+                })
+                .to_string(),
+            ),
+        ));
+    }
+
+    let mut results: Vec<Value> = Vec::with_capacity(entries.len());
+    let mut accepted: Vec<(usize, AcceptedJob)> = Vec::new();
+
+    for (index, entry) in entries.into_iter().enumerate() {
+        let job_id = entry.get("job_id").cloned().unwrap_or(Value::Null);
+
+        let outcome = match validate_creation_request(entry) {
+            Ok(request) => accept_job(submission, request).await?,
+            Err(errors) => Outcome::Rejected(Rejection::new(
+                400,
+                "Invalid conversion request",
+                json!({"details": errors}),
+            )),
+        };
+
+        match outcome {
+            Outcome::Accepted(job) => {
+                results.push(json!({
+                    "index": index,
+                    "job_id": job_id,
+                    "status": "accepted",
+                    "scheduled": job.start == StartPlan::Scheduled,
+                    "start_after": job.request.start_after
+                }));
+                accepted.push((index, *job));
+            }
+            Outcome::Replayed(job_id) => results.push(json!({
+                "index": index,
+                "job_id": job_id,
+                "status": "accepted",
+                "replayed": true
+            })),
+            Outcome::Rejected(rejection) => results.push(rejected_result(index, job_id, rejection)),
+        }
+    }
+
+    // Scheduled jobs are already stored for the dispatcher to queue later
+    for path in [ProcessingPath::Standard, ProcessingPath::Fast] {
+        let jobs: Vec<&(usize, AcceptedJob)> = accepted
+            .iter()
+            .filter(|(_, job)| job.path == path && job.start != StartPlan::Scheduled)
+            .collect();
+
+        for chunk in jobs.chunks(SQS_BATCH_LIMIT) {
+            for index in send_chunk(submission, path, chunk).await? {
+                let Some((_, job)) = chunk.iter().find(|(queued, _)| *queued == index) else {
+                    continue;
+                };
+
+                abandon_job(submission, job).await;
+                results[index] = rejected_result(
+                    index,
+                    json!(job.request.job_id),
+                    Rejection::new(503, "Failed to queue conversion", json!({})),
+                );
+            }
+        }
+    }
+
+    // Estimates only for entries that made it onto a queue
+    let standard_throughput = read_throughput(submission, ProcessingPath::Standard).await;
+    let fast_throughput = read_throughput(submission, ProcessingPath::Fast).await;
+    for (index, job) in &accepted {
+        if results[*index]["status"] == "accepted" {
+            let throughput = match job.path {
+                ProcessingPath::Standard => standard_throughput,
+                ProcessingPath::Fast => fast_throughput,
+            };
+            results[*index]["estimated_seconds"] =
+                json!(estimate_seconds(job.source.bytes, throughput));
+        }
+    }
+
+    let accepted_count = results
+        .iter()
+        .filter(|result| result["status"] == "accepted")
+        .count();
+    info!(
+        entries = results.len(),
+        accepted = accepted_count,
+        rejected = results.len() - accepted_count,
+        "Processed batch submission"
+    );
 
     Ok(create_cors_response(
         200,
         Some(
             json!({
-                "job_id": request.job_id
+                "accepted": accepted_count,
+                "rejected": results.len() - accepted_count,
+                "results": results
             })
             .to_string(),
         ),
     ))
 }
+
+// Sends one SendMessageBatch call and returns the batch indexes of entries SQS didn't take.
+// A call that fails outright fails every entry in it.
+async fn send_chunk(
+    submission: &Submission,
+    path: ProcessingPath,
+    chunk: &[&(usize, AcceptedJob)],
+) -> Result<Vec<usize>, Error> {
+    let mut message_entries = Vec::with_capacity(chunk.len());
+    for (index, job) in chunk {
+        message_entries.push(
+            SendMessageBatchRequestEntry::builder()
+                .id(index.to_string())
+                .message_body(serde_json::to_string(&job.request)?)
+                .set_delay_seconds(job.start.delay_seconds())
+                .set_message_system_attributes(submission.trace_header.clone().map(|header| {
+                    HashMap::from([(MessageSystemAttributeNameForSends::AwsTraceHeader, header)])
+                }))
+                .build()?,
+        );
+    }
+
+    let response = match submission
+        .sqs_client
+        .send_message_batch()
+        .queue_url(submission.queue_for(path))
+        .set_entries(Some(message_entries))
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            error!(
+                path = path.as_str(),
+                entries = chunk.len(),
+                error = %e,
+                "Failed to queue batch of conversions"
+            );
+            return Ok(chunk.iter().map(|(index, _)| *index).collect());
+        }
+    };
+
+    for failure in &response.failed {
+        warn!(
+            entry = %failure.id,
+            code = %failure.code,
+            message = ?failure.message,
+            "SQS rejected a batch entry"
+        );
+    }
+
+    Ok(response
+        .failed
+        .iter()
+        .filter_map(|failure| failure.id.parse::<usize>().ok())
+        .collect())
+}
+
+fn rejected_result(index: usize, job_id: Value, rejection: Rejection) -> Value {
+    let mut result = rejection.details;
+    result.insert("index".to_string(), json!(index));
+    result.insert("job_id".to_string(), job_id);
+    result.insert("status".to_string(), json!("rejected"));
+    result.insert("reason".to_string(), json!(rejection.error));
+    Value::Object(result)
+}
+
+// Only a hint for the UI, so no history or a failed lookup just means no estimate
+async fn read_throughput(submission: &Submission, path: ProcessingPath) -> Option<f64> {
+    match get_throughput(&submission.dynamo_client, &submission.dynamo_name, path).await {
+        Ok(throughput) => throughput,
+        Err(e) => {
+            warn!(path = path.as_str(), error = %e, "Failed to read throughput");
+            None
+        }
+    }
+}
+
+async fn find_source_object(
+    s3_client: &S3Client,
+    bucket: &str,
+    key: &str,
+    wait_for_upload: bool,
+) -> Result<Option<SourceObject>, common::error::Error> {
+    let attempts = if wait_for_upload {
+        UPLOAD_WAIT_ATTEMPTS
+    } else {
+        1
+    };
+
+    let mut source = None;
+    for attempt in 1..=attempts {
+        source = head_object(s3_client, bucket, key).await?;
+        if source.as_ref().is_some_and(|source| source.bytes > 0) {
+            break;
+        }
+        if attempt < attempts {
+            tokio::time::sleep(UPLOAD_WAIT_INTERVAL).await;
+        }
+    }
+
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::test_support::{StubEndpoint, StubResponse};
+
+    const SOURCE_BYTES: &str = "2048";
+
+    // Every source file exists, every job is new and SQS takes every message it is sent
+    fn aws_stub() -> StubEndpoint {
+        StubEndpoint::start(|request| {
+            if request.method == "HEAD" {
+                return StubResponse::bytes(200, Vec::new())
+                    .with_header("Content-Length", SOURCE_BYTES)
+                    .with_header("ETag", "\"source\"");
+            }
+            match request.operation() {
+                Some("SendMessageBatch") => {
+                    let successful: Vec<Value> = request.json()["Entries"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|entry| {
+                            json!({
+                                "Id": entry["Id"],
+                                "MessageId": format!("message-{}", entry["Id"].as_str().unwrap()),
+                                "MD5OfMessageBody": "d41d8cd98f00b204e9800998ecf8427e"
+                            })
+                        })
+                        .collect();
+                    StubResponse::json(json!({"Successful": successful, "Failed": []}))
+                }
+                _ => StubResponse::json(json!({})),
+            }
+        })
+    }
+
+    fn submission(stub: &StubEndpoint) -> Submission {
+        Submission {
+            dynamo_client: stub.dynamodb_client(),
+            sqs_client: stub.sqs_client(),
+            s3_client: stub.s3_client(),
+            dynamo_name: "jobs".to_string(),
+            bucket_name: "uploads".to_string(),
+            queue_url: "https://sqs.us-east-1.amazonaws.com/1/standard".to_string(),
+            fast_queue_url: "https://sqs.us-east-1.amazonaws.com/1/fast".to_string(),
+            fast_path_max_bytes: DEFAULT_FAST_PATH_MAX_BYTES,
+            trace_header: None,
+            principal: Principal {
+                id: "key-1".to_string(),
+            },
+        }
+    }
+
+    fn entry(job_id: &str) -> Value {
+        json!({
+            "job_id": job_id,
+            "s3_key": format!("csvUpload/{}.csv", job_id),
+            "payload": [{"column": "id", "type": "integer"}]
+        })
+    }
+
+    fn body(response: &ApiGatewayProxyResponse) -> Value {
+        match &response.body {
+            Some(aws_lambda_events::encodings::Body::Text(text)) => {
+                serde_json::from_str(text).unwrap()
+            }
+            other => panic!("unexpected body: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn an_invalid_third_entry_is_rejected_alone() {
+        let stub = aws_stub();
+        let mut entries: Vec<Value> = (1..=5).map(|n| entry(&format!("job-{}", n))).collect();
+        entries[2]["payload"] = json!([]);
+
+        let response = submit_batch(&submission(&stub), entries).await.unwrap();
+
+        assert_eq!(response.status_code, 200);
+        let body = body(&response);
+        assert_eq!(
+            (body["accepted"].clone(), body["rejected"].clone()),
+            (json!(4), json!(1))
+        );
+        let statuses: Vec<(&Value, &str)> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| (&result["job_id"], result["status"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (&json!("job-1"), "accepted"),
+                (&json!("job-2"), "accepted"),
+                (&json!("job-3"), "rejected"),
+                (&json!("job-4"), "accepted"),
+                (&json!("job-5"), "accepted"),
+            ]
+        );
+        let rejected = &body["results"][2];
+        assert_eq!(rejected["index"], 2);
+        assert_eq!(rejected["reason"], "Invalid conversion request");
+        assert_eq!(rejected["details"][0]["field"], "payload");
+
+        // Only the accepted entries get a job item and a message, all in one SQS call
+        let created: Vec<Value> = stub
+            .operations("PutItem")
+            .iter()
+            .map(|put| put.json()["Item"]["serviceId"]["S"].clone())
+            .collect();
+        assert_eq!(created, ["job-1", "job-2", "job-4", "job-5"]);
+        let batches = stub.operations("SendMessageBatch");
+        assert_eq!(batches.len(), 1);
+        let queued: Vec<Value> = batches[0].json()["Entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["Id"].clone())
+            .collect();
+        assert_eq!(queued, ["0", "1", "3", "4"]);
+        assert_eq!(
+            batches[0].json()["QueueUrl"],
+            "https://sqs.us-east-1.amazonaws.com/1/fast"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_batch_over_the_limit_is_refused_whole() {
+        let stub = aws_stub();
+        let entries: Vec<Value> = (0..=MAX_BATCH_SIZE)
+            .map(|n| entry(&format!("job-{}", n)))
+            .collect();
+
+        let response = submit_batch(&submission(&stub), entries).await.unwrap();
+
+        assert_eq!(response.status_code, 400);
+        assert!(stub.requests().is_empty());
+    }
+}
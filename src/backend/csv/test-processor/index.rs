@@ -1,6 +1,9 @@
+use aws_sdk_dynamodb::Client as DynamoDbClient;
 use common::{
     creation_types::{ColumnDefinition, DataType},
     dynamo::update_job_status_to_success,
+    logging::init_tracing,
+    metrics::MetricsLogger,
     test_creation_processor::stream_csv_to_parquet_optimized,
 };
 use lambda_runtime::{Error, LambdaEvent, service_fn};
@@ -13,10 +16,7 @@ struct EmptyEvent {}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .without_time()
-        .init();
+    init_tracing();
 
     let handler = service_fn(handler);
     lambda_runtime::run(handler).await?;
@@ -70,12 +70,13 @@ async fn handler(_event: LambdaEvent<EmptyEvent>) -> Result<(), Error> {
     let hardcoded_job_id = "9a621683-8f57-4f50-99c5-607554fb85df";
 
     info!(
-        "Processing hardcoded job {} with {} columns using OPTIMIZED approach",
-        hardcoded_job_id,
-        hardcoded_payload.len()
+        job_id = hardcoded_job_id,
+        columns = hardcoded_payload.len(),
+        "Processing hardcoded job"
     );
 
     let start_time = std::time::Instant::now();
+    let mut metrics = MetricsLogger::new("test-processor", hardcoded_job_id);
 
     let parquet_key = format!("parquet/{}.parquet", hardcoded_job_id);
 
@@ -89,18 +90,34 @@ async fn handler(_event: LambdaEvent<EmptyEvent>) -> Result<(), Error> {
     )
     .await
     {
-        Ok(_) => {
-            let duration = start_time.elapsed().as_secs_f64();
+        Ok(rows_written) => {
+            metrics.put_duration("JobDuration", start_time.elapsed());
+            metrics.put_count("JobsSucceeded", 1);
+            metrics.put_count("JobsFailed", 0);
+            metrics.flush();
+
             info!(
-                "Job {} converted to Parquet using OPTIMIZED processing in {:.2} seconds",
-                hardcoded_job_id, duration
+                job_id = hardcoded_job_id,
+                elapsed_ms = start_time.elapsed().as_millis() as u64,
+                "Converted to Parquet"
             );
 
             // Update job status to success
-            match update_job_status_to_success(&table_name, hardcoded_job_id).await {
+            let config = aws_config::load_from_env().await;
+            let dynamodb_client = DynamoDbClient::new(&config);
+            match update_job_status_to_success(
+                &dynamodb_client,
+                &table_name,
+                hardcoded_job_id,
+                rows_written,
+                &bucket_name,
+                &parquet_key,
+            )
+            .await
+            {
                 Ok(_) => info!("Successfully updated job status to success"),
                 Err(e) => {
-                    tracing::error!("Failed to update job status: {}", e);
+                    tracing::error!(job_id = hardcoded_job_id, error = %e, "Failed to update job status");
                     return Err(format!("Failed to update job status: {}", e).into());
                 }
             }
@@ -108,7 +125,12 @@ async fn handler(_event: LambdaEvent<EmptyEvent>) -> Result<(), Error> {
             info!("Optimized test completed successfully!");
         }
         Err(e) => {
-            tracing::error!("Failed to process CSV to Parquet: {}", e);
+            metrics.put_duration("JobDuration", start_time.elapsed());
+            metrics.put_count("JobsSucceeded", 0);
+            metrics.put_count("JobsFailed", 1);
+            metrics.flush();
+
+            tracing::error!(job_id = hardcoded_job_id, error = %e, "Failed to process CSV to Parquet");
             return Err(format!("Failed to process CSV to Parquet: {}", e).into());
         }
     }
@@ -0,0 +1,98 @@
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use common::auth::{authorize, may_access_job};
+use common::cors::create_cors_response;
+use common::dynamo::{JobStatus, cancel_job, get_job_by_id};
+use common::logging::init_tracing;
+use lambda_runtime::{Error, LambdaEvent, run, service_fn};
+use serde_json::json;
+use tracing::{error, info};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    init_tracing();
+
+    run(service_fn(function_handler)).await
+}
+
+async fn function_handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if event.payload.http_method == "OPTIONS" {
+        return Ok(create_cors_response(200, None));
+    }
+
+    let table_name = std::env::var("DYNAMODB_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let dynamodb_client = DynamoDbClient::new(&config);
+
+    let principal = match authorize(&event.payload, &dynamodb_client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    let job_id = match event.payload.path_parameters.get("job_id") {
+        Some(id) => id,
+        None => {
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Missing job_id in path"}).to_string()),
+            ));
+        }
+    };
+
+    let job = match get_job_by_id(&dynamodb_client, &table_name, job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            return Ok(create_cors_response(
+                404,
+                Some(json!({"error": "Job not found"}).to_string()),
+            ));
+        }
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "Failed to load job");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            ));
+        }
+    };
+
+    if !may_access_job(&principal, job.created_by.as_deref(), job_id) {
+        info!(
+            job_id = %job_id,
+            principal = %principal.id,
+            "Rejected cancellation of a job owned by another principal"
+        );
+        return Ok(create_cors_response(
+            403,
+            Some(json!({"error": "Job belongs to a different API key"}).to_string()),
+        ));
+    }
+
+    // The processor notices the new status at its next batch and stops on its own; a job
+    // that already finished keeps its result
+    match cancel_job(&dynamodb_client, &table_name, job_id).await {
+        Ok(true) => {
+            info!(job_id = %job_id, "Job cancelled");
+            let response_body = json!({
+                "statusCode": 200,
+                "status": JobStatus::Cancelled,
+                "message": "Job cancelled"
+            });
+
+            Ok(create_cors_response(200, Some(response_body.to_string())))
+        }
+        Ok(false) => Ok(create_cors_response(
+            409,
+            Some(json!({"error": "Job is not running and can no longer be cancelled"}).to_string()),
+        )),
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "DynamoDB error");
+            Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Failed to cancel job"}).to_string()),
+            ))
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_s3::Client as S3Client;
+use common::auth::{authorize, may_access_job};
+use common::cors::create_cors_response;
+use common::creation_parsing::parse_boolean;
+use common::dynamo::{Job, JobStatus, cancel_job, delete_job, get_job_by_id};
+use common::logging::init_tracing;
+use common::s3::{delete_prefix, parquet_key, upload_key};
+use lambda_runtime::{Error, LambdaEvent, run, service_fn};
+use serde_json::json;
+use tracing::{error, info};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    init_tracing();
+
+    run(service_fn(function_handler)).await
+}
+
+// Whether a conversion may still be running or about to start for the job
+fn is_active(status: JobStatus) -> bool {
+    matches!(
+        status,
+        JobStatus::Pending | JobStatus::Processing | JobStatus::Scheduled
+    )
+}
+
+// Every (bucket, prefix) the job may have written to: its upload, its parquet along with
+// the footer a checkpointed conversion keeps beside it, and whatever output the job
+// recorded. Jobs derived from this one are jobs of their own and keep their output.
+fn job_object_prefixes(job: &Job, job_id: &str, upload_bucket: &str) -> Vec<(String, String)> {
+    let mut prefixes = vec![
+        (upload_bucket.to_string(), upload_key(job_id)),
+        (upload_bucket.to_string(), parquet_key(job_id)),
+    ];
+    if let Some(output_key) = &job.output_key {
+        let bucket = job.output_bucket.as_deref().unwrap_or(upload_bucket);
+        let output = (bucket.to_string(), output_key.clone());
+        if !prefixes.contains(&output) {
+            prefixes.push(output);
+        }
+    }
+    prefixes
+}
+
+// Deletes a job with its query history, saved queries and S3 objects. A job that is still
+// pending, scheduled or converting is refused with 409 unless `force=true`, which cancels
+// it first.
+async fn function_handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if event.payload.http_method == "OPTIONS" {
+        return Ok(create_cors_response(200, None));
+    }
+
+    let table_name = std::env::var("DYNAMODB_NAME")?;
+    let upload_bucket = std::env::var("S3_UPLOAD_BUCKET_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let dynamodb_client = DynamoDbClient::new(&config);
+    let s3_client = S3Client::new(&config);
+
+    let principal = match authorize(&event.payload, &dynamodb_client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    let job_id = match event.payload.path_parameters.get("job_id") {
+        Some(id) => id,
+        None => {
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Missing job_id in path"}).to_string()),
+            ));
+        }
+    };
+
+    let force = match event.payload.query_string_parameters.first("force") {
+        None => false,
+        Some(value) => match parse_boolean(value) {
+            Some(force) => force,
+            None => {
+                return Ok(create_cors_response(
+                    400,
+                    Some(json!({"error": "force must be true or false"}).to_string()),
+                ));
+            }
+        },
+    };
+
+    let job = match get_job_by_id(&dynamodb_client, &table_name, job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            return Ok(create_cors_response(
+                404,
+                Some(json!({"error": "Job not found"}).to_string()),
+            ));
+        }
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "Failed to load job");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            ));
+        }
+    };
+
+    if !may_access_job(&principal, job.created_by.as_deref(), job_id) {
+        info!(
+            job_id = %job_id,
+            principal = %principal.id,
+            "Rejected delete request for a job owned by another principal"
+        );
+        return Ok(create_cors_response(
+            403,
+            Some(json!({"error": "Job belongs to a different API key"}).to_string()),
+        ));
+    }
+
+    let mut cancelled = false;
+    if is_active(job.status) {
+        if !force {
+            return Ok(create_cors_response(
+                409,
+                Some(
+                    json!({
+                        "error": "Job is still running",
+                        "status": job.status,
+                        "details": "Cancel the job first or pass force=true"
+                    })
+                    .to_string(),
+                ),
+            ));
+        }
+
+        // A job that finished in the meantime simply isn't cancelled; it's deleted either way
+        cancelled = match cancel_job(&dynamodb_client, &table_name, job_id).await {
+            Ok(cancelled) => cancelled,
+            Err(e) => {
+                error!(job_id = %job_id, error = %e, "Failed to cancel job before deleting it");
+                return Ok(create_cors_response(
+                    500,
+                    Some(json!({"error": "Failed to cancel job"}).to_string()),
+                ));
+            }
+        };
+    }
+
+    // Objects go before the items, so a failure leaves the job in place for a retry rather
+    // than orphaned objects nothing points at
+    let mut s3_objects = 0;
+    for (bucket, prefix) in job_object_prefixes(&job, job_id, &upload_bucket) {
+        match delete_prefix(&s3_client, &bucket, &prefix).await {
+            Ok(deleted) => s3_objects += deleted,
+            Err(e) => {
+                error!(job_id = %job_id, bucket, prefix, error = %e, "Failed to delete job objects");
+                return Ok(create_cors_response(
+                    500,
+                    Some(json!({"error": "Failed to delete job objects"}).to_string()),
+                ));
+            }
+        }
+    }
+
+    let items = match delete_job(&dynamodb_client, &table_name, job_id).await {
+        Ok(items) => items,
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "Failed to delete job items");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Failed to delete job"}).to_string()),
+            ));
+        }
+    };
+
+    info!(job_id = %job_id, cancelled, s3_objects, "Job deleted");
+    let response_body = json!({
+        "statusCode": 200,
+        "job_id": job_id,
+        "cancelled": cancelled,
+        "deleted": {
+            "job": items.job,
+            "query_audit": items.query_audit,
+            "saved_queries": items.saved_queries,
+            "s3_objects": s3_objects
+        }
+    });
+
+    Ok(create_cors_response(200, Some(response_body.to_string())))
+}
@@ -1,188 +1,3114 @@
 use aws_config::BehaviorVersion;
 use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
-use aws_sdk_bedrockruntime::{
-    Client as BedrockClient,
-    types::{ContentBlock, ConversationRole, Message, SystemContentBlock},
-};
+use aws_lambda_events::encodings::Body;
+use aws_sdk_bedrockruntime::Client as BedrockClient;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
 use aws_sdk_s3::Client as S3Client;
 use common::{
+    auth::{Principal, authorize, may_access_job},
+    bedrock::{BedrockModels, bedrock_client, converse_request, with_model_fallback},
+    chart_shape::chart_shape,
     cors::create_cors_response,
-    duck_db::{execute_sql_on_parquet_file, get_schema_from_parquet_file, setup_duckdb_connection},
-    dynamo::get_job_by_id,
-    parquet_query::get_converse_output_text,
-    query_prompts::{MAKE_HUMAN_READABLE, USER_MESSAGE},
+    creation_parsing::parse_boolean,
+    duck_db::{
+        ColumnProfile, ExportOptions, MAX_TABLE_ALIAS_CHARS, PARQUET_VIEW_NAME, ParquetColumn,
+        check_read_only_sql, compute_column_stats, count_query_rows, enable_s3_access,
+        execute_sql_page, explain_query, export_query_to_parquet, get_parquet_schema,
+        has_top_level_limit, http_bytes_fetched, is_valid_table_alias, needs_sample_clause,
+        parquet_metadata_report, parquet_row_count, query_column_types, register_parquet_view,
+        render_column_stats, render_schema_for_prompt, run_with_timeout, s3_parquet_url,
+        sample_rows_markdown, set_spill_directory, setup_duckdb_connection, with_row_limit,
+        with_sample,
+    },
+    dynamo::{
+        ColumnProfiles, DerivedJob, GeneratedQuery, Job, JobStatus, QueryAudit, QueryDataset,
+        create_derived_job, get_generated_query, get_job_by_id, record_column_profiles,
+        record_query_audit, save_generated_query,
+    },
+    error::DuckDbErrorKind,
+    logging::{init_tracing, redact},
+    metrics::MetricsLogger,
+    parquet_cache::{ParquetCache, fetch_cached_parquet},
+    parquet_query::{
+        ConverseUsage, MAX_QUESTION_CHARS, SummaryLanguage, get_converse_output_text,
+        get_converse_usage, injection_pattern, repair_prompt, sanitize_sql_response, sql_prompt,
+        sql_system_prompt, summary_language, summary_language_tags, summary_system_prompt,
+    },
+    query_events::{QueryEvent, QueryEvents, sse_frame},
+    query_result::QueryResult,
+    s3::{SourceObject, derived_key, head_object, parquet_key, upload_to_s3},
+    tmp_manager::{TmpManager, scratch_budget_bytes},
+    warm_duckdb::{register_warm_view, reset_warm_database, warm_connection},
 };
-use lambda_runtime::{Error, LambdaEvent, service_fn};
+use lambda_runtime::{Error, LambdaEvent, MetadataPrelude, StreamResponse, service_fn};
 use serde::Deserialize;
 use serde_json::json;
+use std::collections::HashSet;
+use std::convert::Infallible;
 use std::env;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::{Stream, StreamExt, wrappers::UnboundedReceiverStream};
+use tracing::{debug, error, info, warn};
+
+const METRICS_FUNCTION_NAME: &str = "generate-parquet-query";
+
+// Parquet files at least this big are queried where they sit in S3 instead of downloaded
+const DEFAULT_DIRECT_QUERY_THRESHOLD_BYTES: u64 = 256 * 1024 * 1024;
+
+// Result rows per page when the caller doesn't ask for a size, and the most it can ask for.
+// Only the first page is shown to the summary model.
+const DEFAULT_PAGE_SIZE: u64 = 500;
+const MAX_PAGE_SIZE: u64 = 5000;
+
+// Most rows a query without its own LIMIT can return across all its pages
+const DEFAULT_MAX_RESULT_ROWS: u64 = 10_000;
+
+fn max_result_rows() -> u64 {
+    env::var("MAX_RESULT_ROWS")
+        .ok()
+        .and_then(|rows| rows.parse::<u64>().ok())
+        .filter(|rows| *rows > 0)
+        .unwrap_or(DEFAULT_MAX_RESULT_ROWS)
+}
+
+// Most bytes of rows one page holds, well under Lambda's 6 MB response limit; rows past it
+// are left for a smaller page_size to fetch
+const DEFAULT_MAX_RESULT_BYTES: u64 = 4 * 1024 * 1024;
+
+fn max_result_bytes() -> u64 {
+    env::var("MAX_RESULT_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse::<u64>().ok())
+        .filter(|bytes| *bytes > 0)
+        .unwrap_or(DEFAULT_MAX_RESULT_BYTES)
+}
+
+// What the summary model is told when the rows it sees aren't all the query matched, so it
+// doesn't present part of the answer as the whole of it
+fn partial_result_note(shown_rows: usize, total_rows: u64, row_capped: bool) -> Option<String> {
+    if row_capped {
+        Some(format!(
+            "these are only the first {} rows; the query matched more than {}",
+            shown_rows, total_rows
+        ))
+    } else if (shown_rows as u64) < total_rows {
+        Some(format!(
+            "these are only the first {} of {} rows",
+            shown_rows, total_rows
+        ))
+    } else {
+        None
+    }
+}
+
+// Long enough for a real aggregation over a large file, short enough to answer well inside
+// the API Gateway and Lambda timeouts
+const DEFAULT_QUERY_TIMEOUT_SECONDS: u64 = 25;
+
+fn query_timeout() -> Duration {
+    let seconds = env::var("QUERY_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_QUERY_TIMEOUT_SECONDS);
+    Duration::from_secs(seconds)
+}
+
+fn default_page_size() -> u64 {
+    env::var("QUERY_PAGE_SIZE")
+        .ok()
+        .and_then(|rows| rows.parse::<u64>().ok())
+        .filter(|rows| *rows > 0)
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_PAGE_SIZE)
+}
+
+fn direct_query_threshold_bytes() -> u64 {
+    env::var("S3_DIRECT_QUERY_THRESHOLD_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_DIRECT_QUERY_THRESHOLD_BYTES)
+}
+
+// How much of the file a query read in place actually pulled from S3, which is the point of
+// not downloading it
+fn log_bytes_scanned(
+    conn: &duckdb::Connection,
+    metrics: &mut MetricsLogger,
+    job_id: &str,
+    object_bytes: u64,
+) {
+    match http_bytes_fetched(conn) {
+        Ok(bytes_scanned) => {
+            metrics.put_count("S3BytesScanned", bytes_scanned);
+            info!(
+                job_id,
+                bytes_scanned,
+                object_bytes,
+                scanned_percent = bytes_scanned as f64 * 100.0 / object_bytes.max(1) as f64,
+                "Queried parquet in place"
+            );
+        }
+        Err(e) => warn!(job_id, error = %e, "Failed to read DuckDB HTTP stats"),
+    }
+}
+
+// The schema the processor stored for this file, provided the object is still the one it
+// wrote
+fn stored_query_schema<'a>(
+    job: &'a Job,
+    parquet_key: &str,
+    object: &SourceObject,
+) -> Option<&'a [ParquetColumn]> {
+    let schema = job.query_schema.as_deref()?;
+    if job.output_key.as_deref() != Some(parquet_key) {
+        return None;
+    }
+    if object.etag.is_none() || job.query_schema_etag != object.etag {
+        info!(
+            job_id = %job.serviceid,
+            stored_etag = ?job.query_schema_etag,
+            etag = ?object.etag,
+            "Stored query schema is stale"
+        );
+        return None;
+    }
+    Some(schema)
+}
+
+// Profiling scans the whole file, so it gets less time than a query before the question
+// goes ahead without it
+const COLUMN_STATS_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Roughly 1k tokens of the prompt for column stats
+const MAX_PROMPT_COLUMN_STATS_CHARS: usize = 4000;
+
+// Schema, column stats and sample rows together stay within this many characters. The
+// schema always goes in whole; stats and then samples get what it leaves.
+const MAX_PROMPT_CONTEXT_CHARS: usize = 12_000;
+
+const PROMPT_SAMPLE_ROWS: usize = 5;
+
+// The summary of a query that matched nothing, written without asking the model
+const NO_ROWS_MESSAGE: &str = "No rows matched your question.";
+
+// Sample rows put real values from the file into the prompt, so deployments holding
+// personal data leave PROMPT_SAMPLE_ROWS unset or false
+fn sample_rows_enabled() -> bool {
+    env::var("PROMPT_SAMPLE_ROWS")
+        .ok()
+        .and_then(|enabled| parse_boolean(&enabled))
+        .unwrap_or(false)
+}
+
+// The summary language for requests that don't name one. An unsupported tag is logged and
+// left out rather than failing every request.
+fn default_summary_language() -> Option<&'static SummaryLanguage> {
+    let tag = env::var("SUMMARY_LANGUAGE").ok()?;
+    let language = summary_language(&tag);
+    if language.is_none() {
+        warn!(tag, "SUMMARY_LANGUAGE is not a supported language");
+    }
+    language
+}
+
+// How much of the data an approximate answer reads, in percent
+const DEFAULT_SAMPLE_PERCENT: f64 = 10.0;
+
+fn sample_percent() -> f64 {
+    env::var("APPROXIMATE_SAMPLE_PERCENT")
+        .ok()
+        .and_then(|percent| percent.parse::<f64>().ok())
+        .filter(|percent| *percent > 0.0 && *percent <= 100.0)
+        .unwrap_or(DEFAULT_SAMPLE_PERCENT)
+}
+
+// Set on the function behind the streaming route, whose integration reads the response as
+// a stream. Everywhere else the answer goes back as one JSON body.
+fn response_streaming() -> bool {
+    env::var("RESPONSE_STREAMING")
+        .ok()
+        .and_then(|enabled| parse_boolean(&enabled))
+        .unwrap_or(false)
+}
+
+// Column profiles for the prompt: the job's stored ones if they describe this version of
+// the file, otherwise computed on a connection of their own, so a timed-out profile can't
+// take the query's connection with it, and stored for later questions. Failing to profile
+// only costs the prompt its stats.
+#[allow(clippy::too_many_arguments)]
+async fn load_column_profiles(
+    job: &Job,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    parquet_key: &str,
+    object: &SourceObject,
+    file_path: &str,
+    query_in_place: bool,
+    metrics: &mut MetricsLogger,
+) -> Vec<ColumnProfile> {
+    let job_id = job.serviceid.as_str();
+    let stored = job.column_profiles.as_ref().filter(|stored| {
+        stored.key == parquet_key && object.etag.as_deref() == Some(stored.etag.as_str())
+    });
+    if let Some(stored) = stored {
+        metrics.put_count("ColumnStatsCacheHit", 1);
+        return stored.columns.clone();
+    }
+    metrics.put_count("ColumnStatsCacheHit", 0);
+
+    let conn = match setup_duckdb_connection() {
+        Ok(conn) => conn,
+        Err(e) => {
+            warn!(job_id, error = %e, "Failed to set up DuckDB for column stats");
+            return Vec::new();
+        }
+    };
+    let registered = if query_in_place {
+        enable_s3_access(&conn)
+            .and_then(|()| register_parquet_view(&conn, PARQUET_VIEW_NAME, file_path))
+    } else {
+        register_parquet_view(&conn, PARQUET_VIEW_NAME, file_path)
+    };
+    if let Err(e) = registered {
+        warn!(job_id, error = %e, "Failed to open parquet for column stats");
+        return Vec::new();
+    }
+
+    let stats_start = std::time::Instant::now();
+    let columns = match run_with_timeout(conn, COLUMN_STATS_TIMEOUT, compute_column_stats).await {
+        Ok((_, columns)) => columns,
+        Err(e) => {
+            warn!(job_id, error = %e, "Failed to compute column stats");
+            return Vec::new();
+        }
+    };
+    metrics.put_duration("ColumnStatsLatency", stats_start.elapsed());
+    info!(job_id, columns = columns.len(), "Computed column stats");
+
+    // Without an ETag there would be no telling later whether they still apply
+    if let Some(etag) = &object.etag {
+        let profiles = ColumnProfiles {
+            key: parquet_key.to_string(),
+            etag: etag.clone(),
+            columns,
+        };
+        if let Err(e) = record_column_profiles(dynamodb_client, table_name, job_id, &profiles).await
+        {
+            warn!(job_id, error = %e, "Failed to store column stats");
+        }
+        return profiles.columns;
+    }
+    columns
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .without_time()
-        .init();
+    init_tracing();
+
+    let models = BedrockModels::from_env()?;
+    info!(
+        sql_model_ids = %models.sql.join(","),
+        summary_model_ids = %models.summary.join(","),
+        "Bedrock models configured"
+    );
 
-    let handler = service_fn(handler);
-    lambda_runtime::run(handler).await?;
+    // Lives as long as the container, so warm invocations can reuse earlier downloads
+    let parquet_cache = Arc::new(ParquetCache::from_env());
+    let models = Arc::new(models);
+    if response_streaming() {
+        let handler =
+            service_fn(|event| stream_handler(event, parquet_cache.clone(), models.clone()));
+        lambda_runtime::run(handler).await?;
+    } else {
+        let handler =
+            service_fn(|event| handler(event, &parquet_cache, &models, QueryEvents::buffered()));
+        lambda_runtime::run(handler).await?;
+    }
 
     Ok(())
 }
 
+// Streams the request's progress as server-sent events, ending with a `result` event
+// holding the status and body the buffered endpoint would have returned. The stream itself
+// always opens with a 200, as the status isn't known until the end.
+//
+// The answer is worked out on a thread of its own so the events can go out while it runs.
+// Its future holds DuckDB connections, which can't be shared between threads, so it gets
+// a single-threaded runtime there instead of a task on the main one.
+async fn stream_handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+    parquet_cache: Arc<ParquetCache>,
+    models: Arc<BedrockModels>,
+) -> Result<
+    StreamResponse<impl Stream<Item = Result<String, Infallible>> + Unpin + Send + 'static>,
+    Error,
+> {
+    let (events, receiver) = QueryEvents::streamed();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    std::thread::spawn(move || {
+        let response = runtime.block_on(handler(event, &parquet_cache, &models, events.clone()));
+        let response = response.unwrap_or_else(|e| {
+            error!(error = %e, "Failed to answer streamed query");
+            create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            )
+        });
+        events.emit(result_event(&response));
+    });
+
+    Ok(StreamResponse {
+        metadata_prelude: stream_prelude(),
+        stream: UnboundedReceiverStream::new(receiver).map(|event| Ok(sse_frame(&event))),
+    })
+}
+
+fn result_event(response: &ApiGatewayProxyResponse) -> QueryEvent {
+    let body = match &response.body {
+        Some(Body::Text(text)) => serde_json::from_str(text).unwrap_or_else(|_| json!(text)),
+        _ => serde_json::Value::Null,
+    };
+    QueryEvent::Result {
+        status_code: response.status_code,
+        body,
+    }
+}
+
+// The same CORS headers as every other response, for an event stream. The runtime's
+// prelude takes the older `http` crate's types, so the headers are copied across.
+fn stream_prelude() -> MetadataPrelude {
+    let mut headers = http::HeaderMap::new();
+    for (name, value) in create_cors_response(200, None).headers.iter() {
+        let name = http::HeaderName::from_bytes(name.as_str().as_bytes());
+        let value = http::HeaderValue::from_bytes(value.as_bytes());
+        if let (Ok(name), Ok(value)) = (name, value) {
+            headers.insert(name, value);
+        }
+    }
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/event-stream"),
+    );
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        http::HeaderValue::from_static("no-cache"),
+    );
+    MetadataPrelude {
+        status_code: http::StatusCode::OK,
+        headers,
+        cookies: Vec::new(),
+    }
+}
+
+// The 409 for a job whose conversion hasn't produced a parquet to query, or None once it
+// has. Querying earlier would only end in a missing-object error from S3.
+fn unconverted_job_response(job: &Job, dataset: Option<&str>) -> Option<ApiGatewayProxyResponse> {
+    let mut body = match job.status {
+        JobStatus::Success => return None,
+        JobStatus::Failed => json!({
+            "error": "Conversion failed",
+            "status": job.status,
+            "details": job.error_message.as_deref().unwrap_or("Conversion failed")
+        }),
+        status => json!({
+            "error": "Conversion not complete",
+            "status": status
+        }),
+    };
+    if let Some(alias) = dataset {
+        body["dataset"] = json!(alias);
+    }
+    Some(create_cors_response(409, Some(body.to_string())))
+}
+
+// The parquet file a job wrote. Jobs finished before output_key was recorded wrote to the
+// processor's default key.
+fn job_output_key(job: &Job, job_id: &str) -> String {
+    job.output_key
+        .clone()
+        .unwrap_or_else(|| parquet_key(job_id))
+}
+
+// The parquet a job's questions are asked of. It comes from the job record rather than the
+// request, so a caller can't pair their own job with someone else's file. A key the request
+// does send has to be the job's output itself. None when it isn't.
+fn job_parquet_key(job: &Job, job_id: &str, requested: Option<&str>) -> Option<String> {
+    let output_key = job_output_key(job, job_id);
+    match requested {
+        Some(requested) if requested != output_key => None,
+        _ => Some(output_key),
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct GenerateParquetQuery {
+    // The question; optional when `sql` is given, where it only labels the query
+    #[serde(default)]
     message: String,
-    parquet_key: String,
+    // Optional; the job record says which file the job's questions are about, and a key
+    // sent here has to agree with it
+    parquet_key: Option<String>,
     job_id: String,
+    // 1-based. Pages after the first re-run an earlier query, so they need its query_id.
+    page: Option<u64>,
+    page_size: Option<u64>,
+    // Set to page through a query generated by an earlier request instead of asking the
+    // model for a new one; no summary is written for it
+    query_id: Option<String>,
+    // Jobs to join, each queried under its alias. Must include `job_id`. Without these the
+    // question is about `data` alone.
+    #[serde(default)]
+    datasets: Vec<QueryDataset>,
+    // SQL to run as is instead of asking the model for it. It is held to the same read-only
+    // check and row cap as generated SQL.
+    sql: Option<String>,
+    // Whether to write a summary of the results as well as returning them. Without one the
+    // response comes back after a single model call. Defaults to true, except for `sql`,
+    // which is only summarized when asked.
+    summarize: Option<bool>,
+    #[serde(default)]
+    output: OutputMode,
+    // Return DuckDB's plan for the SQL instead of its results
+    #[serde(default)]
+    explain: bool,
+    // Profile the plan with EXPLAIN ANALYZE, which runs the query; only with `explain`
+    #[serde(default)]
+    analyze: bool,
+    // Trade exactness for speed on large data: the model is asked to sample and to use
+    // approximate aggregates, and a plain scan it left unsampled is sampled anyway. Only for
+    // generated SQL.
+    #[serde(default)]
+    approximate: bool,
+    // BCP-47 tag of the language to write the summary in, from a fixed list; SUMMARY_LANGUAGE
+    // when not given
+    language: Option<String>,
+    // Return how the job's parquet is laid out, its row groups and column statistics, instead
+    // of answering a question. Only with `job_id` and `parquet_key`.
+    #[serde(default)]
+    inspect: bool,
+    // Write every row the query returns, past the row cap, to a parquet of its own and
+    // record it as a new job the caller owns, in place of rows and a summary
+    #[serde(default)]
+    materialize: bool,
 }
 
-async fn handler(
-    event: LambdaEvent<ApiGatewayProxyRequest>,
-) -> Result<ApiGatewayProxyResponse, Error> {
-    if event.payload.http_method == "OPTIONS" {
-        return Ok(create_cors_response(200, None));
-    }
+// `chart` adds the page's rows laid out as chart labels and series to the response
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutputMode {
+    #[default]
+    Rows,
+    Chart,
+}
 
-    let body = event.payload.body.unwrap_or_default();
-    let bucket_name = env::var("S3_UPLOAD_BUCKET_NAME")?;
-    let table_name = env::var("DYNAMODB_NAME")?;
+// Most jobs one question can join
+const MAX_DATASETS: usize = 5;
 
-    let request: GenerateParquetQuery = match serde_json::from_str(&body) {
-        Ok(req) => req,
-        Err(e) => {
-            return Ok(create_cors_response(
-                400,
-                Some(
-                    json!({"error": "Failed to parse JSON", "details": e.to_string()}).to_string(),
-                ),
+// Why the datasets a request names can't be queried, if they can't
+fn dataset_error(datasets: &[QueryDataset], job_id: &str) -> Option<String> {
+    if datasets.is_empty() {
+        return None;
+    }
+    if datasets.len() > MAX_DATASETS {
+        return Some(format!(
+            "at most {} datasets can be queried together",
+            MAX_DATASETS
+        ));
+    }
+    let mut aliases = HashSet::new();
+    for dataset in datasets {
+        if !is_valid_table_alias(&dataset.alias) {
+            return Some(format!(
+                "alias '{}' must be a lowercase name of letters, digits and underscores, at \
+                 most {} characters, that isn't an SQL keyword",
+                dataset.alias, MAX_TABLE_ALIAS_CHARS
             ));
         }
-    };
+        if !aliases.insert(dataset.alias.as_str()) {
+            return Some(format!("alias '{}' is used more than once", dataset.alias));
+        }
+    }
+    if !datasets.iter().any(|dataset| dataset.job_id == job_id) {
+        return Some("datasets must include the request's job_id".to_string());
+    }
+    None
+}
 
-    let sdk_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
-    let bedrock_client = BedrockClient::new(&sdk_config);
-    let s3_client = S3Client::new(&sdk_config);
+// Why a request can't be answered as sent, as the 400 to send back. Only looks at the
+// request itself; the jobs and stored query it names are checked once they are read.
+fn request_error(
+    request: &GenerateParquetQuery,
+    page: u64,
+    page_size: u64,
+) -> Option<ApiGatewayProxyResponse> {
+    if page == 0 || page_size == 0 || page_size > MAX_PAGE_SIZE {
+        return Some(create_cors_response(
+            400,
+            Some(
+                json!({
+                    "error": "Invalid page",
+                    "details": format!(
+                        "page must be at least 1 and page_size between 1 and {}",
+                        MAX_PAGE_SIZE
+                    )
+                })
+                .to_string(),
+            ),
+        ));
+    }
+    if page > 1 && request.query_id.is_none() {
+        return Some(create_cors_response(
+            400,
+            Some(json!({"error": "query_id is required for pages after the first"}).to_string()),
+        ));
+    }
 
-    let temp_file_path = format!(
-        "/tmp/{}",
-        request
-            .parquet_key
-            .split('/')
-            .last()
-            .unwrap_or("temp.parquet")
-    );
-    println!(
-        "Downloading S3 object s3://{}/{} to {}",
-        bucket_name, request.parquet_key, temp_file_path
-    );
+    if request.inspect
+        && (request.sql.is_some()
+            || request.query_id.is_some()
+            || !request.datasets.is_empty()
+            || request.explain
+            || request.approximate
+            || request.materialize)
+    {
+        return Some(create_cors_response(
+            400,
+            Some(
+                json!({
+                    "error": "inspect can't be combined with a query",
+                    "details": "send only job_id, and optionally parquet_key"
+                })
+                .to_string(),
+            ),
+        ));
+    }
 
-    match s3_client
-        .get_object()
-        .bucket(&bucket_name)
-        .key(&request.parquet_key)
-        .send()
-        .await
+    if request.materialize && request.explain {
+        return Some(create_cors_response(
+            400,
+            Some(json!({"error": "materialize can't be combined with explain"}).to_string()),
+        ));
+    }
+
+    if request.analyze && !request.explain {
+        return Some(create_cors_response(
+            400,
+            Some(json!({"error": "analyze is only allowed with explain"}).to_string()),
+        ));
+    }
+
+    let user_sql = request.sql.as_deref().map(str::trim);
+    if user_sql.is_some() && request.query_id.is_some() {
+        return Some(create_cors_response(
+            400,
+            Some(json!({"error": "sql can't be combined with query_id"}).to_string()),
+        ));
+    }
+    if user_sql.is_some() && request.approximate {
+        return Some(create_cors_response(
+            400,
+            Some(json!({"error": "approximate is only for generated SQL"}).to_string()),
+        ));
+    }
+    if user_sql.is_some_and(str::is_empty) {
+        return Some(create_cors_response(
+            400,
+            Some(json!({"error": "sql is empty"}).to_string()),
+        ));
+    }
+    if user_sql.is_none()
+        && request.query_id.is_none()
+        && !request.inspect
+        && request.message.trim().is_empty()
     {
-        Ok(s3_output) => {
-            let mut byte_stream = s3_output.body;
-            let mut file = File::create(&temp_file_path).await?;
-            while let Some(chunk) = byte_stream.try_next().await? {
-                file.write_all(&chunk).await?;
+        return Some(create_cors_response(
+            400,
+            Some(json!({"error": "message is required unless sql is given"}).to_string()),
+        ));
+    }
+    if request.message.chars().count() > MAX_QUESTION_CHARS {
+        return Some(create_cors_response(
+            400,
+            Some(
+                json!({
+                    "error": "Question is too long",
+                    "details": format!("message can be at most {} characters", MAX_QUESTION_CHARS)
+                })
+                .to_string(),
+            ),
+        ));
+    }
+    None
+}
+
+// The language a summary is written in: the request's, else SUMMARY_LANGUAGE's, else none.
+// The error explains a tag that isn't on the list.
+fn request_language(tag: Option<&str>) -> Result<Option<&'static SummaryLanguage>, String> {
+    let Some(tag) = tag else {
+        return Ok(default_summary_language());
+    };
+    summary_language(tag).map(Some).ok_or_else(|| {
+        format!(
+            "language must be one of {}",
+            summary_language_tags().join(", ")
+        )
+    })
+}
+
+// Reads a job a question is asked of and checks the caller owns it and that its conversion
+// succeeded. `dataset` is the alias of a joined job, named in the responses so the caller
+// knows which one was refused. The error is the response to send.
+async fn load_dataset_job(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    principal: &Principal,
+    job_id: &str,
+    dataset: Option<&str>,
+) -> Result<Job, ApiGatewayProxyResponse> {
+    let job = match get_job_by_id(dynamodb_client, table_name, job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            let mut body = json!({"error": "Job not found"});
+            if let Some(alias) = dataset {
+                body["details"] = json!(format!("dataset '{}'", alias));
             }
-            println!("Successfully downloaded file to {}", temp_file_path);
+            return Err(create_cors_response(404, Some(body.to_string())));
         }
         Err(e) => {
-            eprintln!("Failed to download from S3: {:?}", e);
-            return Ok(create_cors_response(500, Some(json!({"error": "Failed to download Parquet file from S3", "details": e.to_string()}).to_string())));
+            error!(job_id, error = %e, "Failed to load job");
+            return Err(create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            ));
         }
+    };
+
+    if !may_access_job(principal, job.created_by.as_deref(), job_id) {
+        info!(
+            job_id,
+            principal = %principal.id,
+            dataset,
+            "Rejected query on a job owned by another principal"
+        );
+        return Err(create_cors_response(
+            403,
+            Some(json!({"error": "Job belongs to a different API key"}).to_string()),
+        ));
     }
+    if let Some(response) = unconverted_job_response(&job, dataset) {
+        return Err(response);
+    }
+    Ok(job)
+}
 
-    let conn = match setup_duckdb_connection() {
-        Ok(conn) => conn,
+// A job's parquet output and the name the query reads it by
+struct DatasetSource {
+    alias: String,
+    job: Job,
+    parquet_key: String,
+}
+
+// A dataset registered on the query's connection
+struct Dataset {
+    alias: String,
+    job: Job,
+    parquet_key: String,
+    object: SourceObject,
+    file_path: String,
+    query_in_place: bool,
+}
+
+// Finds the dataset's parquet in S3 and registers it under its alias, downloading it unless
+// it is large enough to read in place. `s3_access` remembers whether httpfs loaded, so it
+// is only tried once per request. The error is the response to send.
+async fn open_dataset(
+    conn: &duckdb::Connection,
+    s3_client: &S3Client,
+    parquet_cache: &ParquetCache,
+    bucket_name: &str,
+    source: DatasetSource,
+    s3_access: &mut Option<bool>,
+    metrics: &mut MetricsLogger,
+) -> Result<Dataset, ApiGatewayProxyResponse> {
+    let job_id = source.job.serviceid.as_str();
+    let object = match head_object(s3_client, bucket_name, &source.parquet_key).await {
+        Ok(Some(object)) => object,
+        Ok(None) => {
+            return Err(create_cors_response(
+                404,
+                Some(json!({"error": "Parquet file not found"}).to_string()),
+            ));
+        }
         Err(e) => {
-            return Ok(create_cors_response(
+            error!(job_id, error = %e, "Failed to look up parquet in S3");
+            return Err(create_cors_response(
                 500,
                 Some(
-                    json!({"error": "Failed to setup DuckDB connection", "details": e.to_string()})
-                        .to_string(),
+                    json!({
+                        "error": "Failed to download Parquet file from S3",
+                        "details": e.user_message()
+                    })
+                    .to_string(),
                 ),
             ));
         }
     };
+    let object_bytes = object.bytes.max(0) as u64;
+
+    // A large file is read in place, so only the footer and the row groups and columns the
+    // query needs are fetched. If httpfs can't be loaded the file is downloaded as usual.
+    let query_in_place = object_bytes >= direct_query_threshold_bytes()
+        && *s3_access.get_or_insert_with(|| match enable_s3_access(conn) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    job_id,
+                    error = %e,
+                    "S3 access unavailable in DuckDB, downloading instead"
+                );
+                false
+            }
+        });
+
+    info!(
+        job_id,
+        alias = %source.alias,
+        bucket = %bucket_name,
+        key = %source.parquet_key,
+        bytes = object_bytes,
+        query_in_place,
+        "Fetching parquet"
+    );
+
+    let file_path = if query_in_place {
+        s3_parquet_url(bucket_name, &source.parquet_key)
+    } else {
+        let download_start = std::time::Instant::now();
+        let cached = match fetch_cached_parquet(
+            s3_client,
+            parquet_cache,
+            bucket_name,
+            &source.parquet_key,
+            &object,
+        )
+        .await
+        {
+            Ok(cached) => cached,
+            Err(e) => {
+                error!(job_id, error = %e, "Failed to download from S3");
+                return Err(create_cors_response(
+                    500,
+                    Some(
+                        json!({
+                            "error": "Failed to download Parquet file from S3",
+                            "details": e.user_message()
+                        })
+                        .to_string(),
+                    ),
+                ));
+            }
+        };
+        metrics.put_duration("ParquetFetchLatency", download_start.elapsed());
+        metrics.put_count("ParquetCacheHit", u64::from(cached.hit));
+        // Views in the warm database may read a file that is gone now
+        if cached.evicted > 0 {
+            reset_warm_database();
+        }
+        info!(
+            job_id,
+            path = %cached.path.display(),
+            bytes = cached.bytes,
+            cache_hit = cached.hit,
+            "Parquet ready"
+        );
+        cached.path.to_string_lossy().into_owned()
+    };
+
+    match register_warm_view(conn, &source.alias, &file_path) {
+        Ok(registered) => metrics.put_count("ViewReused", u64::from(!registered)),
+        Err(e) => {
+            return Err(query_error_response(
+                &e,
+                "Failed to read Parquet file",
+                None,
+                job_id,
+            ));
+        }
+    }
+
+    Ok(Dataset {
+        alias: source.alias,
+        job: source.job,
+        parquet_key: source.parquet_key,
+        object,
+        file_path,
+        query_in_place,
+    })
+}
 
-    let schema_string = match get_schema_from_parquet_file(&conn, &temp_file_path) {
-        Ok(schema) => schema,
+// How many rows the dataset's file holds, read from its footer. Where the file is the one
+// output the job wrote, it should hold the rows the job recorded writing; a file that
+// doesn't is logged, as it was changed or cut short after the job finished.
+fn file_row_count(
+    conn: &duckdb::Connection,
+    dataset: &Dataset,
+    metrics: &mut MetricsLogger,
+) -> Option<u64> {
+    let job_id = dataset.job.serviceid.as_str();
+    let rows = match parquet_row_count(conn, &dataset.file_path) {
+        Ok(rows) => rows,
         Err(e) => {
-            return Ok(create_cors_response(500, Some(json!({"error": "Failed to get schema from local parquet file", "details": e.to_string()}).to_string())));
+            warn!(job_id, error = %e, "Failed to read the parquet row count");
+            return None;
         }
     };
+    if dataset.job.output_key.as_deref() == Some(dataset.parquet_key.as_str())
+        && let Some(recorded) = dataset.job.row_count
+    {
+        metrics.put_count("RowCountMismatch", u64::from(recorded != rows));
+        if recorded != rows {
+            warn!(
+                job_id,
+                recorded_rows = recorded,
+                file_rows = rows,
+                "Parquet holds a different number of rows than the job wrote"
+            );
+        }
+    }
+    Some(rows)
+}
 
-    println!("Schema: {}", schema_string);
+// The schema part of the SQL prompt: each dataset's schema, then its column stats and sample
+// rows as the context budget allows. Joined datasets are each introduced by their table name;
+// a lone `data` view is described as it always has been. The error is the response to send.
+async fn describe_datasets(
+    conn: &duckdb::Connection,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    datasets: &[Dataset],
+    joined: bool,
+    sample_rows_enabled: bool,
+    metrics: &mut MetricsLogger,
+) -> Result<String, ApiGatewayProxyResponse> {
+    let mut schemas = Vec::with_capacity(datasets.len());
+    let mut stored_schemas = 0;
+    for dataset in datasets {
+        let stored_schema =
+            stored_query_schema(&dataset.job, &dataset.parquet_key, &dataset.object);
+        stored_schemas += u64::from(stored_schema.is_some());
+        let columns = match stored_schema {
+            Some(columns) => columns.to_vec(),
+            None => match get_parquet_schema(conn, &dataset.file_path) {
+                Ok(columns) => columns,
+                Err(e) => {
+                    return Err(query_error_response(
+                        &e,
+                        "Failed to get schema from local parquet file",
+                        None,
+                        &dataset.job.serviceid,
+                    ));
+                }
+            },
+        };
+        let schema = render_schema_for_prompt(&columns);
+        debug!(
+            job_id = %dataset.job.serviceid,
+            alias = %dataset.alias,
+            schema = %schema,
+            "Read parquet schema"
+        );
+        schemas.push(schema);
+    }
+    metrics.put_count("SchemaCacheHit", stored_schemas);
 
-    let bedrock_response = bedrock_client
-        .converse()
-        .model_id("apac.anthropic.claude-sonnet-4-20250514-v1:0")
-        .system(SystemContentBlock::Text(USER_MESSAGE.to_string()))
-        .messages(
-            Message::builder()
-                .role(ConversationRole::User)
-                .content(ContentBlock::Text(format!(
-                    "schema: {}, question: {}",
-                    schema_string, request.message
-                )))
-                .build()?,
+    // Every schema goes in whole; the datasets then share what's left evenly, first for
+    // stats and then for samples
+    let tables = datasets.len().max(1);
+    let mut context_budget = MAX_PROMPT_CONTEXT_CHARS
+        .saturating_sub(schemas.iter().map(|schema| schema.len()).sum::<usize>());
+    let mut column_stats = Vec::with_capacity(datasets.len());
+    for dataset in datasets {
+        let column_profiles = load_column_profiles(
+            &dataset.job,
+            dynamodb_client,
+            table_name,
+            &dataset.parquet_key,
+            &dataset.object,
+            &dataset.file_path,
+            dataset.query_in_place,
+            metrics,
         )
-        .send()
         .await;
+        column_stats.push(render_column_stats(
+            &column_profiles,
+            (context_budget / tables).min(MAX_PROMPT_COLUMN_STATS_CHARS),
+        ));
+    }
+    context_budget =
+        context_budget.saturating_sub(column_stats.iter().map(|stats| stats.len()).sum::<usize>());
+
+    let descriptions = datasets
+        .iter()
+        .zip(schemas)
+        .zip(column_stats)
+        .map(|((dataset, schema), column_stats)| {
+            let sample_rows = if sample_rows_enabled {
+                sample_rows_markdown(
+                    conn,
+                    &dataset.alias,
+                    PROMPT_SAMPLE_ROWS,
+                    context_budget / tables,
+                )
+                .unwrap_or_else(|e| {
+                    warn!(job_id = %dataset.job.serviceid, error = %e, "Failed to sample rows");
+                    String::new()
+                })
+            } else {
+                String::new()
+            };
+
+            let mut description = if joined {
+                format!("table {}, schema: {}", dataset.alias, schema)
+            } else {
+                format!("schema: {}", schema)
+            };
+            if !column_stats.is_empty() {
+                description.push_str(&format!(", column stats: {}", column_stats));
+            }
+            if !sample_rows.is_empty() {
+                description.push_str(&format!(", sample rows:\n{}\n", sample_rows));
+            }
+            description
+        })
+        .collect::<Vec<_>>();
+
+    Ok(descriptions.join("\n"))
+}
+
+// Exports the query's rows to `derived/{parent job}/{new job}.parquet` and creates the job
+// item for it. The file is written to the invocation's scratch directory, or /tmp without
+// one, and removed once it is uploaded. The response is the one to send.
+#[allow(clippy::too_many_arguments)]
+async fn materialize_query(
+    conn: duckdb::Connection,
+    mut derived: DerivedJob,
+    tables: Vec<String>,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    scratch: Option<&TmpManager>,
+    query_timeout: Duration,
+    metrics: &mut MetricsLogger,
+) -> ApiGatewayProxyResponse {
+    let out_path = scratch
+        .map(|scratch| scratch.dir().to_path_buf())
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("{}.parquet", derived.job_id));
+
+    let export_start = std::time::Instant::now();
+    let export_sql = derived.sql.clone();
+    let export_path = out_path.clone();
+    let exported = run_with_timeout(conn, query_timeout, move |conn| {
+        let tables: Vec<&str> = tables.iter().map(String::as_str).collect();
+        export_query_to_parquet(
+            conn,
+            &export_sql,
+            &tables,
+            &export_path,
+            ExportOptions::default(),
+        )
+    })
+    .await;
+    metrics.put_duration("MaterializeLatency", export_start.elapsed());
+
+    let uploaded = match exported {
+        Ok((_, rows)) => {
+            derived.row_count = rows;
+            derived.output_key = derived_key(&derived.parent_job_id, &derived.job_id);
+            match std::fs::read(&out_path) {
+                Ok(data) => {
+                    derived.output_bytes = data.len() as u64;
+                    upload_to_s3(
+                        &derived.output_bucket,
+                        &derived.output_key,
+                        data,
+                        &derived.job_id,
+                    )
+                    .await
+                    .map(|_| ())
+                }
+                Err(e) => Err(common::error::Error::S3 {
+                    operation: "PutObject",
+                    message: format!("could not read the exported parquet: {}", e),
+                    retryable: false,
+                }),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let _ = std::fs::remove_file(&out_path);
 
-    let sql_query: String = match bedrock_response {
-        Ok(output) => get_converse_output_text(output)?,
+    match uploaded {
+        Ok(()) => {}
+        Err(common::error::Error::QueryTimeout(timeout)) => {
+            return create_cors_response(
+                408,
+                Some(
+                    json!({
+                        "error": "Query took too long",
+                        "details": format!(
+                            "The export was stopped after {} seconds. Try a narrower question.",
+                            timeout.as_secs()
+                        ),
+                        "sql": derived.sql,
+                        "timeout_seconds": timeout.as_secs()
+                    })
+                    .to_string(),
+                ),
+            );
+        }
         Err(e) => {
-            eprintln!("Bedrock converse error: {:?}", e);
-            return Ok(create_cors_response(500, Some(json!({"error": "Failed to generate SQL query", "details": format!("Bedrock API error: {}", e)}).to_string())));
+            return query_error_response(
+                &e,
+                "Failed to materialize query",
+                Some(&derived.sql),
+                &derived.parent_job_id,
+            );
+        }
+    }
+
+    if let Err(e) = create_derived_job(dynamodb_client, table_name, &derived).await {
+        error!(job_id = %derived.parent_job_id, error = %e, "Failed to create derived job");
+        return create_cors_response(
+            500,
+            Some(
+                json!({"error": "Failed to create derived job", "details": e.user_message()})
+                    .to_string(),
+            ),
+        );
+    }
+    metrics.put_count("MaterializedRows", derived.row_count);
+
+    create_cors_response(
+        200,
+        Some(
+            json!({
+                "job_id": derived.job_id,
+                "parent_job_id": derived.parent_job_id,
+                "parquet_key": derived.output_key,
+                "row_count": derived.row_count,
+                "output_bytes": derived.output_bytes,
+                "sql": derived.sql
+            })
+            .to_string(),
+        ),
+    )
+}
+
+// The SQL to run: a stored query's, the caller's own, or the model's answer to the
+// question over `datasets`. Returned with the model that wrote it and, for generated SQL,
+// the prompt it was written from. The error is the response to send.
+#[allow(clippy::too_many_arguments)]
+async fn query_sql(
+    conn: &duckdb::Connection,
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    bedrock_client: &BedrockClient,
+    models: &BedrockModels,
+    system_prompt: &str,
+    request: &GenerateParquetQuery,
+    stored_query: Option<GeneratedQuery>,
+    datasets: &[Dataset],
+    joined: bool,
+    sample_rows_enabled: bool,
+    metrics: &mut MetricsLogger,
+    events: &QueryEvents,
+    draft: &mut AuditDraft,
+) -> Result<(String, Option<String>, Option<String>), ApiGatewayProxyResponse> {
+    match (stored_query, request.sql.as_deref().map(str::trim)) {
+        (Some(stored), _) => {
+            info!(
+                job_id = %request.job_id,
+                query_id = ?request.query_id,
+                page = request.page.unwrap_or(1),
+                "Re-running generated query"
+            );
+            let model_used = Some(stored.model_id).filter(|_| !stored.user_authored);
+            Ok((stored.sql, model_used, None))
+        }
+        (None, Some(sql)) => {
+            info!(job_id = %request.job_id, "Running caller-supplied SQL");
+            Ok((sql.to_string(), None, None))
         }
+        (None, None) => {
+            events.emit(QueryEvent::GeneratingSql);
+            let tables = describe_datasets(
+                conn,
+                dynamodb_client,
+                table_name,
+                datasets,
+                joined,
+                sample_rows_enabled,
+                metrics,
+            )
+            .await?;
+            let prompt = sql_prompt(&tables, &request.message);
+            let (sql_query, model_used) = generate_sql(
+                bedrock_client,
+                models,
+                system_prompt,
+                prompt.clone(),
+                &request.job_id,
+                false,
+                metrics,
+                draft,
+            )
+            .await?;
+            info!(job_id = %request.job_id, model_id = %model_used, "Generated SQL query");
+            Ok((sql_query, Some(model_used), Some(prompt)))
+        }
+    }
+}
+
+// Asks the SQL models for a query and pulls it out of the reply, returning it with the
+// model that wrote it. `repair` marks a second attempt at a query DuckDB rejected, which is
+// measured apart from the first. The error is the response to send.
+#[allow(clippy::too_many_arguments)]
+async fn generate_sql(
+    bedrock_client: &BedrockClient,
+    models: &BedrockModels,
+    system: &str,
+    prompt: String,
+    job_id: &str,
+    repair: bool,
+    metrics: &mut MetricsLogger,
+    draft: &mut AuditDraft,
+) -> Result<(String, String), ApiGatewayProxyResponse> {
+    let failed = |details: String| {
+        create_cors_response(
+            500,
+            Some(json!({"error": "Failed to generate SQL query", "details": details}).to_string()),
+        )
+    };
+    let (step, latency_metric, fallbacks_metric) = if repair {
+        ("sql_repair", "SqlRepairLatency", "SqlRepairModelFallbacks")
+    } else {
+        ("sql", "SqlGenerationLatency", "ModelFallbacks")
     };
 
-    println!("Generated SQL Query: {}", sql_query);
+    let bedrock_start = std::time::Instant::now();
+    models.sql_inference.log(step, job_id);
+    let sql_request = converse_request(bedrock_client, system, prompt, &models.sql_inference)
+        .map_err(|e| failed(e.to_string()))?;
+    let bedrock_response = with_model_fallback("Converse", job_id, &models.sql, |model| {
+        sql_request.clone().model_id(model).send()
+    })
+    .await;
 
-    let structured_data = match execute_sql_on_parquet_file(&conn, &temp_file_path, &sql_query) {
-        Ok(data) => data,
+    metrics.put_duration(latency_metric, bedrock_start.elapsed());
+
+    let (sql_response, model_used) = match bedrock_response {
+        Ok(served) => {
+            metrics.put_count(fallbacks_metric, served.fallbacks as u64);
+            draft
+                .bedrock_calls
+                .push((served.model_id.clone(), get_converse_usage(&served.output)));
+            let text =
+                get_converse_output_text(served.output).map_err(|e| failed(e.to_string()))?;
+            (text, served.model_id)
+        }
         Err(e) => {
-            return Ok(create_cors_response(500, Some(json!({"error": "Failed to execute SQL query on local data", "details": e.to_string()}).to_string())));
+            metrics.put_count(fallbacks_metric, models.sql.len().saturating_sub(1) as u64);
+            metrics.flush();
+            error!(job_id, error = ?e, "Bedrock converse error");
+            return Err(failed(format!("Bedrock API error: {}", e)));
         }
     };
 
-    let json_data = serde_json::to_string_pretty(&structured_data)?;
-    println!("{:?}", json_data);
+    match sanitize_sql_response(&sql_response) {
+        Ok(sql) => Ok((sql, model_used)),
+        Err(e) => {
+            info!(
+                job_id,
+                response = %redact(&sql_response),
+                "Model response contained no SQL"
+            );
+            Err(failed(e.to_string()))
+        }
+    }
+}
 
-    let job_record = get_job_by_id(&table_name, &request.job_id).await?.unwrap();
+// Asks the model to fix generated SQL that failed to bind with `error`, and binds the fix,
+// returning it with the model that wrote it and its column types. The fix goes through the
+// read-only guard like the first attempt, and isn't repaired again. The error is the
+// response to send.
+#[allow(clippy::too_many_arguments)]
+async fn repair_sql(
+    conn: &duckdb::Connection,
+    bedrock_client: &BedrockClient,
+    models: &BedrockModels,
+    system_prompt: &str,
+    prompt: &str,
+    failed_sql: &str,
+    error: &common::error::Error,
+    tables: &[&str],
+    job_id: &str,
+    metrics: &mut MetricsLogger,
+    draft: &mut AuditDraft,
+) -> Result<(String, String, Vec<(String, String)>), ApiGatewayProxyResponse> {
+    info!(job_id, error = %error, "Generated SQL failed to bind; repairing it");
+    metrics.put_count("SqlRepairs", 1);
+    let repair = repair_prompt(prompt, failed_sql, &error.to_string());
+    let (repaired_sql, repair_model) = generate_sql(
+        bedrock_client,
+        models,
+        system_prompt,
+        repair,
+        job_id,
+        true,
+        metrics,
+        draft,
+    )
+    .await?;
+    draft.audit.sql = Some(repaired_sql.clone());
+    draft.audit.failed_sql = Some(failed_sql.to_string());
+    draft.audit.model_id = Some(repair_model.clone());
 
-    let make_human_presentable = bedrock_client
-        .converse()
-        .model_id("apac.anthropic.claude-sonnet-4-20250514-v1:0")
-        .system(SystemContentBlock::Text(MAKE_HUMAN_READABLE.to_string()))
-        .messages(
-            Message::builder()
-                .role(ConversationRole::User)
-                .content(ContentBlock::Text(format!(
-                    "data that needs to be presentable: {}, user question: {}, dataset context: {}",
-                    json_data, request.message, job_record.context
-                )))
-                .build()?,
-        )
-        .send()
-        .await;
+    if let Some(response) = rejected_sql_response(&repaired_sql, tables, false, job_id, metrics) {
+        return Err(response);
+    }
+    match query_column_types(conn, &repaired_sql) {
+        Ok(column_types) => {
+            info!(job_id, "Repaired generated SQL");
+            Ok((repaired_sql, repair_model, column_types))
+        }
+        Err(e) => {
+            info!(job_id, error = %e, "Repaired SQL failed to bind");
+            metrics.put_count("SqlRepairFailed", 1);
+            metrics.flush();
+            Err(create_cors_response(
+                422,
+                Some(
+                    json!({
+                        "error": "Generated query could not be run",
+                        "details": e.user_message(),
+                        "category": e.duckdb_kind().map(|kind| kind.as_str()),
+                        "sql": repaired_sql,
+                        "failed_sql": failed_sql
+                    })
+                    .to_string(),
+                ),
+            ))
+        }
+    }
+}
+
+// The 422 for SQL that isn't a single read-only query over `tables`, if it isn't
+fn rejected_sql_response(
+    sql_query: &str,
+    tables: &[&str],
+    user_authored: bool,
+    job_id: &str,
+    metrics: &mut MetricsLogger,
+) -> Option<ApiGatewayProxyResponse> {
+    let rejection = check_read_only_sql(sql_query, tables).err()?;
+    info!(
+        job_id,
+        construct = %rejection.construct,
+        user_authored,
+        "Rejected generated SQL"
+    );
+    metrics.put_count("QueryRejected", 1);
+    metrics.flush();
+    Some(create_cors_response(
+        422,
+        Some(
+            json!({
+                "error": if user_authored {
+                    "Query is not a read-only SELECT"
+                } else {
+                    "Generated query is not a read-only SELECT"
+                },
+                "details": rejection.to_string(),
+                "construct": rejection.construct
+            })
+            .to_string(),
+        ),
+    ))
+}
 
-    let readable_output = match make_human_presentable {
-        Ok(output) => get_converse_output_text(output)?,
-        Err(e) => format!("Bedrock make readable error: {}", e),
+// A query that failed, as a response. Syntax and binding errors are the query's own fault
+// and an interrupted one didn't get to finish; anything else is a failure on our side. The
+// caller is told what went wrong in words safe to show them, while DuckDB's full message,
+// which can quote file paths and the SQL as rewritten for the views, is only logged.
+fn query_error_response(
+    e: &common::error::Error,
+    summary: &str,
+    sql: Option<&str>,
+    job_id: &str,
+) -> ApiGatewayProxyResponse {
+    let status = match e.duckdb_kind() {
+        Some(DuckDbErrorKind::Syntax | DuckDbErrorKind::Binder) => 422,
+        Some(DuckDbErrorKind::Interrupted) => 408,
+        _ => 500,
     };
+    error!(job_id, error = %e, sql = sql.unwrap_or_default(), status, "{}", summary);
+    create_cors_response(
+        status,
+        Some(
+            json!({
+                "error": summary,
+                "details": e.user_message(),
+                "category": e.duckdb_kind().map(|kind| kind.as_str())
+            })
+            .to_string(),
+        ),
+    )
+}
 
-    println!("Human readable output: {}", readable_output);
+// What the audit log records about a request, filled in by `answer_query` as it learns
+// each part. Only requests that got as far as an authorised job are recorded.
+#[derive(Default)]
+struct AuditDraft {
+    job_id: Option<String>,
+    audit: QueryAudit,
+    // Each Bedrock call that answered, as the model that served it and its usage
+    bedrock_calls: Vec<(String, Option<ConverseUsage>)>,
+}
 
-    let response_body = json!({ "response_message": readable_output });
-    Ok(create_cors_response(200, Some(response_body.to_string())))
+// The error a failed response reported, with its details when it gave any
+fn response_error(response: &ApiGatewayProxyResponse) -> Option<String> {
+    let Some(Body::Text(text)) = &response.body else {
+        return None;
+    };
+    let body: serde_json::Value = serde_json::from_str(text).ok()?;
+    let error = body["error"].as_str()?;
+    match body["details"].as_str() {
+        Some(details) => Some(format!("{}: {}", error, details)),
+        None => Some(error.to_string()),
+    }
+}
+
+// Answers the question, then adds it to the job's query history. The history is
+// best-effort: failing to write it is logged and the caller still gets their answer.
+async fn handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+    parquet_cache: &ParquetCache,
+    models: &BedrockModels,
+    events: QueryEvents,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    let started = std::time::Instant::now();
+
+    // Nothing else runs in the container between invocations, so a partial download found
+    // now was cut off by an earlier one timing out
+    let removed = parquet_cache.remove_partials();
+    if removed > 0 {
+        info!(removed, "Removed unfinished parquet downloads");
+    }
+    // Dropped when this returns, taking the invocation's spill files with it
+    let scratch = match TmpManager::for_invocation(&event.context.request_id) {
+        Ok(scratch) => Some(scratch),
+        Err(e) => {
+            warn!(error = %e, "Failed to create the invocation's tmp directory");
+            None
+        }
+    };
+
+    let config = aws_config::load_from_env().await;
+    let dynamodb_client = DynamoDbClient::new(&config);
+
+    let mut draft = AuditDraft::default();
+    let response = answer_query(
+        event,
+        &dynamodb_client,
+        parquet_cache,
+        models,
+        &events,
+        scratch.as_ref(),
+        &mut draft,
+    )
+    .await;
+
+    let Some(job_id) = draft.job_id else {
+        return response;
+    };
+    let mut audit = draft.audit;
+    audit.duration_ms = started.elapsed().as_millis() as u64;
+    let usage = models.usage(&draft.bedrock_calls);
+    audit.input_tokens = usage.input_tokens;
+    audit.output_tokens = usage.output_tokens;
+    audit.estimated_usd = usage.estimated_usd;
+    match &response {
+        Ok(response) => {
+            audit.status_code = response.status_code;
+            if response.status_code >= 400 {
+                audit.error = response_error(response);
+            }
+        }
+        Err(e) => {
+            audit.status_code = 500;
+            audit.error = Some(e.to_string());
+        }
+    }
+
+    let table_name = env::var("DYNAMODB_NAME")?;
+    if let Err(e) = record_query_audit(&dynamodb_client, &table_name, &job_id, &audit).await {
+        warn!(job_id = %job_id, error = %e, "Failed to record query audit");
+    }
+
+    response
+}
+
+async fn answer_query(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+    dynamodb_client: &DynamoDbClient,
+    parquet_cache: &ParquetCache,
+    models: &BedrockModels,
+    events: &QueryEvents,
+    scratch: Option<&TmpManager>,
+    draft: &mut AuditDraft,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if event.payload.http_method == "OPTIONS" {
+        return Ok(create_cors_response(200, None));
+    }
+
+    let table_name = env::var("DYNAMODB_NAME")?;
+    let principal = match authorize(&event.payload, dynamodb_client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    let body = event.payload.body.unwrap_or_default();
+    let bucket_name = env::var("S3_UPLOAD_BUCKET_NAME")?;
+
+    let request: GenerateParquetQuery = match serde_json::from_str(&body) {
+        Ok(req) => req,
+        Err(e) => {
+            return Ok(create_cors_response(
+                400,
+                Some(
+                    json!({"error": "Failed to parse JSON", "details": e.to_string()}).to_string(),
+                ),
+            ));
+        }
+    };
+
+    let job_record = match load_dataset_job(
+        dynamodb_client,
+        &table_name,
+        &principal,
+        &request.job_id,
+        None,
+    )
+    .await
+    {
+        Ok(job) => job,
+        Err(response) => return Ok(response),
+    };
+
+    draft.job_id = Some(request.job_id.clone());
+    draft.audit.message = request.message.clone();
+    draft.audit.principal = Some(principal.id.clone());
+    draft.audit.query_id = request.query_id.clone();
+    let sample_rows_enabled = sample_rows_enabled();
+    draft.audit.sample_rows_enabled = Some(sample_rows_enabled);
+
+    let Some(parquet_key) =
+        job_parquet_key(&job_record, &request.job_id, request.parquet_key.as_deref())
+    else {
+        info!(
+            job_id = %request.job_id,
+            principal = %principal.id,
+            parquet_key = ?request.parquet_key,
+            "Rejected parquet_key that isn't the job's output"
+        );
+        return Ok(create_cors_response(
+            403,
+            Some(
+                json!({
+                    "error": "parquet_key does not belong to this job",
+                    "details": "omit parquet_key to query the job's own output"
+                })
+                .to_string(),
+            ),
+        ));
+    };
+
+    let page = request.page.unwrap_or(1);
+    let page_size = request.page_size.unwrap_or_else(default_page_size);
+    let user_sql = request.sql.as_deref().map(str::trim);
+    draft.audit.user_authored = Some(user_sql.is_some());
+    if let Some(response) = request_error(&request, page, page_size) {
+        return Ok(response);
+    }
+    // The prompt keeps the question apart from the instructions and the SQL is checked
+    // before it runs, but a question that is plainly trying to instruct the model is turned
+    // away before it gets that far
+    if let Some(pattern) = injection_pattern(&request.message) {
+        info!(
+            job_id = %request.job_id,
+            principal = %principal.id,
+            pattern,
+            "Refused question that looks like a prompt injection"
+        );
+        return Ok(create_cors_response(
+            422,
+            Some(
+                json!({
+                    "error": "Question was refused",
+                    "details": format!(
+                        "\"{}\" reads as an instruction to the model rather than a question about the data",
+                        pattern
+                    )
+                })
+                .to_string(),
+            ),
+        ));
+    }
+
+    let language = match request_language(request.language.as_deref()) {
+        Ok(language) => language,
+        Err(details) => {
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Unsupported language", "details": details}).to_string()),
+            ));
+        }
+    };
+
+    let stored_query = match &request.query_id {
+        Some(query_id) => {
+            match get_generated_query(dynamodb_client, &table_name, &request.job_id, query_id).await
+            {
+                Ok(Some(query)) => Some(query),
+                Ok(None) => {
+                    return Ok(create_cors_response(
+                        404,
+                        Some(json!({"error": "Query not found"}).to_string()),
+                    ));
+                }
+                Err(e) => {
+                    error!(job_id = %request.job_id, error = %e, "Failed to load generated query");
+                    return Ok(create_cors_response(
+                        500,
+                        Some(json!({"error": "Internal server error"}).to_string()),
+                    ));
+                }
+            }
+        }
+        None => None,
+    };
+
+    // A stored query reads the views it was generated against
+    let dataset_refs = match &stored_query {
+        Some(stored) => stored.datasets.clone(),
+        None => request.datasets.clone(),
+    };
+    if let Some(details) = dataset_error(&dataset_refs, &request.job_id) {
+        return Ok(create_cors_response(
+            400,
+            Some(json!({"error": "Invalid datasets", "details": details}).to_string()),
+        ));
+    }
+    let joined = !dataset_refs.is_empty();
+
+    let sources = if joined {
+        let mut sources = Vec::with_capacity(dataset_refs.len());
+        for dataset in &dataset_refs {
+            if dataset.job_id == request.job_id {
+                sources.push(DatasetSource {
+                    alias: dataset.alias.clone(),
+                    job: job_record.clone(),
+                    parquet_key: parquet_key.clone(),
+                });
+                continue;
+            }
+
+            let job = match load_dataset_job(
+                dynamodb_client,
+                &table_name,
+                &principal,
+                &dataset.job_id,
+                Some(&dataset.alias),
+            )
+            .await
+            {
+                Ok(job) => job,
+                Err(response) => return Ok(response),
+            };
+            let parquet_key = job_output_key(&job, &dataset.job_id);
+            sources.push(DatasetSource {
+                alias: dataset.alias.clone(),
+                job,
+                parquet_key,
+            });
+        }
+        sources
+    } else {
+        vec![DatasetSource {
+            alias: PARQUET_VIEW_NAME.to_string(),
+            job: job_record,
+            parquet_key,
+        }]
+    };
+
+    draft.audit.page = Some(page);
+
+    let mut metrics = MetricsLogger::new(METRICS_FUNCTION_NAME, &request.job_id);
+
+    let sdk_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
+    let bedrock_client = bedrock_client(&sdk_config);
+    let s3_client = S3Client::new(&sdk_config);
+
+    let aliases: Vec<&str> = sources.iter().map(|source| source.alias.as_str()).collect();
+    let conn = match warm_connection(&aliases) {
+        Ok((conn, reused)) => {
+            metrics.put_count("DuckDbReused", u64::from(reused));
+            conn
+        }
+        Err(e) => {
+            return Ok(query_error_response(
+                &e,
+                "Failed to setup DuckDB connection",
+                None,
+                &request.job_id,
+            ));
+        }
+    };
+    // Without a directory of its own the query can still run; it just can't spill
+    if let Some(scratch) = scratch {
+        let spill_budget = scratch_budget_bytes(parquet_cache.budget_bytes());
+        if let Err(e) = set_spill_directory(&conn, scratch.dir(), spill_budget) {
+            warn!(job_id = %request.job_id, error = %e, "Failed to set the spill directory");
+        }
+    }
+
+    events.emit(QueryEvent::Downloading {
+        datasets: sources.len(),
+    });
+    let mut s3_access = None;
+    let mut datasets = Vec::with_capacity(sources.len());
+    for source in sources {
+        let opened = open_dataset(
+            &conn,
+            &s3_client,
+            parquet_cache,
+            &bucket_name,
+            source,
+            &mut s3_access,
+            &mut metrics,
+        )
+        .await;
+        match opened {
+            Ok(dataset) => datasets.push(dataset),
+            Err(response) => return Ok(response),
+        }
+    }
+
+    if request.inspect {
+        let dataset = &datasets[0];
+        return match parquet_metadata_report(&conn, &dataset.file_path) {
+            Ok(report) => {
+                metrics.flush();
+                Ok(create_cors_response(
+                    200,
+                    Some(
+                        json!({
+                            "parquet_key": dataset.parquet_key,
+                            "report": report
+                        })
+                        .to_string(),
+                    ),
+                ))
+            }
+            Err(e) => Ok(query_error_response(
+                &e,
+                "Failed to read parquet metadata",
+                None,
+                &request.job_id,
+            )),
+        };
+    }
+
+    let is_new_query = stored_query.is_none();
+    // Pages of a stored query keep whoever wrote its SQL
+    let user_authored = match &stored_query {
+        Some(stored) => stored.user_authored,
+        None => user_sql.is_some(),
+    };
+    draft.audit.user_authored = Some(user_authored);
+    // Pages of a stored query keep the sample it was answered from
+    let sample_percent = match &stored_query {
+        Some(stored) => stored.sample_percent,
+        None => request.approximate.then(sample_percent),
+    };
+    let system_prompt = sql_system_prompt(sample_percent);
+    // The prompt is kept for generated SQL, in case it needs repairing
+    let (mut sql_query, mut model_used, sql_prompt_used) = match query_sql(
+        &conn,
+        dynamodb_client,
+        &table_name,
+        &bedrock_client,
+        models,
+        &system_prompt,
+        &request,
+        stored_query,
+        &datasets,
+        joined,
+        sample_rows_enabled,
+        &mut metrics,
+        events,
+        draft,
+    )
+    .await
+    {
+        Ok(sql) => sql,
+        Err(response) => return Ok(response),
+    };
+
+    draft.audit.sql = Some(sql_query.clone());
+    draft.audit.model_id = model_used.clone();
+
+    debug!(job_id = %request.job_id, sql = %sql_query, "Generated SQL query");
+
+    // The SQL is model output shaped by the caller's question, or the caller's own, so it is
+    // held to a single read-only query over the views before DuckDB sees it. A stored query
+    // was checked when it was generated, but is checked again rather than trusted from the
+    // table.
+    let aliases: Vec<&str> = datasets
+        .iter()
+        .map(|dataset| dataset.alias.as_str())
+        .collect();
+    if let Some(response) = rejected_sql_response(
+        &sql_query,
+        &aliases,
+        user_authored,
+        &request.job_id,
+        &mut metrics,
+    ) {
+        return Ok(response);
+    }
+
+    // Generated SQL that DuckDB can't bind, most often a column left unquoted or a function
+    // it doesn't have, gets one repair: the model is shown its query and DuckDB's error and
+    // asked for another. Binding only plans the query, so nothing is run twice. The column
+    // types are needed for the response either way.
+    let mut failed_sql = None;
+    let column_types = match (query_column_types(&conn, &sql_query), sql_prompt_used) {
+        (Ok(column_types), _) => column_types,
+        (Err(e), Some(prompt)) => {
+            let repaired = repair_sql(
+                &conn,
+                &bedrock_client,
+                models,
+                &system_prompt,
+                &prompt,
+                &sql_query,
+                &e,
+                &aliases,
+                &request.job_id,
+                &mut metrics,
+                draft,
+            )
+            .await;
+            match repaired {
+                Ok((repaired_sql, repair_model, column_types)) => {
+                    failed_sql = Some(std::mem::replace(&mut sql_query, repaired_sql));
+                    model_used = Some(repair_model);
+                    column_types
+                }
+                Err(response) => return Ok(response),
+            }
+        }
+        // Caller-written and stored SQL aren't repaired; running it gives the caller the error
+        (Err(e), None) => {
+            warn!(job_id = %request.job_id, error = %e, "Failed to read result columns");
+            Vec::new()
+        }
+    };
+
+    // The model is asked to sample, but a plain scan it left whole is sampled here so an
+    // approximate answer never reads all of the data. A stored query was sampled before it
+    // was saved.
+    let mut sample_enforced = false;
+    if let Some(percent) = sample_percent.filter(|_| is_new_query) {
+        if needs_sample_clause(&sql_query) {
+            info!(
+                job_id = %request.job_id,
+                percent,
+                "Sampling a plain scan for an approximate answer"
+            );
+            sql_query = with_sample(&sql_query, percent);
+            draft.audit.sql = Some(sql_query.clone());
+            sample_enforced = true;
+        }
+        metrics.put_count("SampleEnforced", u64::from(sample_enforced));
+    }
+
+    events.emit(QueryEvent::Sql {
+        sql: sql_query.clone(),
+    });
+
+    // Later pages need the SQL; without it they fail, but this page can still be answered
+    let query_id = if is_new_query {
+        let query_id = uuid::Uuid::new_v4().to_string();
+        let generated = GeneratedQuery {
+            sql: sql_query.clone(),
+            question: request.message.clone(),
+            model_id: model_used.clone().unwrap_or_default(),
+            datasets: dataset_refs.clone(),
+            user_authored,
+            sample_percent,
+        };
+        match save_generated_query(
+            dynamodb_client,
+            &table_name,
+            &request.job_id,
+            &query_id,
+            &generated,
+        )
+        .await
+        {
+            Ok(()) => Some(query_id),
+            Err(e) => {
+                warn!(job_id = %request.job_id, error = %e, "Failed to save generated query");
+                None
+            }
+        }
+    } else {
+        request.query_id.clone()
+    };
+    draft.audit.query_id = query_id.clone();
+
+    // The prompt asks for a LIMIT but nothing makes the model add one, so a query without
+    // its own is capped here. A query that sets its own LIMIT is trusted to mean it. The
+    // count runs one row past the cap, so a result that exactly fills it isn't reported as
+    // truncated.
+    let max_rows = max_result_rows();
+    let row_cap_applied = !has_top_level_limit(&sql_query);
+    let (counted_sql, capped_sql) = if row_cap_applied {
+        (
+            with_row_limit(&sql_query, max_rows.saturating_add(1)),
+            with_row_limit(&sql_query, max_rows),
+        )
+    } else {
+        (sql_query.clone(), sql_query.clone())
+    };
+
+    let query_timeout = query_timeout();
+
+    // For working out why a query is slow: the plan of what would run, in place of rows and
+    // a summary. ANALYZE runs the query, so it gets the same timeout.
+    if request.explain {
+        let explain_start = std::time::Instant::now();
+        let analyze = request.analyze;
+        let plan = run_with_timeout(conn, query_timeout, move |conn| {
+            explain_query(conn, &capped_sql, analyze)
+        })
+        .await;
+        metrics.put_duration("ExplainLatency", explain_start.elapsed());
+        metrics.flush();
+
+        let plan = match plan {
+            Ok((_, plan)) => plan,
+            Err(common::error::Error::QueryTimeout(timeout)) => {
+                info!(
+                    job_id = %request.job_id,
+                    timeout_seconds = timeout.as_secs(),
+                    "Explain timed out"
+                );
+                return Ok(create_cors_response(
+                    408,
+                    Some(
+                        json!({
+                            "error": "Query took too long",
+                            "details": format!(
+                                "EXPLAIN ANALYZE was stopped after {} seconds",
+                                timeout.as_secs()
+                            ),
+                            "sql": sql_query,
+                            "timeout_seconds": timeout.as_secs()
+                        })
+                        .to_string(),
+                    ),
+                ));
+            }
+            Err(e) => {
+                return Ok(query_error_response(
+                    &e,
+                    "Failed to explain SQL query",
+                    Some(&sql_query),
+                    &request.job_id,
+                ));
+            }
+        };
+
+        let response_body = json!({
+            "query_id": query_id,
+            "sql": sql_query,
+            "datasets": dataset_refs,
+            "plan": plan,
+            "analyzed": analyze,
+            "explain_ms": explain_start.elapsed().as_millis() as u64,
+            "row_cap_applied": row_cap_applied,
+            "repaired": failed_sql.is_some(),
+            "failed_sql": failed_sql,
+            "approximate": sample_percent.is_some(),
+            "sample_percent": sample_percent,
+            "sample_enforced": sample_enforced,
+            "model_used": model_used,
+            "user_authored": user_authored,
+            "usage": models.usage(&draft.bedrock_calls)
+        });
+        return Ok(create_cors_response(200, Some(response_body.to_string())));
+    }
+
+    if request.materialize {
+        let parent_context = datasets
+            .iter()
+            .find(|dataset| dataset.job.serviceid == request.job_id)
+            .map(|dataset| dataset.job.context.clone())
+            .unwrap_or_default();
+        let derived = DerivedJob {
+            job_id: uuid::Uuid::new_v4().to_string(),
+            parent_job_id: request.job_id.clone(),
+            sql: sql_query.clone(),
+            context: parent_context,
+            created_by: principal.id.clone(),
+            output_bucket: bucket_name.clone(),
+            output_key: String::new(),
+            row_count: 0,
+            output_bytes: 0,
+        };
+        let tables = aliases.iter().map(|alias| alias.to_string()).collect();
+        events.emit(QueryEvent::Executing);
+        let response = materialize_query(
+            conn,
+            derived,
+            tables,
+            dynamodb_client,
+            &table_name,
+            scratch,
+            query_timeout,
+            &mut metrics,
+        )
+        .await;
+        metrics.flush();
+        return Ok(response);
+    }
+
+    events.emit(QueryEvent::Executing);
+    let query_start = std::time::Instant::now();
+    let offset = (page - 1).saturating_mul(page_size);
+    let max_bytes = max_result_bytes();
+    let page_result = run_with_timeout(conn, query_timeout, move |conn| {
+        let matched_rows = count_query_rows(conn, &counted_sql)?;
+        let total_rows = if row_cap_applied {
+            matched_rows.min(max_rows)
+        } else {
+            matched_rows
+        };
+        // Past the end there is nothing to run
+        if offset >= total_rows {
+            return Ok((matched_rows, total_rows, QueryResult::default()));
+        }
+        let page = execute_sql_page(conn, &capped_sql, page_size, offset, max_bytes)?;
+        Ok((matched_rows, total_rows, page))
+    })
+    .await;
+    let (conn, (matched_rows, total_rows, page_rows)) = match page_result {
+        Ok(result) => result,
+        Err(common::error::Error::QueryTimeout(timeout)) => {
+            info!(
+                job_id = %request.job_id,
+                timeout_seconds = timeout.as_secs(),
+                "Query timed out"
+            );
+            metrics.put_count("QueryTimedOut", 1);
+            metrics.flush();
+            return Ok(create_cors_response(
+                408,
+                Some(
+                    json!({
+                        "error": "Query took too long",
+                        "details": format!(
+                            "The query was stopped after {} seconds. Try a narrower question.",
+                            timeout.as_secs()
+                        ),
+                        "sql": sql_query,
+                        "timeout_seconds": timeout.as_secs()
+                    })
+                    .to_string(),
+                ),
+            ));
+        }
+        Err(e) => {
+            metrics.put_count("QueryFailed", 1);
+            metrics.flush();
+            return Ok(query_error_response(
+                &e,
+                "Failed to execute SQL query on local data",
+                Some(&sql_query),
+                &request.job_id,
+            ));
+        }
+    };
+
+    metrics.put_duration("QueryLatency", query_start.elapsed());
+    let in_place_bytes: u64 = datasets
+        .iter()
+        .filter(|dataset| dataset.query_in_place)
+        .map(|dataset| dataset.object.bytes.max(0) as u64)
+        .sum();
+    if in_place_bytes > 0 {
+        log_bytes_scanned(&conn, &mut metrics, &request.job_id, in_place_bytes);
+    }
+    metrics.put_count("QueryFailed", 0);
+    metrics.put_count("QueryTimedOut", 0);
+    metrics.put_count("ResultRows", total_rows);
+    draft.audit.row_count = Some(total_rows);
+    // The job's own file, which a join may not include
+    let total_rows_in_file = datasets
+        .iter()
+        .find(|dataset| dataset.job.serviceid == request.job_id)
+        .and_then(|dataset| file_row_count(&conn, dataset, &mut metrics));
+    let truncated = matched_rows > total_rows;
+    if truncated {
+        info!(job_id = %request.job_id, max_rows, "Result truncated at the row cap");
+    }
+    metrics.put_count("ResultTruncated", u64::from(truncated));
+    // The page stopped short of page_size rows; the rows it left out need a smaller one
+    let page_truncated = page_rows.truncated;
+    if page_truncated {
+        info!(
+            job_id = %request.job_id,
+            rows = page_rows.rows.len(),
+            bytes = page_rows.bytes,
+            max_bytes,
+            "Page truncated at the byte limit"
+        );
+    }
+    metrics.put_count("PageTruncated", u64::from(page_truncated));
+
+    let rows = page_rows.row_objects();
+    let has_more = offset + (rows.len() as u64) < total_rows;
+
+    let columns: Vec<&String> = column_types.iter().map(|(name, _)| name).collect();
+    let chart = (request.output == OutputMode::Chart).then(|| chart_shape(&column_types, &rows));
+
+    let json_data = serde_json::to_string(&rows)?;
+    debug!(
+        job_id = %request.job_id,
+        page,
+        rows = rows.len(),
+        total_rows,
+        data = %redact(&json_data),
+        "Query results"
+    );
+
+    // Later pages are for reading the rows; the summary was written from the first. SQL the
+    // caller wrote is only summarized when they ask for it.
+    let write_summary = is_new_query && request.summarize.unwrap_or(!user_authored);
+    draft.audit.summarized = Some(write_summary && total_rows > 0);
+    let (readable_output, summary_model_used) = if write_summary && total_rows == 0 {
+        // Handed nothing, the model would make an answer up
+        let no_rows = language.map_or(NO_ROWS_MESSAGE, |language| language.no_rows);
+        (Some(no_rows.to_string()), None)
+    } else if write_summary {
+        let dataset_context = if joined {
+            datasets
+                .iter()
+                .map(|dataset| format!("{}: {}", dataset.alias, dataset.job.context))
+                .collect::<Vec<_>>()
+                .join("; ")
+        } else {
+            datasets[0].job.context.clone()
+        };
+
+        // Caller-written SQL may come without a question, in which case the SQL stands in
+        let question = if request.message.trim().is_empty() {
+            format!("results of the query {}", sql_query)
+        } else {
+            request.message.clone()
+        };
+
+        events.emit(QueryEvent::Summarizing);
+        let bedrock_start = std::time::Instant::now();
+        models.summary_inference.log("summary", &request.job_id);
+        let summary_request = converse_request(
+            &bedrock_client,
+            &summary_system_prompt(language),
+            match partial_result_note(rows.len(), total_rows, truncated) {
+                Some(note) => format!(
+                    "data that needs to be presentable: {}, note: {}, user question: {}, dataset context: {}",
+                    json_data, note, question, dataset_context
+                ),
+                None => format!(
+                    "data that needs to be presentable: {}, user question: {}, dataset context: {}",
+                    json_data, question, dataset_context
+                ),
+            },
+            &models.summary_inference,
+        )?;
+        let make_human_presentable =
+            with_model_fallback("Converse", &request.job_id, &models.summary, |model| {
+                summary_request.clone().model_id(model).send()
+            })
+            .await;
+
+        metrics.put_duration("SummaryGenerationLatency", bedrock_start.elapsed());
+
+        match make_human_presentable {
+            Ok(served) => {
+                metrics.put_count("SummaryModelFallbacks", served.fallbacks as u64);
+                draft
+                    .bedrock_calls
+                    .push((served.model_id.clone(), get_converse_usage(&served.output)));
+                (
+                    Some(get_converse_output_text(served.output)?),
+                    Some(served.model_id),
+                )
+            }
+            Err(e) => {
+                warn!(job_id = %request.job_id, error = ?e, "Bedrock summary error");
+                (Some(format!("Bedrock make readable error: {}", e)), None)
+            }
+        }
+    } else {
+        (None, None)
+    };
+    metrics.flush();
+
+    if let Some(readable_output) = &readable_output {
+        debug!(
+            job_id = %request.job_id,
+            output = %redact(readable_output),
+            "Human readable output"
+        );
+    }
+
+    let response_body = json!({
+        "response_message": readable_output,
+        "query_id": query_id,
+        "sql": sql_query,
+        "datasets": dataset_refs,
+        "columns": columns,
+        "rows": rows,
+        "chart": chart,
+        "page": page,
+        "page_size": page_size,
+        "total_rows": total_rows,
+        "total_rows_in_file": total_rows_in_file,
+        "has_more": has_more,
+        "row_cap_applied": row_cap_applied,
+        "truncated": truncated,
+        "page_truncated": page_truncated,
+        "max_result_bytes": max_bytes,
+        "timeout_seconds": query_timeout.as_secs(),
+        "model_used": model_used,
+        "user_authored": user_authored,
+        "repaired": failed_sql.is_some(),
+        "failed_sql": failed_sql,
+        "approximate": sample_percent.is_some(),
+        "sample_percent": sample_percent,
+        "sample_enforced": sample_enforced,
+        "usage": models.usage(&draft.bedrock_calls),
+        "summary_model_used": summary_model_used,
+        "language": language.map(|language| language.tag)
+    });
+    Ok(create_cors_response(200, Some(response_body.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::test_support::{StubEndpoint, StubResponse};
+    use serde_json::Value;
+
+    fn job_with_status(status: JobStatus) -> Job {
+        Job {
+            status,
+            ..Job::default()
+        }
+    }
+
+    fn response_json(response: &ApiGatewayProxyResponse) -> Value {
+        match &response.body {
+            Some(Body::Text(text)) => serde_json::from_str(text).unwrap(),
+            other => panic!("expected a text body, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_a_successful_job_can_be_queried() {
+        for status in JobStatus::ALL {
+            let response = unconverted_job_response(&job_with_status(status), None);
+            match status {
+                JobStatus::Success => assert!(response.is_none()),
+                _ => {
+                    let response = response.unwrap();
+                    assert_eq!(response.status_code, 409, "{:?}", status);
+                    assert_eq!(response_json(&response)["status"], json!(status));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_failed_job_reports_why_it_failed() {
+        let job = Job {
+            error_message: Some("bad header row".to_string()),
+            ..job_with_status(JobStatus::Failed)
+        };
+
+        let body = response_json(&unconverted_job_response(&job, None).unwrap());
+        assert_eq!(body["error"], "Conversion failed");
+        assert_eq!(body["details"], "bad header row");
+    }
+
+    #[test]
+    fn an_unfinished_job_is_not_complete() {
+        let body = response_json(
+            &unconverted_job_response(&job_with_status(JobStatus::Processing), None).unwrap(),
+        );
+        assert_eq!(body["error"], "Conversion not complete");
+        assert!(body.get("details").is_none());
+    }
+
+    #[test]
+    fn a_joined_dataset_is_named() {
+        let response =
+            unconverted_job_response(&job_with_status(JobStatus::Pending), Some("orders")).unwrap();
+        assert_eq!(response_json(&response)["dataset"], "orders");
+    }
+
+    fn job_with_output(output_key: Option<&str>) -> Job {
+        Job {
+            output_key: output_key.map(str::to_string),
+            ..job_with_status(JobStatus::Success)
+        }
+    }
+
+    #[test]
+    fn a_job_is_queried_through_its_own_output() {
+        let job = job_with_output(Some("parquet/job-1.parquet"));
+        assert_eq!(
+            job_parquet_key(&job, "job-1", None).as_deref(),
+            Some("parquet/job-1.parquet")
+        );
+        assert_eq!(
+            job_parquet_key(&job, "job-1", Some("parquet/job-1.parquet")).as_deref(),
+            Some("parquet/job-1.parquet")
+        );
+        assert_eq!(
+            job_parquet_key(&job, "job-1", Some("parquet/job-2.parquet")),
+            None
+        );
+
+        // Recorded before output_key was
+        let legacy = job_with_output(None);
+        assert_eq!(
+            job_parquet_key(&legacy, "job-1", None).as_deref(),
+            Some("parquet/job-1.parquet")
+        );
+    }
+
+    fn job_with_schema(etag: Option<&str>) -> Job {
+        Job {
+            query_schema: Some(vec![ParquetColumn {
+                name: "id".to_string(),
+                duckdb_type: "BIGINT".to_string(),
+                nullable: false,
+            }]),
+            query_schema_etag: etag.map(str::to_string),
+            ..job_with_output(Some("parquet/job-1.parquet"))
+        }
+    }
+
+    fn object(etag: Option<&str>) -> SourceObject {
+        SourceObject {
+            bytes: 1024,
+            etag: etag.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn a_stored_schema_is_used_while_the_file_is_unchanged() {
+        let job = job_with_schema(Some("\"abc\""));
+
+        let schema =
+            stored_query_schema(&job, "parquet/job-1.parquet", &object(Some("\"abc\""))).unwrap();
+
+        assert_eq!(schema.len(), 1);
+        assert_eq!(schema[0].name, "id");
+        assert_eq!(schema[0].duckdb_type, "BIGINT");
+    }
+
+    #[test]
+    fn without_a_stored_schema_the_file_is_described() {
+        // Jobs converted before the schema was stored, and checkpointed outputs
+        let job = Job {
+            query_schema: None,
+            ..job_with_schema(Some("\"abc\""))
+        };
+        let object = object(Some("\"abc\""));
+
+        assert!(stored_query_schema(&job, "parquet/job-1.parquet", &object).is_none());
+    }
+
+    #[test]
+    fn a_stale_stored_schema_is_not_used() {
+        let job = job_with_schema(Some("\"abc\""));
+
+        // The object was rewritten since the schema was stored
+        assert!(
+            stored_query_schema(&job, "parquet/job-1.parquet", &object(Some("\"def\""))).is_none()
+        );
+        // S3 gave no ETag to compare
+        assert!(stored_query_schema(&job, "parquet/job-1.parquet", &object(None)).is_none());
+        // A schema stored without its ETag can't be checked either
+        let unchecked = job_with_schema(None);
+        assert!(stored_query_schema(&unchecked, "parquet/job-1.parquet", &object(None)).is_none());
+        // Another file than the job's output
+        assert!(
+            stored_query_schema(&job, "parquet/other.parquet", &object(Some("\"abc\""))).is_none()
+        );
+    }
+
+    fn request(fields: Value) -> GenerateParquetQuery {
+        let mut body = json!({"job_id": "job-1", "message": "How many orders?"});
+        body.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(body).unwrap()
+    }
+
+    // The error a request is refused with on its first page, if it is
+    fn refusal(fields: Value) -> Option<String> {
+        request_error(&request(fields), 1, DEFAULT_PAGE_SIZE).map(|response| {
+            response_json(&response)["error"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        })
+    }
+
+    fn dataset(alias: &str, job_id: &str) -> QueryDataset {
+        QueryDataset {
+            alias: alias.to_string(),
+            job_id: job_id.to_string(),
+        }
+    }
+
+    fn principal() -> Principal {
+        Principal {
+            id: "key-1".to_string(),
+        }
+    }
+
+    fn job_item(owner: Option<&str>, status: JobStatus) -> Value {
+        let mut item = json!({
+            "service": {"S": "JOB-job-2"},
+            "serviceId": {"S": "job-2"},
+            "status": {"S": status.as_str()}
+        });
+        if let Some(owner) = owner {
+            item["created_by"] = json!({"S": owner});
+        }
+        item
+    }
+
+    async fn load(
+        item: Option<Value>,
+        dataset: Option<&str>,
+    ) -> Result<Job, ApiGatewayProxyResponse> {
+        let stub = StubEndpoint::start(move |_| match &item {
+            Some(item) => StubResponse::json(json!({"Item": item})),
+            None => StubResponse::json(json!({})),
+        });
+        load_dataset_job(
+            &stub.dynamodb_client(),
+            "jobs",
+            &principal(),
+            "job-2",
+            dataset,
+        )
+        .await
+    }
+
+    #[test]
+    fn a_plain_question_is_accepted() {
+        assert_eq!(refusal(json!({})), None);
+        assert_eq!(refusal(json!({"sql": "SELECT 1", "message": ""})), None);
+        assert_eq!(refusal(json!({"explain": true, "analyze": true})), None);
+    }
+
+    #[test]
+    fn pages_must_be_in_range_and_follow_a_stored_query() {
+        let refused = |page, page_size, fields| {
+            request_error(&request(fields), page, page_size)
+                .map(|response| response_json(&response)["error"].clone())
+        };
+
+        assert_eq!(refused(0, 10, json!({})), Some(json!("Invalid page")));
+        assert_eq!(refused(1, 0, json!({})), Some(json!("Invalid page")));
+        assert_eq!(
+            refused(1, MAX_PAGE_SIZE + 1, json!({})),
+            Some(json!("Invalid page"))
+        );
+        assert_eq!(
+            refused(2, 10, json!({})),
+            Some(json!("query_id is required for pages after the first"))
+        );
+        assert_eq!(refused(2, MAX_PAGE_SIZE, json!({"query_id": "q-1"})), None);
+    }
+
+    #[test]
+    fn options_that_contradict_each_other_are_refused() {
+        for fields in [
+            json!({"inspect": true, "sql": "SELECT 1"}),
+            json!({"inspect": true, "materialize": true}),
+            json!({"inspect": true, "datasets": [{"alias": "a", "job_id": "job-1"}]}),
+        ] {
+            assert_eq!(
+                refusal(fields).as_deref(),
+                Some("inspect can't be combined with a query")
+            );
+        }
+        assert_eq!(
+            refusal(json!({"materialize": true, "explain": true})).as_deref(),
+            Some("materialize can't be combined with explain")
+        );
+        assert_eq!(
+            refusal(json!({"analyze": true})).as_deref(),
+            Some("analyze is only allowed with explain")
+        );
+        assert_eq!(
+            refusal(json!({"sql": "SELECT 1", "query_id": "q-1"})).as_deref(),
+            Some("sql can't be combined with query_id")
+        );
+        assert_eq!(
+            refusal(json!({"sql": "SELECT 1", "approximate": true})).as_deref(),
+            Some("approximate is only for generated SQL")
+        );
+    }
+
+    #[test]
+    fn a_request_needs_a_question_or_sql() {
+        assert_eq!(
+            refusal(json!({"sql": "  "})).as_deref(),
+            Some("sql is empty")
+        );
+        assert_eq!(
+            refusal(json!({"message": " "})).as_deref(),
+            Some("message is required unless sql is given")
+        );
+        // Inspecting and paging don't ask anything new
+        assert_eq!(refusal(json!({"message": "", "inspect": true})), None);
+        assert_eq!(refusal(json!({"message": "", "query_id": "q-1"})), None);
+        assert_eq!(
+            refusal(json!({"message": "x".repeat(MAX_QUESTION_CHARS + 1)})).as_deref(),
+            Some("Question is too long")
+        );
+    }
+
+    #[test]
+    fn a_summary_language_must_be_on_the_list() {
+        assert_eq!(request_language(Some("EN")).unwrap().unwrap().tag, "en");
+
+        let details = request_language(Some("xx")).unwrap_err();
+        assert!(details.contains("en"), "{}", details);
+    }
+
+    #[test]
+    fn datasets_need_distinct_valid_aliases_and_the_requested_job() {
+        assert_eq!(dataset_error(&[], "job-1"), None);
+        assert_eq!(
+            dataset_error(
+                &[dataset("orders", "job-1"), dataset("customers", "job-2")],
+                "job-1"
+            ),
+            None
+        );
+
+        let too_many: Vec<QueryDataset> = (0..=MAX_DATASETS)
+            .map(|n| dataset(&format!("t{}", n), "job-1"))
+            .collect();
+        assert!(dataset_error(&too_many, "job-1").is_some());
+        assert!(dataset_error(&[dataset("data; DROP", "job-1")], "job-1").is_some());
+        assert!(
+            dataset_error(
+                &[dataset("orders", "job-1"), dataset("orders", "job-2")],
+                "job-1"
+            )
+            .is_some()
+        );
+        assert!(dataset_error(&[dataset("orders", "job-2")], "job-1").is_some());
+    }
+
+    #[tokio::test]
+    async fn an_owned_converted_job_is_loaded() {
+        let job = load(Some(job_item(Some("key-1"), JobStatus::Success)), None)
+            .await
+            .unwrap();
+
+        assert_eq!(job.serviceid, "job-2");
+    }
+
+    #[tokio::test]
+    async fn another_keys_dataset_is_refused() {
+        let response = load(
+            Some(job_item(Some("key-2"), JobStatus::Success)),
+            Some("customers"),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(response.status_code, 403);
+    }
+
+    #[tokio::test]
+    async fn a_dataset_without_an_owner_is_refused() {
+        let response = load(Some(job_item(None, JobStatus::Success)), Some("customers"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(response.status_code, 403);
+    }
+
+    #[tokio::test]
+    async fn a_missing_dataset_is_named() {
+        let response = load(None, Some("customers")).await.unwrap_err();
+
+        assert_eq!(response.status_code, 404);
+        assert_eq!(response_json(&response)["details"], "dataset 'customers'");
+    }
+
+    #[tokio::test]
+    async fn only_a_successful_job_is_loaded_for_a_question() {
+        for status in [
+            JobStatus::Pending,
+            JobStatus::Processing,
+            JobStatus::Success,
+            JobStatus::Failed,
+            JobStatus::Cancelled,
+        ] {
+            let loaded = load(Some(job_item(Some("key-1"), status)), None).await;
+
+            match loaded {
+                Ok(job) => assert_eq!(job.status, JobStatus::Success),
+                Err(response) => {
+                    assert_ne!(status, JobStatus::Success);
+                    assert_eq!(response.status_code, 409, "{:?}", status);
+                    let body = response_json(&response);
+                    assert_eq!(body["status"], status.as_str());
+                    let expected = match status {
+                        JobStatus::Failed => "Conversion failed",
+                        _ => "Conversion not complete",
+                    };
+                    assert_eq!(body["error"], expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_result_event_carries_the_buffered_response() {
+        let answered = create_cors_response(200, Some(json!({"rows": [1]}).to_string()));
+        let refused = create_cors_response(400, Some("not json".to_string()));
+
+        assert_eq!(
+            result_event(&answered),
+            QueryEvent::Result {
+                status_code: 200,
+                body: json!({"rows": [1]}),
+            }
+        );
+        assert_eq!(
+            result_event(&refused),
+            QueryEvent::Result {
+                status_code: 400,
+                body: json!("not json"),
+            }
+        );
+        assert_eq!(
+            result_event(&create_cors_response(204, None)),
+            QueryEvent::Result {
+                status_code: 204,
+                body: Value::Null,
+            }
+        );
+    }
+
+    #[test]
+    fn a_stream_opens_as_an_event_stream_with_cors_headers() {
+        let prelude = stream_prelude();
+
+        assert_eq!(prelude.status_code, http::StatusCode::OK);
+        assert_eq!(prelude.headers["content-type"], "text/event-stream");
+        assert_eq!(prelude.headers["cache-control"], "no-cache");
+        assert_eq!(prelude.headers["access-control-allow-origin"], "*");
+    }
+
+    #[test]
+    fn a_users_copy_is_refused_before_it_runs() {
+        let mut metrics = MetricsLogger::new(METRICS_FUNCTION_NAME, "job-1");
+        for (sql, construct) in [
+            ("COPY data TO '/tmp/out.csv'", "COPY statement"),
+            (
+                "  copy data to 's3://bucket/out.parquet' (FORMAT PARQUET);",
+                "COPY statement",
+            ),
+            (
+                "SELECT * FROM data; COPY data TO '/tmp/out.csv'",
+                "multiple statements",
+            ),
+            (
+                "WITH x AS (COPY data TO '/tmp/out.csv') SELECT * FROM x",
+                "COPY",
+            ),
+        ] {
+            let response = rejected_sql_response(sql, &["data"], true, "job-1", &mut metrics)
+                .unwrap_or_else(|| panic!("{} was let through", sql));
+
+            assert_eq!(response.status_code, 422, "{}", sql);
+            let body = response_json(&response);
+            assert_eq!(body["error"], "Query is not a read-only SELECT", "{}", sql);
+            assert_eq!(body["construct"], construct, "{}", sql);
+        }
+
+        // The same query from the model is refused in the model's name
+        let generated = rejected_sql_response(
+            "COPY data TO '/tmp/out.csv'",
+            &["data"],
+            false,
+            "job-1",
+            &mut metrics,
+        )
+        .unwrap();
+        assert_eq!(
+            response_json(&generated)["error"],
+            "Generated query is not a read-only SELECT"
+        );
+    }
+
+    // Two jobs' outputs as S3 serves them: a HEAD gives the size and ETag, a GET the file
+    fn parquet_fixture(dir: &std::path::Path, name: &str, select: &str) -> Vec<u8> {
+        let path = dir.join(name);
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "COPY ({}) TO '{}' (FORMAT PARQUET);",
+            select,
+            path.display()
+        ))
+        .unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    fn joined_source(alias: &str, job_id: &str) -> DatasetSource {
+        let parquet_key = format!("parquet/{}.parquet", job_id);
+        DatasetSource {
+            alias: alias.to_string(),
+            job: Job {
+                serviceid: job_id.to_string(),
+                ..job_with_output(Some(&parquet_key))
+            },
+            parquet_key,
+        }
+    }
+
+    #[tokio::test]
+    async fn a_question_joins_two_jobs_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = std::collections::HashMap::from([
+            (
+                "/outputs/parquet/job-sales.parquet".to_string(),
+                parquet_fixture(
+                    dir.path(),
+                    "sales.parquet",
+                    "SELECT * FROM (VALUES (1, 10.0), (1, 5.5), (2, 7.0)) t(store_id, amount)",
+                ),
+            ),
+            (
+                "/outputs/parquet/job-stores.parquet".to_string(),
+                parquet_fixture(
+                    dir.path(),
+                    "stores.parquet",
+                    "SELECT * FROM (VALUES (1, 'North'), (2, 'South')) t(store_id, name)",
+                ),
+            ),
+        ]);
+        let s3 = StubEndpoint::start(move |request| {
+            let key = request.target.split('?').next().unwrap_or_default();
+            let Some(body) = files.get(key) else {
+                return StubResponse::bytes(404, Vec::new());
+            };
+            let etag = format!("\"{}\"", body.len());
+            if request.method == "HEAD" {
+                StubResponse::bytes(200, Vec::new())
+                    .with_header("Content-Length", body.len().to_string())
+                    .with_header("ETag", etag)
+            } else {
+                StubResponse::bytes(200, body.clone()).with_header("ETag", etag)
+            }
+        });
+        // Column stats are stored once computed
+        let dynamodb = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let cache = ParquetCache::new(dir.path().join("cache"), 1024 * 1024);
+        let mut metrics = MetricsLogger::new(METRICS_FUNCTION_NAME, "job-sales");
+
+        let (conn, _) = warm_connection(&["sales", "stores"]).unwrap();
+        let mut s3_access = None;
+        let mut datasets = Vec::new();
+        for source in [
+            joined_source("sales", "job-sales"),
+            joined_source("stores", "job-stores"),
+        ] {
+            let opened = open_dataset(
+                &conn,
+                &s3.s3_client(),
+                &cache,
+                "outputs",
+                source,
+                &mut s3_access,
+                &mut metrics,
+            )
+            .await;
+            datasets.push(opened.map_err(|response| response_json(&response)).unwrap());
+        }
+
+        let tables = describe_datasets(
+            &conn,
+            &dynamodb.dynamodb_client(),
+            "jobs",
+            &datasets,
+            true,
+            false,
+            &mut metrics,
+        )
+        .await
+        .map_err(|response| response_json(&response))
+        .unwrap();
+        let lines: Vec<&str> = tables.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("table sales, schema: "), "{}", tables);
+        assert!(lines[1].starts_with("table stores, schema: "), "{}", tables);
+        assert!(lines[1].contains("name"), "{}", tables);
+
+        let aliases = ["sales", "stores"];
+        let sql = "SELECT s.name, SUM(f.amount) AS total FROM sales f \
+                   JOIN stores s ON s.store_id = f.store_id GROUP BY s.name ORDER BY s.name";
+        assert!(rejected_sql_response(sql, &aliases, false, "job-sales", &mut metrics).is_none());
+        let totals: Vec<(String, f64)> = conn
+            .prepare(sql)
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            totals,
+            [("North".to_string(), 15.5), ("South".to_string(), 7.0)]
+        );
+
+        // Only the registered tables can be joined
+        let rejected = rejected_sql_response(
+            "SELECT * FROM sales JOIN orders USING (store_id)",
+            &aliases,
+            false,
+            "job-sales",
+            &mut metrics,
+        )
+        .unwrap();
+        assert_eq!(rejected.status_code, 422);
+    }
+
+    fn sql_models() -> BedrockModels {
+        let inference = common::bedrock::InferenceSettings {
+            temperature: 0.0,
+            top_p: None,
+            max_tokens: 256,
+            stop_sequences: Vec::new(),
+        };
+        BedrockModels {
+            sql: vec!["model-a".to_string()],
+            summary: vec!["model-a".to_string()],
+            prices: std::collections::HashMap::new(),
+            sql_inference: inference.clone(),
+            summary_inference: inference,
+        }
+    }
+
+    fn bedrock_answering(text: &'static str) -> StubEndpoint {
+        StubEndpoint::start(move |_| {
+            StubResponse::json(json!({
+                "output": {"message": {"role": "assistant", "content": [{"text": text}]}},
+                "stopReason": "end_turn",
+                "usage": {"inputTokens": 10, "outputTokens": 5, "totalTokens": 15},
+                "metrics": {"latencyMs": 100}
+            }))
+        })
+    }
+
+    // A connection with `data` holding ids and amounts, and the bind error `failed_sql` gets
+    // on it
+    fn failed_bind(failed_sql: &str) -> (duckdb::Connection, common::error::Error) {
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE VIEW data AS SELECT * FROM (VALUES (1, 2.5), (2, 4.0)) t(id, amount);",
+        )
+        .unwrap();
+        let error = query_column_types(&conn, failed_sql).unwrap_err();
+        (conn, error)
+    }
+
+    async fn repair(
+        bedrock: &StubEndpoint,
+        conn: &duckdb::Connection,
+        failed_sql: &str,
+        error: &common::error::Error,
+        draft: &mut AuditDraft,
+    ) -> Result<(String, String, Vec<(String, String)>), Value> {
+        let mut metrics = MetricsLogger::new(METRICS_FUNCTION_NAME, "job-1");
+        repair_sql(
+            conn,
+            &bedrock.bedrock_client(),
+            &sql_models(),
+            "system",
+            &sql_prompt("schema: id BIGINT, amount DOUBLE", "What is the total?"),
+            failed_sql,
+            error,
+            &["data"],
+            "job-1",
+            &mut metrics,
+            draft,
+        )
+        .await
+        .map_err(|response| {
+            let mut body = response_json(&response);
+            body["status"] = json!(response.status_code);
+            body
+        })
+    }
+
+    #[tokio::test]
+    async fn sql_that_fails_to_bind_is_repaired_once() {
+        let bedrock = bedrock_answering("```sql\nSELECT SUM(amount) AS total FROM data\n```");
+        let (conn, error) = failed_bind("SELECT SUM(total) FROM data");
+        let mut draft = AuditDraft::default();
+
+        let (sql, model, column_types) = repair(
+            &bedrock,
+            &conn,
+            "SELECT SUM(total) FROM data",
+            &error,
+            &mut draft,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sql, "SELECT SUM(amount) AS total FROM data");
+        assert_eq!(model, "model-a");
+        assert_eq!(column_types.len(), 1);
+        assert_eq!(column_types[0].0, "total");
+        assert_eq!(draft.audit.sql.as_deref(), Some(sql.as_str()));
+        assert_eq!(
+            draft.audit.failed_sql.as_deref(),
+            Some("SELECT SUM(total) FROM data")
+        );
+
+        // The model is shown its query and DuckDB's error, each in a section of its own
+        let calls = bedrock.requests();
+        assert_eq!(calls.len(), 1);
+        let prompt = calls[0].json()["messages"][0]["content"][0]["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(
+            prompt.contains("<question>\nWhat is the total?\n</question>"),
+            "{}",
+            prompt
+        );
+        assert!(
+            prompt.contains("<failed_sql>\nSELECT SUM(total) FROM data\n</failed_sql>"),
+            "{}",
+            prompt
+        );
+        assert!(prompt.contains("<error>\n"), "{}", prompt);
+        assert!(prompt.contains("total"), "{}", prompt);
+    }
+
+    #[tokio::test]
+    async fn a_repair_that_also_fails_to_bind_is_not_repaired_again() {
+        let bedrock = bedrock_answering("SELECT SUM(totals) FROM data");
+        let (conn, error) = failed_bind("SELECT SUM(total) FROM data");
+
+        let body = repair(
+            &bedrock,
+            &conn,
+            "SELECT SUM(total) FROM data",
+            &error,
+            &mut AuditDraft::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(body["status"], 422);
+        assert_eq!(body["error"], "Generated query could not be run");
+        assert_eq!(body["sql"], "SELECT SUM(totals) FROM data");
+        assert_eq!(body["failed_sql"], "SELECT SUM(total) FROM data");
+        assert_eq!(bedrock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_repair_is_held_to_the_read_only_guard() {
+        let bedrock =
+            bedrock_answering("SELECT * FROM data WHERE id IN (DELETE FROM data RETURNING id)");
+        let (conn, error) = failed_bind("SELECT SUM(total) FROM data");
+
+        let body = repair(
+            &bedrock,
+            &conn,
+            "SELECT SUM(total) FROM data",
+            &error,
+            &mut AuditDraft::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(body["status"], 422);
+        assert_eq!(body["error"], "Generated query is not a read-only SELECT");
+        assert_eq!(body["construct"], "DELETE");
+    }
+
+    // The job's output registered as `data` on a connection of its own, as a dataset. The
+    // object has no ETag, so its column stats are computed but never stored.
+    fn local_dataset(dir: &std::path::Path) -> (duckdb::Connection, Dataset) {
+        let bytes = parquet_fixture(
+            dir,
+            "data.parquet",
+            "SELECT * FROM (VALUES ('north', 10), ('north', 5), ('south', 7)) t(region, amount)",
+        );
+        let file_path = dir.join("data.parquet").to_string_lossy().into_owned();
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        register_parquet_view(&conn, PARQUET_VIEW_NAME, &file_path).unwrap();
+        let dataset = Dataset {
+            alias: PARQUET_VIEW_NAME.to_string(),
+            job: Job {
+                serviceid: "job-1".to_string(),
+                ..job_with_output(Some("parquet/job-1.parquet"))
+            },
+            parquet_key: "parquet/job-1.parquet".to_string(),
+            object: SourceObject {
+                bytes: bytes.len() as i64,
+                etag: None,
+            },
+            file_path,
+            query_in_place: false,
+        };
+        (conn, dataset)
+    }
+
+    // The SQL `answer_query` would run for `request`, or the response refusing it
+    async fn sql_for(
+        request: &GenerateParquetQuery,
+        bedrock: &StubEndpoint,
+        conn: &duckdb::Connection,
+        dataset: &Dataset,
+    ) -> Result<(String, Option<String>), ApiGatewayProxyResponse> {
+        let dynamodb = StubEndpoint::start(|_| StubResponse::json(json!({})));
+        let mut metrics = MetricsLogger::new(METRICS_FUNCTION_NAME, "job-1");
+        let (sql, model_used, _) = query_sql(
+            conn,
+            &dynamodb.dynamodb_client(),
+            "jobs",
+            &bedrock.bedrock_client(),
+            &sql_models(),
+            &sql_system_prompt(None),
+            request,
+            None,
+            std::slice::from_ref(dataset),
+            false,
+            false,
+            &mut metrics,
+            &QueryEvents::buffered(),
+            &mut AuditDraft::default(),
+        )
+        .await?;
+        let user_authored = request.sql.is_some();
+        match rejected_sql_response(&sql, &["data"], user_authored, "job-1", &mut metrics) {
+            Some(response) => Err(response),
+            None => Ok((sql, model_used)),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_users_sql_cannot_run_sql_hidden_in_a_string() {
+        let dir = tempfile::tempdir().unwrap();
+        let (conn, dataset) = local_dataset(dir.path());
+        let bedrock = bedrock_answering("SELECT 1");
+        let payload = "SELECT * FROM json_execute_serialized_sql(json_serialize_sql(\
+                       'SELECT content FROM read_text(''/proc/self/environ'')'))";
+
+        let response = sql_for(&request(json!({"sql": payload})), &bedrock, &conn, &dataset)
+            .await
+            .unwrap_err();
+
+        assert_eq!(response.status_code, 422);
+        let body = response_json(&response);
+        assert_eq!(body["error"], "Query is not a read-only SELECT");
+        assert_eq!(body["construct"], "json_execute_serialized_sql()");
+        assert!(bedrock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_users_sql_answers_as_the_same_generated_sql_would() {
+        const SQL: &str =
+            "SELECT region, SUM(amount) AS total FROM data GROUP BY region ORDER BY region";
+        let dir = tempfile::tempdir().unwrap();
+        let (conn, dataset) = local_dataset(dir.path());
+        let bedrock = bedrock_answering(
+            "Here it is:\n```sql\nSELECT region, SUM(amount) AS total\nFROM data\nGROUP BY region\nORDER BY region;\n```",
+        );
+
+        let user = sql_for(
+            &request(json!({"sql": format!("  {}\n", SQL)})),
+            &bedrock,
+            &conn,
+            &dataset,
+        )
+        .await
+        .unwrap();
+        assert!(bedrock.requests().is_empty());
+        let generated = sql_for(&request(json!({})), &bedrock, &conn, &dataset)
+            .await
+            .unwrap();
+        assert_eq!(bedrock.requests().len(), 1);
+
+        assert_eq!(user, (SQL.to_string(), None));
+        assert_eq!(generated, (SQL.to_string(), Some("model-a".to_string())));
+        let answer = |sql: &str| {
+            let capped = with_row_limit(sql, max_result_rows());
+            let page = execute_sql_page(&conn, &capped, 10, 0, max_result_bytes()).unwrap();
+            (
+                query_column_types(&conn, sql).unwrap(),
+                page.columns,
+                page.rows,
+            )
+        };
+        let (column_types, columns, rows) = answer(&user.0);
+        assert_eq!(
+            answer(&generated.0),
+            (column_types.clone(), columns, rows.clone())
+        );
+        assert_eq!(column_types[0].0, "region");
+        assert_eq!(
+            rows,
+            [[json!("north"), json!(15)], [json!("south"), json!(7)]]
+        );
+    }
 }
@@ -0,0 +1,411 @@
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_lambda_events::query_map::QueryMap;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use common::auth::{Principal, authorize};
+use common::cors::create_cors_response;
+use common::dynamo::{JobFilter, JobStatus, LabelFilter, MAX_LIST_JOBS_LIMIT, list_jobs};
+use common::error::Error as CommonError;
+use common::logging::init_tracing;
+use lambda_runtime::{Error, LambdaEvent, run, service_fn};
+use serde_json::{Value, json};
+use std::str::FromStr;
+use tracing::error;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    init_tracing();
+
+    run(service_fn(function_handler)).await
+}
+
+// The caller's jobs, newest first, a page at a time. `status`, `from`, `to` and `label`
+// narrow the listing; `cursor` comes from the previous page's `next_cursor` and only
+// continues a listing with the same filters.
+async fn function_handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if event.payload.http_method == "OPTIONS" {
+        return Ok(create_cors_response(200, None));
+    }
+
+    let table_name = std::env::var("DYNAMODB_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let dynamodb_client = DynamoDbClient::new(&config);
+
+    let principal = match authorize(&event.payload, &dynamodb_client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    Ok(list_page(
+        &dynamodb_client,
+        &table_name,
+        &principal,
+        &event.payload.query_string_parameters,
+    )
+    .await)
+}
+
+async fn list_page(
+    dynamodb_client: &DynamoDbClient,
+    table_name: &str,
+    principal: &Principal,
+    query: &QueryMap,
+) -> ApiGatewayProxyResponse {
+    let filter = match parse_filter(query, &principal.id) {
+        Ok(filter) => filter,
+        Err(details) => return bad_request("Invalid filter", &details),
+    };
+
+    let limit = match query.first("limit").map(|limit| limit.parse::<i32>()) {
+        None => None,
+        Some(Ok(limit)) if (1..=MAX_LIST_JOBS_LIMIT).contains(&limit) => Some(limit),
+        Some(_) => {
+            return bad_request(
+                "Invalid limit",
+                &format!("limit must be between 1 and {}", MAX_LIST_JOBS_LIMIT),
+            );
+        }
+    };
+    let cursor = query.first("cursor").filter(|cursor| !cursor.is_empty());
+
+    let page = match list_jobs(dynamodb_client, table_name, &filter, cursor, limit).await {
+        Ok(page) => page,
+        Err(CommonError::InvalidCursor(reason)) => return bad_request("Invalid cursor", reason),
+        Err(e) => {
+            error!(principal = %principal.id, error = %e, "Failed to list jobs");
+            return create_cors_response(
+                500,
+                Some(json!({"error": "Failed to list jobs"}).to_string()),
+            );
+        }
+    };
+
+    let jobs: Vec<Value> = page
+        .jobs
+        .iter()
+        .map(|job| {
+            json!({
+                "job_id": job.serviceid,
+                "status": job.status,
+                "created_at": job.created_at,
+                "updated_at": job.updated_at,
+                "completed_at": job.completed_at,
+                "original_filename": job.provenance.original_filename,
+                "labels": job.provenance.labels,
+                "row_count": job.row_count,
+                "error_message": job.error_message
+            })
+        })
+        .collect();
+
+    let response_body = json!({
+        "jobs": jobs,
+        "next_cursor": page.next_cursor,
+        "filters": applied_filters(&filter),
+        "returned_count": jobs.len(),
+        "scanned_count": page.scanned_count
+    });
+
+    create_cors_response(200, Some(response_body.to_string()))
+}
+
+fn bad_request(error: &str, details: &str) -> ApiGatewayProxyResponse {
+    create_cors_response(
+        400,
+        Some(json!({"error": error, "details": details}).to_string()),
+    )
+}
+
+// The listing the query string asks for, always limited to the caller's own jobs
+fn parse_filter(query: &QueryMap, owner: &str) -> Result<JobFilter, String> {
+    let param = |name: &str| query.first(name).filter(|value| !value.trim().is_empty());
+
+    let status = param("status")
+        .map(|status| {
+            JobStatus::from_str(status).map_err(|_| {
+                let known: Vec<&str> = JobStatus::ALL.iter().map(|s| s.as_str()).collect();
+                format!("status must be one of {}", known.join(", "))
+            })
+        })
+        .transpose()?;
+    let created_after = param("from")
+        .map(|from| parse_bound("from", from, false))
+        .transpose()?;
+    let created_before = param("to")
+        .map(|to| parse_bound("to", to, true))
+        .transpose()?;
+    if let (Some(from), Some(to)) = (created_after, created_before)
+        && from > to
+    {
+        return Err("from must not be later than to".to_string());
+    }
+    let label = param("label")
+        .map(|label| {
+            LabelFilter::parse(label).ok_or_else(|| "label must be key or key:value".to_string())
+        })
+        .transpose()?;
+
+    Ok(JobFilter {
+        status,
+        created_by: Some(owner.to_string()),
+        created_after,
+        created_before,
+        label,
+    })
+}
+
+// An RFC 3339 time, or a date standing for the whole day: its start for `from` and its
+// last millisecond for `to`, so `from=2025-06-02&to=2025-06-08` covers both end days
+fn parse_bound(name: &str, text: &str, end_of_day: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map_err(|_| format!("{} must be an RFC 3339 time or a YYYY-MM-DD date", name))?;
+    let time = if end_of_day {
+        date.and_hms_milli_opt(23, 59, 59, 999)
+    } else {
+        date.and_hms_opt(0, 0, 0)
+    };
+    Ok(time
+        .expect("midnight and the day's last millisecond are valid times")
+        .and_utc())
+}
+
+// Echoed back so a client can show which filters a page was read with
+fn applied_filters(filter: &JobFilter) -> Value {
+    let bound = |time: Option<DateTime<Utc>>| {
+        time.map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true))
+    };
+    json!({
+        "status": filter.status.map(|status| status.as_str()),
+        "from": bound(filter.created_after),
+        "to": bound(filter.created_before),
+        "label": filter.label.as_ref().map(|label| match &label.value {
+            Some(value) => format!("{}:{}", label.key, value),
+            None => label.key.clone(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::test_support::{StubEndpoint, StubResponse};
+    use std::collections::HashMap;
+
+    fn query(params: &[(&str, &str)]) -> QueryMap {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<String, String>>()
+            .into()
+    }
+
+    fn principal() -> Principal {
+        Principal {
+            id: "key-1".to_string(),
+        }
+    }
+
+    fn job_item(id: &str, status: &str) -> Value {
+        json!({
+            "service": {"S": format!("JOB-{}", id)},
+            "serviceId": {"S": id},
+            "entity": {"S": "JOB"},
+            "created_at": {"S": "2025-06-03T10:00:00.000Z"},
+            "created_by": {"S": "key-1"},
+            "status": {"S": status}
+        })
+    }
+
+    fn body(response: &ApiGatewayProxyResponse) -> Value {
+        match &response.body {
+            Some(aws_lambda_events::encodings::Body::Text(text)) => {
+                serde_json::from_str(text).unwrap()
+            }
+            other => panic!("unexpected body: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn status_and_dates_combine_into_one_filter() {
+        let filter = parse_filter(
+            &query(&[
+                ("status", "failed"),
+                ("from", "2025-06-02"),
+                ("to", "2025-06-08"),
+                ("label", "project:q3"),
+            ]),
+            "key-1",
+        )
+        .unwrap();
+
+        assert_eq!(filter.status, Some(JobStatus::Failed));
+        assert_eq!(filter.created_by.as_deref(), Some("key-1"));
+        assert_eq!(
+            applied_filters(&filter),
+            json!({
+                "status": "failed",
+                "from": "2025-06-02T00:00:00.000Z",
+                "to": "2025-06-08T23:59:59.999Z",
+                "label": "project:q3"
+            })
+        );
+    }
+
+    #[test]
+    fn an_empty_query_lists_all_of_the_callers_jobs() {
+        let filter = parse_filter(&query(&[("status", "")]), "key-1").unwrap();
+
+        assert_eq!(
+            filter,
+            JobFilter {
+                created_by: Some("key-1".to_string()),
+                ..JobFilter::default()
+            }
+        );
+    }
+
+    #[test]
+    fn times_keep_their_offset() {
+        let filter =
+            parse_filter(&query(&[("from", "2025-06-02T10:00:00+02:00")]), "key-1").unwrap();
+
+        assert_eq!(applied_filters(&filter)["from"], "2025-06-02T08:00:00.000Z");
+    }
+
+    #[test]
+    fn bad_filters_are_refused() {
+        for params in [
+            vec![("status", "done")],
+            vec![("from", "last week")],
+            vec![("to", "2025-13-01")],
+            vec![("from", "2025-06-09"), ("to", "2025-06-08")],
+            vec![("label", ":q3")],
+        ] {
+            assert!(
+                parse_filter(&query(&params), "key-1").is_err(),
+                "{:?}",
+                params
+            );
+        }
+        // A single day is a valid range
+        assert!(
+            parse_filter(
+                &query(&[("from", "2025-06-08"), ("to", "2025-06-08")]),
+                "key-1"
+            )
+            .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_filtered_page_reports_what_it_applied_and_read() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::json(json!({
+                "Items": [job_item("job-1", "failed")],
+                "ScannedCount": 40
+            }))
+        });
+
+        let response = list_page(
+            &stub.dynamodb_client(),
+            "jobs",
+            &principal(),
+            &query(&[("status", "failed"), ("from", "2025-06-02")]),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 200);
+        let body = body(&response);
+        assert_eq!(body["jobs"][0]["job_id"], "job-1");
+        assert_eq!(body["filters"]["status"], "failed");
+        assert_eq!(body["returned_count"], 1);
+        assert_eq!(body["scanned_count"], 40);
+        assert_eq!(body["next_cursor"], Value::Null);
+
+        let request = stub.operations("Query").remove(0).json();
+        assert_eq!(
+            request["KeyConditionExpression"],
+            "entity = :entity AND created_at >= :after"
+        );
+        assert_eq!(
+            request["FilterExpression"],
+            "#status = :status AND created_by = :created_by"
+        );
+        assert_eq!(
+            request["ExpressionAttributeValues"][":created_by"]["S"],
+            "key-1"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_cursor_only_continues_the_listing_with_the_same_filters() {
+        let stub = StubEndpoint::start(|request| {
+            if request.json()["ExclusiveStartKey"].is_null() {
+                StubResponse::json(json!({
+                    "Items": [job_item("job-2", "failed")],
+                    "ScannedCount": 3,
+                    "LastEvaluatedKey": {
+                        "service": {"S": "JOB-job-2"},
+                        "serviceId": {"S": "job-2"},
+                        "entity": {"S": "JOB"},
+                        "created_at": {"S": "2025-06-03T10:00:00.000Z"}
+                    }
+                }))
+            } else {
+                StubResponse::json(json!({
+                    "Items": [job_item("job-1", "failed")],
+                    "ScannedCount": 2
+                }))
+            }
+        });
+        let client = stub.dynamodb_client();
+        let filters = [("status", "failed"), ("limit", "1")];
+
+        let first = body(&list_page(&client, "jobs", &principal(), &query(&filters)).await);
+        let cursor = first["next_cursor"].as_str().unwrap().to_string();
+        let second = list_page(
+            &client,
+            "jobs",
+            &principal(),
+            &query(&[filters[0], filters[1], ("cursor", &cursor)]),
+        )
+        .await;
+        let changed = list_page(
+            &client,
+            "jobs",
+            &principal(),
+            &query(&[("status", "success"), ("cursor", &cursor)]),
+        )
+        .await;
+
+        assert_eq!(first["jobs"][0]["job_id"], "job-2");
+        let second = body(&second);
+        assert_eq!(second["jobs"][0]["job_id"], "job-1");
+        assert_eq!(second["next_cursor"], Value::Null);
+        assert_eq!(changed.status_code, 400);
+        assert_eq!(body(&changed)["error"], "Invalid cursor");
+        // The refused cursor never reached the table
+        assert_eq!(stub.operations("Query").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn an_out_of_range_limit_is_refused() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({"Items": []})));
+
+        let response = list_page(
+            &stub.dynamodb_client(),
+            "jobs",
+            &principal(),
+            &query(&[("limit", "0")]),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 400);
+        assert!(stub.requests().is_empty());
+    }
+}
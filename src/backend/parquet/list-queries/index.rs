@@ -0,0 +1,115 @@
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use common::auth::{authorize, may_access_job};
+use common::cors::create_cors_response;
+use common::dynamo::{get_job_by_id, list_query_audit};
+use common::logging::init_tracing;
+use lambda_runtime::{Error, LambdaEvent, run, service_fn};
+use serde_json::json;
+use tracing::{error, info};
+
+const DEFAULT_PAGE_LIMIT: i32 = 25;
+const MAX_PAGE_LIMIT: i32 = 100;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    init_tracing();
+
+    run(service_fn(function_handler)).await
+}
+
+// A job's query history, newest first, a page at a time. `cursor` comes from the previous
+// page's `next_cursor`.
+async fn function_handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if event.payload.http_method == "OPTIONS" {
+        return Ok(create_cors_response(200, None));
+    }
+
+    let table_name = std::env::var("DYNAMODB_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let dynamodb_client = DynamoDbClient::new(&config);
+
+    let principal = match authorize(&event.payload, &dynamodb_client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    let job_id = match event.payload.path_parameters.get("job_id") {
+        Some(id) => id,
+        None => {
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Missing job_id in path"}).to_string()),
+            ));
+        }
+    };
+
+    let query = &event.payload.query_string_parameters;
+    let limit = match query.first("limit").map(|limit| limit.parse::<i32>()) {
+        None => DEFAULT_PAGE_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_PAGE_LIMIT).contains(&limit) => limit,
+        Some(_) => {
+            return Ok(create_cors_response(
+                400,
+                Some(
+                    json!({
+                        "error": "Invalid limit",
+                        "details": format!("limit must be between 1 and {}", MAX_PAGE_LIMIT)
+                    })
+                    .to_string(),
+                ),
+            ));
+        }
+    };
+    let cursor = query.first("cursor").filter(|cursor| !cursor.is_empty());
+
+    let job = match get_job_by_id(&dynamodb_client, &table_name, job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            return Ok(create_cors_response(
+                404,
+                Some(json!({"error": "Job not found"}).to_string()),
+            ));
+        }
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "Failed to load job");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            ));
+        }
+    };
+
+    if !may_access_job(&principal, job.created_by.as_deref(), job_id) {
+        info!(
+            job_id = %job_id,
+            principal = %principal.id,
+            "Rejected query history request for a job owned by another principal"
+        );
+        return Ok(create_cors_response(
+            403,
+            Some(json!({"error": "Job belongs to a different API key"}).to_string()),
+        ));
+    }
+
+    let page = match list_query_audit(&dynamodb_client, &table_name, job_id, limit, cursor).await {
+        Ok(page) => page,
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "Failed to list queries");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Failed to list queries"}).to_string()),
+            ));
+        }
+    };
+
+    let response_body = json!({
+        "job_id": job_id,
+        "queries": page.records,
+        "next_cursor": page.next_cursor
+    });
+
+    Ok(create_cors_response(200, Some(response_body.to_string())))
+}
@@ -1,15 +1,139 @@
 use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
 use aws_sdk_dynamodb::Client;
-use common::cors::create_cors_response;
+use common::auth::{Principal, authorize, may_access_job};
+use common::cors::{create_cors_response, create_cors_response_with_headers};
+use common::dynamo::{Job, JobStatus, batch_get_jobs};
+use common::logging::init_tracing;
+use common::s3::presign_get_url;
 use lambda_runtime::{Error, LambdaEvent, run, service_fn};
-use serde_json::json;
-use std::collections::HashMap;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tracing::{error, info};
+
+// How long a download link stays valid unless DOWNLOAD_URL_EXPIRY_SECONDS says otherwise
+const DEFAULT_DOWNLOAD_URL_EXPIRY_SECONDS: u64 = 15 * 60;
+
+// SigV4 presigned URLs can't outlive a week
+const MAX_DOWNLOAD_URL_EXPIRY_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// Enough for a page of the bulk upload view; DynamoDB reads are chunked below this anyway
+const MAX_BATCH_POLL_JOBS: usize = 50;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    init_tracing();
+
     run(service_fn(function_handler)).await
 }
 
+// Whether a job's parquet is ready to query. `parquet_complete` predates `status` and is
+// kept for clients that only read it.
+fn parquet_complete(status: JobStatus) -> bool {
+    status == JobStatus::Success
+}
+
+fn download_url_expiry_seconds() -> u64 {
+    std::env::var("DOWNLOAD_URL_EXPIRY_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_DOWNLOAD_URL_EXPIRY_SECONDS)
+        .min(MAX_DOWNLOAD_URL_EXPIRY_SECONDS)
+}
+
+// Adds a presigned `download_url` for the job's output. Jobs that finished before outputs
+// were recorded get none, and a presigning failure leaves the link out rather than failing
+// the poll.
+async fn add_download_link(response_body: &mut Value, job: &Job, job_id: &str) {
+    let (Some(bucket), Some(key)) = (&job.output_bucket, &job.output_key) else {
+        return;
+    };
+    response_body["output_bucket"] = json!(bucket);
+    response_body["output_key"] = json!(key);
+
+    let expiry_seconds = download_url_expiry_seconds();
+    let url = match presign_get_url(bucket, key, Duration::from_secs(expiry_seconds)).await {
+        Ok(url) => url,
+        Err(e) => {
+            error!(job_id, error = %e, "Failed to presign job output");
+            return;
+        }
+    };
+
+    response_body["download_url"] = json!(url);
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(expiry_seconds as i64);
+    response_body["expires_at"] = json!(expires_at.to_rfc3339());
+}
+
+// Weak validator for a job's poll response. Every write to a job item bumps updated_at, so
+// the pair only stays the same while nothing the poller reports has changed. Jobs written
+// before updated_at existed get none and are always sent in full.
+fn job_etag(job: &Job) -> Option<String> {
+    let updated_at = job.updated_at.as_deref()?;
+    let digest = Sha256::digest(format!("{}|{}", job.status, updated_at).as_bytes());
+    let tag: String = digest
+        .iter()
+        .take(12)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Some(format!("W/\"{}\"", tag))
+}
+
+// If-None-Match uses weak comparison, so a strong form of the same tag matches too
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == opaque(etag))
+}
+
+// The fields reported for a job, shared by the single and batch polls
+async fn status_payload(job: &Job, job_id: &str) -> Value {
+    let mut response_body = json!({
+        "statusCode": 200,
+        "status": job.status,
+        "parquet_complete": parquet_complete(job.status),
+        "context": job.context,
+        "schema": job.schema,
+        "attempts": job.attempts,
+        "progress_percent": job.progress_percent,
+        "rows_processed": job.rows_processed,
+        "row_count": job.row_count,
+        "output_bytes": job.output_bytes,
+        "unmatched_columns": job.unmatched_columns,
+        "ignored_columns": job.ignored_columns,
+        "header_problems": job.header_problems,
+        "column_stats": job.column_stats,
+        "memory_high_water_bytes": job.memory_high_water_bytes,
+        "original_filename": job.provenance.original_filename,
+        "submitted_by": job.provenance.submitted_by,
+        "labels": job.provenance.labels,
+        "parent_job_id": job.parent_job_id,
+        "derived_sql": job.derived_sql,
+        "start_after": job.start_after,
+        "created_at": job.created_at,
+        "updated_at": job.updated_at,
+        "started_at": job.started_at,
+        "completed_at": job.completed_at,
+        "heartbeat_at": job.heartbeat_at
+    });
+
+    if job.status == JobStatus::Failed {
+        response_body["error_message"] =
+            json!(job.error_message.as_deref().unwrap_or("Conversion failed"));
+        response_body["error_stage"] = json!(job.error_stage);
+    }
+
+    // Only a finished job has output worth handing out
+    if job.status == JobStatus::Success {
+        add_download_link(&mut response_body, job, job_id).await;
+    }
+
+    response_body
+}
+
 async fn function_handler(
     event: LambdaEvent<ApiGatewayProxyRequest>,
 ) -> Result<ApiGatewayProxyResponse, Error> {
@@ -17,27 +141,45 @@ async fn function_handler(
         return Ok(create_cors_response(200, None));
     }
 
-    let job_id = match event.payload.path_parameters.get("job_id") {
+    let table_name = std::env::var("DYNAMODB_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let client = Client::new(&config);
+
+    let principal = match authorize(&event.payload, &client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    if event.payload.http_method == "POST" {
+        return Ok(poll_batch(&client, &event.payload, &table_name, &principal).await);
+    }
+
+    Ok(poll_job(&client, &event.payload, &table_name, &principal).await)
+}
+
+// Reports one job, answering 304 while an If-None-Match still matches its status
+async fn poll_job(
+    client: &Client,
+    payload: &ApiGatewayProxyRequest,
+    table_name: &str,
+    principal: &Principal,
+) -> ApiGatewayProxyResponse {
+    let job_id = match payload.path_parameters.get("job_id") {
         Some(id) => id,
         None => {
-            return Ok(create_cors_response(
+            return create_cors_response(
                 400,
                 Some(json!({"error": "Missing job_id in path"}).to_string()),
-            ));
+            );
         }
     };
 
-    let config = aws_config::load_from_env().await;
-    let client = Client::new(&config);
-
-    let table_name = std::env::var("DYNAMODB_NAME")?;
-
     let pk = format!("JOB-{}", job_id);
     let sk = job_id.clone();
 
     let result = client
         .get_item()
-        .table_name(&table_name)
+        .table_name(table_name)
         .key("service", aws_sdk_dynamodb::types::AttributeValue::S(pk))
         .key("serviceId", aws_sdk_dynamodb::types::AttributeValue::S(sk))
         .send()
@@ -46,73 +188,574 @@ async fn function_handler(
     match result {
         Ok(output) => match output.item {
             Some(item) => {
-                let status = match item.get("status") {
-                    Some(aws_sdk_dynamodb::types::AttributeValue::S(status_value)) => {
-                        status_value.as_str()
-                    }
-                    _ => {
-                        return Ok(create_cors_response(
+                let job = match Job::from_dynamodb_item(item) {
+                    Ok(job) => job,
+                    Err(e) => {
+                        error!(job_id = %job_id, error = %e, "Job item is malformed");
+                        return create_cors_response(
                             500,
                             Some(
                                 json!({"error": "Status field not found or invalid type"})
                                     .to_string(),
                             ),
-                        ));
+                        );
                     }
                 };
-
-                let context = match item.get("context") {
-                    Some(aws_sdk_dynamodb::types::AttributeValue::S(context_value)) => {
-                        context_value.clone()
+                if !may_access_job(principal, job.created_by.as_deref(), job_id) {
+                    info!(
+                        job_id = %job_id,
+                        principal = %principal.id,
+                        "Rejected poll of a job owned by another principal"
+                    );
+                    return create_cors_response(
+                        403,
+                        Some(json!({"error": "Job belongs to a different API key"}).to_string()),
+                    );
+                }
+                // Download links expire, so a response carrying them is never reused
+                let etag = job_etag(&job).filter(|_| job.status != JobStatus::Success);
+                if let Some(etag) = &etag {
+                    let unchanged = payload
+                        .headers
+                        .get("if-none-match")
+                        .and_then(|value| value.to_str().ok())
+                        .is_some_and(|if_none_match| etag_matches(if_none_match, etag));
+                    if unchanged {
+                        return create_cors_response_with_headers(
+                            304,
+                            None,
+                            &[("ETag", etag.as_str())],
+                        );
                     }
-                    _ => String::new(),
-                };
+                }
 
-                let schema = match item.get("schema") {
-                    Some(aws_sdk_dynamodb::types::AttributeValue::M(schema_map)) => {
-                        let mut result_map = HashMap::new();
-                        for (key, value) in schema_map {
-                            if let aws_sdk_dynamodb::types::AttributeValue::S(string_value) = value
-                            {
-                                result_map.insert(key.clone(), string_value.clone());
-                            }
-                        }
-                        result_map
-                    }
-                    _ => HashMap::new(),
-                };
+                let response_body = status_payload(&job, job_id).await;
 
-                let parquet_complete = match status {
-                    "success" => true,
-                    "pending" => false,
-                    _ => {
-                        return Ok(create_cors_response(
-                            400,
-                            Some(json!({"error": "Invalid status value"}).to_string()),
-                        ));
-                    }
-                };
-
-                let response_body = json!({
-                    "statusCode": 200,
-                    "parquet_complete": parquet_complete,
-                    "context": context,
-                    "schema": schema
-                });
-
-                Ok(create_cors_response(200, Some(response_body.to_string())))
+                let headers: Vec<(&'static str, &str)> =
+                    etag.iter().map(|etag| ("ETag", etag.as_str())).collect();
+                create_cors_response_with_headers(200, Some(response_body.to_string()), &headers)
             }
-            None => Ok(create_cors_response(
-                404,
-                Some(json!({"error": "Job not found"}).to_string()),
-            )),
+            None => create_cors_response(404, Some(json!({"error": "Job not found"}).to_string())),
         },
         Err(e) => {
-            eprintln!("DynamoDB error: {:?}", e);
-            Ok(create_cors_response(
+            error!(job_id = %job_id, error = ?e, "DynamoDB error");
+            create_cors_response(
                 500,
                 Some(json!({"error": "Internal server error"}).to_string()),
-            ))
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchPollRequest {
+    job_ids: Vec<String>,
+}
+
+// Polls up to MAX_BATCH_POLL_JOBS jobs in one request and answers with a map of job_id to
+// the same payload a single poll returns. IDs with no job get `"found": false` rather than
+// failing the batch, and one job that can't be reported, or belongs to another API key,
+// doesn't hide the others.
+async fn poll_batch(
+    client: &Client,
+    payload: &ApiGatewayProxyRequest,
+    table_name: &str,
+    principal: &Principal,
+) -> ApiGatewayProxyResponse {
+    let body = payload.body.as_deref().unwrap_or_default();
+    let request: BatchPollRequest = match serde_json::from_str(body) {
+        Ok(request) => request,
+        Err(e) => {
+            return create_cors_response(
+                400,
+                Some(json!({"error": format!("Invalid batch poll request: {}", e)}).to_string()),
+            );
         }
+    };
+
+    if request.job_ids.is_empty() || request.job_ids.len() > MAX_BATCH_POLL_JOBS {
+        return create_cors_response(
+            400,
+            Some(
+                json!({
+                    "error": format!(
+                        "job_ids must contain between 1 and {} IDs",
+                        MAX_BATCH_POLL_JOBS
+                    )
+                })
+                .to_string(),
+            ),
+        );
+    }
+    if request
+        .job_ids
+        .iter()
+        .any(|job_id| job_id.trim().is_empty())
+    {
+        return create_cors_response(
+            400,
+            Some(json!({"error": "job_ids must not contain empty IDs"}).to_string()),
+        );
+    }
+
+    let mut items = match batch_get_jobs(client, table_name, &request.job_ids).await {
+        Ok(items) => items,
+        Err(e) => {
+            error!(jobs = request.job_ids.len(), error = %e, "Batch job read failed");
+            return create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            );
+        }
+    };
+
+    let mut jobs = Map::with_capacity(request.job_ids.len());
+    for job_id in &request.job_ids {
+        if jobs.contains_key(job_id) {
+            continue;
+        }
+
+        let Some(item) = items.remove(job_id) else {
+            jobs.insert(
+                job_id.clone(),
+                json!({"found": false, "error": "Job not found"}),
+            );
+            continue;
+        };
+
+        let job = match Job::from_dynamodb_item(item) {
+            Ok(job) => job,
+            Err(e) => {
+                error!(job_id = %job_id, error = %e, "Job item is malformed");
+                jobs.insert(
+                    job_id.clone(),
+                    json!({"found": true, "error": "Status field not found or invalid type"}),
+                );
+                continue;
+            }
+        };
+
+        if !may_access_job(principal, job.created_by.as_deref(), job_id) {
+            info!(
+                job_id = %job_id,
+                principal = %principal.id,
+                "Left a job owned by another principal out of a batch poll"
+            );
+            jobs.insert(
+                job_id.clone(),
+                json!({
+                    "found": true,
+                    "statusCode": 403,
+                    "error": "Job belongs to a different API key"
+                }),
+            );
+            continue;
+        }
+
+        let mut job_body = status_payload(&job, job_id).await;
+        job_body["found"] = json!(true);
+        jobs.insert(job_id.clone(), job_body);
+    }
+
+    create_cors_response(200, Some(json!({"jobs": jobs}).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_lambda_events::http::HeaderValue;
+    use common::test_support::{StubEndpoint, StubResponse};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const UPDATED_AT: &str = "2025-06-03T10:00:00.000Z";
+
+    fn principal() -> Principal {
+        Principal {
+            id: "key-1".to_string(),
+        }
+    }
+
+    fn job_item(id: &str, status: JobStatus) -> Value {
+        json!({
+            "service": {"S": format!("JOB-{}", id)},
+            "serviceId": {"S": id},
+            "created_by": {"S": "key-1"},
+            "status": {"S": status.as_str()},
+            "updated_at": {"S": UPDATED_AT},
+            "error_message": {"S": "Column 3 is not a number"},
+            "error_stage": {"S": "convert"}
+        })
+    }
+
+    fn job(status: JobStatus, updated_at: Option<&str>) -> Job {
+        Job {
+            status,
+            updated_at: updated_at.map(String::from),
+            ..Job::default()
+        }
+    }
+
+    fn get_request(job_id: &str, if_none_match: Option<&str>) -> ApiGatewayProxyRequest {
+        let mut request = ApiGatewayProxyRequest {
+            http_method: "GET".parse().unwrap(),
+            path_parameters: HashMap::from([("job_id".to_string(), job_id.to_string())]),
+            ..ApiGatewayProxyRequest::default()
+        };
+        if let Some(if_none_match) = if_none_match {
+            request.headers.insert(
+                "if-none-match",
+                HeaderValue::from_str(if_none_match).unwrap(),
+            );
+        }
+        request
+    }
+
+    fn batch_request(job_ids: &[String]) -> ApiGatewayProxyRequest {
+        ApiGatewayProxyRequest {
+            http_method: "POST".parse().unwrap(),
+            body: Some(json!({"job_ids": job_ids}).to_string()),
+            ..ApiGatewayProxyRequest::default()
+        }
+    }
+
+    fn body(response: &ApiGatewayProxyResponse) -> Value {
+        match &response.body {
+            Some(aws_lambda_events::encodings::Body::Text(text)) => {
+                serde_json::from_str(text).unwrap()
+            }
+            other => panic!("unexpected body: {:?}", other),
+        }
+    }
+
+    fn etag_header(response: &ApiGatewayProxyResponse) -> Option<String> {
+        response
+            .headers
+            .get("ETag")
+            .map(|etag| etag.to_str().unwrap().to_string())
+    }
+
+    // The keys a BatchGetItem request asked for, by job ID
+    fn requested_ids(request: &Value) -> Vec<String> {
+        request["RequestItems"]["jobs"]["Keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["serviceId"]["S"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn ids(count: usize) -> Vec<String> {
+        (0..count).map(|n| format!("job-{}", n)).collect()
+    }
+
+    #[test]
+    fn parquet_is_only_complete_once_the_job_succeeds() {
+        for status in JobStatus::ALL {
+            assert_eq!(
+                parquet_complete(status),
+                status == JobStatus::Success,
+                "{:?}",
+                status
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn each_status_is_reported_with_what_it_means() {
+        for status in JobStatus::ALL {
+            let stub = StubEndpoint::start(move |_| {
+                StubResponse::json(json!({"Item": job_item("job-1", status)}))
+            });
+
+            let response = poll_job(
+                &stub.dynamodb_client(),
+                &get_request("job-1", None),
+                "jobs",
+                &principal(),
+            )
+            .await;
+
+            assert_eq!(response.status_code, 200, "{:?}", status);
+            let body = body(&response);
+            assert_eq!(body["status"], status.as_str());
+            assert_eq!(body["parquet_complete"], status == JobStatus::Success);
+            // Only a failed job explains itself
+            if status == JobStatus::Failed {
+                assert_eq!(body["error_message"], "Column 3 is not a number");
+                assert_eq!(body["error_stage"], "convert");
+            } else {
+                assert!(body.get("error_message").is_none(), "{:?}", status);
+            }
+            // A finished job's response carries a download link, so it is never revalidated
+            assert_eq!(
+                etag_header(&response).is_some(),
+                status != JobStatus::Success,
+                "{:?}",
+                status
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_missing_job_is_not_found() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+
+        let response = poll_job(
+            &stub.dynamodb_client(),
+            &get_request("job-1", None),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 404);
+    }
+
+    #[tokio::test]
+    async fn another_keys_job_is_refused() {
+        let stub = StubEndpoint::start(|_| {
+            let mut item = job_item("job-1", JobStatus::Success);
+            item["created_by"] = json!({"S": "key-2"});
+            StubResponse::json(json!({"Item": item}))
+        });
+
+        let response = poll_job(
+            &stub.dynamodb_client(),
+            &get_request("job-1", None),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 403);
+    }
+
+    #[tokio::test]
+    async fn a_status_this_version_doesnt_know_is_an_error() {
+        let stub = StubEndpoint::start(|_| {
+            let mut item = job_item("job-1", JobStatus::Pending);
+            item["status"] = json!({"S": "paused"});
+            StubResponse::json(json!({"Item": item}))
+        });
+
+        let response = poll_job(
+            &stub.dynamodb_client(),
+            &get_request("job-1", None),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 500);
+    }
+
+    #[test]
+    fn an_etag_changes_with_the_status_or_the_last_write() {
+        let etag = job_etag(&job(JobStatus::Processing, Some(UPDATED_AT))).unwrap();
+
+        assert!(etag.starts_with("W/\""));
+        assert_eq!(
+            job_etag(&job(JobStatus::Processing, Some(UPDATED_AT))),
+            Some(etag.clone())
+        );
+        assert_ne!(
+            job_etag(&job(JobStatus::Failed, Some(UPDATED_AT))),
+            Some(etag.clone())
+        );
+        assert_ne!(
+            job_etag(&job(
+                JobStatus::Processing,
+                Some("2025-06-03T10:00:01.000Z")
+            )),
+            Some(etag)
+        );
+    }
+
+    #[test]
+    fn a_job_without_updated_at_has_no_etag() {
+        assert_eq!(job_etag(&job(JobStatus::Processing, None)), None);
+    }
+
+    #[test]
+    fn if_none_match_compares_tags_weakly() {
+        let etag = "W/\"abc\"";
+
+        assert!(etag_matches("W/\"abc\"", etag));
+        assert!(etag_matches("\"abc\"", etag));
+        assert!(etag_matches("\"xyz\", W/\"abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("W/\"xyz\"", etag));
+        assert!(!etag_matches("", etag));
+    }
+
+    #[tokio::test]
+    async fn an_unchanged_job_is_not_modified() {
+        let stub = StubEndpoint::start(|_| {
+            StubResponse::json(json!({"Item": job_item("job-1", JobStatus::Processing)}))
+        });
+        let client = stub.dynamodb_client();
+        let first = poll_job(&client, &get_request("job-1", None), "jobs", &principal()).await;
+        let etag = etag_header(&first).unwrap();
+
+        let unchanged = poll_job(
+            &client,
+            &get_request("job-1", Some(&etag)),
+            "jobs",
+            &principal(),
+        )
+        .await;
+        let stale = poll_job(
+            &client,
+            &get_request("job-1", Some("W/\"stale\"")),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        assert_eq!(unchanged.status_code, 304);
+        assert!(unchanged.body.is_none());
+        assert_eq!(etag_header(&unchanged), Some(etag));
+        assert_eq!(stale.status_code, 200);
+        assert_eq!(body(&stale)["status"], "processing");
+    }
+
+    #[tokio::test]
+    async fn a_job_without_updated_at_is_always_sent_in_full() {
+        let stub = StubEndpoint::start(|_| {
+            let mut item = job_item("job-1", JobStatus::Processing);
+            item.as_object_mut().unwrap().remove("updated_at");
+            StubResponse::json(json!({"Item": item}))
+        });
+
+        let response = poll_job(
+            &stub.dynamodb_client(),
+            &get_request("job-1", Some("*")),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 200);
+        assert_eq!(etag_header(&response), None);
+    }
+
+    #[tokio::test]
+    async fn a_full_batch_is_read_in_chunks() {
+        let stub = StubEndpoint::start(|request| {
+            let items: Vec<Value> = requested_ids(&request.json())
+                .iter()
+                .map(|id| job_item(id, JobStatus::Pending))
+                .collect();
+            StubResponse::json(json!({"Responses": {"jobs": items}}))
+        });
+        let job_ids = ids(MAX_BATCH_POLL_JOBS);
+
+        let response = poll_batch(
+            &stub.dynamodb_client(),
+            &batch_request(&job_ids),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 200);
+        let reads: Vec<Vec<String>> = stub
+            .operations("BatchGetItem")
+            .iter()
+            .map(|request| requested_ids(&request.json()))
+            .collect();
+        assert_eq!(reads.len(), 2);
+        assert!(reads.iter().all(|read| read.len() == 25));
+        let jobs = &body(&response)["jobs"];
+        assert!(
+            job_ids
+                .iter()
+                .all(|id| jobs[id]["found"] == true && jobs[id]["status"] == "pending")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_batch_past_the_limit_is_refused_unread() {
+        let stub = StubEndpoint::start(|_| StubResponse::json(json!({})));
+
+        let response = poll_batch(
+            &stub.dynamodb_client(),
+            &batch_request(&ids(MAX_BATCH_POLL_JOBS + 1)),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        assert_eq!(response.status_code, 400);
+        assert!(stub.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unprocessed_keys_are_read_again() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let stub = StubEndpoint::start(move |request| {
+            let requested = requested_ids(&request.json());
+            // The first read only manages the first job and hands back the rest
+            if seen.fetch_add(1, Ordering::SeqCst) == 0 {
+                let unprocessed: Vec<Value> = requested[1..]
+                    .iter()
+                    .map(|id| json!({"service": {"S": format!("JOB-{}", id)}, "serviceId": {"S": id}}))
+                    .collect();
+                return StubResponse::json(json!({
+                    "Responses": {"jobs": [job_item(&requested[0], JobStatus::Success)]},
+                    "UnprocessedKeys": {"jobs": {"Keys": unprocessed}}
+                }));
+            }
+            let items: Vec<Value> = requested
+                .iter()
+                .map(|id| job_item(id, JobStatus::Processing))
+                .collect();
+            StubResponse::json(json!({"Responses": {"jobs": items}}))
+        });
+
+        let response = poll_batch(
+            &stub.dynamodb_client(),
+            &batch_request(&ids(3)),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        let reads = stub.operations("BatchGetItem");
+        assert_eq!(reads.len(), 2);
+        assert_eq!(requested_ids(&reads[1].json()), ["job-1", "job-2"]);
+        let jobs = &body(&response)["jobs"];
+        assert_eq!(jobs["job-0"]["status"], "success");
+        assert_eq!(jobs["job-1"]["status"], "processing");
+        assert_eq!(jobs["job-2"]["status"], "processing");
+    }
+
+    #[tokio::test]
+    async fn a_batch_reports_missing_and_foreign_jobs_alongside_the_rest() {
+        let stub = StubEndpoint::start(|_| {
+            let mut foreign = job_item("job-2", JobStatus::Pending);
+            foreign["created_by"] = json!({"S": "key-2"});
+            StubResponse::json(json!({"Responses": {"jobs": [
+                job_item("job-0", JobStatus::Failed),
+                foreign
+            ]}}))
+        });
+
+        let response = poll_batch(
+            &stub.dynamodb_client(),
+            &batch_request(&ids(3)),
+            "jobs",
+            &principal(),
+        )
+        .await;
+
+        let jobs = &body(&response)["jobs"];
+        assert_eq!(jobs["job-0"]["status"], "failed");
+        assert_eq!(jobs["job-1"]["found"], false);
+        assert_eq!(jobs["job-2"]["statusCode"], 403);
+        assert!(jobs["job-2"].get("status").is_none());
     }
 }
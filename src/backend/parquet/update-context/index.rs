@@ -1,9 +1,13 @@
 use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
 use aws_sdk_dynamodb::Client;
+use common::auth::{authorize, may_access_job};
 use common::cors::create_cors_response;
+use common::dynamo::{get_job_by_id, timestamp_now};
+use common::logging::{init_tracing, redact};
 use lambda_runtime::{Error, LambdaEvent, run, service_fn};
 use serde::Deserialize;
 use serde_json::json;
+use tracing::{debug, error, info};
 
 #[derive(Deserialize, Debug)]
 struct UpdateContextRequest {
@@ -13,6 +17,8 @@ struct UpdateContextRequest {
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    init_tracing();
+
     run(service_fn(function_handler)).await
 }
 
@@ -23,6 +29,15 @@ async fn function_handler(
         return Ok(create_cors_response(200, None));
     }
 
+    let table_name = std::env::var("DYNAMODB_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let client = Client::new(&config);
+
+    let principal = match authorize(&event.payload, &client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
     let body = event.payload.body.unwrap_or_default();
     let request: UpdateContextRequest = match serde_json::from_str(&body) {
         Ok(req) => req,
@@ -34,12 +49,40 @@ async fn function_handler(
         }
     };
 
-    println!("{:?}", request);
+    debug!(
+        job_id = %request.job_id,
+        context = %redact(&request.context),
+        "Updating job context"
+    );
 
-    let config = aws_config::load_from_env().await;
-    let client = Client::new(&config);
+    let job = match get_job_by_id(&client, &table_name, &request.job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            return Ok(create_cors_response(
+                404,
+                Some(json!({"error": "Job not found"}).to_string()),
+            ));
+        }
+        Err(e) => {
+            error!(job_id = %request.job_id, error = %e, "Failed to load job");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            ));
+        }
+    };
 
-    let table_name = std::env::var("DYNAMODB_NAME")?;
+    if !may_access_job(&principal, job.created_by.as_deref(), &request.job_id) {
+        info!(
+            job_id = %request.job_id,
+            principal = %principal.id,
+            "Rejected context update for a job owned by another principal"
+        );
+        return Ok(create_cors_response(
+            403,
+            Some(json!({"error": "Job belongs to a different API key"}).to_string()),
+        ));
+    }
 
     let pk = format!("JOB-{}", request.job_id);
 
@@ -54,12 +97,18 @@ async fn function_handler(
             "serviceId",
             aws_sdk_dynamodb::types::AttributeValue::S(request.job_id.to_string()),
         )
-        .update_expression("SET #ctx = :context")
+        .update_expression("SET #ctx = :context, updated_at = :now")
+        // Without this an unknown job_id would create a stray item holding only a context
+        .condition_expression("attribute_exists(service)")
         .expression_attribute_names("#ctx", "context")
         .expression_attribute_values(
             ":context",
             aws_sdk_dynamodb::types::AttributeValue::S(request.context.to_string()),
         )
+        .expression_attribute_values(
+            ":now",
+            aws_sdk_dynamodb::types::AttributeValue::S(timestamp_now()),
+        )
         .send()
         .await;
 
@@ -72,8 +121,18 @@ async fn function_handler(
 
             Ok(create_cors_response(200, Some(response_body.to_string())))
         }
+        Err(e)
+            if e.as_service_error().is_some_and(|service_error| {
+                service_error.is_conditional_check_failed_exception()
+            }) =>
+        {
+            Ok(create_cors_response(
+                404,
+                Some(json!({"error": "Job not found"}).to_string()),
+            ))
+        }
         Err(e) => {
-            eprintln!("DynamoDB error: {:?}", e);
+            error!(job_id = %request.job_id, error = ?e, "DynamoDB error");
             Ok(create_cors_response(
                 500,
                 Some(json!({"error": "Failed to update context"}).to_string()),
@@ -0,0 +1,125 @@
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_sdk_dynamodb::Client;
+use common::auth::{authorize, may_access_job};
+use common::cors::create_cors_response;
+use common::creation_validation::parse_labels;
+use common::dynamo::{get_job_by_id, update_job_labels};
+use common::logging::init_tracing;
+use lambda_runtime::{Error, LambdaEvent, run, service_fn};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::{error, info};
+
+#[derive(Deserialize, Debug)]
+struct UpdateLabelsRequest {
+    labels: Value,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    init_tracing();
+
+    run(service_fn(function_handler)).await
+}
+
+// Replaces a job's labels with the ones given; `{"labels": {}}` clears them. Output already
+// written keeps the labels it was converted with.
+async fn function_handler(
+    event: LambdaEvent<ApiGatewayProxyRequest>,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    if event.payload.http_method == "OPTIONS" {
+        return Ok(create_cors_response(200, None));
+    }
+
+    let table_name = std::env::var("DYNAMODB_NAME")?;
+    let config = aws_config::load_from_env().await;
+    let client = Client::new(&config);
+
+    let principal = match authorize(&event.payload, &client, &table_name).await {
+        Ok(principal) => principal,
+        Err(e) => return Ok(e.to_response()),
+    };
+
+    let job_id = match event.payload.path_parameters.get("job_id") {
+        Some(id) => id,
+        None => {
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Missing job_id in path"}).to_string()),
+            ));
+        }
+    };
+
+    let body = event.payload.body.as_deref().unwrap_or_default();
+    let request: UpdateLabelsRequest = match serde_json::from_str(body) {
+        Ok(req) => req,
+        Err(e) => {
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": format!("Invalid request body: {}", e)}).to_string()),
+            ));
+        }
+    };
+
+    let labels = match parse_labels(&request.labels) {
+        Ok(labels) => labels,
+        Err(errors) => {
+            return Ok(create_cors_response(
+                400,
+                Some(json!({"error": "Invalid labels", "details": errors}).to_string()),
+            ));
+        }
+    };
+
+    let job = match get_job_by_id(&client, &table_name, job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            return Ok(create_cors_response(
+                404,
+                Some(json!({"error": "Job not found"}).to_string()),
+            ));
+        }
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "Failed to load job");
+            return Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Internal server error"}).to_string()),
+            ));
+        }
+    };
+
+    if !may_access_job(&principal, job.created_by.as_deref(), job_id) {
+        info!(
+            job_id = %job_id,
+            principal = %principal.id,
+            "Rejected label update for a job owned by another principal"
+        );
+        return Ok(create_cors_response(
+            403,
+            Some(json!({"error": "Job belongs to a different API key"}).to_string()),
+        ));
+    }
+
+    match update_job_labels(&client, &table_name, job_id, &labels).await {
+        Ok(true) => {
+            let response_body = json!({
+                "statusCode": 200,
+                "labels": labels,
+                "message": "Labels updated successfully"
+            });
+
+            Ok(create_cors_response(200, Some(response_body.to_string())))
+        }
+        Ok(false) => Ok(create_cors_response(
+            404,
+            Some(json!({"error": "Job not found"}).to_string()),
+        )),
+        Err(e) => {
+            error!(job_id = %job_id, error = %e, "DynamoDB error");
+            Ok(create_cors_response(
+                500,
+                Some(json!({"error": "Failed to update labels"}).to_string()),
+            ))
+        }
+    }
+}
@@ -16,7 +16,6 @@ export const load: LayoutServerLoad = async () => {
 
 	return {
 		env: {
-			CORE_API_URL: process.env.PRIVATE_CORE_API_URL!,
 			S3_BUCKET_NAME: process.env.PRIVATE_S3_BUCKET_NAME,
 			PRESIGNED_URL: url,
 			job_id,
@@ -0,0 +1,46 @@
+import { error } from '@sveltejs/kit';
+import type { RequestHandler } from './$types';
+
+// The core API calls the app makes. They go through here so the API key stays on the
+// server rather than being handed to every browser in the page data.
+const PROXIED_PATHS = [
+	/^parquet-creation$/,
+	/^generate-parquet-query$/,
+	/^update-context$/,
+	/^poll-parquet-status\/[\w-]+$/,
+	/^cancel-parquet-job\/[\w-]+$/
+];
+
+const proxy: RequestHandler = async ({ params, request, url }) => {
+	if (!PROXIED_PATHS.some((pattern) => pattern.test(params.path))) {
+		error(404, 'Not found');
+	}
+
+	const headers: Record<string, string> = {
+		'Content-Type': 'application/json',
+		'x-api-key': process.env.PRIVATE_CORE_API_KEY!
+	};
+	const ifNoneMatch = request.headers.get('if-none-match');
+	if (ifNoneMatch) {
+		headers['If-None-Match'] = ifNoneMatch;
+	}
+
+	const response = await fetch(`${process.env.PRIVATE_CORE_API_URL!}/${params.path}${url.search}`, {
+		method: request.method,
+		headers,
+		body: request.method === 'GET' ? undefined : await request.text()
+	});
+
+	// The body is passed on as it arrives, so streamed query events aren't held back
+	const responseHeaders = new Headers();
+	for (const name of ['content-type', 'etag', 'cache-control']) {
+		const value = response.headers.get(name);
+		if (value) {
+			responseHeaders.set(name, value);
+		}
+	}
+	return new Response(response.body, { status: response.status, headers: responseHeaders });
+};
+
+export const GET = proxy;
+export const POST = proxy;
@@ -1,10 +1,9 @@
 export async function generateResponseFromMessage(
-	CORE_API_URL: string,
 	message: string,
 	parquet_key: string,
 	job_id: string
 ): Promise<{ statusCode: number; response_message: string }> {
-	const response = await fetch(`${CORE_API_URL}/generate-parquet-query`, {
+	const response = await fetch('/api/generate-parquet-query', {
 		method: 'POST',
 		headers: {
 			'Content-Type': 'application/json'
@@ -23,16 +22,15 @@ export async function generateResponseFromMessage(
 	return { statusCode: response.status, response_message };
 }
 
-export async function pollStatus(
-	CORE_API_URL: string,
-	job_id: string
-): Promise<{
+export async function pollStatus(job_id: string): Promise<{
 	statusCode: number;
 	parquet_complete: boolean;
+	status?: string;
+	error_message?: string;
 	context?: string;
 	schema?: { [key: string]: string };
 }> {
-	const response = await fetch(`${CORE_API_URL}/poll-parquet-status/${job_id}`, {
+	const response = await fetch(`/api/poll-parquet-status/${job_id}`, {
 		method: 'GET',
 		headers: {
 			'Content-Type': 'application/json'
@@ -44,7 +42,26 @@ export async function pollStatus(
 	return {
 		statusCode: response.status,
 		parquet_complete: body.parquet_complete,
+		status: body.status,
+		error_message: body.error_message,
 		context: body.context,
 		schema: body.schema
 	};
 }
+
+export async function cancelJob(job_id: string): Promise<{ statusCode: number; status?: string }> {
+	const response = await fetch(`/api/cancel-parquet-job/${job_id}`, {
+		method: 'POST',
+		headers: {
+			'Content-Type': 'application/json'
+		}
+	});
+
+	const body = await response.json();
+
+	if (response.status !== 200) {
+		throw new Error(JSON.stringify({ error: body.error, detail: body.detail }));
+	}
+
+	return { statusCode: response.status, status: body.status };
+}
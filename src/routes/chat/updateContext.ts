@@ -1,9 +1,8 @@
 export async function updateContext(
-	CORE_API_URL: string,
 	context: string,
 	job_id: string
 ): Promise<{ statusCode: number }> {
-	const response = await fetch(`${CORE_API_URL}/update-context`, {
+	const response = await fetch('/api/update-context', {
 		method: 'POST',
 		headers: {
 			'Content-Type': 'application/json'
@@ -1,5 +1,4 @@
 export async function parseCsvToParquet(
-	CORE_API_URL: string,
 	payload: { column: string; type: string }[],
 	s3_key: string,
 	job_id: string,
@@ -7,7 +6,7 @@ export async function parseCsvToParquet(
 	schema: { [key: string]: string }
 ): Promise<{ statusCode: number; parquet_key: string }> {
 	console.log('WHAT IS SCHEMA', schema);
-	const response = await fetch(`${CORE_API_URL}/parquet-creation`, {
+	const response = await fetch('/api/parquet-creation', {
 		method: 'POST',
 		headers: {
 			'Content-Type': 'application/json'
@@ -20,11 +20,15 @@ export default $config({
 		const dynamo = await import('./infrastructure/dynamo.ts');
 		const coreApi = await import('./infrastructure/api.ts');
 
+		// The web app's own API key; its SHA-256 must be stored as an APIKEY item in the table
+		const coreApiKey = new sst.Secret('CoreApiKey');
+
 		new sst.aws.SvelteKit('easyCSVFe', {
 			link: [coreApi, storage.s3Bucket],
 			environment: {
 				PRIVATE_CORE_API_URL: coreApi.apiGateway.url,
-				PRIVATE_S3_BUCKET_NAME: storage.s3Bucket.name
+				PRIVATE_S3_BUCKET_NAME: storage.s3Bucket.name,
+				PRIVATE_CORE_API_KEY: coreApiKey.value
 			}
 		});
 	}